@@ -0,0 +1,160 @@
+//! Compares a Shard that replays its writes lazily, on the next write, with one that replays them
+//! eagerly, right after they are published, and with the copied layout of the previous design,
+//! where each of the two maps held its own copy of every key and value. Both modes of the Shard
+//! keep a single copy.
+//!
+//! The lazy writer holds on to the log of its last write, for a large batch that is a handle to
+//! every key and value in it. The eager one waits for the readers of the stale map on every write
+//! instead of on the next one. Small values come out ahead in the copied layout because every
+//! shard entry also carries its revisions, expiry and a slot in the sorted key index.
+//!
+//! Run with `cargo bench --bench memory`.
+
+use bytes::Bytes;
+use criterion::black_box;
+use r_db::storage::engine::EngineWriter;
+use r_db::storage::shard::{Replay, Shard};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const KEYS: usize = 100_000;
+const WRITES: usize = 10_000;
+const READERS: usize = 4;
+
+fn measure<T>(build: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Relaxed);
+    let result = build();
+    (result, ALLOCATED.load(Relaxed) - before)
+}
+
+// Fills a shard with a single batch, which stays in the log of a lazy writer until the next write
+fn shard(value_size: usize, replay: Replay) -> usize {
+    let (shard, bytes) = measure(|| {
+        let shard = Shard::new(0);
+        {
+            let writer = shard.writer();
+            let mut writer = writer.lock().unwrap();
+            writer.set_replay(replay);
+            let mut batch = writer.batch();
+            for i in 0..KEYS {
+                let key = Bytes::from(format!("key-{:012}", i));
+                batch.put(key, Bytes::from(vec![b'x'; value_size]));
+            }
+            batch.commit().unwrap();
+        }
+        shard
+    });
+    drop(shard);
+    bytes
+}
+
+// Two maps with their own copy of every key and value
+fn copied(value_size: usize) -> usize {
+    let (maps, bytes) = measure(|| {
+        let mut first = HashMap::new();
+        let mut second = HashMap::new();
+        for i in 0..KEYS {
+            let key = Bytes::from(format!("key-{:012}", i));
+            let val = Bytes::from(vec![b'x'; value_size]);
+            // A clone of Bytes shares the buffer, so the second map gets a copy of it
+            second.insert(Bytes::from(&key[..]), Bytes::from(&val[..]));
+            first.insert(key, val);
+        }
+        (first, second)
+    });
+    drop(maps);
+    bytes
+}
+
+// The average time of a single put while READERS threads keep reading the shard
+fn write_time(replay: Replay) -> Duration {
+    let shard = Shard::new(0);
+    let writer = shard.writer();
+    let mut writer = writer.lock().unwrap();
+    writer.set_replay(replay);
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..READERS)
+        .map(|t| {
+            let reader = shard.reader();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut i = t;
+                while !done.load(Relaxed) {
+                    let key = Bytes::from(format!("key-{:012}", i % WRITES));
+                    black_box(reader.get(&key));
+                    i += 1;
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    for i in 0..WRITES {
+        let key = Bytes::from(format!("key-{:012}", i));
+        writer.put(key, Bytes::from(vec![b'x'; 64])).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    done.store(true, Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    elapsed / WRITES as u32
+}
+
+fn main() {
+    println!("{} keys", KEYS);
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "value size", "copied (MiB)", "lazy (MiB)", "eager (MiB)", "lazy/copied", "eager/copied"
+    );
+    let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+    for &value_size in &[16, 64, 256, 1024, 4096] {
+        let copied = copied(value_size);
+        let lazy = shard(value_size, Replay::Lazy);
+        let eager = shard(value_size, Replay::Eager);
+        println!(
+            "{:>10} {:>12.2} {:>12.2} {:>12.2} {:>12.2} {:>12.2}",
+            value_size,
+            mib(copied),
+            mib(lazy),
+            mib(eager),
+            lazy as f64 / copied as f64,
+            eager as f64 / copied as f64
+        );
+    }
+
+    println!();
+    println!("{} puts with {} reader threads", WRITES, READERS);
+    for &replay in &[Replay::Lazy, Replay::Eager] {
+        let time = write_time(replay);
+        println!(
+            "{:>10} {:>12} per put",
+            format!("{:?}", replay),
+            format!("{:?}", time)
+        );
+    }
+}
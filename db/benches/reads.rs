@@ -0,0 +1,87 @@
+//! Read throughput of a Shard with each Reclamation strategy as the number of reader threads grows.
+//!
+//! Run with `cargo bench --bench reads`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use r_db::storage::engine::EngineWriter;
+use r_db::storage::reclamation::{Reclamation, ShardedCounters, SharedCounters};
+use r_db::storage::shard::Shard;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 10_000;
+const THREADS: [usize; 3] = [1, 8, 32];
+
+fn shard<R: Reclamation>() -> (Shard<R>, Vec<Bytes>) {
+    let shard = Shard::with_reclamation(0);
+    let keys: Vec<_> = (0..KEYS)
+        .map(|i| Bytes::from(format!("key-{:08}", i)))
+        .collect();
+    {
+        let writer = shard.writer();
+        let mut writer = writer.lock().unwrap();
+        let mut batch = writer.batch();
+        for key in &keys {
+            batch.put(key.clone(), Bytes::from(vec![b'x'; 100]));
+        }
+        batch.commit().unwrap();
+    }
+    (shard, keys)
+}
+
+// Every thread does `iters` reads. Returns the time until the slowest one is done.
+fn read<R: Reclamation>(
+    shard: &Shard<R>,
+    keys: &Arc<Vec<Bytes>>,
+    threads: usize,
+    iters: u64,
+) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let reader = shard.reader();
+            let keys = keys.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..iters as usize {
+                    let key = &keys[(i * 7919 + t) % keys.len()];
+                    criterion::black_box(reader.get(key));
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench<R: Reclamation>(c: &mut Criterion, name: &str) {
+    let (shard, keys) = shard::<R>();
+    let keys = Arc::new(keys);
+
+    let mut group = c.benchmark_group(format!("reads/{}", name));
+    for &threads in &THREADS {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| b.iter_custom(|iters| read(&shard, &keys, threads, iters)),
+        );
+    }
+    group.finish();
+}
+
+fn reads(c: &mut Criterion) {
+    bench::<SharedCounters>(c, "shared");
+    bench::<ShardedCounters>(c, "sharded");
+}
+
+criterion_group!(benches, reads);
+criterion_main!(benches);
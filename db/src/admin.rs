@@ -0,0 +1,501 @@
+use crate::api::admin_api::admin_server::Admin;
+use crate::api::admin_api::{
+    self as api, ChangeRaftMemberRequest, ChangeRaftMemberResponse, CreateShardRequest,
+    CreateShardResponse, DescribeShardRequest, DescribeShardResponse, DropShardRequest,
+    DropShardResponse, ExportShardRequest, ExportShardResponse, ImportShardRequest,
+    ImportShardResponse, ListShardsRequest, ListShardsResponse, RaftMember, SetReplicationRequest,
+    SetReplicationResponse, ShardInfo, ShardRecord, SnapshotRequest, SnapshotResponse,
+    StartRaftRequest, StartRaftResponse,
+};
+use crate::raft::transport::Transport;
+use crate::raft::{Member, NodeId, RaftConfig};
+use crate::replication::{self, Backup, RemoteBackup};
+use crate::storage::engine::EngineKind;
+use crate::storage::error::StorageError;
+use crate::storage::shard_map::{Role, ShardMap, ShardState, ShardStats};
+use crate::storage::types::{Key, Record, Val};
+use crate::storage::wal::SyncPolicy;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+// Number of records in a single message of an export
+const EXPORT_CHUNK_SIZE: usize = 1000;
+// Number of messages of an export that are buffered for a slow client
+const EXPORT_BUFFER: usize = 16;
+// How long a write to a primary waits for its backups by default
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Creates, drops and describes the shards of the node. The data of the shards is served by
+/// StorageService.
+#[derive(Clone)]
+pub struct AdminService {
+    shard_map: Arc<ShardMap>,
+    // The id of the node in the Raft groups of its shards
+    node_id: NodeId,
+    transport: Arc<dyn Transport>,
+}
+
+impl AdminService {
+    pub fn new(shard_map: Arc<ShardMap>, node_id: NodeId, transport: Arc<dyn Transport>) -> Self {
+        Self {
+            shard_map,
+            node_id,
+            transport,
+        }
+    }
+
+    fn engine(engine: i32) -> Result<EngineKind, Status> {
+        match api::Engine::from_i32(engine) {
+            Some(api::Engine::LeftRight) => Ok(EngineKind::LeftRight),
+            Some(api::Engine::Locked) => Ok(EngineKind::Locked),
+            Some(api::Engine::Ordered) => Ok(EngineKind::Ordered),
+            Some(api::Engine::Striped) => Ok(EngineKind::Striped),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                format!("Unknown engine: {}", engine),
+            )),
+        }
+    }
+
+    fn wal(wal: i32, sync_interval_ms: u64) -> Result<Option<SyncPolicy>, Status> {
+        match api::Wal::from_i32(wal) {
+            Some(api::Wal::None) => Ok(None),
+            Some(api::Wal::SyncAlways) => Ok(Some(SyncPolicy::Always)),
+            Some(api::Wal::SyncInterval) if sync_interval_ms > 0 => Ok(Some(SyncPolicy::Interval(
+                Duration::from_millis(sync_interval_ms),
+            ))),
+            Some(api::Wal::SyncInterval) => Err(Status::new(
+                Code::InvalidArgument,
+                "SYNC_INTERVAL needs a sync_interval_ms",
+            )),
+            Some(api::Wal::SyncNever) => Ok(Some(SyncPolicy::Never)),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                format!("Unknown wal: {}", wal),
+            )),
+        }
+    }
+
+    fn role(request: &SetReplicationRequest) -> Result<Role, Status> {
+        match api::Role::from_i32(request.role) {
+            Some(api::Role::Standalone) => Ok(Role::Standalone),
+            Some(api::Role::Backup) => Ok(Role::Backup),
+            Some(api::Role::Primary) if request.acks as usize <= request.backups.len() => {
+                let timeout = match request.ack_timeout_ms {
+                    0 => DEFAULT_ACK_TIMEOUT,
+                    ms => Duration::from_millis(ms),
+                };
+                Ok(Role::Primary {
+                    backups: request.backups.len(),
+                    acks: request.acks as usize,
+                    timeout,
+                })
+            }
+            Some(api::Role::Primary) => Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "{} acks from {} backups",
+                    request.acks,
+                    request.backups.len()
+                ),
+            )),
+            Some(api::Role::Raft) => Err(Status::new(
+                Code::InvalidArgument,
+                "A shard joins a Raft group with StartRaft",
+            )),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                format!("Unknown role: {}", request.role),
+            )),
+        }
+    }
+
+    fn member(member: Option<RaftMember>) -> Result<Member, Status> {
+        match member {
+            Some(member) if member.id != 0 => Ok(Member {
+                id: member.id,
+                addr: member.addr,
+            }),
+            _ => Err(Status::new(Code::InvalidArgument, "A member needs an id")),
+        }
+    }
+
+    fn info(stats: ShardStats) -> ShardInfo {
+        let engine = match stats.engine {
+            EngineKind::LeftRight => api::Engine::LeftRight,
+            EngineKind::Locked => api::Engine::Locked,
+            EngineKind::Ordered => api::Engine::Ordered,
+            EngineKind::Striped => api::Engine::Striped,
+        };
+        let state = match stats.state {
+            ShardState::Active => api::ShardState::Active,
+            ShardState::Stopped => api::ShardState::Stopped,
+        };
+        let (wal, sync_interval) = match stats.wal {
+            None => (api::Wal::None, Duration::default()),
+            Some(SyncPolicy::Always) => (api::Wal::SyncAlways, Duration::default()),
+            Some(SyncPolicy::Interval(interval)) => (api::Wal::SyncInterval, interval),
+            Some(SyncPolicy::Never) => (api::Wal::SyncNever, Duration::default()),
+        };
+        let role = match (stats.role, &stats.raft) {
+            (_, Some(_)) => api::Role::Raft,
+            (Role::Standalone, None) => api::Role::Standalone,
+            (Role::Primary { .. }, None) => api::Role::Primary,
+            (Role::Backup, None) => api::Role::Backup,
+        };
+        let (raft_term, raft_leader) = stats.raft.map_or((0, 0), |status| {
+            (status.term, status.leader.unwrap_or_default())
+        });
+
+        ShardInfo {
+            shard_id: stats.shard_id as i64,
+            engine: engine as i32,
+            state: state as i32,
+            key_count: stats.key_count as u64,
+            approximate_bytes: stats.approximate_bytes as u64,
+            revision: stats.revision,
+            wal: wal as i32,
+            sync_interval_ms: sync_interval.as_millis() as u64,
+            role: role as i32,
+            raft_term,
+            raft_leader,
+        }
+    }
+}
+
+/// Converts a record of a shard for ExportShard and for the dumps of r_db-dump.
+pub fn export_record((key, record): (Key, Record)) -> ShardRecord {
+    let expires_at_ms = record.expires_at.map_or(0, |expires_at| {
+        expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    });
+
+    ShardRecord {
+        key: key.to_vec(),
+        val: record.val.to_vec(),
+        create_revision: record.create_revision,
+        mod_revision: record.mod_revision,
+        expires_at_ms,
+    }
+}
+
+/// Converts a record of ImportShard or of a dump back. Fails with InvalidRecord if its revisions
+/// can't come from a shard.
+pub fn import_record(record: ShardRecord) -> Result<(Key, Record), StorageError> {
+    if record.create_revision == 0 || record.mod_revision < record.create_revision {
+        return Err(StorageError::InvalidRecord {
+            create_revision: record.create_revision,
+            mod_revision: record.mod_revision,
+        });
+    }
+    let expires_at = match record.expires_at_ms {
+        0 => None,
+        ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+    };
+
+    let key = Key::from(record.key);
+    let record = Record {
+        val: Val::from(record.val),
+        create_revision: record.create_revision,
+        mod_revision: record.mod_revision,
+        expires_at,
+    };
+    Ok((key, record))
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    type ExportShardStream = mpsc::Receiver<Result<ExportShardResponse, Status>>;
+
+    async fn create_shard(
+        &self,
+        request: Request<CreateShardRequest>,
+    ) -> Result<Response<CreateShardResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let engine = Self::engine(request.engine)?;
+        let wal = Self::wal(request.wal, request.sync_interval_ms)?;
+
+        // A durable shard creates and syncs its log
+        let shard_map = self.shard_map.clone();
+        tokio::task::spawn_blocking(move || {
+            shard_map.install(shard_id, engine, wal, HashMap::new(), false)
+        })
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        log::info!(
+            "Created shard {} with the {:?} engine and wal {:?}",
+            shard_id,
+            engine,
+            wal
+        );
+        Ok(Response::new(CreateShardResponse {}))
+    }
+
+    async fn drop_shard(
+        &self,
+        request: Request<DropShardRequest>,
+    ) -> Result<Response<DropShardResponse>, Status> {
+        let shard_id = StorageError::shard_id(request.into_inner().shard_id)?;
+
+        // Waits for the writer thread to apply the queued writes, so don't block the runtime
+        let shard_map = self.shard_map.clone();
+        let removed = tokio::task::spawn_blocking(move || shard_map.remove(&shard_id))
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+        if !removed {
+            return Err(StorageError::ShardNotFound(shard_id).into());
+        }
+        log::info!("Dropped shard {}", shard_id);
+        Ok(Response::new(DropShardResponse {}))
+    }
+
+    async fn list_shards(
+        &self,
+        _request: Request<ListShardsRequest>,
+    ) -> Result<Response<ListShardsResponse>, Status> {
+        let shard_map = self.shard_map.clone();
+        let stats = tokio::task::spawn_blocking(move || shard_map.all_stats())
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+
+        let shards = stats.into_iter().map(Self::info).collect();
+        Ok(Response::new(ListShardsResponse { shards }))
+    }
+
+    async fn describe_shard(
+        &self,
+        request: Request<DescribeShardRequest>,
+    ) -> Result<Response<DescribeShardResponse>, Status> {
+        let shard_id = StorageError::shard_id(request.into_inner().shard_id)?;
+
+        // Walks all records of the shard
+        let shard_map = self.shard_map.clone();
+        let stats = tokio::task::spawn_blocking(move || shard_map.stats(&shard_id))
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?
+            .ok_or(StorageError::ShardNotFound(shard_id))?;
+
+        Ok(Response::new(DescribeShardResponse {
+            shard: Some(Self::info(stats)),
+        }))
+    }
+
+    async fn export_shard(
+        &self,
+        request: Request<ExportShardRequest>,
+    ) -> Result<Response<Self::ExportShardStream>, Status> {
+        let shard_id = StorageError::shard_id(request.into_inner().shard_id)?;
+        // The records are copied under the writer lock, so the export is consistent and a slow
+        // client holds neither the writer nor a snapshot. Keys and values are shared, not copied.
+        let shard_map = self.shard_map.clone();
+        let records = tokio::task::spawn_blocking(move || shard_map.copy(&shard_id))
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?
+            .ok_or(StorageError::ShardNotFound(shard_id))?;
+        log::info!("Exporting {} records of shard {}", records.len(), shard_id);
+
+        let (mut tx, rx) = mpsc::channel(EXPORT_BUFFER);
+        tokio::spawn(async move {
+            let mut records = records.into_iter().peekable();
+            while records.peek().is_some() {
+                let chunk = records
+                    .by_ref()
+                    .take(EXPORT_CHUNK_SIZE)
+                    .map(export_record)
+                    .collect();
+                if tx
+                    .send(Ok(ExportShardResponse { records: chunk }))
+                    .await
+                    .is_err()
+                {
+                    log::warn!("Export of shard {} was cancelled", shard_id);
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+
+    async fn import_shard(
+        &self,
+        request: Request<Streaming<ImportShardRequest>>,
+    ) -> Result<Response<ImportShardResponse>, Status> {
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Empty import"))?;
+        let shard_id = StorageError::shard_id(first.shard_id)?;
+        let engine = Self::engine(first.engine)?;
+        let wal = Self::wal(first.wal, first.sync_interval_ms)?;
+        let replace = first.replace;
+        // Fail before the client sends all records. The id is checked again when the shard is
+        // installed, in case it was taken in the meantime.
+        if !replace && self.shard_map.contains(&shard_id) {
+            return Err(StorageError::ShardExists(shard_id).into());
+        }
+
+        let mut data = HashMap::new();
+        let mut records = first.records;
+        loop {
+            for record in records {
+                let (key, record) = import_record(record)?;
+                if data.insert(key, record).is_some() {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        "Duplicate key in import",
+                    ));
+                }
+            }
+            records = match stream.message().await? {
+                Some(message) => message.records,
+                None => break,
+            };
+        }
+        let key_count = data.len();
+
+        // Builds the maps of the engine, writes the log of a durable shard and replacing a shard
+        // waits for its writer thread, so don't block the runtime. Readers see the old shard or
+        // the whole new one, never a part.
+        let shard_map = self.shard_map.clone();
+        tokio::task::spawn_blocking(move || {
+            shard_map.install(shard_id, engine, wal, data, replace)
+        })
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+
+        log::info!(
+            "Imported shard {} with {} records into the {:?} engine",
+            shard_id,
+            key_count,
+            engine
+        );
+        Ok(Response::new(ImportShardResponse {
+            key_count: key_count as u64,
+        }))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let shard_id = StorageError::shard_id(request.into_inner().shard_id)?;
+
+        // Copies all records and writes them to the disk
+        let shard_map = self.shard_map.clone();
+        let info = tokio::task::spawn_blocking(move || shard_map.snapshot(&shard_id))
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        log::info!(
+            "Saved a snapshot of shard {} at revision {}",
+            shard_id,
+            info.revision
+        );
+
+        Ok(Response::new(SnapshotResponse {
+            revision: info.revision,
+            key_count: info.key_count as u64,
+            size_bytes: info.size_bytes,
+        }))
+    }
+
+    async fn set_replication(
+        &self,
+        request: Request<SetReplicationRequest>,
+    ) -> Result<Response<SetReplicationResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let role = Self::role(&request)?;
+
+        // Waits for the writer thread to finish the write it is applying in the old role
+        let shard_map = self.shard_map.clone();
+        let primary = tokio::task::spawn_blocking(move || shard_map.set_role(shard_id, role))
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        if let Some(primary) = primary {
+            let backups = request
+                .backups
+                .iter()
+                .map(|addr| Box::new(RemoteBackup::new(addr.clone())) as Box<dyn Backup>)
+                .collect();
+            replication::start_replication(&primary, backups);
+        }
+        log::info!("Shard {} is now {:?}", shard_id, role);
+        Ok(Response::new(SetReplicationResponse {}))
+    }
+
+    async fn start_raft(
+        &self,
+        request: Request<StartRaftRequest>,
+    ) -> Result<Response<StartRaftResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let members = request
+            .members
+            .into_iter()
+            .map(|member| Self::member(Some(member)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !members.is_empty() && !members.iter().any(|member| member.id == self.node_id) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Node {} isn't one of the members", self.node_id),
+            ));
+        }
+
+        // Waits for the writer thread to finish the write it is applying
+        let shard_map = self.shard_map.clone();
+        let node_id = self.node_id;
+        let transport = self.transport.clone();
+        let count = members.len();
+        tokio::task::spawn_blocking(move || {
+            let config = RaftConfig::default();
+            ShardMap::start_raft(&shard_map, shard_id, node_id, members, transport, config)
+        })
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        match count {
+            0 => log::info!("Shard {} waits to join a Raft group", shard_id),
+            count => log::info!(
+                "Shard {} founded a Raft group with {} members",
+                shard_id,
+                count
+            ),
+        }
+        Ok(Response::new(StartRaftResponse {}))
+    }
+
+    async fn change_raft_member(
+        &self,
+        request: Request<ChangeRaftMemberRequest>,
+    ) -> Result<Response<ChangeRaftMemberResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let member = Self::member(request.member)?;
+        let group = self.shard_map.raft(&shard_id)?;
+
+        // Waits until the change is committed
+        let id = member.id;
+        let remove = request.remove;
+        tokio::task::spawn_blocking(move || match remove {
+            true => group.remove_member(member.id),
+            false => group.add_member(member),
+        })
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        match remove {
+            true => log::info!(
+                "Removed node {} from the Raft group of shard {}",
+                id,
+                shard_id
+            ),
+            false => log::info!("Added node {} to the Raft group of shard {}", id, shard_id),
+        }
+        Ok(Response::new(ChangeRaftMemberResponse {}))
+    }
+}
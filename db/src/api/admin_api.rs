@@ -0,0 +1,606 @@
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateShardRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(enumeration = "Engine", tag = "2")]
+    pub engine: i32,
+    /// Fails with FAILED_PRECONDITION if the node has no data directory
+    #[prost(enumeration = "Wal", tag = "3")]
+    pub wal: i32,
+    /// Only used with SYNC_INTERVAL
+    #[prost(uint64, tag = "4")]
+    pub sync_interval_ms: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateShardResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DropShardRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DropShardResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListShardsRequest {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListShardsResponse {
+    /// Ordered by shard id
+    #[prost(message, repeated, tag = "1")]
+    pub shards: ::std::vec::Vec<ShardInfo>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DescribeShardRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DescribeShardResponse {
+    #[prost(message, optional, tag = "1")]
+    pub shard: ::std::option::Option<ShardInfo>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShardInfo {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(enumeration = "Engine", tag = "2")]
+    pub engine: i32,
+    #[prost(enumeration = "ShardState", tag = "3")]
+    pub state: i32,
+    /// Including the expired keys that haven't been deleted yet
+    #[prost(uint64, tag = "4")]
+    pub key_count: u64,
+    /// Memory used by the keys, the values and the map entries
+    #[prost(uint64, tag = "5")]
+    pub approximate_bytes: u64,
+    /// The revision of the last write to the shard
+    #[prost(uint64, tag = "6")]
+    pub revision: u64,
+    #[prost(enumeration = "Wal", tag = "7")]
+    pub wal: i32,
+    #[prost(uint64, tag = "8")]
+    pub sync_interval_ms: u64,
+    #[prost(enumeration = "Role", tag = "9")]
+    pub role: i32,
+    /// The current term and leader of the Raft group, 0 if the shard isn't in one or the leader
+    /// isn't known
+    #[prost(uint64, tag = "10")]
+    pub raft_term: u64,
+    #[prost(uint64, tag = "11")]
+    pub raft_leader: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportShardRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportShardResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub records: ::std::vec::Vec<ShardRecord>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportShardRequest {
+    /// Only read from the first message of the stream
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    /// Only read from the first message of the stream
+    #[prost(enumeration = "Engine", tag = "2")]
+    pub engine: i32,
+    /// Replace the shard with the same id instead of failing with ALREADY_EXISTS. Only read from
+    /// the first message of the stream.
+    #[prost(bool, tag = "3")]
+    pub replace: bool,
+    #[prost(message, repeated, tag = "4")]
+    pub records: ::std::vec::Vec<ShardRecord>,
+    /// Only read from the first message of the stream
+    #[prost(enumeration = "Wal", tag = "5")]
+    pub wal: i32,
+    /// Only read from the first message of the stream
+    #[prost(uint64, tag = "6")]
+    pub sync_interval_ms: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportShardResponse {
+    #[prost(uint64, tag = "1")]
+    pub key_count: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotResponse {
+    /// The revision of the last write in the snapshot
+    #[prost(uint64, tag = "1")]
+    pub revision: u64,
+    #[prost(uint64, tag = "2")]
+    pub key_count: u64,
+    #[prost(uint64, tag = "3")]
+    pub size_bytes: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetReplicationRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(enumeration = "Role", tag = "2")]
+    pub role: i32,
+    /// The nodes with the backups of the shard, e.g. http://10.0.0.2:10000. Only for PRIMARY.
+    #[prost(string, repeated, tag = "3")]
+    pub backups: ::std::vec::Vec<std::string::String>,
+    /// The number of backups that must apply a write before it is acknowledged. Only for PRIMARY,
+    /// at most the number of backups.
+    #[prost(uint32, tag = "4")]
+    pub acks: u32,
+    /// How long a write waits for the acks before it fails with DEADLINE_EXCEEDED, 1000 if 0. Only
+    /// for PRIMARY.
+    #[prost(uint64, tag = "5")]
+    pub ack_timeout_ms: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetReplicationResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StartRaftRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    /// Empty to join a group
+    #[prost(message, repeated, tag = "2")]
+    pub members: ::std::vec::Vec<RaftMember>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StartRaftResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeRaftMemberRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(message, optional, tag = "2")]
+    pub member: ::std::option::Option<RaftMember>,
+    /// Removes the member with the id instead of adding it
+    #[prost(bool, tag = "3")]
+    pub remove: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeRaftMemberResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMember {
+    /// Unique in the cluster, see R_DB_NODE_ID
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    /// The address of the node, e.g. http://10.0.0.2:10000
+    #[prost(string, tag = "2")]
+    pub addr: std::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShardRecord {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub create_revision: u64,
+    #[prost(uint64, tag = "4")]
+    pub mod_revision: u64,
+    /// Milliseconds since the Unix epoch, 0 if the record doesn't expire
+    #[prost(uint64, tag = "5")]
+    pub expires_at_ms: u64,
+}
+/// How a shard stores its records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Engine {
+    /// Reads never block, writes wait for the readers. Takes twice the memory.
+    LeftRight = 0,
+    /// A HashMap behind a RwLock
+    Locked = 1,
+    /// A BTreeMap behind a RwLock. Cheap ordered scans.
+    Ordered = 2,
+    /// HashMaps with a lock each, like DashMap
+    Striped = 3,
+}
+/// When the write-ahead log of a durable shard is synced to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Wal {
+    /// The shard only lives in memory and is lost when the node stops
+    None = 0,
+    /// Before every write is acknowledged
+    SyncAlways = 1,
+    /// Every sync_interval_ms. A crash of the machine loses up to the last interval of writes.
+    SyncInterval = 2,
+    /// Left to the OS. Survives a crash of the process but not of the machine.
+    SyncNever = 3,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Role {
+    /// Accepts writes and doesn't replicate them
+    Standalone = 0,
+    /// Accepts writes and ships them to its backups
+    Primary = 1,
+    /// Only applies the writes of its primary and serves reads
+    Backup = 2,
+    /// A member of a Raft group, only its leader accepts writes. Can't be set with SetReplication.
+    Raft = 3,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ShardState {
+    Active = 0,
+    /// The writer thread of the shard has exited after a write panicked. Reads still work.
+    Stopped = 1,
+}
+#[doc = r" Generated server implementations."]
+pub mod admin_server {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with AdminServer."]
+    #[async_trait]
+    pub trait Admin: Send + Sync + 'static {
+        async fn create_shard(
+            &self,
+            request: tonic::Request<super::CreateShardRequest>,
+        ) -> Result<tonic::Response<super::CreateShardResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        async fn drop_shard(
+            &self,
+            request: tonic::Request<super::DropShardRequest>,
+        ) -> Result<tonic::Response<super::DropShardResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        async fn list_shards(
+            &self,
+            request: tonic::Request<super::ListShardsRequest>,
+        ) -> Result<tonic::Response<super::ListShardsResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        async fn describe_shard(
+            &self,
+            request: tonic::Request<super::DescribeShardRequest>,
+        ) -> Result<tonic::Response<super::DescribeShardResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = "Server streaming response type for the ExportShard method."]
+        type ExportShardStream: Stream<Item = Result<super::ExportShardResponse, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        #[doc = " Streams a consistent snapshot of the live records of a shard, ordered by key"]
+        async fn export_shard(
+            &self,
+            request: tonic::Request<super::ExportShardRequest>,
+        ) -> Result<tonic::Response<Self::ExportShardStream>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Builds a shard from the streamed records and installs it once the stream is complete"]
+        async fn import_shard(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::ImportShardRequest>>,
+        ) -> Result<tonic::Response<super::ImportShardResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Writes a snapshot of a durable shard to the disk and truncates its log"]
+        async fn snapshot(
+            &self,
+            request: tonic::Request<super::SnapshotRequest>,
+        ) -> Result<tonic::Response<super::SnapshotResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Makes a shard the primary or a backup of its replication group, or standalone again. The"]
+        #[doc = " roles aren't persisted, a restarted node serves its shards standalone until they are set"]
+        #[doc = " again."]
+        async fn set_replication(
+            &self,
+            request: tonic::Request<super::SetReplicationRequest>,
+        ) -> Result<tonic::Response<super::SetReplicationResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Makes a shard a member of a Raft group. With members the shard founds the group with them,"]
+        #[doc = " they must all have the same records. Without members the shard waits until the leader adds"]
+        #[doc = " it and then gets a copy of the shard of the leader. SetReplication leaves the group. A"]
+        #[doc = " durable member rejoins its group with its log after a restart."]
+        async fn start_raft(
+            &self,
+            request: tonic::Request<super::StartRaftRequest>,
+        ) -> Result<tonic::Response<super::StartRaftResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Adds a member to the Raft group of a shard or removes one. Only the leader takes the"]
+        #[doc = " change, the others fail with FAILED_PRECONDITION."]
+        async fn change_raft_member(
+            &self,
+            request: tonic::Request<super::ChangeRaftMemberRequest>,
+        ) -> Result<tonic::Response<super::ChangeRaftMemberResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+    }
+    #[doc = " Manages the shards of a single node"]
+    #[derive(Debug)]
+    #[doc(hidden)]
+    pub struct AdminServer<T: Admin> {
+        inner: Arc<T>,
+    }
+    impl<T: Admin> AdminServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            Self { inner }
+        }
+    }
+    impl<T: Admin> Service<http::Request<HyperBody>> for AdminServer<T> {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<HyperBody>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/admin_api.Admin/CreateShard" => {
+                    struct CreateShardSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::CreateShardRequest> for CreateShardSvc<T> {
+                        type Response = super::CreateShardResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CreateShardRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.create_shard(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = CreateShardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/DropShard" => {
+                    struct DropShardSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::DropShardRequest> for DropShardSvc<T> {
+                        type Response = super::DropShardResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DropShardRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.drop_shard(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = DropShardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/ListShards" => {
+                    struct ListShardsSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::ListShardsRequest> for ListShardsSvc<T> {
+                        type Response = super::ListShardsResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListShardsRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.list_shards(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ListShardsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/DescribeShard" => {
+                    struct DescribeShardSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::DescribeShardRequest> for DescribeShardSvc<T> {
+                        type Response = super::DescribeShardResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DescribeShardRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.describe_shard(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = DescribeShardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/ExportShard" => {
+                    struct ExportShardSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::ServerStreamingService<super::ExportShardRequest>
+                        for ExportShardSvc<T>
+                    {
+                        type Response = super::ExportShardResponse;
+                        type ResponseStream = T::ExportShardStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ExportShardRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.export_shard(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ExportShardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/ImportShard" => {
+                    struct ImportShardSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::ClientStreamingService<super::ImportShardRequest>
+                        for ImportShardSvc<T>
+                    {
+                        type Response = super::ImportShardResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::ImportShardRequest>>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.import_shard(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ImportShardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.client_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/Snapshot" => {
+                    struct SnapshotSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::SnapshotRequest> for SnapshotSvc<T> {
+                        type Response = super::SnapshotResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SnapshotRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.snapshot(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = SnapshotSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/SetReplication" => {
+                    struct SetReplicationSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::SetReplicationRequest> for SetReplicationSvc<T> {
+                        type Response = super::SetReplicationResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SetReplicationRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.set_replication(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = SetReplicationSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/StartRaft" => {
+                    struct StartRaftSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::StartRaftRequest> for StartRaftSvc<T> {
+                        type Response = super::StartRaftResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::StartRaftRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.start_raft(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = StartRaftSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/admin_api.Admin/ChangeRaftMember" => {
+                    struct ChangeRaftMemberSvc<T: Admin>(pub Arc<T>);
+                    impl<T: Admin> tonic::server::UnaryService<super::ChangeRaftMemberRequest>
+                        for ChangeRaftMemberSvc<T>
+                    {
+                        type Response = super::ChangeRaftMemberResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ChangeRaftMemberRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.change_raft_member(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ChangeRaftMemberSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .body(tonic::body::BoxBody::empty())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: Admin> Clone for AdminServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self { inner }
+        }
+    }
+    impl<T: Admin> tonic::transport::ServiceName for AdminServer<T> {
+        const NAME: &'static str = "admin_api.Admin";
+    }
+}
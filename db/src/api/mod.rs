@@ -1,2 +1,7 @@
 /// Auto-generated gRPC services
+pub mod admin_api;
+pub mod raft_api;
+/// Messages that are encoded by hand in place of the generated ones
+pub mod raw;
+pub mod replication_api;
 pub mod storage_api;
@@ -0,0 +1,269 @@
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Message {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(uint64, tag = "2")]
+    pub from: u64,
+    #[prost(uint64, tag = "3")]
+    pub to: u64,
+    /// The term of the sender
+    #[prost(uint64, tag = "4")]
+    pub term: u64,
+    /// The address of the node of the sender, e.g. http://10.0.0.2:10000, so a member that joins
+    /// can answer before it knows the other members
+    #[prost(string, tag = "5")]
+    pub from_addr: std::string::String,
+    #[prost(oneof = "message::Body", tags = "6, 7, 8, 9, 10")]
+    pub body: ::std::option::Option<message::Body>,
+}
+pub mod message {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag = "6")]
+        Vote(super::Vote),
+        #[prost(message, tag = "7")]
+        VoteResponse(super::VoteResponse),
+        #[prost(message, tag = "8")]
+        Append(super::Append),
+        #[prost(message, tag = "9")]
+        AppendResponse(super::AppendResponse),
+        #[prost(message, tag = "10")]
+        Snapshot(super::Snapshot),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StepResponse {}
+/// A candidate asks for a vote
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Vote {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub last_term: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VoteResponse {
+    #[prost(bool, tag = "1")]
+    pub granted: bool,
+}
+/// The leader sends the entries after prev_index, or none as a heartbeat
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Append {
+    #[prost(uint64, tag = "1")]
+    pub prev_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub prev_term: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub entries: ::std::vec::Vec<Entry>,
+    #[prost(uint64, tag = "4")]
+    pub commit: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendResponse {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    /// On success the log of the member matches the leader up to here, otherwise where the leader
+    /// should try next
+    #[prost(uint64, tag = "2")]
+    pub index: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Entry {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub term: u64,
+    #[prost(oneof = "entry::Command", tags = "3, 4, 5")]
+    pub command: ::std::option::Option<entry::Command>,
+}
+pub mod entry {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Command {
+        /// Appended by every new leader
+        #[prost(message, tag = "3")]
+        Noop(super::Noop),
+        #[prost(message, tag = "4")]
+        Write(super::Write),
+        /// The members of the group from this entry on
+        #[prost(message, tag = "5")]
+        Config(super::Config),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Noop {}
+/// A write to the shard, applied with a single revision
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Write {
+    /// When the leader took the write, in nanoseconds since the Unix epoch
+    #[prost(uint64, tag = "1")]
+    pub now_ns: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub mutations: ::std::vec::Vec<super::replication_api::Mutation>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Config {
+    #[prost(message, repeated, tag = "1")]
+    pub members: ::std::vec::Vec<Member>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Member {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub addr: std::string::String,
+}
+/// A copy of the shard of the leader, for a member that is behind the log
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {
+    /// The last entry in the copy
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub term: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub members: ::std::vec::Vec<Member>,
+    /// The revision of the shard
+    #[prost(uint64, tag = "4")]
+    pub revision: u64,
+    #[prost(message, repeated, tag = "5")]
+    pub records: ::std::vec::Vec<super::replication_api::Record>,
+}
+#[doc = r" Generated server implementations."]
+pub mod raft_client {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = " Carries the messages between the members of the Raft groups of the shards. Served by every node"]
+    #[doc = " and called by the members on the other nodes, see StartRaft in the Admin service."]
+    pub struct RaftClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl RaftClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> RaftClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + HttpBody + Send + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as HttpBody>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        #[doc = " Hands a message to the member of the shard on this node. Messages are one way, the answer"]
+        #[doc = " is a message of its own."]
+        pub async fn step(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Message>,
+        ) -> Result<tonic::Response<super::StepResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/raft_api.Raft/Step");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+    }
+    impl<T: Clone> Clone for RaftClient<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+            }
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod raft_server {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with RaftServer."]
+    #[async_trait]
+    pub trait Raft: Send + Sync + 'static {
+        #[doc = " Hands a message to the member of the shard on this node. Messages are one way, the answer"]
+        #[doc = " is a message of its own."]
+        async fn step(
+            &self,
+            request: tonic::Request<super::Message>,
+        ) -> Result<tonic::Response<super::StepResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+    }
+    #[doc = " Carries the messages between the members of the Raft groups of the shards. Served by every node"]
+    #[doc = " and called by the members on the other nodes, see StartRaft in the Admin service."]
+    #[derive(Debug)]
+    #[doc(hidden)]
+    pub struct RaftServer<T: Raft> {
+        inner: Arc<T>,
+    }
+    impl<T: Raft> RaftServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            Self { inner }
+        }
+    }
+    impl<T: Raft> Service<http::Request<HyperBody>> for RaftServer<T> {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<HyperBody>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/raft_api.Raft/Step" => {
+                    struct StepSvc<T: Raft>(pub Arc<T>);
+                    impl<T: Raft> tonic::server::UnaryService<super::Message> for StepSvc<T> {
+                        type Response = super::StepResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Message>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.step(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = StepSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .body(tonic::body::BoxBody::empty())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: Raft> Clone for RaftServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self { inner }
+        }
+    }
+    impl<T: Raft> tonic::transport::ServiceName for RaftServer<T> {
+        const NAME: &'static str = "raft_api.Raft";
+    }
+}
@@ -0,0 +1,127 @@
+//! Messages that are encoded by hand instead of being generated. prost 0.5 only generates Vec<u8>
+//! for bytes fields, so a generated response would need its own copy of every value. These hold
+//! the shared buffers of the shard and encode them straight into the gRPC frame.
+//!
+//! The proto tool replaces the generated struct with a re-export of the type here, so they must
+//! stay wire-compatible with their messages in the .proto files.
+
+use bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{self, WireType};
+use prost::{DecodeError, Message};
+
+/// storage-api.proto GetResponse
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetResponse {
+    pub val: Bytes,
+    pub create_revision: u64,
+    pub mod_revision: u64,
+}
+
+const VAL: u32 = 1;
+const CREATE_REVISION: u32 = 2;
+const MOD_REVISION: u32 = 3;
+
+impl Message for GetResponse {
+    fn encode_raw<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+    {
+        // Default values aren't encoded, like in proto3
+        if !self.val.is_empty() {
+            encoding::encode_key(VAL, WireType::LengthDelimited, buf);
+            encoding::encode_varint(self.val.len() as u64, buf);
+            buf.put_slice(&self.val);
+        }
+        if self.create_revision != 0 {
+            encoding::uint64::encode(CREATE_REVISION, &self.create_revision, buf);
+        }
+        if self.mod_revision != 0 {
+            encoding::uint64::encode(MOD_REVISION, &self.mod_revision, buf);
+        }
+    }
+
+    fn merge_field<B>(&mut self, buf: &mut B) -> Result<(), DecodeError>
+    where
+        B: Buf,
+    {
+        let (tag, wire_type) = encoding::decode_key(buf)?;
+        match tag {
+            VAL => {
+                let mut val = Vec::new();
+                encoding::bytes::merge(wire_type, &mut val, buf)?;
+                self.val = Bytes::from(val);
+                Ok(())
+            }
+            CREATE_REVISION => encoding::uint64::merge(wire_type, &mut self.create_revision, buf),
+            MOD_REVISION => encoding::uint64::merge(wire_type, &mut self.mod_revision, buf),
+            _ => encoding::skip_field(wire_type, buf),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if !self.val.is_empty() {
+            len += encoding::key_len(VAL)
+                + encoding::encoded_len_varint(self.val.len() as u64)
+                + self.val.len();
+        }
+        if self.create_revision != 0 {
+            len += encoding::uint64::encoded_len(CREATE_REVISION, &self.create_revision);
+        }
+        if self.mod_revision != 0 {
+            len += encoding::uint64::encoded_len(MOD_REVISION, &self.mod_revision);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GetResponse;
+    use bytes::Bytes;
+    use prost::Message;
+
+    // What prost generates for GetResponse in storage-api.proto
+    #[derive(Clone, PartialEq, Message)]
+    struct Generated {
+        #[prost(bytes, tag = "1")]
+        val: Vec<u8>,
+        #[prost(uint64, tag = "2")]
+        create_revision: u64,
+        #[prost(uint64, tag = "3")]
+        mod_revision: u64,
+    }
+
+    // Must decode like the generated message and the other way around
+    #[test]
+    fn test_get_response() {
+        let responses = vec![
+            GetResponse {
+                val: Bytes::from(vec![0u8, 1, 255]),
+                create_revision: 1,
+                mod_revision: 300,
+            },
+            GetResponse::default(),
+        ];
+
+        for response in responses {
+            let mut buf = Vec::new();
+            response.encode(&mut buf).unwrap();
+            assert_eq!(buf.len(), response.encoded_len());
+
+            let generated = Generated::decode(&buf).unwrap();
+            assert_eq!(generated.val, response.val.to_vec());
+            assert_eq!(generated.create_revision, response.create_revision);
+            assert_eq!(generated.mod_revision, response.mod_revision);
+
+            let mut encoded = Vec::new();
+            generated.encode(&mut encoded).unwrap();
+            assert_eq!(encoded, buf);
+            assert_eq!(GetResponse::decode(&encoded).unwrap(), response);
+        }
+    }
+}
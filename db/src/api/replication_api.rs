@@ -0,0 +1,266 @@
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    /// Ordered by revision. The backup skips the ones it already has and stops at a gap.
+    #[prost(message, repeated, tag = "2")]
+    pub entries: ::std::vec::Vec<LogEntry>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendResponse {
+    /// The revision of the backup after the append. The primary resends the entries after it.
+    #[prost(uint64, tag = "1")]
+    pub revision: u64,
+}
+/// A write of the primary, applied with a single revision
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogEntry {
+    #[prost(uint64, tag = "1")]
+    pub revision: u64,
+    /// When the primary applied the write, in nanoseconds since the Unix epoch
+    #[prost(uint64, tag = "2")]
+    pub now_ns: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub mutations: ::std::vec::Vec<Mutation>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Mutation {
+    #[prost(enumeration = "mutation::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    /// Only used by PUT
+    #[prost(bytes, tag = "3")]
+    pub val: std::vec::Vec<u8>,
+    /// Nanoseconds since the Unix epoch, 0 if the key doesn't expire. Not used by DELETE.
+    #[prost(uint64, tag = "4")]
+    pub expires_at_ns: u64,
+}
+pub mod mutation {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Put = 0,
+        /// Changes the expiration of an existing key
+        Expire = 1,
+        Delete = 2,
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallRequest {
+    /// Only read from the first message of the stream
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    /// The revision of the primary when the records were copied. Only read from the first message
+    /// of the stream.
+    #[prost(uint64, tag = "2")]
+    pub revision: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub records: ::std::vec::Vec<Record>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Record {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub create_revision: u64,
+    #[prost(uint64, tag = "4")]
+    pub mod_revision: u64,
+    /// Nanoseconds since the Unix epoch, 0 if the record doesn't expire
+    #[prost(uint64, tag = "5")]
+    pub expires_at_ns: u64,
+}
+#[doc = r" Generated server implementations."]
+pub mod replication_client {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = " Ships the writes of a primary shard to its backups. Served by every node and called by the"]
+    #[doc = " primaries, see SetReplication in the Admin service."]
+    pub struct ReplicationClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl ReplicationClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> ReplicationClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + HttpBody + Send + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as HttpBody>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        #[doc = " Applies the next writes of the primary to a backup shard"]
+        pub async fn append(
+            &mut self,
+            request: impl tonic::IntoRequest<super::AppendRequest>,
+        ) -> Result<tonic::Response<super::AppendResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/replication_api.Replication/Append");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        #[doc = " Replaces the records of a backup shard with a copy of the primary, for a backup that is new"]
+        #[doc = " or has fallen behind the log of the primary"]
+        pub async fn install(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::InstallRequest>,
+        ) -> Result<tonic::Response<super::InstallResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/replication_api.Replication/Install");
+            self.inner
+                .client_streaming(request.into_streaming_request(), path, codec)
+                .await
+        }
+    }
+    impl<T: Clone> Clone for ReplicationClient<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+            }
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod replication_server {
+    #![allow(unused_variables, dead_code, missing_docs)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with ReplicationServer."]
+    #[async_trait]
+    pub trait Replication: Send + Sync + 'static {
+        #[doc = " Applies the next writes of the primary to a backup shard"]
+        async fn append(
+            &self,
+            request: tonic::Request<super::AppendRequest>,
+        ) -> Result<tonic::Response<super::AppendResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+        #[doc = " Replaces the records of a backup shard with a copy of the primary, for a backup that is new"]
+        #[doc = " or has fallen behind the log of the primary"]
+        async fn install(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::InstallRequest>>,
+        ) -> Result<tonic::Response<super::InstallResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Not yet implemented"))
+        }
+    }
+    #[doc = " Ships the writes of a primary shard to its backups. Served by every node and called by the"]
+    #[doc = " primaries, see SetReplication in the Admin service."]
+    #[derive(Debug)]
+    #[doc(hidden)]
+    pub struct ReplicationServer<T: Replication> {
+        inner: Arc<T>,
+    }
+    impl<T: Replication> ReplicationServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            Self { inner }
+        }
+    }
+    impl<T: Replication> Service<http::Request<HyperBody>> for ReplicationServer<T> {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<HyperBody>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/replication_api.Replication/Append" => {
+                    struct AppendSvc<T: Replication>(pub Arc<T>);
+                    impl<T: Replication> tonic::server::UnaryService<super::AppendRequest> for AppendSvc<T> {
+                        type Response = super::AppendResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::AppendRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.append(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = AppendSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/replication_api.Replication/Install" => {
+                    struct InstallSvc<T: Replication>(pub Arc<T>);
+                    impl<T: Replication>
+                        tonic::server::ClientStreamingService<super::InstallRequest>
+                        for InstallSvc<T>
+                    {
+                        type Response = super::InstallResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::InstallRequest>>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { inner.install(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = InstallSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec);
+                        let res = grpc.client_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .body(tonic::body::BoxBody::empty())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: Replication> Clone for ReplicationServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self { inner }
+        }
+    }
+    impl<T: Replication> tonic::transport::ServiceName for ReplicationServer<T> {
+        const NAME: &'static str = "replication_api.Replication";
+    }
+}
@@ -0,0 +1,8 @@
+#![warn(clippy::all)]
+
+pub mod admin;
+pub mod api;
+pub mod raft;
+pub mod replication;
+pub mod server;
+pub mod storage;
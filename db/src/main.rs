@@ -1,23 +1,62 @@
 #![warn(clippy::all)]
 
-use crate::api::storage_api::storage_server::StorageServer;
-use crate::server::StorageService;
-use crate::storage::shard_map::ShardMap;
+use r_db::admin::AdminService;
+use r_db::api::admin_api::admin_server::AdminServer;
+use r_db::api::raft_api::raft_server::RaftServer;
+use r_db::api::replication_api::replication_server::ReplicationServer;
+use r_db::api::storage_api::storage_server::StorageServer;
+use r_db::raft::service::{GrpcTransport, RaftService};
+use r_db::raft::RaftConfig;
+use r_db::replication::ReplicationService;
+use r_db::server::StorageService;
+use r_db::storage::shard_map::ShardMap;
+use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use tonic::transport::Server;
 
-mod api;
-mod server;
-mod storage;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:10000".parse().unwrap();
+    // Every service is served here, the other nodes need an address they can reach like
+    // 0.0.0.0:10000
+    let addr = env::var("R_DB_LISTEN_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:10000".to_string())
+        .parse()?;
 
     println!("StorageService listening on: {}", addr);
-    let shard_map = Arc::new(ShardMap::new());
+    // The durable shards keep their logs here and are recovered from them on startup
+    let data_dir = env::var("R_DB_DATA_DIR").unwrap_or_else(|_| "data".to_string());
+    let shard_map = Arc::new(ShardMap::open(data_dir)?);
+    ShardMap::start_sweeper(&shard_map, Duration::from_secs(1));
+    // Bounds the log that is replayed after a restart to about 64MiB per shard
+    ShardMap::start_snapshots(&shard_map, Duration::from_secs(60), 64 << 20);
+    // Identifies the node in the Raft groups of its shards, unique in the cluster
+    let node_id = env::var("R_DB_NODE_ID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(1);
+    // Where the other nodes reach this one
+    let node_addr =
+        env::var("R_DB_NODE_ADDR").unwrap_or_else(|_| "http://127.0.0.1:10000".to_string());
+    let transport = Arc::new(GrpcTransport::new(node_addr));
+    // The durable members of Raft groups rejoin them with the logs they have saved
+    ShardMap::restart_raft(
+        &shard_map,
+        node_id,
+        transport.clone(),
+        RaftConfig::default(),
+    );
+    // Shards are created through the Admin service
+    let admin_service = AdminService::new(shard_map.clone(), node_id, transport);
+    // The members of the Raft groups on the other nodes send their messages here
+    let raft_service = RaftService::new(shard_map.clone());
+    // The primaries on the other nodes ship their writes to the backups here
+    let replication_service = ReplicationService::new(shard_map.clone());
     let storage_service = StorageService::new(shard_map);
     Server::builder()
+        .add_service(AdminServer::new(admin_service))
+        .add_service(RaftServer::new(raft_service))
+        .add_service(ReplicationServer::new(replication_service))
         .add_service(StorageServer::new(storage_service))
         .serve(addr)
         .await?;
@@ -0,0 +1,1026 @@
+use super::node::{RaftNode, Rejected, State};
+use super::state::{self, HardState};
+use super::transport::Transport;
+use super::{Command, Index, Member, Message, NodeId, RaftConfig, Snapshot, Term};
+use crate::storage::durable::Durable;
+use crate::storage::engine::{
+    Batch, EngineKind, EngineSnapshot, EngineWriter, Page, StorageEngine, WriteError,
+};
+use crate::storage::error::StorageError;
+use crate::storage::sync;
+use crate::storage::types::{Key, Operation, Record, Revision, Val};
+use crate::storage::wal::SyncPolicy;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::mem;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Upper bound for the messages and proposals handled between two rounds of applying and sending
+const MAX_INPUTS: usize = 256;
+// Upper bound for the entries applied in one go
+const APPLY_BATCH_SIZE: usize = 1000;
+
+/// Replaces the shard of a member with the records of a snapshot of its leader, skips to the
+/// revision of the snapshot and returns the new engine.
+pub type Install = Box<
+    dyn FnMut(Revision, HashMap<Key, Record>) -> Result<Arc<dyn StorageEngine>, StorageError>
+        + Send,
+>;
+
+/// Runs a function with the directory of a durable shard, where its member saves its log and its
+/// state, see state. Fails if the shard has left the group. The member neither sends its messages
+/// nor applies its entries until they are saved.
+pub type Persist =
+    Box<dyn FnMut(&mut dyn FnMut(&Path) -> io::Result<()>) -> Result<(), StorageError> + Send>;
+
+/// A point in time description of a member, see RaftGroup::status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftStatus {
+    pub node_id: NodeId,
+    pub term: Term,
+    pub state: State,
+    pub leader: Option<NodeId>,
+    pub commit: Index,
+    pub applied: Index,
+    pub members: Vec<Member>,
+}
+
+/// Runs the member of a shard in its Raft group on a thread of its own. The thread ticks the
+/// RaftNode, hands it the messages of the other members, sends its messages through the
+/// transport and applies the committed writes to the shard, on every member in the same order
+/// and with the time of the leader.
+///
+/// The writes of clients go through the RaftWriter. Only the leader takes them and a write
+/// returns once it is applied. The thread stops once the group is dropped.
+pub struct RaftGroup {
+    // Tells this group apart from the ones the shard had before
+    id: usize,
+    shard_id: usize,
+    inbox: Sender<Input>,
+    status: Arc<Mutex<RaftStatus>>,
+    writer: Arc<Mutex<RaftWriter>>,
+    timeout: Duration,
+}
+
+/// Wraps the engine of a shard in a Raft group. Reads go straight to the engine, every write goes
+/// through the RaftWriter of the group.
+pub struct RaftEngine {
+    engine: Arc<dyn StorageEngine>,
+    writer: Arc<Mutex<RaftWriter>>,
+}
+
+/// Turns the writes to a shard into entries of the Raft log. `apply_at` proposes the operations
+/// and waits until the group has committed them and the shard has applied them, so a write is
+/// only acknowledged once a majority has it. Each write is a separate round, even in a group.
+///
+/// A write that reads the shard first, like a compare-and-swap, waits until the leader has
+/// applied the writes of its predecessors and fails if the leader changes before the write is
+/// proposed.
+pub struct RaftWriter {
+    inbox: Sender<Input>,
+    // The current engine of the shard, which a snapshot of the leader replaces
+    engine: Arc<Mutex<Arc<dyn StorageEngine>>>,
+    timeout: Duration,
+    // The term the write that is being made has read the shard in
+    term: Option<Term>,
+}
+
+enum Input {
+    // With the address of the sender if the transport knows it
+    Message(Message, Option<String>),
+    Propose {
+        term: Option<Term>,
+        now: SystemTime,
+        ops: Vec<Operation>,
+        reply: Sender<Result<Vec<Option<Val>>, WriteError>>,
+    },
+    // Answered with the term once the leader is ready to take writes
+    Barrier(Sender<Result<Term, WriteError>>),
+    Change(Change, Sender<Result<(), StorageError>>),
+    Stop,
+}
+
+enum Change {
+    Add(Member),
+    Remove(NodeId),
+}
+
+// A proposal of this member that waits to be applied
+enum Waiter {
+    Write(Sender<Result<Vec<Option<Val>>, WriteError>>),
+    Change(Sender<Result<(), StorageError>>),
+}
+
+struct Driver {
+    shard_id: usize,
+    node: RaftNode,
+    inbox: Receiver<Input>,
+    engine: Arc<dyn StorageEngine>,
+    // Shared with the RaftWriter
+    current: Arc<Mutex<Arc<dyn StorageEngine>>>,
+    transport: Arc<dyn Transport>,
+    install: Install,
+    // None for a shard in memory
+    persist: Option<Persist>,
+    // What `persist` has saved last, the log as its start, its end and the term of its end
+    persisted: HardState,
+    persisted_log: (Index, Index, Term),
+    config: RaftConfig,
+    status: Arc<Mutex<RaftStatus>>,
+    addrs: HashMap<NodeId, String>,
+    // By index, with the term they were proposed in
+    waiting: BTreeMap<Index, (Term, Waiter)>,
+    // The proposals that arrived before the new leader applied the writes of its predecessors
+    deferred: Vec<Input>,
+}
+
+impl RaftGroup {
+    /// Starts the thread of the member. `engine` is the shard without a wrapper and `install`
+    /// replaces it, see Install. The log and the state of `node` are already saved, `persist`
+    /// saves the ones after them.
+    pub fn start(
+        id: usize,
+        node: RaftNode,
+        engine: Arc<dyn StorageEngine>,
+        transport: Arc<dyn Transport>,
+        config: RaftConfig,
+        install: Install,
+        persist: Option<Persist>,
+    ) -> Self {
+        let shard_id = engine.id();
+        let (inbox, receiver) = mpsc::channel();
+        let current = Arc::new(Mutex::new(engine.clone()));
+        let status = Arc::new(Mutex::new(status(&node)));
+        let writer = RaftWriter {
+            inbox: inbox.clone(),
+            engine: current.clone(),
+            timeout: config.propose_timeout,
+            term: None,
+        };
+        let timeout = config.propose_timeout;
+        let persisted = node.hard_state();
+        let log = node.log();
+        let persisted_log = (log.snapshot_index(), log.last_index(), log.last_term());
+
+        let driver = Driver {
+            shard_id,
+            node,
+            inbox: receiver,
+            engine,
+            current,
+            transport,
+            install,
+            persist,
+            persisted,
+            persisted_log,
+            config,
+            status: status.clone(),
+            addrs: HashMap::new(),
+            waiting: BTreeMap::new(),
+            deferred: Vec::new(),
+        };
+        // Not joined, the thread only holds its own state and exits on its own once stopped
+        thread::Builder::new()
+            .name(format!("shard-{}-raft", shard_id))
+            .spawn(move || driver.run())
+            .unwrap_or_else(|_| panic!("Can't start Raft thread for shard: {}", shard_id));
+
+        Self {
+            id,
+            shard_id,
+            inbox,
+            status,
+            writer: Arc::new(Mutex::new(writer)),
+            timeout,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn writer(&self) -> Arc<Mutex<RaftWriter>> {
+        self.writer.clone()
+    }
+
+    /// The state of the member after the last message or tick it handled.
+    pub fn status(&self) -> RaftStatus {
+        sync::lock(&self.status).clone()
+    }
+
+    /// Hands a message of another member to the group, with the address of the sender if the
+    /// transport knows it.
+    pub fn receive(&self, message: Message, from_addr: Option<String>) {
+        let _ = self.inbox.send(Input::Message(message, from_addr));
+    }
+
+    /// Adds a member to the group. Only the leader can change the members and it waits until the
+    /// change is committed. The new member gets a copy of the shard.
+    pub fn add_member(&self, member: Member) -> Result<(), StorageError> {
+        self.change(Change::Add(member))
+    }
+
+    /// Removes a member from the group, see add_member.
+    pub fn remove_member(&self, id: NodeId) -> Result<(), StorageError> {
+        self.change(Change::Remove(id))
+    }
+
+    fn change(&self, change: Change) -> Result<(), StorageError> {
+        let (reply, receiver) = mpsc::channel();
+        let _ = self.inbox.send(Input::Change(change, reply));
+        receiver
+            .recv_timeout(self.timeout)
+            .unwrap_or(Err(StorageError::NotCommitted(self.shard_id)))
+    }
+}
+
+impl Drop for RaftGroup {
+    fn drop(&mut self) {
+        let _ = self.inbox.send(Input::Stop);
+    }
+}
+
+impl RaftEngine {
+    pub fn new(engine: Arc<dyn StorageEngine>, writer: Arc<Mutex<RaftWriter>>) -> Self {
+        Self { engine, writer }
+    }
+}
+
+impl StorageEngine for RaftEngine {
+    fn id(&self) -> usize {
+        self.engine.id()
+    }
+
+    fn kind(&self) -> EngineKind {
+        self.engine.kind()
+    }
+
+    fn wal(&self) -> Option<SyncPolicy> {
+        self.engine.wal()
+    }
+
+    fn durable(&self) -> Option<&Durable> {
+        self.engine.durable()
+    }
+
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        self.engine.snapshot()
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        self.engine.get_record(key)
+    }
+
+    fn get(&self, key: &Key) -> Option<Val> {
+        self.engine.get(key)
+    }
+
+    fn ttl(&self, key: &Key) -> Option<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        self.engine.scan(start, end, limit)
+    }
+
+    fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        self.engine.scan_prefix(prefix, after, limit)
+    }
+
+    fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    fn approximate_bytes(&self) -> usize {
+        self.engine.approximate_bytes()
+    }
+}
+
+impl RaftWriter {
+    fn engine(&self) -> Arc<dyn StorageEngine> {
+        sync::lock(&self.engine).clone()
+    }
+
+    // Waits until this member is a leader that can take writes and returns its term
+    fn barrier(&mut self) -> Result<Term, WriteError> {
+        let (reply, receiver) = mpsc::channel();
+        let _ = self.inbox.send(Input::Barrier(reply));
+        let term = receiver
+            .recv_timeout(self.timeout)
+            .unwrap_or(Err(WriteError::NotCommitted))?;
+        match *self.term.get_or_insert(term) == term {
+            true => Ok(term),
+            // The write has read the shard under an earlier leader
+            false => Err(WriteError::NotLeader(None)),
+        }
+    }
+}
+
+impl EngineWriter for RaftWriter {
+    fn revision(&self) -> Revision {
+        let engine = self.engine();
+        let writer = engine.writer();
+        let revision = sync::lock(&*writer).revision();
+        revision
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        let engine = self.engine();
+        let writer = engine.writer();
+        sync::lock(&*writer).skip_to(revision);
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        self.barrier()?;
+        let engine = self.engine();
+        let writer = engine.writer();
+        let current = sync::lock(&*writer).current(key, now);
+        current
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        // The next write reads the shard again
+        let term = self.term.take();
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (reply, receiver) = mpsc::channel();
+        let _ = self.inbox.send(Input::Propose {
+            term,
+            now,
+            ops,
+            reply,
+        });
+        receiver
+            .recv_timeout(self.timeout)
+            .unwrap_or(Err(WriteError::NotCommitted))
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        // Only the leader deletes the expired keys, the others apply its deletes
+        if self.barrier().is_err() {
+            self.term = None;
+            return Ok(Vec::new());
+        }
+        let engine = self.engine();
+        let writer = engine.writer();
+        let expired = sync::lock(&*writer).expired(now);
+        expired
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        self.term = None;
+        f(self);
+        self.term = None;
+        Ok(())
+    }
+}
+
+impl Driver {
+    fn run(mut self) {
+        let mut next_tick = Instant::now() + self.config.tick;
+        loop {
+            let timeout = next_tick.saturating_duration_since(Instant::now());
+            let first = match self.inbox.recv_timeout(timeout) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let inputs: Vec<_> = first
+                .into_iter()
+                .chain(self.inbox.try_iter().take(MAX_INPUTS))
+                .collect();
+            for input in inputs {
+                match input {
+                    Input::Stop => return,
+                    input => self.handle(input),
+                }
+            }
+
+            if Instant::now() >= next_tick {
+                self.node.tick();
+                next_tick = Instant::now() + self.config.tick;
+            }
+            self.process();
+        }
+    }
+
+    fn handle(&mut self, input: Input) {
+        if let Input::Message(message, addr) = input {
+            if let Some(addr) = addr {
+                self.addrs.insert(message.from, addr);
+            }
+            self.node.step(message);
+            return;
+        }
+
+        match self.node.state() {
+            State::Leader if self.node.is_ready() => self.execute(input),
+            State::Leader => self.deferred.push(input),
+            _ => self.reject(input),
+        }
+    }
+
+    fn execute(&mut self, input: Input) {
+        let term = self.node.term();
+        match input {
+            Input::Propose {
+                term: read,
+                now,
+                ops,
+                reply,
+            } => {
+                // The leader changed after the write read the shard
+                if read.is_some_and(|read| read != term) {
+                    let _ = reply.send(Err(WriteError::NotLeader(self.node.leader())));
+                    return;
+                }
+                match self.node.propose(Command::Write { now, ops }) {
+                    Ok(index) => {
+                        self.waiting.insert(index, (term, Waiter::Write(reply)));
+                    }
+                    Err(rejected) => {
+                        let _ = reply.send(Err(WriteError::NotLeader(leader(rejected))));
+                    }
+                }
+            }
+            Input::Barrier(reply) => {
+                let _ = reply.send(Ok(term));
+            }
+            Input::Change(change, reply) => {
+                let (result, message) = match change {
+                    Change::Add(member) => {
+                        let message = format!("Node {} is already a member", member.id);
+                        (self.node.add_member(member), message)
+                    }
+                    Change::Remove(id) => (
+                        self.node.remove_member(id),
+                        format!("Node {} isn't a member or is the last one", id),
+                    ),
+                };
+                let shard_id = self.shard_id;
+                let err = match result {
+                    Ok(index) => {
+                        self.waiting.insert(index, (term, Waiter::Change(reply)));
+                        return;
+                    }
+                    Err(Rejected::NotLeader(leader)) => {
+                        StorageError::NotLeader { shard_id, leader }
+                    }
+                    Err(Rejected::ChangePending) => StorageError::MembershipChange {
+                        shard_id,
+                        message: "The last change isn't committed yet".to_string(),
+                    },
+                    Err(Rejected::NoChange) => StorageError::MembershipChange { shard_id, message },
+                };
+                let _ = reply.send(Err(err));
+            }
+            Input::Message(..) | Input::Stop => unreachable!("Handled by the caller"),
+        }
+    }
+
+    fn reject(&mut self, input: Input) {
+        let leader = self.node.leader();
+        match input {
+            Input::Propose { reply, .. } => {
+                let _ = reply.send(Err(WriteError::NotLeader(leader)));
+            }
+            Input::Barrier(reply) => {
+                let _ = reply.send(Err(WriteError::NotLeader(leader)));
+            }
+            Input::Change(_, reply) => {
+                let _ = reply.send(Err(StorageError::NotLeader {
+                    shard_id: self.shard_id,
+                    leader,
+                }));
+            }
+            Input::Message(..) | Input::Stop => unreachable!("Handled by the caller"),
+        }
+    }
+
+    // Applies, installs and sends whatever the messages and ticks have brought about
+    fn process(&mut self) {
+        self.install();
+        self.apply();
+        if !self.deferred.is_empty() && (self.node.state() != State::Leader || self.node.is_ready())
+        {
+            for input in mem::take(&mut self.deferred) {
+                self.handle(input);
+            }
+            self.apply();
+        }
+        self.send_snapshots();
+
+        let applied = self.node.applied();
+        let max = self.config.max_log_entries as Index;
+        if applied - self.node.log().snapshot_index() > max {
+            self.node.compact(applied - max / 2);
+        }
+
+        for member in self.node.log().members() {
+            self.addrs.insert(member.id, member.addr.clone());
+        }
+        let messages = self.node.take_messages();
+        // A lost message is sent again by the protocol
+        if self.persist() {
+            for message in messages {
+                let addr = self.addrs.get(&message.to).map_or("", String::as_str);
+                self.transport.send(self.shard_id, addr, message);
+            }
+        }
+        *sync::lock(&self.status) = status(&self.node);
+    }
+
+    fn apply(&mut self) {
+        // A write is only acknowledged once the member remembers that it has the entry
+        if !self.persist() {
+            return;
+        }
+        loop {
+            let entries = self.node.committed_entries(APPLY_BATCH_SIZE);
+            if entries.is_empty() {
+                return;
+            }
+            for entry in entries {
+                let result = match entry.command {
+                    Command::Write { now, ops } => {
+                        let writer = self.engine.writer();
+                        let result = sync::lock(&*writer).apply_at(ops, now);
+                        match result {
+                            Ok(previous) => previous,
+                            Err(err) => {
+                                // Nothing was applied, so the entry is tried again after a tick
+                                log::warn!(
+                                    "Can't apply entry {} to shard {}: {}",
+                                    entry.index,
+                                    self.shard_id,
+                                    err
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    Command::Noop | Command::Config(_) => Vec::new(),
+                };
+                self.node.applied_to(entry.index);
+
+                if let Some((term, waiter)) = self.waiting.remove(&entry.index) {
+                    let result = match term == entry.term {
+                        true => Ok(result),
+                        // A new leader replaced the proposal
+                        false => Err(WriteError::NotLeader(self.node.leader())),
+                    };
+                    waiter.reply(self.shard_id, result);
+                }
+            }
+        }
+    }
+
+    // Saves the log and then the state of the member if they have changed. Returns false if they
+    // couldn't be saved.
+    fn persist(&mut self) -> bool {
+        let persist = match &mut self.persist {
+            Some(persist) => persist,
+            None => return true,
+        };
+        let state = self.node.hard_state();
+        let log = self.node.log();
+        let (start, end, end_term) = self.persisted_log;
+        // Entries with the same index and term are preceded by the same ones, otherwise the log
+        // was truncated
+        let rewrite = log.snapshot_index() != start || log.term(end) != Some(end_term);
+        if !rewrite && log.last_index() == end && state == self.persisted {
+            return true;
+        }
+        let revision = match rewrite {
+            true => {
+                let writer = self.engine.writer();
+                let revision = sync::lock(&*writer).revision();
+                Some(self.node.log_revision(revision))
+            }
+            false => None,
+        };
+
+        let persisted = self.persisted;
+        let result = persist(&mut |dir| {
+            match revision {
+                Some(revision) => state::save_log(dir, log, revision)?,
+                None if log.last_index() > end => {
+                    state::append_log(dir, &log.entries(end + 1, usize::MAX))?
+                }
+                None => {}
+            }
+            match state == persisted {
+                true => Ok(()),
+                false => state::save(dir, &state),
+            }
+        });
+        match result {
+            Ok(()) => {
+                self.persisted = state;
+                self.persisted_log = (log.snapshot_index(), log.last_index(), log.last_term());
+                true
+            }
+            Err(err) => {
+                // A failed append can leave a partial record behind
+                self.persisted_log = (Index::MAX, 0, 0);
+                log::error!(
+                    "Can't save the Raft log of shard {}: {}",
+                    self.shard_id,
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    fn install(&mut self) {
+        let snapshot = match self.node.take_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let Snapshot {
+            index,
+            term,
+            members,
+            revision,
+            records,
+        } = snapshot;
+        match (self.install)(revision, records.into_iter().collect()) {
+            Ok(engine) => {
+                self.engine = engine.clone();
+                *sync::lock(&self.current) = engine;
+                self.node.restore(index, term, members);
+                // Whatever this member proposed before is either in the snapshot or lost
+                let later = self.waiting.split_off(&(index + 1));
+                for (_, (_, waiter)) in mem::replace(&mut self.waiting, later) {
+                    waiter.reply(self.shard_id, Err(WriteError::NotCommitted));
+                }
+                log::info!(
+                    "Installed a snapshot of shard {} at index {} and revision {}",
+                    self.shard_id,
+                    index,
+                    revision
+                );
+            }
+            // The leader sends the snapshot again
+            Err(err) => log::error!(
+                "Can't install a snapshot of shard {} at index {}: {}",
+                self.shard_id,
+                index,
+                err
+            ),
+        }
+    }
+
+    fn send_snapshots(&mut self) {
+        for to in self.node.take_snapshot_requests() {
+            let snapshot = self.snapshot();
+            log::info!(
+                "Sending a snapshot of shard {} at index {} to node {}",
+                self.shard_id,
+                snapshot.index,
+                to
+            );
+            self.node.send_snapshot(to, snapshot);
+        }
+    }
+
+    // Copies the shard at the applied index
+    fn snapshot(&self) -> Snapshot {
+        let index = self.node.applied();
+        let log = self.node.log();
+        let writer = self.engine.writer();
+        let writer = sync::lock(&*writer);
+        let records = self
+            .engine
+            .snapshot()
+            .records()
+            .iter()
+            .map(|(key, record)| (key.clone(), record.clone()))
+            .collect();
+
+        Snapshot {
+            index,
+            term: log.term(index).unwrap_or_default(),
+            members: log.members_at(index).to_vec(),
+            revision: writer.revision(),
+            records,
+        }
+    }
+}
+
+impl Waiter {
+    fn reply(self, shard_id: usize, result: Result<Vec<Option<Val>>, WriteError>) {
+        match self {
+            Waiter::Write(reply) => {
+                let _ = reply.send(result);
+            }
+            Waiter::Change(reply) => {
+                let _ = reply.send(result.map(|_| ()).map_err(StorageError::write(shard_id)));
+            }
+        }
+    }
+}
+
+fn leader(rejected: Rejected) -> Option<NodeId> {
+    match rejected {
+        Rejected::NotLeader(leader) => leader,
+        // Only a change of the members is rejected for anything else
+        Rejected::ChangePending | Rejected::NoChange => None,
+    }
+}
+
+fn status(node: &RaftNode) -> RaftStatus {
+    RaftStatus {
+        node_id: node.id(),
+        term: node.term(),
+        state: node.state(),
+        leader: node.leader(),
+        commit: node.commit(),
+        applied: node.applied(),
+        members: node.log().members().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RaftStatus;
+    use crate::raft::node::State;
+    use crate::raft::transport::LocalNetwork;
+    use crate::raft::{Member, NodeId, RaftConfig};
+    use crate::storage::engine::{EngineKind, WriteError};
+    use crate::storage::error::StorageError;
+    use crate::storage::shard_map::{Role, ShardMap};
+    use crate::storage::wal::SyncPolicy;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn member(id: NodeId) -> Member {
+        Member {
+            id,
+            addr: String::new(),
+        }
+    }
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            tick: Duration::from_millis(5),
+            propose_timeout: Duration::from_secs(2),
+            max_log_entries: 20,
+            ..RaftConfig::default()
+        }
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn status(node: &ShardMap) -> RaftStatus {
+        node.raft(&1).unwrap().status()
+    }
+
+    // Waits until one of the nodes is the leader and returns its position
+    fn leader_of(nodes: &[Arc<ShardMap>]) -> usize {
+        let mut leader = None;
+        wait_for("a leader", || {
+            leader = nodes
+                .iter()
+                .position(|node| status(node).state == State::Leader);
+            leader.is_some()
+        });
+        leader.unwrap()
+    }
+
+    fn put(node: &ShardMap, key: &str, val: &str) -> Result<(), WriteError> {
+        let writer = node.writer(&1).unwrap();
+        let result = writer.lock().unwrap().put_str(key, val);
+        result.map(|_| ())
+    }
+
+    fn get(node: &ShardMap, key: &str) -> Option<Bytes> {
+        node.engine(&1).unwrap().get(&Bytes::from(key.to_string()))
+    }
+
+    #[test]
+    fn test_group() {
+        let network = Arc::new(LocalNetwork::new());
+        let nodes: Vec<_> = (1..=3)
+            .map(|id| {
+                let node = Arc::new(ShardMap::new());
+                node.create(1, EngineKind::LeftRight);
+                network.add(id, &node);
+                node
+            })
+            .collect();
+        // The founder keeps its records, the others get a copy
+        nodes[0]
+            .writer(&1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put_str("0", "-")
+            .unwrap();
+        nodes[1]
+            .writer(&1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put_str("stale", "-")
+            .unwrap();
+
+        let groups: Vec<_> = nodes
+            .iter()
+            .zip(1..)
+            .map(|(node, id)| {
+                let members = match id {
+                    1 => vec![member(1)],
+                    _ => Vec::new(),
+                };
+                ShardMap::start_raft(node, 1, id, members, network.clone(), config()).unwrap()
+            })
+            .collect();
+        wait_for("the founder to lead", || {
+            groups[0].status().state == State::Leader
+        });
+        groups[0].add_member(member(2)).unwrap();
+        groups[0].add_member(member(3)).unwrap();
+        assert_eq!(
+            groups[1].add_member(member(4)).err(),
+            Some(StorageError::NotLeader {
+                shard_id: 1,
+                leader: Some(1)
+            })
+        );
+        assert!(matches!(
+            groups[0].add_member(member(3)),
+            Err(StorageError::MembershipChange { .. })
+        ));
+
+        // More writes than the log holds, so it is compacted
+        for i in 1..=30 {
+            put(&nodes[0], &i.to_string(), "a").unwrap();
+        }
+        assert!(status(&nodes[0]).commit > 30);
+        for node in &nodes {
+            wait_for("the writes", || get(node, "30").is_some());
+            assert_eq!(get(node, "0"), Some(Bytes::from("-")));
+            assert_eq!(get(node, "stale"), None);
+            assert_eq!(node.stats(&1).unwrap().revision, 31);
+            assert_eq!(status(node).members, vec![member(1), member(2), member(3)]);
+        }
+        assert_eq!(
+            put(&nodes[1], "1", "b"),
+            Err(WriteError::NotLeader(Some(1)))
+        );
+
+        // The others elect a new leader without the cut off one
+        network.disconnect(1);
+        let leader = leader_of(&nodes[1..]) + 1;
+        put(&nodes[leader], "1", "b").unwrap();
+        assert!(put(&nodes[0], "1", "c").is_err());
+        wait_for("the old leader to step down", || {
+            status(&nodes[0]).state != State::Leader
+        });
+
+        // The old leader catches up once it is back
+        network.reconnect(1);
+        wait_for("the old leader to catch up", || {
+            get(&nodes[0], "1") == Some(Bytes::from("b"))
+        });
+
+        // It is removed and the group goes on with the two others. It might have forced an
+        // election on its return and won it.
+        let leader = leader_of(&nodes);
+        groups[leader].remove_member(1).unwrap();
+        wait_for("the last write", || {
+            let leader = leader_of(&nodes[1..]) + 1;
+            put(&nodes[leader], "2", "b").is_ok()
+        });
+        for node in &nodes[1..] {
+            wait_for("the last write", || {
+                get(node, "2") == Some(Bytes::from("b"))
+            });
+            assert_eq!(status(node).members, vec![member(2), member(3)]);
+        }
+        assert_eq!(get(&nodes[0], "2"), Some(Bytes::from("a")));
+
+        // Leaving the group makes the shard standalone again
+        drop(groups);
+        nodes[0].set_role(1, Role::Standalone).unwrap();
+        assert!(matches!(nodes[0].raft(&1), Err(StorageError::NotRaft(1))));
+        put(&nodes[0], "3", "c").unwrap();
+    }
+
+    #[test]
+    fn test_restart() {
+        let network = Arc::new(LocalNetwork::new());
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let open = |id: NodeId| {
+            let dir = dirs[id as usize - 1].path();
+            // The thread of the old member can hold on to its ShardMap for a moment
+            let mut node = ShardMap::open(dir);
+            while matches!(&node, Err(err) if err.kind() == io::ErrorKind::WouldBlock) {
+                thread::sleep(Duration::from_millis(5));
+                node = ShardMap::open(dir);
+            }
+            let node = Arc::new(node.unwrap());
+            network.add(id, &node);
+            node
+        };
+        let mut leader = open(1);
+        let mut node = open(2);
+        for node in &[&leader, &node] {
+            let sync = Some(SyncPolicy::Never);
+            node.install(1, EngineKind::Ordered, sync, HashMap::new(), false)
+                .unwrap();
+        }
+        let group = ShardMap::start_raft(&leader, 1, 1, vec![member(1)], network.clone(), config())
+            .unwrap();
+        ShardMap::start_raft(&node, 1, 2, Vec::new(), network.clone(), config()).unwrap();
+        wait_for("the founder to lead", || {
+            group.status().state == State::Leader
+        });
+        group.add_member(member(2)).unwrap();
+        drop(group);
+        // Enough to compact the logs
+        for i in 0..30 {
+            put(&leader, &i.to_string(), "a").unwrap();
+        }
+        wait_for("the writes", || get(&node, "29").is_some());
+
+        // A restarted member only serves reads until it rejoins with its log
+        let term = status(&node).term;
+        drop(node);
+        node = open(2);
+        let not_leader = StorageError::NotLeader {
+            shard_id: 1,
+            leader: None,
+        };
+        assert_eq!(node.client_queue(&1).err(), Some(not_leader));
+        assert!(matches!(node.raft(&1), Err(StorageError::NotRaft(1))));
+        let raft = node.stats(&1).unwrap().raft.unwrap();
+        assert_eq!(raft.node_id, 2);
+        assert_eq!(raft.state, State::Follower);
+        assert!(raft.term >= term);
+        assert_eq!(get(&node, "29"), Some(Bytes::from("a")));
+
+        ShardMap::restart_raft(&node, 2, network.clone(), config());
+        assert!(status(&node).term >= term);
+        assert_eq!(status(&node).members, vec![member(1), member(2)]);
+        put(&leader, "30", "b").unwrap();
+        wait_for("the member to catch up", || get(&node, "30").is_some());
+        assert!(node.client_queue(&1).is_ok());
+
+        // The whole group restarts on its own
+        drop(leader);
+        drop(node);
+        leader = open(1);
+        node = open(2);
+        ShardMap::restart_raft(&leader, 1, network.clone(), config());
+        ShardMap::restart_raft(&node, 2, network.clone(), config());
+        let nodes = [leader.clone(), node.clone()];
+        let first = &nodes[leader_of(&nodes)];
+        put(first, "31", "c").unwrap();
+        for node in &nodes {
+            wait_for("the write", || get(node, "31").is_some());
+            assert_eq!(get(node, "0"), Some(Bytes::from("a")));
+            assert_eq!(get(node, "30"), Some(Bytes::from("b")));
+        }
+        drop(nodes);
+
+        // Leaving the group deletes the state and the log
+        node.set_role(1, Role::Standalone).unwrap();
+        drop(node);
+        let node = open(2);
+        assert!(node.client_queue(&1).is_ok());
+        assert_eq!(node.stats(&1).unwrap().raft, None);
+        assert_eq!(get(&node, "31"), Some(Bytes::from("c")));
+    }
+}
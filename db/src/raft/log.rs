@@ -0,0 +1,212 @@
+use super::{Command, Entry, Index, Member, Term};
+
+/// The entries of a member after the last compaction. The entries before it are in the shard, the
+/// log only remembers where they end and who the members were at that point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftLog {
+    // The last compacted entry
+    snapshot_index: Index,
+    snapshot_term: Term,
+    snapshot_members: Vec<Member>,
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    /// A log that starts after the given entry.
+    pub fn new(index: Index, term: Term, members: Vec<Member>) -> Self {
+        Self {
+            snapshot_index: index,
+            snapshot_term: term,
+            snapshot_members: members,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn snapshot_index(&self) -> Index {
+        self.snapshot_index
+    }
+
+    pub fn first_index(&self) -> Index {
+        self.snapshot_index + 1
+    }
+
+    pub fn last_index(&self) -> Index {
+        self.snapshot_index + self.entries.len() as Index
+    }
+
+    pub fn last_term(&self) -> Term {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`. None if it was compacted or isn't there yet.
+    pub fn term(&self, index: Index) -> Option<Term> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub fn entry(&self, index: Index) -> Option<&Entry> {
+        let offset = index.checked_sub(self.first_index())?;
+        self.entries.get(offset as usize)
+    }
+
+    /// Up to `limit` entries from `from` on, which must not be compacted.
+    pub fn entries(&self, from: Index, limit: usize) -> Vec<Entry> {
+        let offset = (from - self.first_index()) as usize;
+        self.entries
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Appends a new entry and returns its index.
+    pub fn push(&mut self, term: Term, command: Command) -> Index {
+        let index = self.last_index() + 1;
+        self.entries.push(Entry {
+            index,
+            term,
+            command,
+        });
+        index
+    }
+
+    /// Appends the entries of the leader, which follow an entry that matches. The entries that
+    /// are already there are skipped, the first one that conflicts and everything after it is
+    /// replaced.
+    pub fn append(&mut self, entries: Vec<Entry>) {
+        for entry in entries {
+            match self.term(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    let offset = (entry.index - self.first_index()) as usize;
+                    self.entries.truncate(offset);
+                }
+                None => {}
+            }
+            debug_assert_eq!(entry.index, self.last_index() + 1);
+            self.entries.push(entry);
+        }
+    }
+
+    /// The members from the last config entry on, committed or not.
+    pub fn members(&self) -> &[Member] {
+        self.members_at(self.last_index())
+    }
+
+    /// The members at the time of the entry at `index`, which must not be compacted.
+    pub fn members_at(&self, index: Index) -> &[Member] {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.command {
+                Command::Config(members) => Some(members.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&self.snapshot_members)
+    }
+
+    /// The index of the last config entry, or of the compaction if there is none after it.
+    pub fn config_index(&self) -> Index {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.command, Command::Config(_)))
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    /// Drops the entries up to `index`, which the shard has applied.
+    pub fn compact(&mut self, index: Index) {
+        if index <= self.snapshot_index || index > self.last_index() {
+            return;
+        }
+        let members = self.members_at(index).to_vec();
+        self.snapshot_term = self.term(index).unwrap();
+        let offset = (index - self.snapshot_index) as usize;
+        self.entries.drain(..offset);
+        self.snapshot_index = index;
+        self.snapshot_members = members;
+    }
+
+    /// Replaces the whole log with a snapshot that ends at `index`.
+    pub fn restore(&mut self, index: Index, term: Term, members: Vec<Member>) {
+        *self = Self::new(index, term, members);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RaftLog;
+    use crate::raft::{Command, Entry, Member};
+
+    fn member(id: u64) -> Member {
+        Member {
+            id,
+            addr: String::new(),
+        }
+    }
+
+    fn entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let mut log = RaftLog::new(1, 1, vec![member(1)]);
+        assert_eq!(log.last_index(), 1);
+        assert_eq!(log.term(1), Some(1));
+        assert_eq!(log.term(0), None);
+        assert_eq!(log.push(2, Command::Noop), 2);
+        assert_eq!(log.push(2, Command::Config(vec![member(1), member(2)])), 3);
+        assert_eq!(log.push(2, Command::Noop), 4);
+        assert_eq!(log.members(), &[member(1), member(2)]);
+        assert_eq!(log.members_at(2), &[member(1)]);
+        assert_eq!(log.config_index(), 3);
+
+        // The entries that match are kept, the conflicting config is dropped with the rest
+        log.append(vec![entry(2, 2), entry(3, 3), entry(4, 3), entry(5, 3)]);
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.last_term(), 3);
+        assert_eq!(log.members(), &[member(1)]);
+        assert_eq!(log.config_index(), 1);
+        assert_eq!(log.entries(3, 2), vec![entry(3, 3), entry(4, 3)]);
+
+        // A duplicate append changes nothing
+        log.append(vec![entry(3, 3)]);
+        assert_eq!(log.last_index(), 5);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut log = RaftLog::new(0, 0, vec![member(1)]);
+        log.push(1, Command::Noop);
+        log.push(1, Command::Config(vec![member(2)]));
+        log.push(2, Command::Noop);
+        log.compact(2);
+        assert_eq!(log.first_index(), 3);
+        assert_eq!(log.term(2), Some(1));
+        assert_eq!(log.entry(2), None);
+        assert_eq!(log.members(), &[member(2)]);
+        assert_eq!(log.config_index(), 2);
+        assert_eq!(log.entries(3, 10), vec![entry(3, 2)]);
+
+        // Can't go back or past the end
+        log.compact(1);
+        log.compact(4);
+        assert_eq!(log.snapshot_index(), 2);
+
+        log.restore(7, 3, vec![member(3)]);
+        assert_eq!(log.last_index(), 7);
+        assert_eq!(log.last_term(), 3);
+        assert_eq!(log.members(), &[member(3)]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Raft groups that replicate a shard across nodes, with a leader that takes the writes and
+//! automatic failover.
+//!
+//! The members of a group are the same shard on different nodes. RaftNode is the protocol without
+//! any I/O, RaftGroup runs it on a thread per shard, applies the committed writes to the engine
+//! and sends the messages through a Transport. The shard itself is the snapshot of the group:
+//! the log is compacted once the shard has applied it, and a member that needs the compacted
+//! entries gets a copy of the records of the shard instead. A durable shard keeps the log and the
+//! vote of its member in its directory, see state.
+
+pub mod group;
+pub mod log;
+pub mod node;
+pub mod service;
+pub mod state;
+pub mod transport;
+
+use crate::storage::types::{Key, Operation, Record, Revision};
+use std::time::{Duration, SystemTime};
+
+/// Identifies a node in all groups it is a member of.
+pub type NodeId = u64;
+pub type Term = u64;
+/// The position of an entry in the log of a group. The first entry has index 1.
+pub type Index = u64;
+
+/// A member of a group, with the address its node is reached at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub id: NodeId,
+    /// e.g. http://10.0.0.2:10000, only used by the gRPC transport
+    pub addr: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Appended by every new leader. Once it is committed so is everything before it.
+    Noop,
+    /// A write to the shard, applied with a single revision at the time of the leader
+    Write {
+        now: SystemTime,
+        ops: Vec<Operation>,
+    },
+    /// The members of the group from this entry on, whether it is committed or not
+    Config(Vec<Member>),
+}
+
+impl Command {
+    /// Whether applying the entry moves the revision of the shard, see EngineWriter::apply_at.
+    pub fn moves_revision(&self) -> bool {
+        match self {
+            Command::Write { ops, .. } => !ops.is_empty(),
+            Command::Noop | Command::Config(_) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub index: Index,
+    pub term: Term,
+    pub command: Command,
+}
+
+/// A copy of the shard of the leader that replaces the log up to `index` on a member that is too
+/// far behind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub index: Index,
+    pub term: Term,
+    pub members: Vec<Member>,
+    /// The revision of the shard, which can be ahead of the last write in the records
+    pub revision: Revision,
+    pub records: Vec<(Key, Record)>,
+}
+
+/// A message between the members of a group. Messages are one way and can be lost, duplicated or
+/// reordered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// The term of the sender
+    pub term: Term,
+    pub body: Body,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Body {
+    /// A candidate asks for a vote
+    Vote {
+        last_index: Index,
+        last_term: Term,
+    },
+    VoteResponse {
+        granted: bool,
+    },
+    /// The leader sends the entries after `prev_index`, or none as a heartbeat
+    Append {
+        prev_index: Index,
+        prev_term: Term,
+        entries: Vec<Entry>,
+        commit: Index,
+    },
+    /// On success the log of the member matches the leader up to `index`, otherwise `index` is
+    /// where the leader should try next
+    AppendResponse {
+        success: bool,
+        index: Index,
+    },
+    /// The leader replaces the shard of a member, answered with an AppendResponse
+    Snapshot(Snapshot),
+}
+
+/// The timing of a group. Every timeout is counted in ticks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftConfig {
+    pub tick: Duration,
+    /// A follower that doesn't hear from its leader for this many ticks, plus a random part of up
+    /// to as many, starts an election. A leader that doesn't hear from a majority steps down.
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    /// How long a write waits to be committed before it fails with NotCommitted
+    pub propose_timeout: Duration,
+    /// The log is compacted once it holds more applied entries than this. Half of them are kept
+    /// for the members that are a little behind.
+    pub max_log_entries: usize,
+    /// Upper bound for the number of entries in a single append
+    pub max_append_entries: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            tick: Duration::from_millis(100),
+            election_ticks: 10,
+            heartbeat_ticks: 1,
+            propose_timeout: Duration::from_secs(5),
+            max_log_entries: 10_000,
+            max_append_entries: 1000,
+        }
+    }
+}
@@ -0,0 +1,1073 @@
+use super::log::RaftLog;
+use super::state::HardState;
+use super::{Body, Command, Entry, Index, Member, Message, NodeId, RaftConfig, Snapshot, Term};
+use crate::storage::types::Revision;
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+
+// How many ticks a leader waits for a snapshot to be installed before it sends another one
+const SNAPSHOT_TICKS: u32 = 50;
+
+/// The Raft protocol for a single member of a group, without any I/O. The caller feeds it the
+/// messages of the other members and ticks, sends the messages it queues, applies the entries it
+/// commits and installs the snapshots it receives, see RaftGroup.
+///
+/// Members are added and removed one at a time, so the majorities of the old and the new members
+/// always overlap. A change takes effect as soon as it is in the log and the next one has to wait
+/// until it is committed. A member doesn't start elections while it isn't in the group, and
+/// ignores the elections of others while it hears from a leader, so a removed member can't
+/// disrupt the group.
+pub struct RaftNode {
+    id: NodeId,
+    election_ticks: u32,
+    heartbeat_ticks: u32,
+    max_append_entries: usize,
+    term: Term,
+    vote: Option<NodeId>,
+    state: State,
+    leader: Option<NodeId>,
+    log: RaftLog,
+    commit: Index,
+    applied: Index,
+    // Ticks since the last message of the leader or since the election started. A leader counts
+    // the ticks until it checks that a majority is still there.
+    elapsed: u32,
+    // The randomized election timeout
+    timeout: u32,
+    heartbeat_elapsed: u32,
+    votes: HashMap<NodeId, bool>,
+    progress: HashMap<NodeId, Progress>,
+    // The Noop the leader appended when it was elected
+    term_start: Index,
+    messages: Vec<Message>,
+    // Received from the leader and not installed yet
+    snapshot: Option<Snapshot>,
+    // The members that need a snapshot from the leader
+    snapshot_requests: Vec<NodeId>,
+    // The end of the log the member had acknowledged before it was restarted without its log
+    recovered: (Term, Index),
+    rng: u64,
+}
+
+/// The part a member plays in the current term.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Why a member rejected a proposal. Nothing was appended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rejected {
+    /// Only the leader takes proposals. Holds the leader if it is known.
+    NotLeader(Option<NodeId>),
+    /// The last change of the members isn't committed yet
+    ChangePending,
+    /// The member is already in the group, or isn't there to be removed
+    NoChange,
+}
+
+// What the leader knows about a follower
+struct Progress {
+    // The next entry to send
+    next: Index,
+    // The last entry that is known to match
+    matched: Index,
+    // Whether the follower answered since the leader last checked
+    active: bool,
+    // Ticks until the leader sends another snapshot
+    snapshot_wait: u32,
+}
+
+impl RaftNode {
+    /// A member that founds a group with `members`, which all have the same shard. A member that
+    /// joins a group starts without members and waits until the leader adds it.
+    pub fn new(id: NodeId, members: Vec<Member>, config: &RaftConfig) -> Self {
+        // The founders start with the shard they have as the first entry, so the ones that join
+        // later get a copy of it
+        let (log, start) = match members.is_empty() {
+            true => (RaftLog::new(0, 0, members), 0),
+            false => (RaftLog::new(1, 1, members), 1),
+        };
+        let mut node = Self {
+            id,
+            election_ticks: config.election_ticks,
+            heartbeat_ticks: config.heartbeat_ticks,
+            max_append_entries: config.max_append_entries,
+            term: start,
+            vote: None,
+            state: State::Follower,
+            leader: None,
+            log,
+            commit: start,
+            applied: start,
+            elapsed: 0,
+            timeout: 0,
+            heartbeat_elapsed: 0,
+            votes: HashMap::new(),
+            progress: HashMap::new(),
+            term_start: 0,
+            messages: Vec::new(),
+            snapshot: None,
+            snapshot_requests: Vec::new(),
+            recovered: (0, 0),
+            // Any odd seed will do, it only has to differ between the members
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+        };
+        node.reset_timeout();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn term(&self) -> Term {
+        self.term
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn commit(&self) -> Index {
+        self.commit
+    }
+
+    pub fn applied(&self) -> Index {
+        self.applied
+    }
+
+    pub fn log(&self) -> &RaftLog {
+        &self.log
+    }
+
+    /// What the member has to remember across a restart. It changes with the term, the vote and
+    /// the end of the log, and has to be saved before the messages are sent and the entries are
+    /// applied.
+    pub fn hard_state(&self) -> HardState {
+        let (last_term, last_index) = self.last();
+        HardState {
+            node_id: self.id,
+            term: self.term,
+            vote: self.vote,
+            last_index,
+            last_term,
+        }
+    }
+
+    /// Restores the state a member saved before it was restarted, with its log and the last entry
+    /// the shard has applied if they were saved too, see state::load_log. Without them the member
+    /// joins the group like a new one, see new, but keeps its vote in the term and doesn't vote
+    /// for a candidate that is behind what it had acknowledged.
+    pub fn recover(&mut self, state: &HardState, log: Option<(RaftLog, Index)>) {
+        if state.term >= self.term {
+            self.term = state.term;
+            self.vote = state.vote;
+        }
+        self.recovered = cmp::max(self.recovered, (state.last_term, state.last_index));
+        if let Some((log, applied)) = log {
+            self.log = log;
+            self.commit = applied;
+            self.applied = applied;
+        }
+    }
+
+    /// The revision of the shard at the start of the log, from the revision it has now. Every
+    /// write up to the applied entry has moved the revision by one.
+    pub fn log_revision(&self, revision: Revision) -> Revision {
+        let from = self.log.first_index();
+        let count = (self.applied - self.log.snapshot_index()) as usize;
+        let entries = self.log.entries(from, count);
+        let writes = entries
+            .iter()
+            .filter(|entry| entry.command.moves_revision())
+            .count();
+        revision - writes as Revision
+    }
+
+    /// Whether the leader has applied every entry of the earlier terms. Until then its shard
+    /// might miss committed writes, so it shouldn't read from it to make a write.
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Leader && self.applied >= self.term_start
+    }
+
+    /// Advances the clock by one tick.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.state != State::Leader {
+            if self.elapsed >= self.timeout {
+                self.campaign();
+            }
+            return;
+        }
+
+        for progress in self.progress.values_mut() {
+            progress.snapshot_wait = progress.snapshot_wait.saturating_sub(1);
+        }
+        self.heartbeat_elapsed += 1;
+        if self.heartbeat_elapsed >= self.heartbeat_ticks {
+            self.heartbeat_elapsed = 0;
+            self.broadcast();
+        }
+        if self.elapsed >= self.election_ticks {
+            self.elapsed = 0;
+            // A leader cut off from the majority steps down instead of taking writes it can't
+            // commit
+            let active = self
+                .log
+                .members()
+                .iter()
+                .filter(|member| {
+                    member.id == self.id
+                        || self
+                            .progress
+                            .get(&member.id)
+                            .is_some_and(|progress| progress.active)
+                })
+                .count();
+            if active < self.quorum() {
+                log::warn!("Node {} lost the majority in term {}", self.id, self.term);
+                self.become_follower(self.term, None);
+                return;
+            }
+            for progress in self.progress.values_mut() {
+                progress.active = false;
+            }
+        }
+    }
+
+    /// Handles a message of another member.
+    pub fn step(&mut self, message: Message) {
+        if message.to != self.id {
+            return;
+        }
+        if message.term > self.term {
+            if let Body::Vote { .. } = message.body {
+                // Keeps a removed member, or one that was cut off, from disrupting a working group
+                if self.state == State::Leader
+                    || (self.leader.is_some() && self.elapsed < self.election_ticks)
+                {
+                    return;
+                }
+            }
+            let leader = match message.body {
+                Body::Append { .. } | Body::Snapshot(_) => Some(message.from),
+                _ => None,
+            };
+            self.become_follower(message.term, leader);
+        } else if message.term < self.term {
+            // The stale leader or candidate learns about the new term from the response
+            match message.body {
+                Body::Vote { .. } => {
+                    self.send(message.from, Body::VoteResponse { granted: false });
+                }
+                Body::Append { .. } | Body::Snapshot(_) => {
+                    let index = self.log.last_index();
+                    self.send(
+                        message.from,
+                        Body::AppendResponse {
+                            success: false,
+                            index,
+                        },
+                    );
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let from = message.from;
+        match message.body {
+            Body::Vote {
+                last_index,
+                last_term,
+            } => self.handle_vote(from, last_index, last_term),
+            Body::VoteResponse { granted } => self.handle_vote_response(from, granted),
+            Body::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => self.handle_append(from, prev_index, prev_term, entries, commit),
+            Body::AppendResponse { success, index } => {
+                self.handle_append_response(from, success, index)
+            }
+            Body::Snapshot(snapshot) => self.handle_snapshot(from, snapshot),
+        }
+    }
+
+    /// Appends a write to the log of the leader and returns its index. It is applied once it is
+    /// committed, unless a new leader replaces it.
+    pub fn propose(&mut self, command: Command) -> Result<Index, Rejected> {
+        if self.state != State::Leader {
+            return Err(Rejected::NotLeader(self.leader));
+        }
+        let index = self.log.push(self.term, command);
+        self.maybe_commit();
+        self.broadcast();
+        Ok(index)
+    }
+
+    /// Proposes to add a member. Returns the index of the config entry.
+    pub fn add_member(&mut self, member: Member) -> Result<Index, Rejected> {
+        let mut members = self.log.members().to_vec();
+        if members.iter().any(|m| m.id == member.id) {
+            return Err(Rejected::NoChange);
+        }
+        members.push(member);
+        self.change_members(members)
+    }
+
+    /// Proposes to remove a member. A leader that removes itself steps down once the change is
+    /// committed.
+    pub fn remove_member(&mut self, id: NodeId) -> Result<Index, Rejected> {
+        let mut members = self.log.members().to_vec();
+        let len = members.len();
+        members.retain(|m| m.id != id);
+        if members.len() == len || members.is_empty() {
+            return Err(Rejected::NoChange);
+        }
+        self.change_members(members)
+    }
+
+    fn change_members(&mut self, members: Vec<Member>) -> Result<Index, Rejected> {
+        if self.state != State::Leader {
+            return Err(Rejected::NotLeader(self.leader));
+        }
+        if self.log.config_index() > self.commit {
+            return Err(Rejected::ChangePending);
+        }
+
+        let index = self.log.push(self.term, Command::Config(members.clone()));
+        self.progress
+            .retain(|id, _| members.iter().any(|member| member.id == *id));
+        for member in members {
+            if member.id != self.id && !self.progress.contains_key(&member.id) {
+                self.progress.insert(member.id, Progress::new(index));
+            }
+        }
+        self.maybe_commit();
+        self.broadcast();
+        Ok(index)
+    }
+
+    /// The committed entries that aren't applied yet, at most `limit` of them.
+    pub fn committed_entries(&self, limit: usize) -> Vec<Entry> {
+        let from = self.applied + 1;
+        let count = cmp::min((self.commit - self.applied) as usize, limit);
+        self.log.entries(from, count)
+    }
+
+    /// Records that the shard has applied every entry up to `index`.
+    pub fn applied_to(&mut self, index: Index) {
+        debug_assert!(index <= self.commit);
+        self.applied = cmp::max(self.applied, index);
+    }
+
+    /// Drops the applied entries up to `index` from the log, see RaftLog::compact.
+    pub fn compact(&mut self, index: Index) {
+        self.log.compact(cmp::min(index, self.applied));
+    }
+
+    /// The messages for the other members, in the order they were queued.
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        mem::take(&mut self.messages)
+    }
+
+    /// A snapshot of the leader that has to replace the shard. The caller installs it and calls
+    /// restore.
+    pub fn take_snapshot(&mut self) -> Option<Snapshot> {
+        self.snapshot.take()
+    }
+
+    /// Records that the shard was replaced with a snapshot that ends at `index`.
+    pub fn restore(&mut self, index: Index, term: Term, members: Vec<Member>) {
+        self.log.restore(index, term, members);
+        self.commit = index;
+        self.applied = index;
+        if let Some(leader) = self.leader {
+            self.send(
+                leader,
+                Body::AppendResponse {
+                    success: true,
+                    index,
+                },
+            );
+        }
+    }
+
+    /// The members that are too far behind the log and need a snapshot. The caller copies the
+    /// shard at the applied index and hands it to send_snapshot.
+    pub fn take_snapshot_requests(&mut self) -> Vec<NodeId> {
+        mem::take(&mut self.snapshot_requests)
+    }
+
+    pub fn send_snapshot(&mut self, to: NodeId, snapshot: Snapshot) {
+        if self.state == State::Leader {
+            self.send(to, Body::Snapshot(snapshot));
+        }
+    }
+
+    fn campaign(&mut self) {
+        self.elapsed = 0;
+        self.reset_timeout();
+        if !self.is_member(self.id) {
+            return;
+        }
+
+        self.term += 1;
+        self.state = State::Candidate;
+        self.vote = Some(self.id);
+        self.leader = None;
+        self.votes.clear();
+        self.votes.insert(self.id, true);
+        log::info!("Node {} starts an election in term {}", self.id, self.term);
+        if self.quorum() == 1 {
+            self.become_leader();
+            return;
+        }
+
+        let last_index = self.log.last_index();
+        let last_term = self.log.last_term();
+        for id in self.peers() {
+            self.send(
+                id,
+                Body::Vote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+    }
+
+    fn become_leader(&mut self) {
+        log::info!("Node {} is the leader in term {}", self.id, self.term);
+        self.state = State::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.heartbeat_elapsed = 0;
+        let next = self.log.last_index() + 1;
+        self.progress = self
+            .peers()
+            .into_iter()
+            .map(|id| (id, Progress::new(next)))
+            .collect();
+        self.term_start = self.log.push(self.term, Command::Noop);
+        self.maybe_commit();
+        self.broadcast();
+    }
+
+    fn become_follower(&mut self, term: Term, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.vote = None;
+        }
+        self.state = State::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+        self.reset_timeout();
+        self.votes.clear();
+        self.progress.clear();
+    }
+
+    fn handle_vote(&mut self, from: NodeId, last_index: Index, last_term: Term) {
+        let free = match self.vote {
+            Some(vote) => vote == from,
+            None => self.leader.is_none(),
+        };
+        // The candidate must have every entry this member has, so it has every committed one
+        let up_to_date = (last_term, last_index) >= self.last();
+        let granted = free && up_to_date;
+        if granted {
+            self.vote = Some(from);
+            self.elapsed = 0;
+        }
+        self.send(from, Body::VoteResponse { granted });
+    }
+
+    fn handle_vote_response(&mut self, from: NodeId, granted: bool) {
+        if self.state != State::Candidate {
+            return;
+        }
+        self.votes.insert(from, granted);
+        let count = |granted: bool| {
+            self.log
+                .members()
+                .iter()
+                .filter(|member| self.votes.get(&member.id) == Some(&granted))
+                .count()
+        };
+        if count(true) >= self.quorum() {
+            self.become_leader();
+        } else if count(false) >= self.quorum() {
+            self.become_follower(self.term, None);
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        prev_index: Index,
+        prev_term: Term,
+        entries: Vec<Entry>,
+        commit: Index,
+    ) {
+        if self.state != State::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.elapsed = 0;
+
+        // Everything up to the commit index matches the leader already
+        if prev_index < self.commit {
+            let index = self.commit;
+            self.send(
+                from,
+                Body::AppendResponse {
+                    success: true,
+                    index,
+                },
+            );
+            return;
+        }
+        if self.log.term(prev_index) != Some(prev_term) {
+            let index = cmp::min(prev_index.saturating_sub(1), self.log.last_index());
+            self.send(
+                from,
+                Body::AppendResponse {
+                    success: false,
+                    index,
+                },
+            );
+            return;
+        }
+
+        let last = prev_index + entries.len() as Index;
+        self.log.append(entries);
+        self.commit = cmp::max(self.commit, cmp::min(commit, last));
+        self.send(
+            from,
+            Body::AppendResponse {
+                success: true,
+                index: last,
+            },
+        );
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, success: bool, index: Index) {
+        if self.state != State::Leader {
+            return;
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.active = true;
+        if success {
+            if index > progress.matched {
+                progress.matched = index;
+                progress.snapshot_wait = 0;
+            }
+            progress.next = cmp::max(progress.next, index + 1);
+            let behind = progress.next <= self.log.last_index();
+            self.maybe_commit();
+            if behind {
+                self.send_append(from);
+            }
+        } else {
+            // The follower is missing entries or has conflicting ones, go back and try again
+            progress.next = cmp::max(1, cmp::min(progress.next - 1, index + 1));
+            self.send_append(from);
+        }
+    }
+
+    fn handle_snapshot(&mut self, from: NodeId, snapshot: Snapshot) {
+        if self.state != State::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.elapsed = 0;
+
+        if snapshot.index <= self.commit {
+            let index = self.commit;
+            self.send(
+                from,
+                Body::AppendResponse {
+                    success: true,
+                    index,
+                },
+            );
+        } else if self.log.term(snapshot.index) == Some(snapshot.term) {
+            // The member has the entries, it only didn't know they were committed
+            self.commit = snapshot.index;
+            self.send(
+                from,
+                Body::AppendResponse {
+                    success: true,
+                    index: snapshot.index,
+                },
+            );
+        } else {
+            self.snapshot = Some(snapshot);
+        }
+    }
+
+    // Sends the entries a follower is missing, or asks for a snapshot if they were compacted
+    fn send_append(&mut self, to: NodeId) {
+        let progress = match self.progress.get_mut(&to) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if progress.next <= self.log.snapshot_index() {
+            if progress.snapshot_wait == 0 {
+                progress.snapshot_wait = SNAPSHOT_TICKS;
+                self.snapshot_requests.push(to);
+            }
+            return;
+        }
+
+        let prev_index = progress.next - 1;
+        let prev_term = self.log.term(prev_index).unwrap();
+        let entries = self.log.entries(progress.next, self.max_append_entries);
+        let commit = self.commit;
+        self.send(
+            to,
+            Body::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            },
+        );
+    }
+
+    // Sends the missing entries to every follower, or a heartbeat to the ones that have them all
+    fn broadcast(&mut self) {
+        let mut peers: Vec<_> = self.progress.keys().copied().collect();
+        peers.sort_unstable();
+        for id in peers {
+            self.send_append(id);
+        }
+    }
+
+    // Commits the last entry of the current term that a majority has. The entries of the earlier
+    // terms are committed with it.
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<_> = self
+            .log
+            .members()
+            .iter()
+            .map(|member| match member.id == self.id {
+                true => self.log.last_index(),
+                false => self
+                    .progress
+                    .get(&member.id)
+                    .map_or(0, |progress| progress.matched),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = match matched.get(self.quorum() - 1) {
+            Some(&index) => index,
+            None => return,
+        };
+        if index <= self.commit || self.log.term(index) != Some(self.term) {
+            return;
+        }
+
+        self.commit = index;
+        // Tell the followers right away instead of with the next heartbeat
+        self.broadcast();
+        if !self.is_member(self.id) && self.commit >= self.log.config_index() {
+            log::info!("Node {} was removed and steps down", self.id);
+            self.become_follower(self.term, None);
+        }
+    }
+
+    fn send(&mut self, to: NodeId, body: Body) {
+        self.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.log
+            .members()
+            .iter()
+            .map(|member| member.id)
+            .filter(|id| *id != self.id)
+            .collect()
+    }
+
+    // The end of the log, or what it was before a restart if the member hasn't caught up yet
+    fn last(&self) -> (Term, Index) {
+        cmp::max(
+            (self.log.last_term(), self.log.last_index()),
+            self.recovered,
+        )
+    }
+
+    fn is_member(&self, id: NodeId) -> bool {
+        self.log.members().iter().any(|member| member.id == id)
+    }
+
+    fn quorum(&self) -> usize {
+        self.log.members().len() / 2 + 1
+    }
+
+    fn reset_timeout(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.timeout = self.election_ticks + (self.rng % self.election_ticks as u64) as u32;
+    }
+}
+
+impl Progress {
+    fn new(next: Index) -> Self {
+        Self {
+            next,
+            matched: 0,
+            active: true,
+            snapshot_wait: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RaftNode, Rejected, State};
+    use crate::raft::{Command, Entry, Member, NodeId, RaftConfig, Snapshot};
+    use crate::storage::types::{Key, Operation};
+    use std::collections::{HashMap, HashSet};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn member(id: NodeId) -> Member {
+        Member {
+            id,
+            addr: String::new(),
+        }
+    }
+
+    // The time tells the writes apart
+    fn write(n: u64) -> Command {
+        Command::Write {
+            now: UNIX_EPOCH + Duration::from_secs(n),
+            ops: vec![Operation::Delete(Key::from("key"))],
+        }
+    }
+
+    // Delivers the messages between the nodes right away and applies what they commit
+    struct Cluster {
+        nodes: HashMap<NodeId, RaftNode>,
+        applied: HashMap<NodeId, Vec<Entry>>,
+        down: HashSet<NodeId>,
+    }
+
+    impl Cluster {
+        fn new(ids: &[NodeId]) -> Self {
+            let members: Vec<_> = ids.iter().copied().map(member).collect();
+            let mut cluster = Self {
+                nodes: HashMap::new(),
+                applied: HashMap::new(),
+                down: HashSet::new(),
+            };
+            for id in ids {
+                cluster.add(RaftNode::new(*id, members.clone(), &RaftConfig::default()));
+            }
+            cluster
+        }
+
+        fn add(&mut self, node: RaftNode) {
+            self.applied.insert(node.id(), Vec::new());
+            self.nodes.insert(node.id(), node);
+        }
+
+        fn node(&mut self, id: NodeId) -> &mut RaftNode {
+            self.nodes.get_mut(&id).unwrap()
+        }
+
+        // Ticks the node until it is the leader
+        fn elect(&mut self, id: NodeId) {
+            for _ in 0..100 {
+                self.node(id).tick();
+                self.deliver();
+                if self.node(id).state() == State::Leader {
+                    return;
+                }
+            }
+            panic!("Node {} wasn't elected", id);
+        }
+
+        fn tick_all(&mut self, ticks: usize) {
+            for _ in 0..ticks {
+                let mut ids: Vec<_> = self.nodes.keys().copied().collect();
+                ids.sort_unstable();
+                for id in ids {
+                    self.node(id).tick();
+                }
+                self.deliver();
+            }
+        }
+
+        fn deliver(&mut self) {
+            loop {
+                let mut ids: Vec<_> = self.nodes.keys().copied().collect();
+                ids.sort_unstable();
+                let mut messages = Vec::new();
+                for id in ids {
+                    let node = self.nodes.get_mut(&id).unwrap();
+                    for to in node.take_snapshot_requests() {
+                        let index = node.applied();
+                        let snapshot = Snapshot {
+                            index,
+                            term: node.log().term(index).unwrap(),
+                            members: node.log().members_at(index).to_vec(),
+                            revision: 0,
+                            records: Vec::new(),
+                        };
+                        node.send_snapshot(to, snapshot);
+                    }
+                    if let Some(snapshot) = node.take_snapshot() {
+                        node.restore(snapshot.index, snapshot.term, snapshot.members);
+                    }
+                    let entries = node.committed_entries(usize::MAX);
+                    if let Some(last) = entries.last() {
+                        node.applied_to(last.index);
+                    }
+                    self.applied.get_mut(&id).unwrap().extend(entries);
+                    messages.extend(node.take_messages());
+                }
+                if messages.is_empty() {
+                    return;
+                }
+                for message in messages {
+                    if self.down.contains(&message.from) || self.down.contains(&message.to) {
+                        continue;
+                    }
+                    if let Some(node) = self.nodes.get_mut(&message.to) {
+                        node.step(message);
+                    }
+                }
+            }
+        }
+
+        // The writes each node has applied, in order
+        fn writes(&self, id: NodeId) -> Vec<Command> {
+            self.applied[&id]
+                .iter()
+                .filter(|entry| matches!(entry.command, Command::Write { .. }))
+                .map(|entry| entry.command.clone())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_election() {
+        let mut cluster = Cluster::new(&[1, 2, 3]);
+        cluster.elect(1);
+        assert_eq!(cluster.node(1).term(), 2);
+        for id in &[2, 3] {
+            assert_eq!(cluster.node(*id).state(), State::Follower);
+            assert_eq!(cluster.node(*id).leader(), Some(1));
+        }
+        assert_eq!(
+            cluster.node(2).propose(write(1)),
+            Err(Rejected::NotLeader(Some(1)))
+        );
+
+        // The Noop of the new term, then the write
+        let index = cluster.node(1).propose(write(1)).unwrap();
+        assert_eq!(index, 3);
+        cluster.deliver();
+        for id in &[1, 2, 3] {
+            assert_eq!(cluster.node(*id).commit(), 3);
+            assert_eq!(cluster.writes(*id), vec![write(1)]);
+        }
+
+        // The heartbeats keep the leader
+        cluster.tick_all(50);
+        assert_eq!(cluster.node(1).state(), State::Leader);
+        assert_eq!(cluster.node(1).term(), 2);
+    }
+
+    #[test]
+    fn test_failover() {
+        let mut cluster = Cluster::new(&[1, 2, 3]);
+        cluster.elect(1);
+        cluster.node(1).propose(write(1)).unwrap();
+        cluster.deliver();
+
+        // The write of the cut off leader is never committed
+        cluster.down.insert(1);
+        cluster.node(1).propose(write(2)).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.node(1).commit(), 3);
+
+        // It steps down once it misses the majority, the others elect a new leader
+        cluster.tick_all(30);
+        assert_ne!(cluster.node(1).state(), State::Leader);
+        let leader = cluster.node(2).leader().unwrap();
+        assert_ne!(leader, 1);
+        assert_eq!(cluster.node(leader).state(), State::Leader);
+        cluster.node(leader).propose(write(3)).unwrap();
+        cluster.deliver();
+
+        // The old leader replaces its write with the ones of the new leader. It might have raised
+        // its term in the meantime and forced another election, which it can't win.
+        cluster.down.clear();
+        cluster.tick_all(100);
+        let leader = cluster.node(1).leader().unwrap();
+        assert_ne!(leader, 1);
+        for id in &[1, 2, 3] {
+            assert_eq!(cluster.node(*id).leader(), Some(leader));
+            assert_eq!(cluster.writes(*id), vec![write(1), write(3)]);
+        }
+        let last_index = cluster.node(leader).log().last_index();
+        assert_eq!(cluster.node(1).log().last_index(), last_index);
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut cluster = Cluster::new(&[1]);
+        cluster.elect(1);
+        for n in 0..10 {
+            cluster.node(1).propose(write(n)).unwrap();
+        }
+        cluster.deliver();
+        cluster.node(1).compact(8);
+        assert_eq!(cluster.node(1).log().snapshot_index(), 8);
+
+        // The new member is behind the compaction and gets a snapshot first
+        cluster.add(RaftNode::new(2, Vec::new(), &RaftConfig::default()));
+        for _ in 0..50 {
+            cluster.node(2).tick();
+        }
+        assert_eq!(cluster.node(2).state(), State::Follower);
+        let index = cluster.node(1).add_member(member(2)).unwrap();
+        assert_eq!(
+            cluster.node(1).add_member(member(3)),
+            Err(Rejected::ChangePending)
+        );
+        cluster.deliver();
+        assert_eq!(cluster.node(1).commit(), index);
+        assert_eq!(cluster.node(2).commit(), index);
+        assert_eq!(cluster.node(2).log().snapshot_index(), 12);
+        assert_eq!(cluster.node(2).log().members(), &[member(1), member(2)]);
+        assert_eq!(
+            cluster.node(1).add_member(member(2)),
+            Err(Rejected::NoChange)
+        );
+
+        // Both are needed for a commit now
+        cluster.node(1).propose(write(10)).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.writes(2), vec![write(10)]);
+
+        // The leader removes itself and steps down, the other one takes over
+        cluster.node(1).remove_member(1).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.node(1).state(), State::Follower);
+        cluster.elect(2);
+        assert_eq!(cluster.node(2).log().members(), &[member(2)]);
+        cluster.node(2).propose(write(11)).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.writes(2), vec![write(10), write(11)]);
+
+        // The removed member doesn't campaign
+        cluster.tick_all(50);
+        assert_eq!(cluster.node(2).state(), State::Leader);
+        assert_eq!(cluster.node(1).state(), State::Follower);
+    }
+
+    #[test]
+    fn test_recover() {
+        let mut cluster = Cluster::new(&[1, 2, 3]);
+        cluster.elect(1);
+        // Only the leader and 2 have the write
+        cluster.down.insert(3);
+        cluster.node(1).propose(write(1)).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.node(1).commit(), 3);
+
+        // 2 is restarted without its log while the leader is down
+        let state = cluster.node(2).hard_state();
+        assert_eq!(
+            (state.term, state.vote, state.last_index, state.last_term),
+            (2, Some(1), 3, 2)
+        );
+        let mut node = RaftNode::new(2, Vec::new(), &RaftConfig::default());
+        node.recover(&state, None);
+        assert_eq!(node.hard_state(), state);
+        cluster.add(node);
+        cluster.down = [1].iter().copied().collect();
+
+        // 3 misses the committed write, so 2 doesn't vote for it
+        cluster.tick_all(100);
+        assert_ne!(cluster.node(3).state(), State::Leader);
+        assert!(cluster.node(2).term() > 2);
+        assert_eq!(cluster.node(2).hard_state().last_index, 3);
+
+        // The old leader wins and 2 gets a copy of its shard
+        cluster.down.clear();
+        cluster.tick_all(200);
+        assert_eq!(cluster.node(1).state(), State::Leader);
+        assert_eq!(
+            cluster.node(2).log().members(),
+            &[member(1), member(2), member(3)]
+        );
+        assert_eq!(cluster.writes(3), vec![write(1)]);
+        cluster.node(1).propose(write(2)).unwrap();
+        cluster.deliver();
+        assert_eq!(cluster.writes(2), vec![write(2)]);
+    }
+
+    #[test]
+    fn test_recover_log() {
+        let mut cluster = Cluster::new(&[1, 2, 3]);
+        cluster.elect(1);
+        cluster.node(1).propose(write(1)).unwrap();
+        cluster.node(1).propose(write(2)).unwrap();
+        cluster.deliver();
+        cluster.tick_all(1);
+        assert_eq!(cluster.node(3).applied(), 4);
+
+        // The whole group is restarted with the logs, but the shard of 3 lost both writes
+        let mut restarted = Cluster::new(&[]);
+        for id in 1..=3 {
+            let node = cluster.node(id);
+            let applied = match id {
+                3 => 2,
+                _ => node.applied(),
+            };
+            let mut recovered = RaftNode::new(id, Vec::new(), &RaftConfig::default());
+            recovered.recover(&node.hard_state(), Some((node.log().clone(), applied)));
+            assert_eq!(recovered.hard_state(), node.hard_state());
+            // Entry 2 is the Noop of the leader
+            assert_eq!(recovered.log_revision(10), 10 - (applied - 2));
+            restarted.add(recovered);
+        }
+
+        // They elect a leader without a copy of a shard, and 3 applies the writes again
+        restarted.tick_all(100);
+        let leader = (1..=3)
+            .find(|id| restarted.node(*id).state() == State::Leader)
+            .unwrap();
+        restarted.node(leader).propose(write(3)).unwrap();
+        restarted.deliver();
+        restarted.tick_all(1);
+        assert_eq!(restarted.writes(leader), vec![write(3)]);
+        assert_eq!(restarted.writes(3), vec![write(1), write(2), write(3)]);
+        for id in 1..=3 {
+            assert_eq!(restarted.node(id).log().snapshot_index(), 1);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use super::transport::Transport;
+use super::{Body, Command, Entry, Member, Message, Snapshot};
+use crate::api::raft_api::raft_client::RaftClient;
+use crate::api::raft_api::raft_server::Raft;
+use crate::api::raft_api::{self as api, entry, message, StepResponse};
+use crate::replication::{decode_ops, decode_record, encode_ops, encode_record, nanos, time};
+use crate::storage::error::StorageError;
+use crate::storage::shard_map::ShardMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status};
+
+// Number of messages buffered for a node. A node that is slow or down loses the ones after them.
+const PEER_BUFFER: usize = 256;
+
+/// Hands the messages of the members on the other nodes to the Raft groups of the shards of the
+/// node. They are sent by a GrpcTransport.
+#[derive(Clone)]
+pub struct RaftService {
+    shard_map: Arc<ShardMap>,
+}
+
+/// Sends the messages of the Raft groups of the node through the Raft API. A task per node keeps
+/// the connection, it connects on the first message and again after an error.
+pub struct GrpcTransport {
+    addr: String,
+    messages: mpsc::UnboundedSender<(String, api::Message)>,
+}
+
+impl RaftService {
+    pub fn new(shard_map: Arc<ShardMap>) -> Self {
+        Self { shard_map }
+    }
+}
+
+#[tonic::async_trait]
+impl Raft for RaftService {
+    async fn step(&self, request: Request<api::Message>) -> Result<Response<StepResponse>, Status> {
+        let message = request.into_inner();
+        let shard_id = StorageError::shard_id(message.shard_id)?;
+        let group = self.shard_map.raft(&shard_id)?;
+        let from_addr = match message.from_addr.is_empty() {
+            true => None,
+            false => Some(message.from_addr.clone()),
+        };
+
+        group.receive(decode_message(message)?, from_addr);
+        Ok(Response::new(StepResponse {}))
+    }
+}
+
+impl GrpcTransport {
+    /// `addr` is where the other nodes reach this one, e.g. http://10.0.0.1:10000. Must be called
+    /// on the runtime, which runs the tasks.
+    pub fn new(addr: String) -> Self {
+        let (messages, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(dispatch(receiver));
+        Self { addr, messages }
+    }
+}
+
+impl Transport for GrpcTransport {
+    fn send(&self, shard_id: usize, addr: &str, message: Message) {
+        if addr.is_empty() {
+            log::debug!("No address for node {}", message.to);
+            return;
+        }
+        let message = encode_message(shard_id, self.addr.clone(), message);
+        let _ = self.messages.send((addr.to_string(), message));
+    }
+}
+
+// Hands the messages to the task of their node
+async fn dispatch(mut messages: mpsc::UnboundedReceiver<(String, api::Message)>) {
+    let mut peers: HashMap<String, mpsc::Sender<api::Message>> = HashMap::new();
+    while let Some((addr, message)) = messages.recv().await {
+        let peer = peers.entry(addr).or_insert_with_key(|addr| {
+            let (sender, receiver) = mpsc::channel(PEER_BUFFER);
+            tokio::spawn(deliver(addr.clone(), receiver));
+            sender
+        });
+        // The protocol sends the lost messages again
+        let _ = peer.try_send(message);
+    }
+}
+
+async fn deliver(addr: String, mut messages: mpsc::Receiver<api::Message>) {
+    let mut client = None;
+    while let Some(message) = messages.recv().await {
+        if client.is_none() {
+            match RaftClient::connect(addr.clone()).await {
+                Ok(connected) => client = Some(connected),
+                Err(err) => {
+                    log::debug!("Can't connect to {}: {}", addr, err);
+                    continue;
+                }
+            }
+        }
+        if let Err(err) = client.as_mut().unwrap().step(message).await {
+            log::debug!("Can't send a Raft message to {}: {}", addr, err);
+            client = None;
+        }
+    }
+}
+
+pub(crate) fn encode_message(shard_id: usize, from_addr: String, message: Message) -> api::Message {
+    let body = match message.body {
+        Body::Vote {
+            last_index,
+            last_term,
+        } => message::Body::Vote(api::Vote {
+            last_index,
+            last_term,
+        }),
+        Body::VoteResponse { granted } => {
+            message::Body::VoteResponse(api::VoteResponse { granted })
+        }
+        Body::Append {
+            prev_index,
+            prev_term,
+            entries,
+            commit,
+        } => message::Body::Append(api::Append {
+            prev_index,
+            prev_term,
+            entries: entries.into_iter().map(encode_entry).collect(),
+            commit,
+        }),
+        Body::AppendResponse { success, index } => {
+            message::Body::AppendResponse(api::AppendResponse { success, index })
+        }
+        Body::Snapshot(snapshot) => message::Body::Snapshot(api::Snapshot {
+            index: snapshot.index,
+            term: snapshot.term,
+            members: encode_members(snapshot.members),
+            revision: snapshot.revision,
+            records: snapshot.records.into_iter().map(encode_record).collect(),
+        }),
+    };
+
+    api::Message {
+        shard_id: shard_id as i64,
+        from: message.from,
+        to: message.to,
+        term: message.term,
+        from_addr,
+        body: Some(body),
+    }
+}
+
+pub(crate) fn decode_message(message: api::Message) -> Result<Message, Status> {
+    let body = match message.body {
+        Some(message::Body::Vote(vote)) => Body::Vote {
+            last_index: vote.last_index,
+            last_term: vote.last_term,
+        },
+        Some(message::Body::VoteResponse(response)) => Body::VoteResponse {
+            granted: response.granted,
+        },
+        Some(message::Body::Append(append)) => Body::Append {
+            prev_index: append.prev_index,
+            prev_term: append.prev_term,
+            entries: append
+                .entries
+                .into_iter()
+                .map(decode_entry)
+                .collect::<Result<_, _>>()?,
+            commit: append.commit,
+        },
+        Some(message::Body::AppendResponse(response)) => Body::AppendResponse {
+            success: response.success,
+            index: response.index,
+        },
+        Some(message::Body::Snapshot(snapshot)) => Body::Snapshot(Snapshot {
+            index: snapshot.index,
+            term: snapshot.term,
+            members: decode_members(snapshot.members),
+            revision: snapshot.revision,
+            records: snapshot.records.into_iter().map(decode_record).collect(),
+        }),
+        None => return Err(Status::new(Code::InvalidArgument, "Empty Raft message")),
+    };
+
+    Ok(Message {
+        from: message.from,
+        to: message.to,
+        term: message.term,
+        body,
+    })
+}
+
+fn encode_entry(entry: Entry) -> api::Entry {
+    let command = match entry.command {
+        Command::Noop => entry::Command::Noop(api::Noop {}),
+        Command::Write { now, ops } => entry::Command::Write(api::Write {
+            now_ns: nanos(now),
+            mutations: encode_ops(ops),
+        }),
+        Command::Config(members) => entry::Command::Config(api::Config {
+            members: encode_members(members),
+        }),
+    };
+
+    api::Entry {
+        index: entry.index,
+        term: entry.term,
+        command: Some(command),
+    }
+}
+
+fn decode_entry(entry: api::Entry) -> Result<Entry, Status> {
+    let command = match entry.command {
+        Some(entry::Command::Noop(_)) => Command::Noop,
+        Some(entry::Command::Write(write)) => Command::Write {
+            now: time(write.now_ns),
+            ops: decode_ops(write.mutations)?,
+        },
+        Some(entry::Command::Config(config)) => Command::Config(decode_members(config.members)),
+        None => return Err(Status::new(Code::InvalidArgument, "Empty Raft entry")),
+    };
+
+    Ok(Entry {
+        index: entry.index,
+        term: entry.term,
+        command,
+    })
+}
+
+fn encode_members(members: Vec<Member>) -> Vec<api::Member> {
+    members
+        .into_iter()
+        .map(|member| api::Member {
+            id: member.id,
+            addr: member.addr,
+        })
+        .collect()
+}
+
+fn decode_members(members: Vec<api::Member>) -> Vec<Member> {
+    members
+        .into_iter()
+        .map(|member| Member {
+            id: member.id,
+            addr: member.addr,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_message, encode_message};
+    use crate::raft::{Body, Command, Entry, Member, Message, Snapshot};
+    use crate::storage::types::{Operation, Record};
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    fn message(body: Body) -> Message {
+        Message {
+            from: 1,
+            to: 2,
+            term: 3,
+            body,
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let now = SystemTime::now();
+        let member = Member {
+            id: 2,
+            addr: "http://127.0.0.1:10001".to_string(),
+        };
+        let entries = vec![
+            Entry {
+                index: 4,
+                term: 3,
+                command: Command::Noop,
+            },
+            Entry {
+                index: 5,
+                term: 3,
+                command: Command::Write {
+                    now,
+                    ops: vec![
+                        Operation::Put(Bytes::from("a"), Bytes::from("1")),
+                        Operation::Expire(Bytes::from("b"), Some(now + Duration::from_secs(1))),
+                        Operation::Delete(Bytes::from("c")),
+                    ],
+                },
+            },
+            Entry {
+                index: 6,
+                term: 3,
+                command: Command::Config(vec![member.clone()]),
+            },
+        ];
+        let record = Record {
+            val: Bytes::from("1"),
+            create_revision: 1,
+            mod_revision: 2,
+            expires_at: None,
+        };
+        let messages = vec![
+            message(Body::Vote {
+                last_index: 3,
+                last_term: 2,
+            }),
+            message(Body::VoteResponse { granted: true }),
+            message(Body::Append {
+                prev_index: 3,
+                prev_term: 2,
+                entries,
+                commit: 3,
+            }),
+            message(Body::AppendResponse {
+                success: false,
+                index: 2,
+            }),
+            message(Body::Snapshot(Snapshot {
+                index: 6,
+                term: 3,
+                members: vec![member],
+                revision: 9,
+                records: vec![(Bytes::from("a"), record)],
+            })),
+        ];
+
+        for message in messages {
+            let encoded = encode_message(7, "http://127.0.0.1:10000".to_string(), message.clone());
+            assert_eq!(encoded.shard_id, 7);
+            assert_eq!(decode_message(encoded).unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! What a member of a Raft group keeps in the directory of a durable shard, next to the WAL of the
+//! shard: its HardState and its log.
+//!
+//! The log starts with a record of where it starts, followed by a record per entry. New entries
+//! are appended and synced, and an entry replaces the ones from its index on. The log is written
+//! again once it is compacted or replaced with a snapshot. A restarted member finds the last
+//! entry its shard has applied from the revision the shard was recovered at, as every write moves
+//! the revision by one, and rejoins its group with its log, see ShardMap::restart_raft.
+
+use super::log::RaftLog;
+use super::{Command, Entry, Index, Member, NodeId, Term};
+use crate::storage::types::Revision;
+use crate::storage::wal::{self, Decoder, Encoder};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+const FILE: &str = "raft";
+const TMP_FILE: &str = "raft.tmp";
+const LEN: usize = 41;
+const LOG_FILE: &str = "raft.log";
+const LOG_TMP_FILE: &str = "raft.log.tmp";
+
+// The records of the log
+const START: u8 = 0;
+const ENTRY: u8 = 1;
+
+// The commands of the entries
+const NOOP: u8 = 0;
+const WRITE: u8 = 1;
+const CONFIG: u8 = 2;
+
+/// What a member of a Raft group has to remember across a restart besides its log, see
+/// RaftNode::hard_state. The member saves it before it sends its messages or applies its entries,
+/// so it never votes twice in a term and never forgets that it has acknowledged an entry.
+///
+/// A member whose log is lost or doesn't match its shard rejoins its group like a new member and
+/// gets a copy of the shard of the leader, see RaftNode::recover.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HardState {
+    pub node_id: NodeId,
+    pub term: Term,
+    pub vote: Option<NodeId>,
+    /// The end of the log the member has acknowledged. After a restart it only votes for the
+    /// candidates whose log goes at least as far, as they might miss a committed entry otherwise.
+    pub last_index: Index,
+    pub last_term: Term,
+}
+
+/// Reads the Raft state in the directory of a shard. None if the shard isn't a member of a group.
+pub fn load(dir: &Path) -> io::Result<Option<HardState>> {
+    let data = match fs::read(dir.join(FILE)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if data.len() != LEN || data[16] > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Damaged Raft state in {}", dir.display()),
+        ));
+    }
+
+    let u64_at = |pos: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[pos..pos + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    Ok(Some(HardState {
+        node_id: u64_at(0),
+        term: u64_at(8),
+        vote: match data[16] {
+            1 => Some(u64_at(17)),
+            _ => None,
+        },
+        last_index: u64_at(25),
+        last_term: u64_at(33),
+    }))
+}
+
+/// Replaces the Raft state in the directory of a shard. The new state is only read once it is
+/// complete and synced.
+pub fn save(dir: &Path, state: &HardState) -> io::Result<()> {
+    let mut data = Vec::with_capacity(LEN);
+    data.extend_from_slice(&state.node_id.to_le_bytes());
+    data.extend_from_slice(&state.term.to_le_bytes());
+    data.push(state.vote.is_some() as u8);
+    data.extend_from_slice(&state.vote.unwrap_or_default().to_le_bytes());
+    data.extend_from_slice(&state.last_index.to_le_bytes());
+    data.extend_from_slice(&state.last_term.to_le_bytes());
+
+    let tmp = dir.join(TMP_FILE);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+/// Deletes the Raft state and the log of a shard that left its group, so it isn't read-only after
+/// a restart.
+pub fn remove(dir: &Path) -> io::Result<()> {
+    for file in &[FILE, LOG_FILE] {
+        match fs::remove_file(dir.join(file)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    File::open(dir)?.sync_all()
+}
+
+/// Reads the log of a member and finds the last entry its shard has applied, from the `revision`
+/// the shard was recovered at. None if there is no log, or if the shard isn't at a revision of
+/// the log, e.g. because a crash of the machine lost writes that weren't synced before the log
+/// was compacted.
+pub fn load_log(dir: &Path, revision: Revision) -> io::Result<Option<(RaftLog, Index)>> {
+    let data = match fs::read(dir.join(LOG_FILE)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let damaged = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Damaged Raft log in {}", dir.display()),
+        )
+    };
+
+    // An incomplete record at the end is an append that wasn't acknowledged
+    let (records, _) = wal::records(&data);
+    let mut records = records.into_iter();
+    let mut input = Decoder(records.next().ok_or_else(damaged)?);
+    if input.u8()? != START {
+        return Err(damaged());
+    }
+    let (index, term, start) = (input.u64()?, input.u64()?, input.u64()?);
+    let members = members(&mut input)?;
+    let mut entries: Vec<Entry> = Vec::new();
+    for record in records {
+        let entry = decode_entry(record)?;
+        if entry.index <= index || entry.index > index + entries.len() as Index + 1 {
+            return Err(damaged());
+        }
+        entries.truncate((entry.index - index - 1) as usize);
+        entries.push(entry);
+    }
+
+    let (mut applied, mut at) = (index, start);
+    for entry in &entries {
+        if at >= revision {
+            break;
+        }
+        applied = entry.index;
+        if entry.command.moves_revision() {
+            at += 1;
+        }
+    }
+    if at != revision {
+        log::warn!(
+            "The Raft log in {} goes from revision {} to {}, the shard is at {}",
+            dir.display(),
+            start,
+            at,
+            revision
+        );
+        return Ok(None);
+    }
+    let mut log = RaftLog::new(index, term, members);
+    log.append(entries);
+    Ok(Some((log, applied)))
+}
+
+/// Replaces the log of a member. `revision` is the revision of the shard at the start of the
+/// log, see RaftNode::log_revision. The new log is only read once it is complete and synced.
+pub fn save_log(dir: &Path, log: &RaftLog, revision: Revision) -> io::Result<()> {
+    let index = log.snapshot_index();
+    let mut out = Encoder(Vec::new());
+    out.u8(START);
+    out.u64(index);
+    out.u64(log.term(index).unwrap_or_default());
+    out.u64(revision);
+    encode_members(&mut out, log.members_at(index));
+    let mut data = wal::frame(&out.0)?;
+    for entry in log.entries(log.first_index(), usize::MAX) {
+        data.extend_from_slice(&wal::frame(&encode_entry(&entry))?);
+    }
+
+    let tmp = dir.join(LOG_TMP_FILE);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(LOG_FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+/// Appends entries to the log of a member and syncs them. An entry replaces the ones from its
+/// index on.
+pub fn append_log(dir: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut data = Vec::new();
+    for entry in entries {
+        data.extend_from_slice(&wal::frame(&encode_entry(entry))?);
+    }
+    let mut file = OpenOptions::new().append(true).open(dir.join(LOG_FILE))?;
+    file.write_all(&data)?;
+    file.sync_data()
+}
+
+fn encode_entry(entry: &Entry) -> Vec<u8> {
+    let mut out = Encoder(Vec::new());
+    out.u8(ENTRY);
+    out.u64(entry.index);
+    out.u64(entry.term);
+    match &entry.command {
+        Command::Noop => out.u8(NOOP),
+        Command::Write { now, ops } => {
+            out.u8(WRITE);
+            out.time(*now);
+            out.ops(ops);
+        }
+        Command::Config(members) => {
+            out.u8(CONFIG);
+            encode_members(&mut out, members);
+        }
+    }
+    out.0
+}
+
+fn decode_entry(payload: &[u8]) -> io::Result<Entry> {
+    let mut input = Decoder(payload);
+    if input.u8()? != ENTRY {
+        return Err(invalid("Expected a Raft log entry".to_string()));
+    }
+    let (index, term) = (input.u64()?, input.u64()?);
+    let command = match input.u8()? {
+        NOOP => Command::Noop,
+        WRITE => Command::Write {
+            now: input.time()?,
+            ops: input.ops()?,
+        },
+        CONFIG => Command::Config(members(&mut input)?),
+        command => return Err(invalid(format!("Unknown Raft command: {}", command))),
+    };
+    Ok(Entry {
+        index,
+        term,
+        command,
+    })
+}
+
+fn encode_members(out: &mut Encoder, members: &[Member]) {
+    out.u64(members.len() as u64);
+    for member in members {
+        out.u64(member.id);
+        out.bytes(member.addr.as_bytes());
+    }
+}
+
+fn members(input: &mut Decoder) -> io::Result<Vec<Member>> {
+    let count = input.u64()?;
+    let mut members = Vec::new();
+    for _ in 0..count {
+        let id = input.u64()?;
+        let addr = String::from_utf8(input.bytes()?.to_vec())
+            .map_err(|_| invalid(format!("Invalid address of member {}", id)))?;
+        members.push(Member { id, addr });
+    }
+    Ok(members)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HardState;
+    use crate::raft::log::RaftLog;
+    use crate::raft::{Command, Entry, Member};
+    use crate::storage::types::{Key, Operation};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::slice;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_state() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(super::load(dir.path()).unwrap(), None);
+
+        let mut state = HardState {
+            node_id: 2,
+            term: 7,
+            vote: Some(0),
+            last_index: 120,
+            last_term: 6,
+        };
+        super::save(dir.path(), &state).unwrap();
+        assert_eq!(super::load(dir.path()).unwrap(), Some(state));
+        state.vote = None;
+        super::save(dir.path(), &state).unwrap();
+        assert_eq!(super::load(dir.path()).unwrap(), Some(state));
+
+        fs::write(dir.path().join("raft"), [0; 40]).unwrap();
+        assert!(super::load(dir.path()).is_err());
+        super::remove(dir.path()).unwrap();
+        super::remove(dir.path()).unwrap();
+        assert_eq!(super::load(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_log() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(super::load_log(dir.path(), 0).unwrap(), None);
+
+        let members = vec![Member {
+            id: 1,
+            addr: "http://10.0.0.1:10000".to_string(),
+        }];
+        let write = || Command::Write {
+            now: UNIX_EPOCH,
+            ops: vec![Operation::Put(Key::from("key"), Key::from("val"))],
+        };
+        let mut log = RaftLog::new(3, 2, members.clone());
+        log.push(3, Command::Noop);
+        log.push(3, write());
+        log.push(3, Command::Config(Vec::new()));
+        log.push(3, write());
+        super::save_log(dir.path(), &log, 10).unwrap();
+
+        // The last entry the shard has applied follows from its revision
+        for (revision, applied) in &[(10, 3), (11, 5), (12, 7)] {
+            let loaded = super::load_log(dir.path(), *revision).unwrap();
+            assert_eq!(loaded, Some((log.clone(), *applied)));
+        }
+        assert_eq!(super::load_log(dir.path(), 9).unwrap(), None);
+        assert_eq!(super::load_log(dir.path(), 13).unwrap(), None);
+
+        // An appended entry replaces the ones from its index on, a partial one is ignored
+        let entry = Entry {
+            index: 6,
+            term: 4,
+            command: write(),
+        };
+        super::append_log(dir.path(), slice::from_ref(&entry)).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("raft.log"))
+            .unwrap();
+        file.write_all(&[1, 0, 0]).unwrap();
+        let mut expected = RaftLog::new(3, 2, members);
+        expected.append(log.entries(4, 2));
+        expected.append(vec![entry]);
+        let loaded = super::load_log(dir.path(), 12).unwrap();
+        assert_eq!(loaded, Some((expected, 6)));
+
+        super::remove(dir.path()).unwrap();
+        assert_eq!(super::load_log(dir.path(), 12).unwrap(), None);
+    }
+}
@@ -0,0 +1,59 @@
+use super::{Message, NodeId};
+use crate::storage::shard_map::ShardMap;
+use crate::storage::sync;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, Weak};
+
+/// Carries the messages of the Raft groups to the other nodes. The gRPC one is GrpcTransport,
+/// LocalNetwork connects the nodes of a single process.
+pub trait Transport: Send + Sync {
+    /// Sends a message to the member of the shard on the node at `addr`, which is empty if the
+    /// address isn't known. Doesn't block and doesn't report errors, a lost message is sent
+    /// again by the protocol.
+    fn send(&self, shard_id: usize, addr: &str, message: Message);
+}
+
+/// Connects the ShardMaps of a process as if they were nodes of a cluster, for tests. Messages are
+/// routed by node id and delivered right away. A disconnected node neither sends nor receives.
+#[derive(Default)]
+pub struct LocalNetwork {
+    nodes: RwLock<HashMap<NodeId, Weak<ShardMap>>>,
+    down: RwLock<HashSet<NodeId>>,
+}
+
+impl LocalNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, node_id: NodeId, shard_map: &Arc<ShardMap>) {
+        sync::write(&self.nodes).insert(node_id, Arc::downgrade(shard_map));
+    }
+
+    /// Drops the messages from and to the node until it is reconnected.
+    pub fn disconnect(&self, node_id: NodeId) {
+        sync::write(&self.down).insert(node_id);
+    }
+
+    pub fn reconnect(&self, node_id: NodeId) {
+        sync::write(&self.down).remove(&node_id);
+    }
+}
+
+impl Transport for LocalNetwork {
+    fn send(&self, shard_id: usize, _addr: &str, message: Message) {
+        {
+            let down = sync::read(&self.down);
+            if down.contains(&message.from) || down.contains(&message.to) {
+                return;
+            }
+        }
+        let shard_map = match sync::read(&self.nodes).get(&message.to) {
+            Some(shard_map) => shard_map.upgrade(),
+            None => None,
+        };
+        if let Some(Ok(group)) = shard_map.map(|shard_map| shard_map.raft(&shard_id)) {
+            group.receive(message, None);
+        }
+    }
+}
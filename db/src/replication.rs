@@ -0,0 +1,698 @@
+use crate::api::replication_api::replication_client::ReplicationClient;
+use crate::api::replication_api::replication_server::Replication;
+use crate::api::replication_api::{
+    self as api, mutation, AppendRequest, AppendResponse, InstallRequest, InstallResponse, Mutation,
+};
+use crate::storage::engine::StorageEngine;
+use crate::storage::error::StorageError;
+use crate::storage::replicated::{LogEntry, Replicated};
+use crate::storage::shard_map::ShardMap;
+use crate::storage::types::{Key, Operation, Record, Revision, Val};
+use std::cmp;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+// Upper bound for the number of entries in a single append
+const APPEND_BATCH_SIZE: usize = 1000;
+// Number of records in a single message of an install
+const INSTALL_CHUNK_SIZE: usize = 1000;
+// How long a primary waits before it tries a failed backup again, doubled up to the maximum
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Applies the writes of the primaries to the backup shards of the node. The primaries call it
+/// through the Replication API.
+#[derive(Clone)]
+pub struct ReplicationService {
+    shard_map: Arc<ShardMap>,
+}
+
+/// A backup of a primary shard. The one on another node is a RemoteBackup, a ReplicationService
+/// is a backup in the same process.
+#[tonic::async_trait]
+pub trait Backup: Send + 'static {
+    /// Applies the entries that follow the revision of the backup and returns its new revision.
+    async fn append(&mut self, shard_id: usize, entries: Vec<LogEntry>)
+        -> Result<Revision, Status>;
+
+    /// Replaces the records of the backup with a copy of the primary at `revision`.
+    async fn install(
+        &mut self,
+        shard_id: usize,
+        revision: Revision,
+        records: Vec<(Key, Record)>,
+    ) -> Result<(), Status>;
+}
+
+/// A backup on another node. Connects on the first call and again after an error.
+pub struct RemoteBackup {
+    addr: String,
+    client: Option<ReplicationClient<Channel>>,
+}
+
+impl ReplicationService {
+    pub fn new(shard_map: Arc<ShardMap>) -> Self {
+        Self { shard_map }
+    }
+
+    /// Applies the entries that follow the revision of the backup shard on its writer thread and
+    /// returns the new revision. The entries it already has are skipped and a gap stops the
+    /// append, so the primary resends from the returned revision.
+    pub async fn apply(
+        &self,
+        shard_id: usize,
+        entries: Vec<LogEntry>,
+    ) -> Result<Revision, StorageError> {
+        let queue = self.shard_map.backup_queue(&shard_id)?;
+        queue
+            .submit(move |writer| {
+                for entry in entries {
+                    let revision = writer.revision();
+                    if entry.revision <= revision {
+                        continue;
+                    }
+                    if entry.revision != revision + 1 {
+                        break;
+                    }
+                    writer
+                        .apply_at(entry.ops, entry.now)
+                        .map_err(StorageError::write(shard_id))?;
+                }
+                Ok(writer.revision())
+            })
+            .await?
+    }
+
+    /// Replaces the records of the backup shard, see ShardMap::restore_backup.
+    pub async fn restore(
+        &self,
+        shard_id: usize,
+        revision: Revision,
+        records: Vec<(Key, Record)>,
+    ) -> Result<(), Status> {
+        // Builds the maps of the engine and writes the log of a durable shard
+        let shard_map = self.shard_map.clone();
+        tokio::task::spawn_blocking(move || {
+            let data = records.into_iter().collect();
+            shard_map.restore_backup(shard_id, revision, data)
+        })
+        .await
+        .map_err(|err| Status::new(Code::Internal, err.to_string()))??;
+        log::info!(
+            "Restored backup shard {} at revision {}",
+            shard_id,
+            revision
+        );
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Replication for ReplicationService {
+    async fn append(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let entries = request
+            .entries
+            .into_iter()
+            .map(decode_entry)
+            .collect::<Result<_, _>>()?;
+
+        let revision = self.apply(shard_id, entries).await?;
+        Ok(Response::new(AppendResponse { revision }))
+    }
+
+    async fn install(
+        &self,
+        request: Request<Streaming<InstallRequest>>,
+    ) -> Result<Response<InstallResponse>, Status> {
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Empty install"))?;
+        let shard_id = StorageError::shard_id(first.shard_id)?;
+        let revision = first.revision;
+
+        let mut records = Vec::new();
+        let mut chunk = first.records;
+        loop {
+            records.extend(chunk.into_iter().map(decode_record));
+            chunk = match stream.message().await? {
+                Some(message) => message.records,
+                None => break,
+            };
+        }
+
+        self.restore(shard_id, revision, records).await?;
+        Ok(Response::new(InstallResponse {}))
+    }
+}
+
+#[tonic::async_trait]
+impl Backup for ReplicationService {
+    async fn append(
+        &mut self,
+        shard_id: usize,
+        entries: Vec<LogEntry>,
+    ) -> Result<Revision, Status> {
+        Ok(self.apply(shard_id, entries).await?)
+    }
+
+    async fn install(
+        &mut self,
+        shard_id: usize,
+        revision: Revision,
+        records: Vec<(Key, Record)>,
+    ) -> Result<(), Status> {
+        self.restore(shard_id, revision, records).await
+    }
+}
+
+impl RemoteBackup {
+    /// The address of the node, e.g. http://10.0.0.2:10000
+    pub fn new(addr: String) -> Self {
+        Self { addr, client: None }
+    }
+
+    async fn client(&mut self) -> Result<&mut ReplicationClient<Channel>, Status> {
+        if self.client.is_none() {
+            let client = ReplicationClient::connect(self.addr.clone())
+                .await
+                .map_err(|err| Status::new(Code::Unavailable, err.to_string()))?;
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    // Drops the connection after an error, the next call connects again
+    fn reset<T>(&mut self, result: Result<T, Status>) -> Result<T, Status> {
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+}
+
+#[tonic::async_trait]
+impl Backup for RemoteBackup {
+    async fn append(
+        &mut self,
+        shard_id: usize,
+        entries: Vec<LogEntry>,
+    ) -> Result<Revision, Status> {
+        let request = AppendRequest {
+            shard_id: shard_id as i64,
+            entries: entries.into_iter().map(encode_entry).collect(),
+        };
+        let result = self.client().await?.append(request).await;
+        Ok(self.reset(result)?.into_inner().revision)
+    }
+
+    async fn install(
+        &mut self,
+        shard_id: usize,
+        revision: Revision,
+        records: Vec<(Key, Record)>,
+    ) -> Result<(), Status> {
+        // The first message names the shard, even if there are no records
+        let mut chunks: Vec<_> = records
+            .chunks(INSTALL_CHUNK_SIZE)
+            .map(<[_]>::to_vec)
+            .collect();
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+        let messages: Vec<_> = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, records)| InstallRequest {
+                shard_id: if i == 0 { shard_id as i64 } else { 0 },
+                revision: if i == 0 { revision } else { 0 },
+                records: records.into_iter().map(encode_record).collect(),
+            })
+            .collect();
+
+        let result = self
+            .client()
+            .await?
+            .install(tokio::stream::iter(messages))
+            .await;
+        self.reset(result)?;
+        Ok(())
+    }
+}
+
+/// Ships the log of a primary shard to its backups, with a task per backup. A task installs a
+/// copy of the shard on its backup first and then sends the writes in the order of their
+/// revisions, retrying until the backup has applied them. A backup that falls behind the log
+/// gets a new copy. The tasks exit once the shard stops being the primary.
+pub fn start_replication(primary: &Arc<Replicated>, backups: Vec<Box<dyn Backup>>) {
+    for (index, backup) in backups.into_iter().enumerate() {
+        let head = primary.log().head();
+        tokio::spawn(ship(Arc::downgrade(primary), head, index, backup));
+    }
+}
+
+async fn ship(
+    primary: Weak<Replicated>,
+    mut head: watch::Receiver<Revision>,
+    index: usize,
+    mut backup: Box<dyn Backup>,
+) {
+    // The revision the backup has applied, None until it has a copy of the shard
+    let mut acked: Option<Revision> = None;
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        let result = {
+            let primary = match primary.upgrade() {
+                Some(primary) => primary,
+                None => return,
+            };
+            let shard_id = primary.id();
+            let log = primary.log();
+
+            match acked.and_then(|revision| log.entries_after(revision, APPEND_BATCH_SIZE)) {
+                Some(entries) if entries.is_empty() => Ok(()),
+                Some(entries) => {
+                    let last = entries.last().unwrap().revision;
+                    backup.append(shard_id, entries).await.map(|revision| {
+                        // A backup ahead of the primary has diverged and needs a new copy
+                        acked = Some(revision).filter(|revision| *revision <= last);
+                        if let Some(revision) = acked {
+                            log.ack(index, revision);
+                        }
+                    })
+                }
+                None => {
+                    let copy = primary.clone();
+                    let (revision, records) = tokio::task::spawn_blocking(move || copy.copy())
+                        .await
+                        .expect("Copying the primary panicked");
+                    log::info!(
+                        "Installing shard {} at revision {} on backup {}",
+                        shard_id,
+                        revision,
+                        index
+                    );
+                    backup.install(shard_id, revision, records).await.map(|()| {
+                        acked = Some(revision);
+                        log.ack(index, revision);
+                    })
+                }
+            }
+            .map_err(|status| (shard_id, status))
+        };
+
+        match result {
+            Ok(()) => delay = MIN_RETRY_DELAY,
+            Err((shard_id, status)) => {
+                log::warn!(
+                    "Can't replicate shard {} to backup {}: {}",
+                    shard_id,
+                    index,
+                    status.message()
+                );
+                tokio::time::delay_for(delay).await;
+                delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+                continue;
+            }
+        }
+
+        // Waits for the next write once the backup has all of them
+        if let Some(revision) = acked {
+            loop {
+                match head.recv().await {
+                    Some(head) if head > revision => break,
+                    Some(_) => {}
+                    // The shard isn't the primary anymore
+                    None => return,
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+pub(crate) fn time(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+fn expires_at(nanos: u64) -> Option<SystemTime> {
+    match nanos {
+        0 => None,
+        nanos => Some(time(nanos)),
+    }
+}
+
+fn encode_entry(entry: LogEntry) -> api::LogEntry {
+    api::LogEntry {
+        revision: entry.revision,
+        now_ns: nanos(entry.now),
+        mutations: encode_ops(entry.ops),
+    }
+}
+
+fn decode_entry(entry: api::LogEntry) -> Result<LogEntry, Status> {
+    Ok(LogEntry {
+        revision: entry.revision,
+        now: time(entry.now_ns),
+        ops: decode_ops(entry.mutations)?,
+    })
+}
+
+pub(crate) fn encode_ops(ops: Vec<Operation>) -> Vec<Mutation> {
+    ops.into_iter()
+        .map(|op| {
+            let (kind, key, val, expires_at) = match op {
+                Operation::Put(key, val) => (mutation::Kind::Put, key, Some(val), None),
+                Operation::PutWithExpiry(key, val, expires_at) => {
+                    (mutation::Kind::Put, key, Some(val), Some(expires_at))
+                }
+                Operation::Expire(key, expires_at) => {
+                    (mutation::Kind::Expire, key, None, expires_at)
+                }
+                Operation::Delete(key) => (mutation::Kind::Delete, key, None, None),
+            };
+            Mutation {
+                kind: kind as i32,
+                key: key.to_vec(),
+                val: val.map_or_else(Vec::new, |val| val.to_vec()),
+                expires_at_ns: expires_at.map_or(0, nanos),
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn decode_ops(mutations: Vec<Mutation>) -> Result<Vec<Operation>, Status> {
+    mutations
+        .into_iter()
+        .map(|mutation| {
+            let key = Key::from(mutation.key);
+            let expires_at = expires_at(mutation.expires_at_ns);
+            match mutation::Kind::from_i32(mutation.kind) {
+                Some(mutation::Kind::Put) => {
+                    let val = Val::from(mutation.val);
+                    Ok(match expires_at {
+                        Some(expires_at) => Operation::PutWithExpiry(key, val, expires_at),
+                        None => Operation::Put(key, val),
+                    })
+                }
+                Some(mutation::Kind::Expire) => Ok(Operation::Expire(key, expires_at)),
+                Some(mutation::Kind::Delete) => Ok(Operation::Delete(key)),
+                None => Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("Unknown mutation: {}", mutation.kind),
+                )),
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn encode_record((key, record): (Key, Record)) -> api::Record {
+    api::Record {
+        key: key.to_vec(),
+        val: record.val.to_vec(),
+        create_revision: record.create_revision,
+        mod_revision: record.mod_revision,
+        expires_at_ns: record.expires_at.map_or(0, nanos),
+    }
+}
+
+pub(crate) fn decode_record(record: api::Record) -> (Key, Record) {
+    let key = Key::from(record.key);
+    let record = Record {
+        val: Val::from(record.val),
+        create_revision: record.create_revision,
+        mod_revision: record.mod_revision,
+        expires_at: expires_at(record.expires_at_ns),
+    };
+    (key, record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_entry, encode_entry, start_replication, Backup, ReplicationService};
+    use crate::storage::engine::EngineKind;
+    use crate::storage::error::StorageError;
+    use crate::storage::replicated::LogEntry;
+    use crate::storage::shard_map::{Role, ShardMap};
+    use crate::storage::types::{Key, Operation, Record, Revision};
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tonic::{Code, Status};
+
+    fn primary(backups: usize, acks: usize) -> Role {
+        Role::Primary {
+            backups,
+            acks,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn backup_node() -> Arc<ShardMap> {
+        let shard_map = Arc::new(ShardMap::new());
+        shard_map.create(1, EngineKind::Ordered);
+        shard_map.set_role(1, Role::Backup).unwrap();
+        shard_map
+    }
+
+    // Writes through the queue of the shard and waits for the backups, like StorageService
+    async fn put(shard_map: &ShardMap, key: &'static str, val: &'static str) {
+        let (queue, replicated) = shard_map.client_queue(&1).unwrap();
+        let revision = queue
+            .submit(move |writer| {
+                writer.put_str(key, val).unwrap();
+                writer.revision()
+            })
+            .await
+            .unwrap();
+        replicated
+            .unwrap()
+            .log()
+            .replicated(revision)
+            .await
+            .unwrap();
+    }
+
+    // Fails every call while `failing` is set
+    struct Flaky {
+        backup: ReplicationService,
+        failing: Arc<AtomicUsize>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), Status> {
+            match self.failing.load(Ordering::SeqCst) {
+                0 => Ok(()),
+                _ => Err(Status::new(Code::Unavailable, "Down")),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Backup for Flaky {
+        async fn append(
+            &mut self,
+            shard_id: usize,
+            entries: Vec<LogEntry>,
+        ) -> Result<Revision, Status> {
+            self.check()?;
+            self.backup.append(shard_id, entries).await
+        }
+
+        async fn install(
+            &mut self,
+            shard_id: usize,
+            revision: Revision,
+            records: Vec<(Key, Record)>,
+        ) -> Result<(), Status> {
+            self.check()?;
+            self.backup.install(shard_id, revision, records).await
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let now = SystemTime::now();
+        let entry = LogEntry {
+            revision: 7,
+            now,
+            ops: vec![
+                Operation::Put(Bytes::from("1"), Bytes::from("a")),
+                Operation::PutWithExpiry(Bytes::from("2"), Bytes::from("b"), now),
+                Operation::Expire(Bytes::from("2"), None),
+                Operation::Expire(Bytes::from("1"), Some(now)),
+                Operation::Delete(Bytes::from("3")),
+            ],
+        };
+        assert_eq!(decode_entry(encode_entry(entry.clone())).unwrap(), entry);
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let node = Arc::new(ShardMap::new());
+        node.create(1, EngineKind::LeftRight);
+        // Written before there are backups, so they get it with the copy of the shard
+        node.writer(&1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put_str("0", "-")
+            .unwrap();
+        let backups = [backup_node(), backup_node()];
+
+        let primary = node.set_role(1, primary(2, 2)).unwrap().unwrap();
+        let services = backups
+            .iter()
+            .map(|shard_map| {
+                Box::new(ReplicationService::new(shard_map.clone())) as Box<dyn Backup>
+            })
+            .collect();
+        start_replication(&primary, services);
+
+        put(&node, "1", "a").await;
+        put(&node, "2", "b").await;
+        {
+            let (queue, _) = node.client_queue(&1).unwrap();
+            queue
+                .submit(|writer| {
+                    writer.delete(&Bytes::from("0")).unwrap();
+                    writer.expire(&Bytes::from("1"), Some(Duration::from_secs(60)))
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        put(&node, "3", "c").await;
+
+        let expected = node
+            .engine(&1)
+            .unwrap()
+            .scan_prefix(&Bytes::new(), None, 10)
+            .entries;
+        assert_eq!(expected.len(), 3);
+        for shard_map in &backups {
+            let engine = shard_map.engine(&1).unwrap();
+            assert_eq!(
+                engine.scan_prefix(&Bytes::new(), None, 10).entries,
+                expected
+            );
+            assert_eq!(
+                shard_map.stats(&1).unwrap().revision,
+                node.stats(&1).unwrap().revision
+            );
+
+            // The backups only take the writes of the primary
+            assert_eq!(
+                shard_map.client_queue(&1).err(),
+                Some(StorageError::NotPrimary(1))
+            );
+        }
+        assert_eq!(
+            node.backup_queue(&1).err(),
+            Some(StorageError::NotBackup(1))
+        );
+
+        // The tasks stop with the primary, the old log doesn't take writes anymore
+        node.set_role(1, Role::Standalone).unwrap();
+        let (queue, replicated) = node.client_queue(&1).unwrap();
+        assert!(replicated.is_none());
+        queue
+            .submit(|writer| writer.put_str("4", "d"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(primary.log().entries_after(6, 10), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_failing_backup() {
+        let node = Arc::new(ShardMap::new());
+        node.create(1, EngineKind::Striped);
+        let backups = [backup_node(), backup_node()];
+        let failing = Arc::new(AtomicUsize::new(0));
+
+        // A write only needs one of the backups
+        let primary = node.set_role(1, primary(2, 1)).unwrap().unwrap();
+        let services: Vec<Box<dyn Backup>> = vec![
+            Box::new(ReplicationService::new(backups[0].clone())),
+            Box::new(Flaky {
+                backup: ReplicationService::new(backups[1].clone()),
+                failing: failing.clone(),
+            }),
+        ];
+        start_replication(&primary, services);
+        put(&node, "1", "a").await;
+
+        failing.store(1, Ordering::SeqCst);
+        put(&node, "2", "b").await;
+        put(&node, "3", "c").await;
+        assert_eq!(backups[0].engine(&1).unwrap().len(), 3);
+
+        // The backup catches up once it is back
+        failing.store(0, Ordering::SeqCst);
+        put(&node, "4", "d").await;
+        let revision = node.stats(&1).unwrap().revision;
+        for _ in 0..500 {
+            if backups[1].stats(&1).unwrap().revision == revision {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(backups[1].engine(&1).unwrap().len(), 4);
+        assert_eq!(backups[1].stats(&1).unwrap().revision, revision);
+
+        // Without enough backups the write is applied but fails
+        let node = Arc::new(ShardMap::new());
+        node.create(1, EngineKind::Locked);
+        let primary = node
+            .set_role(
+                1,
+                Role::Primary {
+                    backups: 1,
+                    acks: 1,
+                    timeout: Duration::from_millis(50),
+                },
+            )
+            .unwrap()
+            .unwrap();
+        failing.store(1, Ordering::SeqCst);
+        start_replication(
+            &primary,
+            vec![Box::new(Flaky {
+                backup: ReplicationService::new(backup_node()),
+                failing,
+            })],
+        );
+        let (queue, _) = node.client_queue(&1).unwrap();
+        let revision = queue
+            .submit(|writer| {
+                writer.put_str("1", "a").unwrap();
+                writer.revision()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            primary.log().replicated(revision).await,
+            Err(StorageError::NotReplicated {
+                shard_id: 1,
+                revision
+            })
+        );
+        assert_eq!(node.engine(&1).unwrap().len(), 1);
+    }
+}
@@ -1,11 +1,31 @@
 use crate::api::storage_api::storage_server::Storage;
 use crate::api::storage_api::{
-    DeleteRequest, DeleteResponse, GetRequest, GetResponse, PutRequest, PutResponse,
+    self as api, operation, precondition, CompareAndSwapRequest, CompareAndSwapResponse,
+    DeleteIfEqualsRequest, DeleteIfEqualsResponse, DeleteRequest, DeleteResponse, ExpireRequest,
+    ExpireResponse, GetRequest, GetResponse, GetResult, KeyValue, MultiDeleteRequest,
+    MultiDeleteResponse, MultiGetRequest, MultiGetResponse, MultiPutRequest, MultiPutResponse,
+    PrefixRequest, PutIfAbsentRequest, PutIfAbsentResponse, PutRequest, PutResponse, ScanRequest,
+    ScanResponse, TransactionRequest, TransactionResponse, TtlRequest, TtlResponse, Value,
+    WriteBatchRequest, WriteBatchResponse, WriteResult,
 };
+use crate::storage::engine::{EngineWriter, Page, StorageEngine};
+use crate::storage::error::StorageError;
+use crate::storage::replicated::Replicated;
 use crate::storage::shard_map::ShardMap;
+use crate::storage::types::{Key, Operation, Precondition, Revision, Val};
+use crate::storage::write_queue::WriteQueue;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
 use tonic::{Code, Request, Response, Status};
 
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10_000;
+// Number of entries in a single message of a streamed page
+const CHUNK_SIZE: usize = 100;
+
 #[derive(Clone)]
 pub struct StorageService {
     shard_map: Arc<ShardMap>,
@@ -15,44 +35,277 @@ impl StorageService {
     pub fn new(shard_map: Arc<ShardMap>) -> Self {
         Self { shard_map }
     }
+
+    fn engine(&self, shard_id: usize) -> Result<Arc<dyn StorageEngine>, StorageError> {
+        self.shard_map
+            .engine(&shard_id)
+            .ok_or(StorageError::ShardNotFound(shard_id))
+    }
+
+    fn write_queue(
+        &self,
+        shard_id: usize,
+    ) -> Result<(WriteQueue, Option<Arc<Replicated>>), StorageError> {
+        self.shard_map.client_queue(&shard_id)
+    }
+
+    /// Runs `f` on the writer thread of the shard, together with the other writes that are
+    /// waiting for it. The handler doesn't block a runtime thread while the write is applied.
+    /// On a primary it then waits for the backups.
+    async fn write<T, F>(&self, shard_id: usize, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&mut dyn EngineWriter) -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (queue, replicated) = self.write_queue(shard_id)?;
+        let (result, revision) = queue
+            .submit(move |writer| {
+                let result = f(writer);
+                (result, writer.revision())
+            })
+            .await?;
+        let result = result?;
+        Self::replicated(replicated, revision).await?;
+        Ok(result)
+    }
+
+    /// Waits until enough backups of a primary have applied the writes up to `revision`. The
+    /// other shards have nothing to wait for.
+    async fn replicated(
+        replicated: Option<Arc<Replicated>>,
+        revision: Revision,
+    ) -> Result<(), StorageError> {
+        match replicated {
+            Some(replicated) => replicated.log().replicated(revision).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Applies a single operation, guarded by the key's revision unless expected_revision is 0.
+    fn checked(
+        writer: &mut dyn EngineWriter,
+        shard_id: usize,
+        key: &Key,
+        expected_revision: Revision,
+        op: Operation,
+    ) -> Result<Option<Val>, StorageError> {
+        if expected_revision == 0 {
+            let mut result = writer
+                .apply(vec![op])
+                .map_err(StorageError::write(shard_id))?;
+            return Ok(result.pop().unwrap());
+        }
+
+        let precondition = Precondition::Revision(key.clone(), expected_revision);
+        match writer
+            .transaction(&[precondition], vec![op])
+            .map_err(StorageError::write(shard_id))?
+        {
+            Ok(mut result) => Ok(result.pop().unwrap()),
+            Err(_) => Err(StorageError::RevisionMismatch {
+                shard_id,
+                expected: expected_revision,
+            }),
+        }
+    }
+
+    #[inline]
+    fn previous(previous: Option<Val>, return_previous: bool) -> Option<Value> {
+        if return_previous {
+            previous.map(|val| Value { val: val.to_vec() })
+        } else {
+            None
+        }
+    }
+
+    fn put_operation(key: Key, val: Val, ttl_ms: u64) -> Operation {
+        if ttl_ms == 0 {
+            Operation::Put(key, val)
+        } else {
+            let expires_at = SystemTime::now() + Duration::from_millis(ttl_ms);
+            Operation::PutWithExpiry(key, val, expires_at)
+        }
+    }
+
+    /// Groups the items of a multi-shard request by shard, keeping their index in the request.
+    fn group_by_shard<T>(
+        items: impl Iterator<Item = (i64, T)>,
+    ) -> Result<BTreeMap<usize, Vec<(usize, T)>>, StorageError> {
+        let mut shards = BTreeMap::new();
+        for (index, (shard_id, item)) in items.enumerate() {
+            shards
+                .entry(StorageError::shard_id(shard_id)?)
+                .or_insert_with(Vec::new)
+                .push((index, item));
+        }
+        Ok(shards)
+    }
+
+    /// Sends the operations of every shard to its writer thread, where they are applied with a
+    /// single swap. The shards apply their writes in parallel. If one of them can't accept writes
+    /// the request fails but the writes to the other shards are kept. Nothing is written if one
+    /// of the shards doesn't exist or is a backup.
+    async fn apply_by_shard(
+        &self,
+        shards: BTreeMap<usize, Vec<(usize, Operation)>>,
+        count: usize,
+        return_previous: bool,
+    ) -> Result<Vec<WriteResult>, StorageError> {
+        let queues = shards
+            .keys()
+            .map(|shard_id| self.write_queue(*shard_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Queue the writes on all shards before waiting for any of them
+        let writes: Vec<_> = shards
+            .into_iter()
+            .zip(queues)
+            .map(|((shard_id, ops), (queue, replicated))| {
+                let (indices, ops): (Vec<_>, Vec<_>) = ops.into_iter().unzip();
+                let previous = queue.submit(move |writer| {
+                    let previous = writer.apply(ops).map_err(StorageError::write(shard_id));
+                    (previous, writer.revision())
+                });
+                (indices, previous, replicated)
+            })
+            .collect();
+
+        let mut results = vec![WriteResult::default(); count];
+        for (indices, previous, replicated) in writes {
+            let (previous, revision) = previous.await?;
+            let previous = previous?;
+            Self::replicated(replicated, revision).await?;
+
+            for (index, previous) in indices.into_iter().zip(previous) {
+                results[index] = WriteResult {
+                    existed: previous.is_some(),
+                    previous: Self::previous(previous, return_previous),
+                };
+            }
+        }
+        Ok(results)
+    }
+
+    fn page_size(limit: u32) -> usize {
+        match limit as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            limit => limit.min(MAX_PAGE_SIZE),
+        }
+    }
+
+    /// Splits a page into chunks. The page is copied out of the shard before it is sent, so a
+    /// slow client doesn't pin the records while it reads.
+    fn stream(page: Page) -> mpsc::Receiver<Result<ScanResponse, Status>> {
+        let next_page_token = page.next.map_or_else(Vec::new, |key| key.to_vec());
+
+        let mut chunks = vec![];
+        let mut entries = page.entries.into_iter().peekable();
+        while chunks.is_empty() || entries.peek().is_some() {
+            let chunk = entries
+                .by_ref()
+                .take(CHUNK_SIZE)
+                .map(|(key, record)| KeyValue {
+                    key: key.to_vec(),
+                    val: record.val.to_vec(),
+                    create_revision: record.create_revision,
+                    mod_revision: record.mod_revision,
+                })
+                .collect();
+            chunks.push(ScanResponse {
+                entries: chunk,
+                next_page_token: vec![],
+            });
+        }
+        chunks.last_mut().unwrap().next_page_token = next_page_token;
+
+        let (mut tx, rx) = mpsc::channel(chunks.len());
+        for chunk in chunks {
+            // Can't fail because the channel has room for all chunks and the receiver is alive
+            tx.try_send(Ok(chunk)).unwrap();
+        }
+        rx
+    }
+
+    fn operations(ops: Vec<api::Operation>) -> Result<Vec<Operation>, Status> {
+        ops.into_iter()
+            .map(|op| match op.operation {
+                Some(operation::Operation::Put(put)) => Ok(Self::put_operation(
+                    Key::from(put.key),
+                    Val::from(put.val),
+                    put.ttl_ms,
+                )),
+                Some(operation::Operation::Delete(delete)) => {
+                    Ok(Operation::Delete(Key::from(delete.key)))
+                }
+                None => Err(Status::new(Code::InvalidArgument, "Empty operation")),
+            })
+            .collect()
+    }
+
+    fn preconditions(conditions: Vec<api::Precondition>) -> Result<Vec<Precondition>, Status> {
+        conditions
+            .into_iter()
+            .map(|condition| match condition.condition {
+                Some(precondition::Condition::Exists(c)) => {
+                    Ok(Precondition::Exists(Key::from(c.key)))
+                }
+                Some(precondition::Condition::NotExists(c)) => {
+                    Ok(Precondition::NotExists(Key::from(c.key)))
+                }
+                Some(precondition::Condition::Equals(c)) => {
+                    Ok(Precondition::Equals(Key::from(c.key), Val::from(c.val)))
+                }
+                Some(precondition::Condition::Revision(c)) => {
+                    Ok(Precondition::Revision(Key::from(c.key), c.revision))
+                }
+                None => Err(Status::new(Code::InvalidArgument, "Empty precondition")),
+            })
+            .collect()
+    }
 }
 
 #[tonic::async_trait]
 impl Storage for StorageService {
+    type ScanStream = mpsc::Receiver<Result<ScanResponse, Status>>;
+    type PrefixStream = mpsc::Receiver<Result<ScanResponse, Status>>;
+
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         let request = request.into_inner();
-        let shard_id = request.shard_id as usize;
-        let key = request.key;
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
 
-        let reader = self
-            .shard_map
-            .reader(&shard_id)
-            .expect(&*format!("Missing shard with id: {}", shard_id));
+        let engine = self.engine(shard_id)?;
 
-        let result = reader.get(&key);
+        let result = engine.get_record(&key);
         match result {
-            Some(val) => Ok(Response::new(GetResponse { val })),
+            // The response holds the buffer of the shard, it is only copied into the gRPC frame
+            Some(record) => Ok(Response::new(GetResponse {
+                val: record.val,
+                create_revision: record.create_revision,
+                mod_revision: record.mod_revision,
+            })),
             None => Err(Status::new(Code::NotFound, "Not found")),
         }
     }
 
     async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
         let request = request.into_inner();
-        let shard_id = request.shard_id as usize;
-        let key = request.key;
-        let val = request.val;
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let op = Self::put_operation(key.clone(), Val::from(request.val), request.ttl_ms);
+        let expected_revision = request.expected_revision;
 
-        let writer = self
-            .shard_map
-            .writer(&shard_id)
-            .expect(&*format!("Missing shard with id: {}", shard_id));
+        let (previous, revision) = self
+            .write(shard_id, move |writer| {
+                let previous = Self::checked(writer, shard_id, &key, expected_revision, op)?;
+                Ok((previous, writer.revision()))
+            })
+            .await?;
 
-        writer
-            .lock()
-            .expect(&*format!("Can't lock writer for shard: {}", shard_id))
-            .put(key, val);
-
-        Ok(Response::new(PutResponse {}))
+        Ok(Response::new(PutResponse {
+            previous: Self::previous(previous, request.return_previous),
+            revision,
+        }))
     }
 
     async fn delete(
@@ -60,19 +313,334 @@ impl Storage for StorageService {
         request: Request<DeleteRequest>,
     ) -> Result<Response<DeleteResponse>, Status> {
         let request = request.into_inner();
-        let shard_id = request.shard_id as usize;
-        let key = request.key;
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let expected_revision = request.expected_revision;
+
+        let (previous, revision) = self
+            .write(shard_id, move |writer| {
+                let op = Operation::Delete(key.clone());
+                let previous = Self::checked(writer, shard_id, &key, expected_revision, op)?;
+                Ok((previous, writer.revision()))
+            })
+            .await?;
+
+        Ok(Response::new(DeleteResponse {
+            previous: Self::previous(previous, request.return_previous),
+            revision,
+        }))
+    }
+
+    async fn write_batch(
+        &self,
+        request: Request<WriteBatchRequest>,
+    ) -> Result<Response<WriteBatchResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let ops = Self::operations(request.ops)?;
+
+        self.write(shard_id, move |writer| {
+            writer.apply(ops).map_err(StorageError::write(shard_id))
+        })
+        .await?;
+
+        Ok(Response::new(WriteBatchResponse {}))
+    }
+
+    async fn transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let preconditions = Self::preconditions(request.preconditions)?;
+        let ops = Self::operations(request.ops)?;
+
+        let result = self
+            .write(shard_id, move |writer| {
+                writer
+                    .transaction(&preconditions, ops)
+                    .map_err(StorageError::write(shard_id))
+            })
+            .await?;
+
+        let response = match result {
+            Ok(_) => TransactionResponse {
+                succeeded: true,
+                failed_precondition: 0,
+            },
+            Err(index) => TransactionResponse {
+                succeeded: false,
+                failed_precondition: index as u32,
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn compare_and_swap(
+        &self,
+        request: Request<CompareAndSwapRequest>,
+    ) -> Result<Response<CompareAndSwapResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let expected = Val::from(request.expected);
+        let new = Val::from(request.new.clone());
+
+        let result = self
+            .write(shard_id, move |writer| {
+                writer
+                    .compare_and_swap(key, &expected, new)
+                    .map_err(StorageError::write(shard_id))
+            })
+            .await?;
+
+        let response = match result {
+            Ok(_) => CompareAndSwapResponse {
+                applied: true,
+                current: Some(Value { val: request.new }),
+            },
+            Err(current) => CompareAndSwapResponse {
+                applied: false,
+                current: current.map(|val| Value { val: val.to_vec() }),
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn put_if_absent(
+        &self,
+        request: Request<PutIfAbsentRequest>,
+    ) -> Result<Response<PutIfAbsentResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let val = Val::from(request.val.clone());
+
+        let result = self
+            .write(shard_id, move |writer| {
+                writer
+                    .put_if_absent(key, val)
+                    .map_err(StorageError::write(shard_id))
+            })
+            .await?;
+
+        let response = match result {
+            Ok(()) => PutIfAbsentResponse {
+                applied: true,
+                current: Some(Value { val: request.val }),
+            },
+            Err(val) => PutIfAbsentResponse {
+                applied: false,
+                current: Some(Value { val: val.to_vec() }),
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn delete_if_equals(
+        &self,
+        request: Request<DeleteIfEqualsRequest>,
+    ) -> Result<Response<DeleteIfEqualsResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let expected = Val::from(request.expected);
 
-        let writer = self
-            .shard_map
-            .writer(&shard_id)
-            .expect(&*format!("Missing shard with id: {}", shard_id));
+        let result = self
+            .write(shard_id, move |writer| {
+                writer
+                    .delete_if_equals(&key, &expected)
+                    .map_err(StorageError::write(shard_id))
+            })
+            .await?;
+
+        let response = match result {
+            Ok(_) => DeleteIfEqualsResponse {
+                applied: true,
+                current: None,
+            },
+            Err(current) => DeleteIfEqualsResponse {
+                applied: false,
+                current: current.map(|val| Value { val: val.to_vec() }),
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn expire(
+        &self,
+        request: Request<ExpireRequest>,
+    ) -> Result<Response<ExpireResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let key = Key::from(request.key);
+        let ttl = match request.ttl_ms {
+            0 => None,
+            ttl_ms => Some(Duration::from_millis(ttl_ms)),
+        };
+
+        let applied = self
+            .write(shard_id, move |writer| {
+                writer
+                    .expire(&key, ttl)
+                    .map_err(StorageError::write(shard_id))
+            })
+            .await?;
+
+        Ok(Response::new(ExpireResponse { applied }))
+    }
+
+    async fn ttl(&self, request: Request<TtlRequest>) -> Result<Response<TtlResponse>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+
+        let engine = self.engine(shard_id)?;
+
+        match engine.ttl(&Key::from(request.key)) {
+            Some(ttl) => Ok(Response::new(TtlResponse {
+                ttl_ms: ttl.map_or(-1, |ttl| ttl.as_millis() as i64),
+            })),
+            None => Err(Status::new(Code::NotFound, "Not found")),
+        }
+    }
 
-        writer
-            .lock()
-            .expect(&*format!("Can't lock writer for shard: {}", shard_id))
-            .delete(&key);
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let limit = Self::page_size(request.limit);
+        let start = Key::from(request.start);
+        let end = Key::from(request.end);
+        let page_token = Key::from(request.page_token);
+        let start = if page_token.is_empty() {
+            Bound::Included(&start)
+        } else {
+            Bound::Excluded(&page_token)
+        };
+        let end = if end.is_empty() {
+            Bound::Unbounded
+        } else {
+            Bound::Excluded(&end)
+        };
+
+        let engine = self.engine(shard_id)?;
+
+        let page = engine.scan(start, end, limit);
+        Ok(Response::new(Self::stream(page)))
+    }
+
+    async fn prefix(
+        &self,
+        request: Request<PrefixRequest>,
+    ) -> Result<Response<Self::PrefixStream>, Status> {
+        let request = request.into_inner();
+        let shard_id = StorageError::shard_id(request.shard_id)?;
+        let limit = Self::page_size(request.limit);
+        let prefix = Key::from(request.prefix);
+        let page_token = Key::from(request.page_token);
+        let after = if page_token.is_empty() {
+            None
+        } else {
+            Some(&page_token)
+        };
+
+        let engine = self.engine(shard_id)?;
+
+        let page = engine.scan_prefix(&prefix, after, limit);
+        Ok(Response::new(Self::stream(page)))
+    }
+
+    async fn multi_get(
+        &self,
+        request: Request<MultiGetRequest>,
+    ) -> Result<Response<MultiGetResponse>, Status> {
+        let request = request.into_inner();
+        if !request.keys.is_empty() && !request.shard_keys.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Either keys or shard_keys can be set, not both",
+            ));
+        }
+
+        let shard_id = request.shard_id;
+        let keys: Vec<_> = request
+            .keys
+            .into_iter()
+            .map(|key| (shard_id, Key::from(key)))
+            .chain(
+                request
+                    .shard_keys
+                    .into_iter()
+                    .map(|key| (key.shard_id, Key::from(key.key))),
+            )
+            .collect();
+        let count = keys.len();
+        let shards = Self::group_by_shard(keys.into_iter())?;
+
+        let mut results = vec![GetResult::default(); count];
+        for (shard_id, keys) in shards {
+            let engine = self.engine(shard_id)?;
+
+            let snapshot = engine.snapshot();
+            let records: Vec<_> = keys
+                .into_iter()
+                .map(|(index, key)| (index, snapshot.get_record(&key)))
+                .collect();
+            drop(snapshot);
+
+            for (index, record) in records {
+                if let Some(record) = record {
+                    results[index] = GetResult {
+                        found: true,
+                        val: record.val.to_vec(),
+                        create_revision: record.create_revision,
+                        mod_revision: record.mod_revision,
+                    };
+                }
+            }
+        }
+
+        Ok(Response::new(MultiGetResponse { results }))
+    }
+
+    async fn multi_put(
+        &self,
+        request: Request<MultiPutRequest>,
+    ) -> Result<Response<MultiPutResponse>, Status> {
+        let request = request.into_inner();
+        let count = request.entries.len();
+        let shards = Self::group_by_shard(request.entries.into_iter().map(|entry| {
+            (
+                entry.shard_id,
+                Operation::Put(Key::from(entry.key), Val::from(entry.val)),
+            )
+        }))?;
+
+        let results = self
+            .apply_by_shard(shards, count, request.return_previous)
+            .await?;
+        Ok(Response::new(MultiPutResponse { results }))
+    }
+
+    async fn multi_delete(
+        &self,
+        request: Request<MultiDeleteRequest>,
+    ) -> Result<Response<MultiDeleteResponse>, Status> {
+        let request = request.into_inner();
+        let count = request.keys.len();
+        let shards = Self::group_by_shard(
+            request
+                .keys
+                .into_iter()
+                .map(|key| (key.shard_id, Operation::Delete(Key::from(key.key)))),
+        )?;
 
-        Ok(Response::new(DeleteResponse {}))
+        let results = self
+            .apply_by_shard(shards, count, request.return_previous)
+            .await?;
+        Ok(Response::new(MultiDeleteResponse { results }))
     }
 }
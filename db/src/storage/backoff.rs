@@ -0,0 +1,56 @@
+// The real backoff is not used under loom
+#![cfg_attr(r_db_loom, allow(dead_code, unused_imports))]
+
+use std::cmp;
+use std::hint;
+use std::thread;
+use std::time::Duration;
+
+const SPIN_LIMIT: u32 = 6;
+const YIELD_LIMIT: u32 = 10;
+const MAX_PARK: Duration = Duration::from_millis(1);
+
+/// Exponential backoff for the writer while it waits for the readers to leave a map.
+/// A read holds the counter for well under a microsecond so the writer spins first. If that is
+/// not enough it starts yielding its time slice and finally parks for up to a millisecond at a
+/// time so a long running snapshot doesn't burn a whole core.
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    pub fn snooze(&mut self) {
+        // Loom has to know the thread is waiting for the others
+        #[cfg(r_db_loom)]
+        loom::thread::yield_now();
+        #[cfg(not(r_db_loom))]
+        self.wait();
+
+        self.step = self.step.saturating_add(1);
+    }
+
+    #[cfg(not(r_db_loom))]
+    fn wait(&self) {
+        if self.step <= SPIN_LIMIT {
+            for _ in 0..1 << self.step {
+                hint::spin_loop();
+            }
+        } else if self.step <= YIELD_LIMIT {
+            thread::yield_now();
+        } else {
+            // Nobody unparks the writer so this is just a sleep that ends early on a spurious wakeup
+            let park = Duration::from_micros(1 << cmp::min(self.step - YIELD_LIMIT, 10));
+            thread::park_timeout(cmp::min(park, MAX_PARK));
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,470 @@
+use super::engine::{
+    Batch, EngineKind, EngineSnapshot, EngineWriter, Page, StorageEngine, WriteError,
+};
+use super::sync;
+use super::types::{Key, Operation, Record, Revision, Val};
+use super::wal::{self, Entry, Header, SyncPolicy, Wal};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Wraps the engine of a shard and appends every write to a write-ahead log before it is applied,
+/// so the shard can be rebuilt after a restart. Reads go straight to the engine.
+pub struct Durable {
+    engine: Box<dyn StorageEngine>,
+    header: Header,
+    writer: Arc<Mutex<DurableWriter>>,
+    // Only one snapshot is written at a time
+    snapshots: Mutex<()>,
+}
+
+/// Describes a snapshot written by Durable::save_snapshot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    pub revision: Revision,
+    pub key_count: usize,
+    pub size_bytes: u64,
+}
+
+pub struct DurableWriter {
+    // Only locked through the DurableWriter, so every write goes through the log
+    writer: Arc<Mutex<dyn EngineWriter>>,
+    wal: Wal,
+}
+
+// The writer of the engine together with the log, for the time of a single call
+struct Logged<'a> {
+    writer: &'a mut dyn EngineWriter,
+    wal: &'a mut Wal,
+}
+
+impl Durable {
+    /// Creates the shard with the given records and starts its log in `dir`, which must not
+    /// exist yet. The records are on the disk before it returns.
+    pub fn create(dir: &Path, header: Header, data: HashMap<Key, Record>) -> io::Result<Self> {
+        let wal = Wal::create(dir, header, &data)?;
+        let engine = header.kind.import(header.shard_id, data);
+        Ok(Self::start(engine, header, wal))
+    }
+
+    /// Rebuilds the shard from the latest snapshot and the log after it in `dir`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let (header, entries, wal) = Wal::open(dir)?;
+        let mut engine = header.kind.create(header.shard_id);
+        for entry in entries {
+            match entry {
+                Entry::Load(data) => engine = header.kind.import(header.shard_id, data),
+                Entry::Snapshot { revision, data } => {
+                    engine = header.kind.import(header.shard_id, data);
+                    // The last writes before the snapshot might have deleted their keys
+                    sync::lock(&*engine.writer()).skip_to(revision);
+                }
+                Entry::Write { revision, now, ops } => {
+                    let writer = engine.writer();
+                    let mut writer = sync::lock(&*writer);
+                    if writer.revision() + 1 != revision {
+                        log::warn!(
+                            "Replaying revision {} of shard {} onto revision {}",
+                            revision,
+                            header.shard_id,
+                            writer.revision()
+                        );
+                    }
+                    // Nobody is reading the new shard, so there is nothing to wait for
+                    writer
+                        .apply_at(ops, now)
+                        .map_err(|err| io::Error::other(err.to_string()))?;
+                }
+            }
+        }
+        Ok(Self::start(engine, header, wal))
+    }
+
+    fn start(engine: Box<dyn StorageEngine>, header: Header, wal: Wal) -> Self {
+        let writer = DurableWriter {
+            writer: engine.writer(),
+            wal,
+        };
+
+        Self {
+            engine,
+            header,
+            writer: Arc::new(Mutex::new(writer)),
+            snapshots: Mutex::new(()),
+        }
+    }
+
+    /// Writes a snapshot of the records to the disk and deletes the part of the log it replaces,
+    /// so a restart only replays the writes after it. The writes wait while the records are
+    /// copied, the reads go on and nothing waits while the snapshot is written.
+    pub fn save_snapshot(&self) -> io::Result<SnapshotInfo> {
+        let _snapshots = sync::lock(&self.snapshots);
+        let (dir, index, revision, data) = {
+            let mut writer = sync::lock(&*self.writer);
+            let index = writer.wal.roll()?;
+            // The writer is locked, so every write is published and the copy matches the end of
+            // the segments before the new one
+            let revision = writer.revision();
+            let snapshot = self.engine.snapshot();
+            let data: HashMap<_, _> = snapshot
+                .records()
+                .iter()
+                .map(|(key, record)| (key.clone(), record.clone()))
+                .collect();
+            (writer.wal.dir().to_path_buf(), index, revision, data)
+        };
+
+        let size_bytes = wal::write_snapshot(&dir, self.header, index, revision, &data)?;
+        wal::truncate(&dir, index);
+        Ok(SnapshotInfo {
+            revision,
+            key_count: data.len(),
+            size_bytes,
+        })
+    }
+
+    /// The size of the log that would be replayed after a restart.
+    pub fn since_snapshot(&self) -> u64 {
+        sync::lock(&*self.writer).wal.since_snapshot()
+    }
+
+    /// Points the log to its directory after the directory was renamed.
+    pub fn relocate(&self, dir: PathBuf) {
+        sync::lock(&*self.writer).wal.set_dir(dir);
+    }
+}
+
+impl StorageEngine for Durable {
+    fn id(&self) -> usize {
+        self.engine.id()
+    }
+
+    fn kind(&self) -> EngineKind {
+        self.engine.kind()
+    }
+
+    fn wal(&self) -> Option<SyncPolicy> {
+        Some(self.header.sync)
+    }
+
+    fn durable(&self) -> Option<&Durable> {
+        Some(self)
+    }
+
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        self.engine.snapshot()
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        self.engine.get_record(key)
+    }
+
+    fn get(&self, key: &Key) -> Option<Val> {
+        self.engine.get(key)
+    }
+
+    fn ttl(&self, key: &Key) -> Option<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        self.engine.scan(start, end, limit)
+    }
+
+    fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        self.engine.scan_prefix(prefix, after, limit)
+    }
+
+    fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    fn approximate_bytes(&self) -> usize {
+        self.engine.approximate_bytes()
+    }
+}
+
+impl DurableWriter {
+    fn logged<T>(&mut self, f: impl FnOnce(&mut Logged<'_>) -> T) -> T {
+        let mut writer = sync::lock(&*self.writer);
+        f(&mut Logged {
+            writer: &mut *writer,
+            wal: &mut self.wal,
+        })
+    }
+}
+
+impl EngineWriter for DurableWriter {
+    fn revision(&self) -> Revision {
+        sync::lock(&*self.writer).revision()
+    }
+
+    // Only used while the shard is recovered, so it isn't logged
+    fn skip_to(&mut self, revision: Revision) {
+        sync::lock(&*self.writer).skip_to(revision)
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        self.logged(|writer| writer.current(key, now))
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        self.logged(|writer| writer.apply_at(ops, now))
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        self.logged(|writer| writer.expired(now))
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        self.logged(|writer| writer.group(f))
+    }
+
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        self.logged(|writer| writer.prepare())
+    }
+}
+
+impl<'a> EngineWriter for Logged<'a> {
+    fn revision(&self) -> Revision {
+        self.writer.revision()
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        self.writer.skip_to(revision)
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        self.writer.current(key, now)
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Once the write is in the log it must not fail, so wait for the engine first
+        self.writer.prepare()?;
+        let revision = self.writer.revision() + 1;
+        self.wal
+            .append(revision, now, &ops)
+            .map_err(|err| WriteError::Wal(err.to_string()))?;
+        self.writer.apply_at(ops, now)
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        self.writer.expired(now)
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        let wal = &mut *self.wal;
+        self.writer.group(&mut |writer| {
+            f(&mut Logged {
+                writer,
+                wal: &mut *wal,
+            })
+        })
+    }
+
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        self.writer.prepare()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Durable;
+    use crate::storage::engine::{EngineKind, StorageEngine};
+    use crate::storage::types::Record;
+    use crate::storage::wal::{Header, SyncPolicy};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn header(kind: EngineKind, sync: SyncPolicy) -> Header {
+        Header {
+            shard_id: 42,
+            kind,
+            sync,
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        let policies = [
+            SyncPolicy::Always,
+            SyncPolicy::Interval(Duration::from_millis(5)),
+            SyncPolicy::Never,
+        ];
+        for kind in EngineKind::ALL.iter().copied() {
+            for sync in policies.iter().copied() {
+                let dir = tempfile::tempdir().unwrap();
+                let path = dir.path().join("shard");
+                {
+                    let shard = Durable::create(&path, header(kind, sync), HashMap::new()).unwrap();
+                    let writer = shard.writer();
+                    let mut writer = writer.lock().unwrap();
+                    writer.put_str("1", "a").unwrap();
+                    writer.put_str("2", "b").unwrap();
+                    writer.delete(&Bytes::from("1")).unwrap();
+                    writer
+                        .put_with_ttl(Bytes::from("3"), Bytes::from("c"), Duration::from_secs(60))
+                        .unwrap();
+                    writer
+                        .group(&mut |w| {
+                            w.put_str("4", "d").unwrap();
+                            let mut batch = w.batch();
+                            batch.put(Bytes::from("5"), Bytes::from("e"));
+                            batch.commit().unwrap();
+                        })
+                        .unwrap();
+                }
+
+                let shard = Durable::open(&path).unwrap();
+                assert_eq!(shard.kind(), kind);
+                assert_eq!(shard.wal(), Some(sync));
+                assert_eq!(shard.get(&Bytes::from("1")), None);
+                assert_eq!(shard.get(&Bytes::from("2")), Some(Bytes::from("b")));
+                assert!(shard.ttl(&Bytes::from("3")).unwrap().is_some());
+                assert_eq!(shard.len(), 4);
+                let record = shard.get_record(&Bytes::from("5")).unwrap();
+                assert_eq!(record.create_revision, 6);
+                assert_eq!(shard.writer().lock().unwrap().revision(), 6);
+
+                // Writes go on from the recovered revision
+                shard.put(Bytes::from("6"), Bytes::from("f")).unwrap();
+                drop(shard);
+                let shard = Durable::open(&path).unwrap();
+                assert_eq!(shard.get_record(&Bytes::from("6")).unwrap().mod_revision, 7);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard");
+        let mut data = HashMap::new();
+        data.insert(
+            Bytes::from("1"),
+            Record {
+                val: Bytes::from("a"),
+                create_revision: 3,
+                mod_revision: 5,
+                expires_at: None,
+            },
+        );
+        let shard =
+            Durable::create(&path, header(EngineKind::Ordered, SyncPolicy::Always), data).unwrap();
+        shard.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+        drop(shard);
+
+        let shard = Durable::open(&path).unwrap();
+        assert_eq!(shard.get_record(&Bytes::from("1")).unwrap().mod_revision, 5);
+        assert_eq!(shard.get_record(&Bytes::from("2")).unwrap().mod_revision, 6);
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard");
+        let shard = Durable::create(
+            &path,
+            header(EngineKind::Locked, SyncPolicy::Never),
+            HashMap::new(),
+        )
+        .unwrap();
+        shard.put(Bytes::from("1"), Bytes::from("a")).unwrap();
+        shard.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+        drop(shard);
+
+        // Cut the last record in half, as a crash in the middle of a write would
+        let segment = fs::read_dir(&path).unwrap().next().unwrap().unwrap().path();
+        let len = fs::metadata(&segment).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(len - 5).unwrap();
+        drop(file);
+
+        let shard = Durable::open(&path).unwrap();
+        assert_eq!(shard.get(&Bytes::from("1")), Some(Bytes::from("a")));
+        assert_eq!(shard.get(&Bytes::from("2")), None);
+        // The torn record was truncated, so the next write follows the last complete one
+        shard.put(Bytes::from("3"), Bytes::from("c")).unwrap();
+        drop(shard);
+        let shard = Durable::open(&path).unwrap();
+        assert_eq!(shard.len(), 2);
+        assert_eq!(shard.get_record(&Bytes::from("3")).unwrap().mod_revision, 2);
+
+        // A flipped bit fails the checksum and is treated as torn as well
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[8, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])
+            .unwrap();
+        drop(file);
+        let shard = Durable::open(&path).unwrap();
+        assert_eq!(shard.len(), 2);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        for kind in EngineKind::ALL.iter().copied() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("shard");
+            let shard =
+                Durable::create(&path, header(kind, SyncPolicy::Always), HashMap::new()).unwrap();
+            shard.put(Bytes::from("1"), Bytes::from("a")).unwrap();
+            shard.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+            shard.delete(&Bytes::from("2")).unwrap();
+
+            let info = shard.save_snapshot().unwrap();
+            assert_eq!(info.revision, 3);
+            assert_eq!(info.key_count, 1);
+            assert!(info.size_bytes > 0);
+            // Only the new segment is replayed
+            assert!(shard.since_snapshot() < info.size_bytes);
+            shard.put(Bytes::from("3"), Bytes::from("c")).unwrap();
+            drop(shard);
+
+            let files: Vec<_> = fs::read_dir(&path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            assert_eq!(files.len(), 2);
+
+            let shard = Durable::open(&path).unwrap();
+            assert_eq!(shard.len(), 2);
+            assert_eq!(shard.get(&Bytes::from("1")), Some(Bytes::from("a")));
+            // The delete at the end of the snapshot didn't turn the revision back
+            assert_eq!(shard.get_record(&Bytes::from("3")).unwrap().mod_revision, 4);
+            shard.save_snapshot().unwrap();
+            drop(shard);
+            let shard = Durable::open(&path).unwrap();
+            assert_eq!(shard.writer().lock().unwrap().revision(), 4);
+            assert_eq!(shard.len(), 2);
+        }
+    }
+}
@@ -0,0 +1,1181 @@
+use super::durable::Durable;
+use super::locked::{LockedBTreeMap, LockedHashMap};
+use super::map::{self, Map};
+use super::shard::{DrainTimeout, Shard};
+use super::striped::Striped;
+use super::sync;
+use super::types::{Key, Operation, Precondition, Record, Revision, Val};
+use super::wal::SyncPolicy;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How many expired records a scan skips before it ends the page early. The sweeper deletes them
+/// in the background, until then a scan over many of them would keep the version of the records
+/// pinned for a long time.
+pub const MAX_SKIPPED: usize = 1024;
+
+/// The ways a shard can store its records. The shard picks one when it is created. All engines
+/// have the same semantics, they only differ in what they are fast at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum EngineKind {
+    /// Two copies of a HashMap, see Shard. Reads never block and never wait for a write but a
+    /// write has to wait for the readers of the stale copy. The keys and values are shared by the
+    /// two copies, only the hash tables and the key index are kept twice.
+    #[default]
+    LeftRight,
+    /// A single HashMap behind a RwLock. Needs one hash table instead of two and writes are
+    /// cheaper than with LeftRight, but the readers block while a write is applied. Scans sort
+    /// the whole map.
+    Locked,
+    /// A BTreeMap behind a RwLock. Point reads are slower than with a HashMap but scans walk the
+    /// keys in order and never have to sort them.
+    Ordered,
+    /// Many HashMaps with a RwLock each, like DashMap. A read or a write only locks the part of
+    /// the map its key is in. Snapshots and scans lock all of it and scans sort the whole map.
+    Striped,
+}
+
+impl EngineKind {
+    pub const ALL: [EngineKind; 4] = [
+        EngineKind::LeftRight,
+        EngineKind::Locked,
+        EngineKind::Ordered,
+        EngineKind::Striped,
+    ];
+
+    /// Creates an empty shard.
+    pub fn create(self, id: usize) -> Box<dyn StorageEngine> {
+        match self {
+            EngineKind::LeftRight => Box::new(Shard::new(id)),
+            EngineKind::Locked => Box::new(LockedHashMap::new(id)),
+            EngineKind::Ordered => Box::new(LockedBTreeMap::new(id)),
+            EngineKind::Striped => Box::new(Striped::new(id)),
+        }
+    }
+
+    /// Creates a shard with existing data, e.g. when a shard is moved to a different server with
+    /// the ExportShard and ImportShard RPCs.
+    pub fn import(self, id: usize, data: HashMap<Key, Record>) -> Box<dyn StorageEngine> {
+        match self {
+            EngineKind::LeftRight => Box::new(Shard::with_data(id, data)),
+            EngineKind::Locked => Box::new(LockedHashMap::with_data(id, data)),
+            EngineKind::Ordered => Box::new(LockedBTreeMap::with_data(id, data)),
+            EngineKind::Striped => Box::new(Striped::with_data(id, data)),
+        }
+    }
+}
+
+/// Stores the records of a single shard.
+///
+/// Reads go straight to the engine. All writes go through its single writer, so the writer can
+/// check a condition and apply a write without anyone else changing the records in between.
+pub trait StorageEngine: Send + Sync {
+    fn id(&self) -> usize;
+
+    fn kind(&self) -> EngineKind;
+
+    /// How the write-ahead log of the shard is synced, None if the shard only lives in memory.
+    fn wal(&self) -> Option<SyncPolicy> {
+        None
+    }
+
+    /// The shard if it keeps a write-ahead log, to write its snapshots.
+    fn durable(&self) -> Option<&Durable> {
+        None
+    }
+
+    /// Pins the current version of the records. All reads through the snapshot see the same data.
+    /// Depending on the engine a snapshot holds back the writes, so keep snapshots short-lived.
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_>;
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>>;
+
+    /// Same as get but also returns the revisions of the value. The engines implement it
+    /// directly, a point read shouldn't pay for a snapshot.
+    fn get_record(&self, key: &Key) -> Option<Record>;
+
+    fn get(&self, key: &Key) -> Option<Val> {
+        self.get_record(key).map(|record| record.val)
+    }
+
+    /// Returns the time left before the key expires or None if it never expires.
+    /// The outer Option is None if the key is missing.
+    fn ttl(&self, key: &Key) -> Option<Option<Duration>> {
+        Some(self.get_record(key)?.ttl())
+    }
+
+    /// Returns up to `limit` live records with keys between the bounds in key order.
+    /// All of them are read from the same version of the records. See Page for where the next
+    /// page starts.
+    fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        self.snapshot().scan(start, end, limit)
+    }
+
+    /// Returns up to `limit` live records with keys that start with `prefix` in key order.
+    /// `after` is the last key of the previous page.
+    fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        self.snapshot().scan_prefix(prefix, after, limit)
+    }
+
+    /// The number of records, including the expired ones that the sweeper hasn't deleted yet.
+    fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Roughly the memory used by the records. Walks all of them, so it is as slow as a full scan.
+    fn approximate_bytes(&self) -> usize {
+        approximate_bytes(self.snapshot().records(), 1)
+    }
+
+    fn put(&self, key: Key, value: Val) -> Result<Option<Val>, WriteError> {
+        sync::lock(&*self.writer()).put(key, value)
+    }
+
+    fn delete(&self, key: &Key) -> Result<Option<Val>, WriteError> {
+        sync::lock(&*self.writer()).delete(key)
+    }
+}
+
+/// The records of a scan. The scan stops after `limit` live records or after skipping
+/// MAX_SKIPPED expired ones, so a page can be short even if there are more records.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Page {
+    pub entries: Vec<(Key, Record)>,
+    /// The last key the scan looked at if it stopped before the end of the range. The next page
+    /// starts right after it.
+    pub next: Option<Key>,
+}
+
+/// A pinned version of the records of an engine. See StorageEngine::snapshot.
+pub trait EngineSnapshot {
+    fn records(&self) -> &dyn Records;
+
+    /// When the snapshot was taken. All reads through it agree on which keys have expired.
+    fn now(&self) -> SystemTime;
+
+    fn get(&self, key: &Key) -> Option<Val> {
+        self.get_record(key).map(|record| record.val)
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        // Values are reference counted so this clone is just a counter bump
+        live(self.records(), key, self.now()).cloned()
+    }
+
+    fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        collect(self.records(), start, end, |_| true, limit, self.now())
+    }
+
+    fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        let start = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Included(prefix),
+        };
+        collect(
+            self.records(),
+            start,
+            Bound::Unbounded,
+            |key| key.starts_with(prefix),
+            limit,
+            self.now(),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.records().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The single writer of an engine. It lives behind a Mutex, so everything it reads stays
+/// unchanged until it writes.
+///
+/// Only the left-right engine can fail with a DrainTimeout, see Shard, and only a durable shard can
+/// fail to write its log. The writes to the other engines never fail.
+pub trait EngineWriter: Send {
+    /// The revision of the last mutation of the shard.
+    fn revision(&self) -> Revision;
+
+    /// Moves the revision forward without a write, e.g. to the revision of a snapshot that ends
+    /// with deletes. Never moves it back.
+    fn skip_to(&mut self, revision: Revision);
+
+    /// The live record of the key, including the writes that aren't published to the readers yet.
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError>;
+
+    /// Applies all operations as a single mutation, so it bumps the revision only once and the
+    /// readers see either all of them or none. Returns the previous value for every operation in
+    /// the same order. The time is fixed for the whole batch so all of it agrees on which keys
+    /// have expired.
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError>;
+
+    /// Takes the keys that have expired by `now` off the list of expiring keys. They are still
+    /// there until they are deleted, see remove_expired.
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError>;
+
+    /// Starts a batch of operations that will be applied as a single mutation.
+    /// The engines implement it with Batch::new, a default would need Self: Sized.
+    fn batch(&mut self) -> Batch<'_>;
+
+    /// Runs `f` and lets the engine publish all writes it makes together. Each write is still a
+    /// separate mutation with its own revision. The engines that can't defer a write publish
+    /// each one as it is made.
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError>;
+
+    /// Waits until the next write can be applied without failing. Changes nothing.
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    /// Deletes all keys that have expired. Returns the number of deleted keys.
+    fn remove_expired(&mut self) -> Result<usize, WriteError> {
+        let now = SystemTime::now();
+        let keys = self.expired(now)?;
+        let count = keys.len();
+        self.apply_at(keys.into_iter().map(Operation::Delete).collect(), now)?;
+        Ok(count)
+    }
+
+    /// Applies the operations at the current time. Deletes and expires of missing keys change
+    /// nothing, so if they are all there is the revision stays the same.
+    fn apply(&mut self, ops: Vec<Operation>) -> Result<Vec<Option<Val>>, WriteError> {
+        let now = SystemTime::now();
+        if changes_nothing(self, &ops, now)? {
+            return Ok(vec![None; ops.len()]);
+        }
+        self.apply_at(ops, now)
+    }
+
+    fn put(&mut self, key: Key, value: Val) -> Result<Option<Val>, WriteError> {
+        Ok(self.apply(vec![Operation::Put(key, value)])?.pop().unwrap())
+    }
+
+    /// Convenience for users that store UTF-8 strings.
+    fn put_str(&mut self, key: &str, value: &str) -> Result<Option<Val>, WriteError> {
+        self.put(Key::from(key), Val::from(value))
+    }
+
+    /// Puts a value that will expire after the given ttl.
+    fn put_with_ttl(
+        &mut self,
+        key: Key,
+        value: Val,
+        ttl: Duration,
+    ) -> Result<Option<Val>, WriteError> {
+        let expires_at = SystemTime::now() + ttl;
+        Ok(self
+            .apply(vec![Operation::PutWithExpiry(key, value, expires_at)])?
+            .pop()
+            .unwrap())
+    }
+
+    /// Sets a new ttl for an existing key. None makes the key persistent.
+    /// Returns false if the key is missing.
+    fn expire(&mut self, key: &Key, ttl: Option<Duration>) -> Result<bool, WriteError> {
+        let now = SystemTime::now();
+        if self.current(key, now)?.is_none() {
+            return Ok(false);
+        }
+
+        let expires_at = ttl.map(|ttl| now + ttl);
+        self.apply_at(vec![Operation::Expire(key.clone(), expires_at)], now)?;
+        Ok(true)
+    }
+
+    fn delete(&mut self, key: &Key) -> Result<Option<Val>, WriteError> {
+        Ok(self
+            .apply(vec![Operation::Delete(key.clone())])?
+            .pop()
+            .unwrap())
+    }
+
+    // The conditional writes return the outcome of the condition in the inner Result. The outer
+    // one only fails if the writer couldn't get to the data at all.
+
+    /// Replaces the value only if the current one is equal to `expected`.
+    /// Returns the replaced value on success or the current value on failure.
+    fn compare_and_swap(
+        &mut self,
+        key: Key,
+        expected: &Val,
+        value: Val,
+    ) -> Result<Result<Val, Option<Val>>, WriteError> {
+        let current = self.current(&key, SystemTime::now())?;
+        match current.map(|record| record.val) {
+            Some(current) if current == expected => Ok(Ok(self.put(key, value)?.unwrap())),
+            current => Ok(Err(current)),
+        }
+    }
+
+    /// Inserts the value only if the key is missing. Returns the current value on failure.
+    fn put_if_absent(&mut self, key: Key, value: Val) -> Result<Result<(), Val>, WriteError> {
+        match self.current(&key, SystemTime::now())? {
+            Some(current) => Ok(Err(current.val)),
+            None => {
+                self.put(key, value)?;
+                Ok(Ok(()))
+            }
+        }
+    }
+
+    /// Deletes the key only if its value is equal to `expected`.
+    /// Returns the deleted value on success or the current value on failure.
+    fn delete_if_equals(
+        &mut self,
+        key: &Key,
+        expected: &Val,
+    ) -> Result<Result<Val, Option<Val>>, WriteError> {
+        let current = self.current(key, SystemTime::now())?;
+        match current.map(|record| record.val) {
+            Some(current) if current == expected => Ok(Ok(self.delete(key)?.unwrap())),
+            current => Ok(Err(current)),
+        }
+    }
+
+    /// Checks all preconditions and, only if every one of them holds, applies the operations as
+    /// a single mutation, so the readers see either the whole transaction or none of it.
+    /// On failure returns the index of the first precondition that did not hold.
+    fn transaction(
+        &mut self,
+        preconditions: &[Precondition],
+        ops: Vec<Operation>,
+    ) -> Result<Result<Vec<Option<Val>>, usize>, WriteError> {
+        let now = SystemTime::now();
+        for (index, condition) in preconditions.iter().enumerate() {
+            let current = self.current(condition.key(), now)?;
+            if !holds(current.as_ref(), condition) {
+                return Ok(Err(index));
+            }
+        }
+
+        if changes_nothing(self, &ops, now)? {
+            return Ok(Ok(vec![None; ops.len()]));
+        }
+        Ok(Ok(self.apply_at(ops, now)?))
+    }
+}
+
+/// Why a writer didn't apply a write. Nothing was changed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteError {
+    DrainTimeout(DrainTimeout),
+    /// The write couldn't be appended to the write-ahead log of a durable shard
+    Wal(String),
+    /// The shard is in a Raft group and isn't its leader. Holds the node id of the leader if it
+    /// is known.
+    NotLeader(Option<u64>),
+    /// The write was proposed to the Raft group of the shard but wasn't applied in time. It might
+    /// still be.
+    NotCommitted,
+}
+
+impl From<DrainTimeout> for WriteError {
+    fn from(err: DrainTimeout) -> Self {
+        WriteError::DrainTimeout(err)
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::DrainTimeout(err) => err.fmt(f),
+            WriteError::Wal(err) => write!(f, "Can't write the log: {}", err),
+            WriteError::NotLeader(Some(leader)) => write!(f, "The leader is node {}", leader),
+            WriteError::NotLeader(None) => write!(f, "The Raft group has no leader"),
+            WriteError::NotCommitted => write!(f, "The write wasn't committed in time"),
+        }
+    }
+}
+
+impl Error for WriteError {}
+
+pub struct Batch<'a> {
+    writer: &'a mut dyn EngineWriter,
+    ops: Vec<Operation>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(writer: &'a mut dyn EngineWriter) -> Self {
+        Self {
+            writer,
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: Key, value: Val) -> &mut Self {
+        self.ops.push(Operation::Put(key, value));
+        self
+    }
+
+    pub fn put_with_ttl(&mut self, key: Key, value: Val, ttl: Duration) -> &mut Self {
+        let expires_at = SystemTime::now() + ttl;
+        self.ops
+            .push(Operation::PutWithExpiry(key, value, expires_at));
+        self
+    }
+
+    pub fn delete(&mut self, key: Key) -> &mut Self {
+        self.ops.push(Operation::Delete(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the previous value for every operation in the batch.
+    pub fn commit(self) -> Result<Vec<Option<Val>>, WriteError> {
+        self.writer.apply(self.ops)
+    }
+}
+
+/// Read access to the records of an engine, expired ones included.
+pub trait Records {
+    fn get(&self, key: &Key) -> Option<&Record>;
+
+    /// Iterates over all records in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Record)> + '_>;
+
+    /// Iterates over the records with keys between the bounds in key order.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a Key>,
+        end: Bound<&'a Key>,
+    ) -> Box<dyn Iterator<Item = (&'a Key, &'a Record)> + 'a>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Everything `replay` needs to apply an operation.
+pub trait RecordsMut: Records {
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Record>;
+
+    fn insert(&mut self, key: Key, record: Record) -> Option<Record>;
+
+    fn remove(&mut self, key: &Key) -> Option<Record>;
+}
+
+impl Records for Map {
+    #[inline]
+    fn get(&self, key: &Key) -> Option<&Record> {
+        Map::get(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Record)> + '_> {
+        Box::new(Map::iter(self))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a Key>,
+        end: Bound<&'a Key>,
+    ) -> Box<dyn Iterator<Item = (&'a Key, &'a Record)> + 'a> {
+        Box::new(Map::range(self, start, end))
+    }
+
+    fn len(&self) -> usize {
+        Map::len(self)
+    }
+}
+
+impl RecordsMut for Map {
+    #[inline]
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Record> {
+        Map::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: Key, record: Record) -> Option<Record> {
+        Map::insert(self, key, record)
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Record> {
+        Map::remove(self, key)
+    }
+}
+
+impl Records for HashMap<Key, Record> {
+    #[inline]
+    fn get(&self, key: &Key) -> Option<&Record> {
+        HashMap::get(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Record)> + '_> {
+        Box::new(HashMap::iter(self))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a Key>,
+        end: Bound<&'a Key>,
+    ) -> Box<dyn Iterator<Item = (&'a Key, &'a Record)> + 'a> {
+        sorted(HashMap::iter(self), start, end)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+}
+
+impl RecordsMut for HashMap<Key, Record> {
+    #[inline]
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Record> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: Key, record: Record) -> Option<Record> {
+        HashMap::insert(self, key, record)
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Record> {
+        HashMap::remove(self, key)
+    }
+}
+
+impl Records for BTreeMap<Key, Record> {
+    #[inline]
+    fn get(&self, key: &Key) -> Option<&Record> {
+        BTreeMap::get(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Record)> + '_> {
+        Box::new(BTreeMap::iter(self))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&'a Key>,
+        end: Bound<&'a Key>,
+    ) -> Box<dyn Iterator<Item = (&'a Key, &'a Record)> + 'a> {
+        if map::is_inverted(start, end) {
+            return Box::new(iter::empty());
+        }
+        Box::new(BTreeMap::range::<Key, _>(self, (start, end)))
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+impl RecordsMut for BTreeMap<Key, Record> {
+    #[inline]
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Record> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: Key, record: Record) -> Option<Record> {
+        BTreeMap::insert(self, key, record)
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Record> {
+        BTreeMap::remove(self, key)
+    }
+}
+
+/// Range for the unordered maps. Every call goes through all records and sorts the ones between
+/// the bounds, so it is O(n log n) no matter how small the range is.
+pub(crate) fn sorted<'a>(
+    records: impl Iterator<Item = (&'a Key, &'a Record)>,
+    start: Bound<&'a Key>,
+    end: Bound<&'a Key>,
+) -> Box<dyn Iterator<Item = (&'a Key, &'a Record)> + 'a> {
+    let mut records: Vec<_> = records
+        .filter(|(key, _)| RangeBounds::<Key>::contains(&(start, end), *key))
+        .collect();
+    records.sort_unstable_by_key(|(key, _)| *key);
+    Box::new(records.into_iter())
+}
+
+fn collect<F>(
+    records: &dyn Records,
+    start: Bound<&Key>,
+    end: Bound<&Key>,
+    in_range: F,
+    limit: usize,
+    now: SystemTime,
+) -> Page
+where
+    F: Fn(&Key) -> bool,
+{
+    let mut page = Page::default();
+    if limit == 0 {
+        return page;
+    }
+
+    let mut skipped = 0;
+    for (key, record) in records
+        .range(start, end)
+        .take_while(|(key, _)| in_range(key))
+    {
+        if record.is_expired(now) {
+            skipped += 1;
+            if skipped < MAX_SKIPPED {
+                continue;
+            }
+        } else {
+            page.entries.push((key.clone(), record.clone()));
+            if page.entries.len() < limit {
+                continue;
+            }
+        }
+        page.next = Some(key.clone());
+        break;
+    }
+    page
+}
+
+/// The keys and the values plus `entries` map entries for every record. Short buffers are stored
+/// inline in the entry and get counted twice, close enough for an estimate.
+pub(crate) fn approximate_bytes(records: &dyn Records, entries: usize) -> usize {
+    let entry = mem::size_of::<(Key, Record)>();
+    records
+        .iter()
+        .map(|(key, record)| key.len() + record.val.len() + entries * entry)
+        .sum()
+}
+
+/// Returns the record if it is live right now, for the point reads that don't take a snapshot.
+/// Expired keys stay in the map until the sweeper deletes them. Only the keys with a ttl have to
+/// look at the clock.
+#[inline]
+pub(crate) fn get_live<M>(records: &M, key: &Key) -> Option<Record>
+where
+    M: Records + ?Sized,
+{
+    let record = records.get(key)?;
+    if record.expires_at.is_some() && record.is_expired(SystemTime::now()) {
+        return None;
+    }
+    Some(record.clone())
+}
+
+/// Returns the record only if it has not expired yet.
+#[inline]
+pub(crate) fn live<'a, M>(records: &'a M, key: &Key, now: SystemTime) -> Option<&'a Record>
+where
+    M: Records + ?Sized,
+{
+    records.get(key).filter(|record| !record.is_expired(now))
+}
+
+fn holds(current: Option<&Record>, condition: &Precondition) -> bool {
+    match condition {
+        Precondition::Exists(_) => current.is_some(),
+        Precondition::NotExists(_) => current.is_none(),
+        Precondition::Equals(_, value) => current.map(|record| &record.val) == Some(value),
+        Precondition::Revision(_, revision) => {
+            current.map_or(0, |record| record.mod_revision) == *revision
+        }
+    }
+}
+
+/// Whether the operations only delete or expire keys that are missing. None of them can create a
+/// key, so the earlier ones can't change the outcome of the later ones.
+fn changes_nothing<W>(
+    writer: &mut W,
+    ops: &[Operation],
+    now: SystemTime,
+) -> Result<bool, WriteError>
+where
+    W: EngineWriter + ?Sized,
+{
+    for op in ops {
+        let key = match op {
+            Operation::Delete(key) | Operation::Expire(key, _) => key,
+            Operation::Put(..) | Operation::PutWithExpiry(..) => return Ok(false),
+        };
+        if writer.current(key, now)?.is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Applies a single operation. Returns the previous record if it was live.
+/// Must be deterministic because the left-right engine executes it once for each of its maps.
+#[inline]
+pub(crate) fn replay<M>(
+    data: &mut M,
+    op: Operation,
+    revision: Revision,
+    now: SystemTime,
+) -> Option<Record>
+where
+    M: RecordsMut + ?Sized,
+{
+    match op {
+        Operation::Put(key, val) => insert(data, key, val, None, revision, now),
+        Operation::PutWithExpiry(key, val, expires_at) => {
+            insert(data, key, val, Some(expires_at), revision, now)
+        }
+        Operation::Expire(key, expires_at) => match data.get_mut(&key) {
+            Some(record) if !record.is_expired(now) => {
+                let previous = record.clone();
+                record.expires_at = expires_at;
+                record.mod_revision = revision;
+                Some(previous)
+            }
+            _ => None,
+        },
+        Operation::Delete(key) => data.remove(&key).filter(|record| !record.is_expired(now)),
+    }
+}
+
+#[inline]
+fn insert<M>(
+    data: &mut M,
+    key: Key,
+    val: Val,
+    expires_at: Option<SystemTime>,
+    revision: Revision,
+    now: SystemTime,
+) -> Option<Record>
+where
+    M: RecordsMut + ?Sized,
+{
+    // An expired key is created anew
+    let create_revision = live(data, &key, now).map_or(revision, |record| record.create_revision);
+    let record = Record {
+        val,
+        create_revision,
+        mod_revision: revision,
+        expires_at,
+    };
+    data.insert(key, record)
+        .filter(|record| !record.is_expired(now))
+}
+
+/// The revision of the last mutation of existing records.
+pub(crate) fn last_revision<'a>(records: impl Iterator<Item = (&'a Key, &'a Record)>) -> Revision {
+    records
+        .map(|(_, record)| record.mod_revision)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Min-heap of the expiration times, used by the sweeper. Entries are not removed when a key is
+/// overwritten or deleted so they have to be checked against the records before deleting.
+#[derive(Default)]
+pub(crate) struct Expirations {
+    heap: BinaryHeap<Reverse<(SystemTime, Key)>>,
+}
+
+impl Expirations {
+    pub(crate) fn new<'a>(records: impl Iterator<Item = (&'a Key, &'a Record)>) -> Self {
+        let heap = records
+            .filter_map(|(key, record)| Some(Reverse((record.expires_at?, key.clone()))))
+            .collect();
+        Self { heap }
+    }
+
+    /// Must see every operation that is applied.
+    #[inline]
+    pub(crate) fn track(&mut self, op: &Operation) {
+        match op {
+            Operation::PutWithExpiry(key, _, expires_at)
+            | Operation::Expire(key, Some(expires_at)) => {
+                self.heap.push(Reverse((*expires_at, key.clone())));
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes the entries that are due and returns the keys that still expire at the time of
+    /// their entry. `expires_at` looks up the current expiration of a key.
+    pub(crate) fn expired<F>(&mut self, now: SystemTime, mut expires_at: F) -> Vec<Key>
+    where
+        F: FnMut(&Key) -> Option<SystemTime>,
+    {
+        let mut keys = Vec::new();
+        while let Some(Reverse((at, _))) = self.heap.peek() {
+            if *at > now {
+                break;
+            }
+
+            let Reverse((at, key)) = self.heap.pop().unwrap();
+            // The key might have been deleted or given a new ttl since the entry was pushed
+            if expires_at(&key) == Some(at) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EngineKind, StorageEngine, MAX_SKIPPED};
+    use crate::storage::types::{Operation, Precondition, Record};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    fn key(i: usize) -> Bytes {
+        Bytes::from(format!("{:03}", i))
+    }
+
+    // Every engine has to pass the same tests
+    fn engines() -> Vec<Box<dyn StorageEngine>> {
+        EngineKind::ALL.iter().map(|kind| kind.create(42)).collect()
+    }
+
+    #[test]
+    fn test_kind() {
+        for kind in &EngineKind::ALL {
+            let engine = kind.create(7);
+            assert_eq!(engine.kind(), *kind);
+            assert_eq!(engine.id(), 7);
+            assert!(engine.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_basic() {
+        for engine in engines() {
+            let k = Bytes::from("1");
+            assert_eq!(engine.get(&k), None);
+            assert_eq!(engine.put(k.clone(), Bytes::from("a")).unwrap(), None);
+            assert_eq!(engine.get(&k), Some(Bytes::from("a")));
+            assert_eq!(
+                engine.put(k.clone(), Bytes::from("b")).unwrap(),
+                Some(Bytes::from("a"))
+            );
+            assert_eq!(engine.len(), 1);
+            assert_eq!(engine.delete(&k).unwrap(), Some(Bytes::from("b")));
+            assert_eq!(engine.get(&k), None, "{:?}", engine.kind());
+            assert!(engine.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_approximate_bytes() {
+        for engine in engines() {
+            assert_eq!(engine.approximate_bytes(), 0);
+            engine.put(key(1), Bytes::from(vec![0u8; 1000])).unwrap();
+            let one = engine.approximate_bytes();
+            assert!(one > 1000, "{:?}", engine.kind());
+            engine.put(key(2), Bytes::from(vec![0u8; 1000])).unwrap();
+            assert_eq!(engine.approximate_bytes(), 2 * one);
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        for kind in &EngineKind::ALL {
+            let data: HashMap<_, _> = (0..10)
+                .map(|i| {
+                    let record = Record {
+                        val: key(i),
+                        create_revision: i as u64,
+                        mod_revision: i as u64,
+                        expires_at: None,
+                    };
+                    (key(i), record)
+                })
+                .collect();
+
+            let engine = kind.import(42, data);
+            assert_eq!(engine.len(), 10);
+            assert_eq!(engine.get(&key(3)), Some(key(3)));
+            let writer = engine.writer();
+            let mut writer = writer.lock().unwrap();
+            assert_eq!(writer.revision(), 9);
+            writer.put(key(3), Bytes::from("x")).unwrap();
+            assert_eq!(engine.get_record(&key(3)).unwrap().create_revision, 3);
+            assert_eq!(engine.get_record(&key(3)).unwrap().mod_revision, 10);
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        for engine in engines() {
+            {
+                let writer = engine.writer();
+                let mut writer = writer.lock().unwrap();
+                // Out of order so the unordered engines have to sort
+                for i in (0..20).rev() {
+                    writer.put(key(i), key(i)).unwrap();
+                }
+                writer.put(Bytes::from("1"), Bytes::from("x")).unwrap();
+            }
+
+            let keys = |entries: Vec<(Bytes, Record)>| -> Vec<_> {
+                entries.into_iter().map(|(key, _)| key).collect()
+            };
+            let all = engine.scan(Bound::Unbounded, Bound::Unbounded, 100).entries;
+            assert_eq!(all.len(), 21);
+            let page = engine
+                .scan(Bound::Included(&key(5)), Bound::Excluded(&key(8)), 100)
+                .entries;
+            assert_eq!(keys(page), vec![key(5), key(6), key(7)]);
+            let page = engine.scan(Bound::Excluded(&key(5)), Bound::Unbounded, 2);
+            assert_eq!(page.next, Some(key(7)));
+            assert_eq!(keys(page.entries), vec![key(6), key(7)]);
+            assert_eq!(
+                engine.scan(Bound::Unbounded, Bound::Unbounded, 100).next,
+                None
+            );
+            let empty = engine
+                .scan(Bound::Included(&key(8)), Bound::Excluded(&key(5)), 100)
+                .entries;
+            assert!(empty.is_empty());
+
+            let prefix = engine.scan_prefix(&Bytes::from("01"), None, 100).entries;
+            assert_eq!(prefix.len(), 10);
+            assert_eq!(prefix[0].0, key(10));
+            let prefix = engine
+                .scan_prefix(&Bytes::from("01"), Some(&key(17)), 100)
+                .entries;
+            assert_eq!(keys(prefix), vec![key(18), key(19)]);
+            let prefix = engine.scan_prefix(&Bytes::from("1"), None, 100).entries;
+            assert_eq!(keys(prefix), vec![Bytes::from("1")]);
+        }
+    }
+
+    #[test]
+    fn test_scan_skips_expired() {
+        let wide = |i: usize| Bytes::from(format!("{:05}", i));
+        for engine in engines() {
+            {
+                let writer = engine.writer();
+                let mut writer = writer.lock().unwrap();
+                let expired = SystemTime::now() - Duration::from_secs(1);
+                let ops = (0..MAX_SKIPPED + 10)
+                    .map(|i| Operation::PutWithExpiry(wide(i), wide(i), expired))
+                    .collect();
+                writer.apply(ops).unwrap();
+                writer.put(wide(MAX_SKIPPED + 10), wide(0)).unwrap();
+            }
+
+            // The scan gives up after MAX_SKIPPED expired records and the next page goes on
+            let page = engine.scan(Bound::Unbounded, Bound::Unbounded, 10);
+            assert!(page.entries.is_empty());
+            assert_eq!(page.next, Some(wide(MAX_SKIPPED - 1)));
+            let next = page.next.unwrap();
+            let page = engine.scan(Bound::Excluded(&next), Bound::Unbounded, 10);
+            assert_eq!(page.entries.len(), 1);
+            assert_eq!(page.entries[0].0, wide(MAX_SKIPPED + 10));
+            assert_eq!(page.next, None);
+
+            let page = engine.scan_prefix(&Bytes::new(), None, 10);
+            assert_eq!(page.next, Some(wide(MAX_SKIPPED - 1)));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_time() {
+        for engine in engines() {
+            let expires_at = SystemTime::now() + Duration::from_millis(50);
+            let op = Operation::PutWithExpiry(key(1), key(1), expires_at);
+            engine.writer().lock().unwrap().apply(vec![op]).unwrap();
+
+            // The snapshot keeps the key it saw as live, even after it has expired
+            let snapshot = engine.snapshot();
+            let now = snapshot.now();
+            assert!(now < expires_at);
+            thread::sleep(expires_at.duration_since(now).unwrap());
+            assert_eq!(engine.get(&key(1)), None);
+            assert_eq!(snapshot.now(), now);
+            assert_eq!(snapshot.get(&key(1)), Some(key(1)));
+            let page = snapshot.scan(Bound::Unbounded, Bound::Unbounded, 10);
+            assert_eq!(page.entries.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_ttl() {
+        for engine in engines() {
+            let writer = engine.writer();
+            let mut writer = writer.lock().unwrap();
+            let short = Bytes::from("short");
+            let long = Bytes::from("long");
+            writer
+                .put_with_ttl(short.clone(), Bytes::from("1"), Duration::from_millis(20))
+                .unwrap();
+            writer
+                .put_with_ttl(long.clone(), Bytes::from("2"), Duration::from_secs(60))
+                .unwrap();
+            writer
+                .put(Bytes::from("forever"), Bytes::from("3"))
+                .unwrap();
+            assert!(engine.ttl(&long).unwrap().unwrap() > Duration::from_secs(59));
+            assert_eq!(engine.ttl(&Bytes::from("forever")), Some(None));
+
+            thread::sleep(Duration::from_millis(30));
+            assert_eq!(engine.get(&short), None);
+            assert_eq!(engine.ttl(&short), None);
+            assert!(!writer.expire(&short, None).unwrap());
+            assert!(writer.expire(&long, None).unwrap());
+            assert_eq!(engine.ttl(&long), Some(None));
+
+            assert_eq!(engine.len(), 3);
+            assert_eq!(writer.remove_expired().unwrap(), 1);
+            assert_eq!(writer.remove_expired().unwrap(), 0);
+            assert_eq!(engine.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        for engine in engines() {
+            let writer = engine.writer();
+            let mut writer = writer.lock().unwrap();
+            let k = Bytes::from("1");
+            let (a, b) = (Bytes::from("a"), Bytes::from("b"));
+
+            assert_eq!(writer.put_if_absent(k.clone(), a.clone()).unwrap(), Ok(()));
+            assert_eq!(
+                writer.put_if_absent(k.clone(), b.clone()).unwrap(),
+                Err(a.clone())
+            );
+            assert_eq!(
+                writer.compare_and_swap(k.clone(), &b, b.clone()).unwrap(),
+                Err(Some(a.clone()))
+            );
+            assert_eq!(
+                writer.compare_and_swap(k.clone(), &a, b.clone()).unwrap(),
+                Ok(a.clone())
+            );
+            assert_eq!(
+                writer.delete_if_equals(&k, &a).unwrap(),
+                Err(Some(b.clone()))
+            );
+            assert_eq!(writer.delete_if_equals(&k, &b).unwrap(), Ok(b.clone()));
+            assert_eq!(engine.get(&k), None);
+            assert_eq!(writer.revision(), 3);
+        }
+    }
+
+    #[test]
+    fn test_transaction() {
+        for engine in engines() {
+            let writer = engine.writer();
+            let mut writer = writer.lock().unwrap();
+            let ops: Vec<_> = (0..10).map(|i| Operation::Put(key(i), key(i))).collect();
+            writer.put(key(0), Bytes::from("x")).unwrap();
+
+            let preconditions = [
+                Precondition::Exists(key(0)),
+                Precondition::NotExists(key(1)),
+                Precondition::Equals(key(0), Bytes::from("y")),
+            ];
+            assert_eq!(
+                writer.transaction(&preconditions, ops.clone()).unwrap(),
+                Err(2)
+            );
+            assert_eq!(engine.get(&key(1)), None);
+
+            let preconditions = [
+                Precondition::Revision(key(0), 1),
+                Precondition::Revision(key(1), 0),
+            ];
+            let result = writer.transaction(&preconditions, ops).unwrap().unwrap();
+            assert_eq!(result[0], Some(Bytes::from("x")));
+            assert_eq!(engine.len(), 10);
+            // The whole transaction is a single mutation
+            assert_eq!(writer.revision(), 2);
+            assert_eq!(engine.get_record(&key(9)).unwrap().mod_revision, 2);
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        for engine in engines() {
+            engine.put(key(1), key(1)).unwrap();
+            engine.put(key(2), key(2)).unwrap();
+
+            let snapshot = engine.snapshot();
+            assert_eq!(snapshot.len(), 2);
+            assert_eq!(snapshot.get(&key(1)), Some(key(1)));
+            assert_eq!(snapshot.get(&key(3)), None);
+            let all = snapshot
+                .scan(Bound::Unbounded, Bound::Unbounded, 10)
+                .entries;
+            assert_eq!(all.len(), 2);
+            drop(snapshot);
+
+            // Groups are applied by every engine, whether or not it can defer the writes
+            let writer = engine.writer();
+            let mut writer = writer.lock().unwrap();
+            writer
+                .group(&mut |writer| {
+                    writer.put(key(3), key(3)).unwrap();
+                    writer.delete(&key(1)).unwrap();
+                })
+                .unwrap();
+            assert_eq!(engine.get(&key(3)), Some(key(3)));
+            assert_eq!(engine.get(&key(1)), None);
+            assert_eq!(writer.revision(), 4);
+        }
+    }
+
+    #[test]
+    fn test_concurrent() {
+        for engine in engines() {
+            let engine: Arc<dyn StorageEngine> = engine.into();
+            let n = 200;
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    let engine = engine.clone();
+                    thread::spawn(move || {
+                        let mut i = 0;
+                        while i < n {
+                            match engine.get(&key(i)) {
+                                Some(val) => {
+                                    assert_eq!(val, key(i));
+                                    i += 1;
+                                }
+                                None => thread::yield_now(),
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            let writers: Vec<_> = (0..2)
+                .map(|_| {
+                    let writer = engine.writer();
+                    thread::spawn(move || {
+                        for i in 0..n {
+                            writer.lock().unwrap().put(key(i), key(i)).unwrap();
+                        }
+                    })
+                })
+                .collect();
+
+            for handle in readers.into_iter().chain(writers) {
+                handle.join().unwrap();
+            }
+            assert_eq!(engine.len(), n);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use super::engine::WriteError;
+use super::shard::DrainTimeout;
+use super::types::Revision;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use tonic::{Code, Status};
+
+/// Why a request to a shard failed. All of them are caused by the request or by the state of a
+/// single shard, the node keeps serving the other requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageError {
+    /// The API carries the shard ids as int64 but they can't be negative
+    InvalidShardId(i64),
+    /// An imported record with revisions that no shard gives out
+    InvalidRecord {
+        create_revision: Revision,
+        mod_revision: Revision,
+    },
+    ShardNotFound(usize),
+    ShardExists(usize),
+    /// The key was changed after the revision the client expected. Nothing was written.
+    RevisionMismatch {
+        shard_id: usize,
+        expected: Revision,
+    },
+    /// The readers of the shard didn't release the stale map in time. Nothing was written and it
+    /// is safe to retry.
+    DrainTimeout {
+        shard_id: usize,
+        timeout: DrainTimeout,
+    },
+    /// The write couldn't be appended to the write-ahead log of the shard. Nothing was written.
+    Wal {
+        shard_id: usize,
+        message: String,
+    },
+    /// A durable shard was requested but the node doesn't have a data directory
+    WalDisabled,
+    /// The shard only lives in memory, so it has no log to snapshot
+    NotDurable(usize),
+    /// The snapshot couldn't be written. The log is still complete.
+    SnapshotFailed {
+        shard_id: usize,
+        message: String,
+    },
+    /// The shard is a backup, only its primary accepts writes
+    NotPrimary(usize),
+    /// Replication to a shard that isn't a backup
+    NotBackup(usize),
+    /// The write was applied on the primary, but not enough backups acknowledged it in time
+    NotReplicated {
+        shard_id: usize,
+        revision: Revision,
+    },
+    /// The shard is in a Raft group and isn't its leader, or hasn't rejoined the group since a
+    /// restart. Holds the node id of the leader if it is known.
+    NotLeader {
+        shard_id: usize,
+        leader: Option<u64>,
+    },
+    /// The write was proposed to the Raft group of the shard but wasn't committed in time. It
+    /// might still be.
+    NotCommitted(usize),
+    /// The shard isn't a member of a Raft group
+    NotRaft(usize),
+    /// The Raft group of the shard rejected a change of its members
+    MembershipChange {
+        shard_id: usize,
+        message: String,
+    },
+    /// The writer thread of the shard has exited, e.g. because the shard was dropped or changed
+    /// its role. The write wasn't applied.
+    WriterStopped(usize),
+    /// A write to the shard panicked on the writer thread, this one or an earlier one. The shard
+    /// is stopped and rejects all writes, the writes of the failed group might have been applied
+    /// in part. See ShardState::Stopped.
+    WriteFailed(usize),
+}
+
+impl StorageError {
+    /// Converts a shard id of the API.
+    pub fn shard_id(shard_id: i64) -> Result<usize, Self> {
+        usize::try_from(shard_id).map_err(|_| StorageError::InvalidShardId(shard_id))
+    }
+
+    /// Wraps the error of a write to the shard.
+    pub fn write(shard_id: usize) -> impl FnOnce(WriteError) -> Self {
+        move |err| match err {
+            WriteError::DrainTimeout(timeout) => StorageError::DrainTimeout { shard_id, timeout },
+            WriteError::Wal(message) => StorageError::Wal { shard_id, message },
+            WriteError::NotLeader(leader) => StorageError::NotLeader { shard_id, leader },
+            WriteError::NotCommitted => StorageError::NotCommitted(shard_id),
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            StorageError::InvalidShardId(_) | StorageError::InvalidRecord { .. } => {
+                Code::InvalidArgument
+            }
+            StorageError::ShardNotFound(_) => Code::NotFound,
+            StorageError::ShardExists(_) => Code::AlreadyExists,
+            StorageError::RevisionMismatch { .. } => Code::FailedPrecondition,
+            StorageError::DrainTimeout { .. } | StorageError::WriterStopped(_) => Code::Unavailable,
+            StorageError::Wal { .. } | StorageError::WriteFailed(_) => Code::Internal,
+            StorageError::WalDisabled | StorageError::NotDurable(_) => Code::FailedPrecondition,
+            StorageError::SnapshotFailed { .. } => Code::Internal,
+            StorageError::NotPrimary(_) | StorageError::NotBackup(_) => Code::FailedPrecondition,
+            // Like a deadline, the write might have been replicated after all
+            StorageError::NotReplicated { .. } | StorageError::NotCommitted(_) => {
+                Code::DeadlineExceeded
+            }
+            StorageError::NotLeader { .. } | StorageError::NotRaft(_) => Code::FailedPrecondition,
+            StorageError::MembershipChange { .. } => Code::FailedPrecondition,
+        }
+    }
+
+    /// A stable name of the error that clients can match on, unlike the rest of the message.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StorageError::InvalidShardId(_) => "INVALID_SHARD_ID",
+            StorageError::InvalidRecord { .. } => "INVALID_RECORD",
+            StorageError::ShardNotFound(_) => "SHARD_NOT_FOUND",
+            StorageError::ShardExists(_) => "SHARD_EXISTS",
+            StorageError::RevisionMismatch { .. } => "REVISION_MISMATCH",
+            StorageError::DrainTimeout { .. } => "DRAIN_TIMEOUT",
+            StorageError::Wal { .. } => "WAL_FAILED",
+            StorageError::WalDisabled => "WAL_DISABLED",
+            StorageError::NotDurable(_) => "NOT_DURABLE",
+            StorageError::SnapshotFailed { .. } => "SNAPSHOT_FAILED",
+            StorageError::NotPrimary(_) => "NOT_PRIMARY",
+            StorageError::NotBackup(_) => "NOT_BACKUP",
+            StorageError::NotReplicated { .. } => "NOT_REPLICATED",
+            StorageError::NotLeader { .. } => "NOT_LEADER",
+            StorageError::NotCommitted(_) => "NOT_COMMITTED",
+            StorageError::NotRaft(_) => "NOT_RAFT",
+            StorageError::MembershipChange { .. } => "MEMBERSHIP_CHANGE_REJECTED",
+            StorageError::WriterStopped(_) => "WRITER_STOPPED",
+            StorageError::WriteFailed(_) => "WRITE_FAILED",
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::InvalidShardId(shard_id) => write!(f, "Invalid shard id: {}", shard_id),
+            StorageError::InvalidRecord {
+                create_revision,
+                mod_revision,
+            } => write!(
+                f,
+                "Invalid revisions of a record: created at {}, modified at {}",
+                create_revision, mod_revision
+            ),
+            StorageError::ShardNotFound(shard_id) => {
+                write!(f, "Missing shard with id: {}", shard_id)
+            }
+            StorageError::ShardExists(shard_id) => write!(f, "Shard {} already exists", shard_id),
+            StorageError::RevisionMismatch { shard_id, expected } => write!(
+                f,
+                "Revision mismatch in shard {}, expected revision {}",
+                shard_id, expected
+            ),
+            StorageError::DrainTimeout { shard_id, timeout } => {
+                write!(f, "Shard {} can't accept writes: {}", shard_id, timeout)
+            }
+            StorageError::Wal { shard_id, message } => {
+                write!(f, "Can't write the log of shard {}: {}", shard_id, message)
+            }
+            StorageError::WalDisabled => write!(f, "The node has no data directory"),
+            StorageError::NotDurable(shard_id) => {
+                write!(f, "Shard {} only lives in memory", shard_id)
+            }
+            StorageError::SnapshotFailed { shard_id, message } => {
+                write!(
+                    f,
+                    "Can't save a snapshot of shard {}: {}",
+                    shard_id, message
+                )
+            }
+            StorageError::NotPrimary(shard_id) => {
+                write!(f, "Shard {} is a backup, write to its primary", shard_id)
+            }
+            StorageError::NotBackup(shard_id) => write!(f, "Shard {} isn't a backup", shard_id),
+            StorageError::NotReplicated { shard_id, revision } => write!(
+                f,
+                "Revision {} of shard {} was applied but not acknowledged by enough backups",
+                revision, shard_id
+            ),
+            StorageError::NotLeader {
+                shard_id,
+                leader: Some(leader),
+            } => write!(
+                f,
+                "Shard {} isn't the leader of its Raft group, write to node {}",
+                shard_id, leader
+            ),
+            StorageError::NotLeader {
+                shard_id,
+                leader: None,
+            } => write!(f, "The Raft group of shard {} has no leader", shard_id),
+            StorageError::NotCommitted(shard_id) => write!(
+                f,
+                "A write to shard {} wasn't committed in time, it might still be applied",
+                shard_id
+            ),
+            StorageError::NotRaft(shard_id) => {
+                write!(f, "Shard {} isn't in a Raft group", shard_id)
+            }
+            StorageError::MembershipChange { shard_id, message } => write!(
+                f,
+                "Can't change the members of the Raft group of shard {}: {}",
+                shard_id, message
+            ),
+            StorageError::WriterStopped(shard_id) => {
+                write!(f, "Writer thread for shard {} has stopped", shard_id)
+            }
+            StorageError::WriteFailed(shard_id) => {
+                write!(
+                    f,
+                    "Shard {} stopped accepting writes after a write panicked",
+                    shard_id
+                )
+            }
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+/// The message starts with the reason, e.g. "SHARD_NOT_FOUND: Missing shard with id: 7".
+// TODO: Send the reason and the shard id as google.rpc.ErrorInfo in grpc-status-details-bin once
+//  tonic can set the details of a Status. 0.1 can only read them.
+impl From<StorageError> for Status {
+    fn from(err: StorageError) -> Self {
+        Status::new(err.code(), format!("{}: {}", err.reason(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StorageError;
+    use crate::storage::engine::WriteError;
+    use crate::storage::shard::DrainTimeout;
+    use std::time::Duration;
+    use tonic::{Code, Status};
+
+    #[test]
+    fn test_shard_id() {
+        assert_eq!(StorageError::shard_id(0), Ok(0));
+        assert_eq!(StorageError::shard_id(42), Ok(42));
+        assert_eq!(
+            StorageError::shard_id(-1),
+            Err(StorageError::InvalidShardId(-1))
+        );
+        assert_eq!(
+            StorageError::shard_id(i64::MIN),
+            Err(StorageError::InvalidShardId(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status::from(StorageError::ShardNotFound(7));
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(
+            status.message(),
+            "SHARD_NOT_FOUND: Missing shard with id: 7"
+        );
+
+        let status = Status::from(StorageError::RevisionMismatch {
+            shard_id: 1,
+            expected: 3,
+        });
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(status.message().starts_with("REVISION_MISMATCH: "));
+
+        let timeout = DrainTimeout {
+            readers: 2,
+            waited: Duration::from_millis(10),
+        };
+        let status = Status::from(StorageError::write(1)(WriteError::DrainTimeout(timeout)));
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(status.message().starts_with("DRAIN_TIMEOUT: Shard 1"));
+
+        let status = Status::from(StorageError::write(2)(WriteError::NotLeader(Some(3))));
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(
+            status.message(),
+            "NOT_LEADER: Shard 2 isn't the leader of its Raft group, write to node 3"
+        );
+
+        let status = Status::from(StorageError::InvalidShardId(-1));
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,166 @@
+use super::engine::{
+    self, Batch, EngineKind, EngineSnapshot, EngineWriter, Expirations, Records, RecordsMut,
+    StorageEngine, WriteError,
+};
+use super::sync;
+use super::types::{Key, Operation, Record, Revision, Val};
+use std::collections::{BTreeMap, HashMap};
+use std::iter::FromIterator;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+
+/// A single map behind a RwLock. Only one copy of the records is kept and a write is applied in
+/// place, so it is the cheapest engine to write to. The readers block while a write is applied
+/// and a snapshot holds back the writer until it is dropped.
+pub struct Locked<M: LockedMap> {
+    id: usize,
+    data: Arc<RwLock<M>>,
+    writer: Arc<Mutex<LockedWriter<M>>>,
+}
+
+pub type LockedHashMap = Locked<HashMap<Key, Record>>;
+
+/// Keeps the keys in order so scans don't have to sort them.
+pub type LockedBTreeMap = Locked<BTreeMap<Key, Record>>;
+
+/// A map that can be used by a Locked engine.
+pub trait LockedMap:
+    RecordsMut + FromIterator<(Key, Record)> + Default + Send + Sync + 'static
+{
+    const KIND: EngineKind;
+}
+
+impl LockedMap for HashMap<Key, Record> {
+    const KIND: EngineKind = EngineKind::Locked;
+}
+
+impl LockedMap for BTreeMap<Key, Record> {
+    const KIND: EngineKind = EngineKind::Ordered;
+}
+
+// The read guard pins the records until it is dropped
+struct LockedSnapshot<'a, M> {
+    data: RwLockReadGuard<'a, M>,
+    now: SystemTime,
+}
+
+pub struct LockedWriter<M> {
+    data: Arc<RwLock<M>>,
+    // Bumped by every mutation
+    revision: Revision,
+    expirations: Expirations,
+}
+
+impl<M: LockedMap> Locked<M> {
+    pub fn new(id: usize) -> Self {
+        Self::start(id, M::default(), 0, Expirations::default())
+    }
+
+    pub fn with_data(id: usize, data: HashMap<Key, Record>) -> Self {
+        let revision = engine::last_revision(data.iter());
+        let expirations = Expirations::new(data.iter());
+        Self::start(id, data.into_iter().collect(), revision, expirations)
+    }
+
+    fn start(id: usize, data: M, revision: Revision, expirations: Expirations) -> Self {
+        let data = Arc::new(RwLock::new(data));
+        let writer = LockedWriter {
+            data: data.clone(),
+            revision,
+            expirations,
+        };
+
+        Self {
+            id,
+            data,
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl<M: LockedMap> StorageEngine for Locked<M> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn kind(&self) -> EngineKind {
+        M::KIND
+    }
+
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        Box::new(LockedSnapshot {
+            data: sync::read(&self.data),
+            now: SystemTime::now(),
+        })
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        engine::get_live(&*sync::read(&self.data), key)
+    }
+}
+
+impl<'a, M: Records> EngineSnapshot for LockedSnapshot<'a, M> {
+    fn records(&self) -> &dyn Records {
+        &*self.data
+    }
+
+    fn now(&self) -> SystemTime {
+        self.now
+    }
+}
+
+impl<M: LockedMap> EngineWriter for LockedWriter<M> {
+    fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        self.revision = self.revision.max(revision);
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        let data = sync::read(&self.data);
+        Ok(engine::live(&*data, key, now).cloned())
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.revision += 1;
+        let mut data = sync::write(&self.data);
+        let mut result = Vec::with_capacity(ops.len());
+        for op in ops {
+            self.expirations.track(&op);
+            let previous = engine::replay(&mut *data, op, self.revision, now);
+            result.push(previous.map(|record| record.val));
+        }
+        Ok(result)
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        let data = sync::read(&self.data);
+        Ok(self
+            .expirations
+            .expired(now, |key| Records::get(&*data, key)?.expires_at))
+    }
+
+    // Every write is visible as soon as it is applied
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        f(self);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use super::types::{Key, Record};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// The HashMap that backs one side of a Shard plus a sorted index of its keys.
+/// Point lookups go straight to the HashMap and only range scans touch the index.
+///
+/// The index doubles the memory used by the keys. It is a lot cheaper than switching
+/// the whole shard to an ordered map and giving up the SwissTable lookups.
+#[derive(Clone, Default)]
+pub struct Map {
+    records: HashMap<Key, Record>,
+    index: BTreeSet<Key>,
+    #[cfg(r_db_loom)]
+    access: Access,
+}
+
+// Lets loom check that the writer never changes a map while a reader is using it. Every read and
+// write of the map is recorded as an access to the cell and loom reports the ones that race.
+#[cfg(r_db_loom)]
+#[derive(Default)]
+struct Access(loom::cell::UnsafeCell<()>);
+
+#[cfg(r_db_loom)]
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(r_db_loom)]
+unsafe impl Sync for Access {}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn get(&self, key: &Key) -> Option<&Record> {
+        self.read();
+        self.records.get(key)
+    }
+
+    /// Gives mutable access to a record. The key itself can't be changed so the index stays valid.
+    #[inline]
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut Record> {
+        self.write();
+        self.records.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Key, record: Record) -> Option<Record> {
+        self.write();
+        if !self.records.contains_key(&key) {
+            self.index.insert(key.clone());
+        }
+        self.records.insert(key, record)
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<Record> {
+        self.write();
+        let result = self.records.remove(key);
+        if result.is_some() {
+            self.index.remove(key);
+        }
+        result
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Record)> {
+        self.read();
+        self.records.iter()
+    }
+
+    /// Iterates over the records with keys between the bounds in key order.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a Key>,
+        end: Bound<&'a Key>,
+    ) -> impl Iterator<Item = (&'a Key, &'a Record)> + 'a {
+        self.read();
+        let range = if is_inverted(start, end) {
+            None
+        } else {
+            Some(self.index.range::<Key, _>((start, end)))
+        };
+
+        range
+            .into_iter()
+            .flatten()
+            .map(move |key| (key, &self.records[key]))
+    }
+
+    /// The number of records, including the expired ones that the sweeper hasn't deleted yet.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    #[inline]
+    fn read(&self) {
+        #[cfg(r_db_loom)]
+        self.access.0.with(|_| ());
+    }
+
+    #[inline]
+    fn write(&self) {
+        #[cfg(r_db_loom)]
+        self.access.0.with_mut(|_| ());
+    }
+}
+
+impl From<HashMap<Key, Record>> for Map {
+    fn from(records: HashMap<Key, Record>) -> Self {
+        let index = records.keys().cloned().collect();
+        Self {
+            records,
+            index,
+            #[cfg(r_db_loom)]
+            access: Access::default(),
+        }
+    }
+}
+
+/// True if no key can be between the bounds. BTreeMap::range and BTreeSet::range panic on them.
+pub(crate) fn is_inverted(start: Bound<&Key>, end: Bound<&Key>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
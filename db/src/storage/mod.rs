@@ -1,3 +1,15 @@
+pub mod backoff;
+pub mod durable;
+pub mod engine;
+pub mod error;
+pub mod locked;
+pub mod map;
+pub mod reclamation;
+pub mod replicated;
 pub mod shard;
 pub mod shard_map;
+pub mod striped;
+pub(crate) mod sync;
 pub mod types;
+pub mod wal;
+pub mod write_queue;
@@ -0,0 +1,103 @@
+use super::sync::AtomicUsize;
+use std::sync::atomic::AtomicUsize as StdAtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+use std::thread;
+
+/// Keeps track of the readers of the two maps of a Shard, so the writer knows when nobody is
+/// using the stale map anymore. `mode` selects the map: false for the first one, true for the
+/// second one.
+pub trait Reclamation: Default + Send + Sync + 'static {
+    /// Registers a reader of the map. The returned token must be passed to `exit`.
+    fn enter(&self, mode: bool) -> usize;
+
+    fn exit(&self, mode: bool, token: usize);
+
+    /// The number of readers of the map. Only the writer calls it, after no new readers can enter
+    /// the map, so it is allowed to be slow.
+    fn readers(&self, mode: bool) -> usize;
+}
+
+/// A single pair of counters shared by all readers. The cheapest option with a few reader
+/// threads but every read writes to the same cache line, so it doesn't scale with the cores.
+#[derive(Default)]
+pub struct SharedCounters {
+    counters: [AtomicUsize; 2],
+}
+
+impl Reclamation for SharedCounters {
+    #[inline]
+    fn enter(&self, mode: bool) -> usize {
+        self.counters[mode as usize].fetch_add(1, AcqRel);
+        0
+    }
+
+    #[inline]
+    fn exit(&self, mode: bool, _token: usize) {
+        self.counters[mode as usize].fetch_sub(1, AcqRel);
+    }
+
+    fn readers(&self, mode: bool) -> usize {
+        self.counters[mode as usize].load(Acquire)
+    }
+}
+
+// Padded to two cache lines because of the adjacent line prefetcher
+#[derive(Default)]
+#[repr(align(128))]
+struct Slot {
+    counters: [AtomicUsize; 2],
+}
+
+// Source of the slot indices for the reader threads
+static NEXT_SLOT: StdAtomicUsize = StdAtomicUsize::new(0);
+
+thread_local! {
+    static SLOT: usize = NEXT_SLOT.fetch_add(1, Relaxed);
+}
+
+/// A pair of counters per slot and every reader thread sticks to one slot, so threads on
+/// different cores don't write to the same cache line. The writer has to sum all slots.
+pub struct ShardedCounters {
+    slots: Box<[Slot]>,
+}
+
+impl ShardedCounters {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: (0..slots.max(1)).map(|_| Slot::default()).collect(),
+        }
+    }
+}
+
+impl Default for ShardedCounters {
+    /// One slot per core, rounded up to a power of two.
+    fn default() -> Self {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        Self::new(cores.next_power_of_two())
+    }
+}
+
+impl Reclamation for ShardedCounters {
+    #[inline]
+    fn enter(&self, mode: bool) -> usize {
+        let slot = SLOT.with(|slot| *slot) % self.slots.len();
+        self.slots[slot].counters[mode as usize].fetch_add(1, AcqRel);
+        slot
+    }
+
+    #[inline]
+    fn exit(&self, mode: bool, token: usize) {
+        // The token is the slot. The reader might exit on another thread, e.g. a Snapshot
+        // that was moved.
+        self.slots[token].counters[mode as usize].fetch_sub(1, AcqRel);
+    }
+
+    // Adding up counters that are read at different times is fine, new readers can't enter the
+    // map so the counters only go down.
+    fn readers(&self, mode: bool) -> usize {
+        self.slots
+            .iter()
+            .map(|slot| slot.counters[mode as usize].load(Acquire))
+            .sum()
+    }
+}
@@ -0,0 +1,506 @@
+use super::durable::Durable;
+use super::engine::{
+    Batch, EngineKind, EngineSnapshot, EngineWriter, Page, StorageEngine, WriteError,
+};
+use super::error::StorageError;
+use super::sync;
+use super::types::{Key, Operation, Record, Revision, Val};
+use super::wal::SyncPolicy;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+// Upper bound for the writes a primary keeps for its backups. A backup that falls further behind
+// gets a copy of the whole shard instead.
+const MAX_LOG_ENTRIES: usize = 100_000;
+
+/// A write of a primary shard with the revision it was applied at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub revision: Revision,
+    pub now: SystemTime,
+    pub ops: Vec<Operation>,
+}
+
+/// Wraps the engine of a primary shard and appends every write to a ReplicationLog once it is
+/// applied, in the order of the revisions. Reads go straight to the engine.
+pub struct Replicated {
+    engine: Arc<dyn StorageEngine>,
+    log: Arc<ReplicationLog>,
+    writer: Arc<Mutex<ReplicatedWriter>>,
+}
+
+/// The writes of a primary shard that its backups might still need and the revisions each backup
+/// has acknowledged.
+pub struct ReplicationLog {
+    shard_id: usize,
+    // The number of backups that must apply a write before it is acknowledged
+    acks: usize,
+    timeout: Duration,
+    state: Mutex<LogState>,
+    // The revision of the last write in the log. Closed once the shard stops being the primary.
+    head: watch::Sender<Revision>,
+    head_receiver: watch::Receiver<Revision>,
+    // The highest revision that `acks` backups have applied
+    committed: watch::Sender<Revision>,
+    committed_receiver: watch::Receiver<Revision>,
+}
+
+struct LogState {
+    // The revision right before the first entry
+    start: Revision,
+    entries: VecDeque<LogEntry>,
+    // The revision each backup has applied, by the index of the backup
+    acked: Vec<Revision>,
+}
+
+pub struct ReplicatedWriter {
+    // Only locked through the ReplicatedWriter, so every write goes into the log
+    writer: Arc<Mutex<dyn EngineWriter>>,
+    log: Arc<ReplicationLog>,
+}
+
+// The writer of the engine together with the log, for the time of a single call
+struct Shipped<'a> {
+    writer: &'a mut dyn EngineWriter,
+    log: &'a ReplicationLog,
+    // The writes of the group that is being applied, they are pushed once it is published
+    group: Option<&'a mut Vec<LogEntry>>,
+}
+
+impl Replicated {
+    /// Starts replicating the writes to the engine from its current revision on. Every write must
+    /// go through the returned engine from now on.
+    pub fn new(
+        engine: Arc<dyn StorageEngine>,
+        backups: usize,
+        acks: usize,
+        timeout: Duration,
+    ) -> Self {
+        let inner = engine.writer();
+        let start = sync::lock(&*inner).revision();
+        let log = Arc::new(ReplicationLog::new(
+            engine.id(),
+            start,
+            backups,
+            acks,
+            timeout,
+        ));
+        let writer = ReplicatedWriter {
+            writer: inner,
+            log: log.clone(),
+        };
+
+        Self {
+            engine,
+            log,
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    pub fn log(&self) -> &ReplicationLog {
+        &self.log
+    }
+
+    /// Copies the records together with the revision of the last write in them. The writes wait
+    /// while the records are copied, the reads go on.
+    pub fn copy(&self) -> (Revision, Vec<(Key, Record)>) {
+        let writer = sync::lock(&*self.writer);
+        // The writer is locked, so every write is published and the copy matches the revision
+        let revision = writer.revision();
+        let snapshot = self.engine.snapshot();
+        let records = snapshot
+            .records()
+            .iter()
+            .map(|(key, record)| (key.clone(), record.clone()))
+            .collect();
+        (revision, records)
+    }
+}
+
+impl StorageEngine for Replicated {
+    fn id(&self) -> usize {
+        self.engine.id()
+    }
+
+    fn kind(&self) -> EngineKind {
+        self.engine.kind()
+    }
+
+    fn wal(&self) -> Option<SyncPolicy> {
+        self.engine.wal()
+    }
+
+    fn durable(&self) -> Option<&Durable> {
+        self.engine.durable()
+    }
+
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        self.engine.snapshot()
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        self.engine.get_record(key)
+    }
+
+    fn get(&self, key: &Key) -> Option<Val> {
+        self.engine.get(key)
+    }
+
+    fn ttl(&self, key: &Key) -> Option<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        self.engine.scan(start, end, limit)
+    }
+
+    fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        self.engine.scan_prefix(prefix, after, limit)
+    }
+
+    fn len(&self) -> usize {
+        self.engine.len()
+    }
+
+    fn approximate_bytes(&self) -> usize {
+        self.engine.approximate_bytes()
+    }
+}
+
+impl ReplicationLog {
+    fn new(
+        shard_id: usize,
+        start: Revision,
+        backups: usize,
+        acks: usize,
+        timeout: Duration,
+    ) -> Self {
+        let (head, head_receiver) = watch::channel(start);
+        let (committed, committed_receiver) = watch::channel(start);
+        Self {
+            shard_id,
+            acks,
+            timeout,
+            state: Mutex::new(LogState {
+                start,
+                entries: VecDeque::new(),
+                acked: vec![start; backups],
+            }),
+            head,
+            head_receiver,
+            committed,
+            committed_receiver,
+        }
+    }
+
+    /// The revision of the last write. The receiver is closed once the log is dropped.
+    pub fn head(&self) -> watch::Receiver<Revision> {
+        self.head_receiver.clone()
+    }
+
+    /// Up to `limit` entries after `revision`. None if the log doesn't go back that far or the
+    /// revision is ahead of the primary, the backup needs a copy of the shard then.
+    pub fn entries_after(&self, revision: Revision, limit: usize) -> Option<Vec<LogEntry>> {
+        let state = sync::lock(&self.state);
+        let head = state
+            .entries
+            .back()
+            .map_or(state.start, |entry| entry.revision);
+        if revision < state.start || revision > head {
+            return None;
+        }
+
+        let skip = state
+            .entries
+            .partition_point(|entry| entry.revision <= revision);
+        Some(
+            state
+                .entries
+                .iter()
+                .skip(skip)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Records that the backup with the given index has applied every write up to `revision`.
+    /// The entries that every backup has applied are dropped.
+    pub fn ack(&self, backup: usize, revision: Revision) {
+        let mut state = sync::lock(&self.state);
+        state.acked[backup] = revision;
+
+        let oldest = state.acked.iter().copied().min().unwrap_or(revision);
+        while state
+            .entries
+            .front()
+            .is_some_and(|entry| entry.revision <= oldest)
+        {
+            let entry = state.entries.pop_front().unwrap();
+            state.start = entry.revision;
+        }
+
+        let mut acked = state.acked.clone();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        if let Some(&committed) = self.acks.checked_sub(1).and_then(|i| acked.get(i)) {
+            if committed > *self.committed_receiver.borrow() {
+                let _ = self.committed.broadcast(committed);
+            }
+        }
+    }
+
+    /// Waits until enough backups have applied the write with the given revision.
+    pub async fn replicated(&self, revision: Revision) -> Result<(), StorageError> {
+        if self.acks == 0 {
+            return Ok(());
+        }
+
+        let mut committed = self.committed_receiver.clone();
+        let wait = async {
+            while let Some(acked) = committed.recv().await {
+                if acked >= revision {
+                    return true;
+                }
+            }
+            false
+        };
+        match tokio::time::timeout(self.timeout, wait).await {
+            Ok(true) => Ok(()),
+            // The log was dropped because the shard stopped being the primary, or the backups
+            // didn't keep up
+            Ok(false) | Err(_) => Err(StorageError::NotReplicated {
+                shard_id: self.shard_id,
+                revision,
+            }),
+        }
+    }
+
+    fn push(&self, entry: LogEntry) {
+        let revision = entry.revision;
+        {
+            let mut state = sync::lock(&self.state);
+            state.entries.push_back(entry);
+            if state.entries.len() > MAX_LOG_ENTRIES {
+                let dropped = state.entries.pop_front().unwrap();
+                state.start = dropped.revision;
+            }
+        }
+        let _ = self.head.broadcast(revision);
+    }
+}
+
+impl ReplicatedWriter {
+    fn shipped<T>(&mut self, f: impl FnOnce(&mut Shipped<'_>) -> T) -> T {
+        let mut writer = sync::lock(&*self.writer);
+        f(&mut Shipped {
+            writer: &mut *writer,
+            log: &self.log,
+            group: None,
+        })
+    }
+}
+
+impl EngineWriter for ReplicatedWriter {
+    fn revision(&self) -> Revision {
+        sync::lock(&*self.writer).revision()
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        sync::lock(&*self.writer).skip_to(revision)
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        self.shipped(|writer| writer.current(key, now))
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        self.shipped(|writer| writer.apply_at(ops, now))
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        self.shipped(|writer| writer.expired(now))
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        self.shipped(|writer| writer.group(f))
+    }
+
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        self.shipped(|writer| writer.prepare())
+    }
+}
+
+impl<'a> EngineWriter for Shipped<'a> {
+    fn revision(&self) -> Revision {
+        self.writer.revision()
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        self.writer.skip_to(revision)
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        self.writer.current(key, now)
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // The backups only get the writes that were applied and published
+        let result = self.writer.apply_at(ops.clone(), now)?;
+        let entry = LogEntry {
+            revision: self.writer.revision(),
+            now,
+            ops,
+        };
+        match &mut self.group {
+            Some(group) => group.push(entry),
+            None => self.log.push(entry),
+        }
+        Ok(result)
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        self.writer.expired(now)
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        let log = self.log;
+        if let Some(group) = self.group.as_deref_mut() {
+            // Nested, the writes are pushed with the outer group
+            return self.writer.group(&mut |writer| {
+                f(&mut Shipped {
+                    writer,
+                    log,
+                    group: Some(&mut *group),
+                })
+            });
+        }
+
+        let mut group = Vec::new();
+        self.writer.group(&mut |writer| {
+            f(&mut Shipped {
+                writer,
+                log,
+                group: Some(&mut group),
+            })
+        })?;
+        for entry in group {
+            log.push(entry);
+        }
+        Ok(())
+    }
+
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        self.writer.prepare()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogEntry, Replicated};
+    use crate::storage::engine::{EngineKind, StorageEngine};
+    use crate::storage::error::StorageError;
+    use crate::storage::types::Operation;
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_log() {
+        let engine: Arc<dyn StorageEngine> = EngineKind::LeftRight.create(1).into();
+        engine.put(Bytes::from("0"), Bytes::from("-")).unwrap();
+        let primary = Replicated::new(engine, 2, 1, Duration::from_millis(10));
+        let log = primary.log();
+        assert_eq!(*log.head().borrow(), 1);
+        assert_eq!(log.entries_after(1, 10), Some(vec![]));
+        // The writes before the shard became the primary aren't in the log
+        assert_eq!(log.entries_after(0, 10), None);
+
+        {
+            let writer = primary.writer();
+            let mut writer = writer.lock().unwrap();
+            writer.put_str("1", "a").unwrap();
+            writer
+                .group(&mut |writer| {
+                    writer.put_str("2", "b").unwrap();
+                    writer.delete(&Bytes::from("1")).unwrap();
+                    // Changes nothing, so it isn't replicated
+                    writer.apply(vec![]).unwrap();
+                    // The backups don't see the group before it is published
+                    assert_eq!(*log.head().borrow(), 2);
+                    assert_eq!(log.entries_after(2, 10), Some(vec![]));
+                })
+                .unwrap();
+        }
+        assert_eq!(primary.get(&Bytes::from("2")), Some(Bytes::from("b")));
+        assert_eq!(*log.head().borrow(), 4);
+
+        let entries = log.entries_after(1, 10).unwrap();
+        let revisions: Vec<_> = entries.iter().map(|entry| entry.revision).collect();
+        assert_eq!(revisions, vec![2, 3, 4]);
+        assert_eq!(entries[2].ops, vec![Operation::Delete(Bytes::from("1"))]);
+        assert_eq!(log.entries_after(2, 1).unwrap().len(), 1);
+        assert_eq!(log.entries_after(5, 10), None);
+
+        // Only the entries that both backups have are dropped
+        log.ack(0, 4);
+        assert_eq!(log.entries_after(1, 10).unwrap().len(), 3);
+        log.ack(1, 3);
+        assert_eq!(log.entries_after(1, 10), None);
+        let entries: Vec<LogEntry> = log.entries_after(3, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let (revision, records) = primary.copy();
+        assert_eq!(revision, 4);
+        assert_eq!(records.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_acks() {
+        let engine: Arc<dyn StorageEngine> = EngineKind::Locked.create(1).into();
+        let primary = Replicated::new(engine, 3, 2, Duration::from_millis(50));
+        let log = primary.log();
+        primary.put(Bytes::from("1"), Bytes::from("a")).unwrap();
+        primary.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+
+        log.ack(0, 2);
+        assert_eq!(
+            log.replicated(1).await,
+            Err(StorageError::NotReplicated {
+                shard_id: 1,
+                revision: 1
+            })
+        );
+        log.ack(2, 1);
+        assert_eq!(log.replicated(1).await, Ok(()));
+        assert!(log.replicated(2).await.is_err());
+        log.ack(1, 2);
+        assert_eq!(log.replicated(2).await, Ok(()));
+    }
+}
@@ -1,13 +1,29 @@
 #![allow(dead_code)]
 
-use super::types::{Key, Val};
+use super::backoff::Backoff;
+use super::engine::{
+    self, Batch, EngineKind, EngineSnapshot, EngineWriter, Expirations, Page, Records,
+    StorageEngine, WriteError,
+};
+use super::map::Map;
+use super::reclamation::{Reclamation, SharedCounters};
+use super::sync::{fence, AtomicUsize};
+use super::types::{Key, Operation, Record, Revision, Val};
 use std::collections::HashMap;
-use std::sync::atomic::Ordering::{AcqRel, Acquire, Release};
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::ops::Bound;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, SeqCst};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-type Map = HashMap<Key, Val>;
+/// How long a write waits for the readers of the stale map before it gives up.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The lowest bit of the published pointer is the mode of the map. Boxed maps are aligned to at
+// least 8 bytes so the bit is always free.
+const MODE: usize = 1;
 
 /// A lock-free* concurrent hash map that will store the data for a single database shard.
 /// It is backed by the std::collections::HashMap which, after Rust 1.36, is a port of
@@ -16,182 +32,407 @@ type Map = HashMap<Key, Val>;
 /// Heavily optimized for reads - reads will never block, writes are behind a Mutex.
 /// Instead of using a reader-writer lock which will block the reads while writing, the Shard keeps
 /// 2 maps behind atomic pointers. The readers read from one and the writers write to the other one.
-/// After a write the two pointers are swapped and the write is replayed to she stale map.
+/// After a write the two pointers are swapped and the write is kept in a log. It is replayed to the
+/// stale map right before the next write, so a write doesn't have to wait for the readers. See
+/// Replay for replaying it right away instead.
 ///
 /// The main difficulty is keeping track of all readers that have already dereferenced a pointer to the other map.
 /// To solve this every Reader increments an Atomic counter when it dereferences the pointer -> reads -> decrements the counter.
-/// The writer swaps the two pointers and then waits for the counter for the swapped map to get to 0.
-/// Then it knows that there is no one else using the map and the writes can be applied.
+/// The next write waits for the counter for the swapped map to get to 0.
+/// Then it knows that there is no one else using the map and the log can be replayed.
+/// How the counters are laid out is up to the Reclamation strategy. A single shared pair is the
+/// default, ShardedCounters keeps the reader threads from fighting over one cache line.
+///
+/// The pointer and the mode that selects its counter are a single atomic word, see
+/// Reader::snapshot for why a reader never uses a map the writer is not waiting for.
+/// A reader that never lets go of the map (e.g. a forgotten Snapshot) would block all writes, so
+/// the wait is bounded by a drain timeout and the write fails with a DrainTimeout instead.
+///
+/// Keys and values are reference counted buffers that are shared between the two maps, so only
+/// the hash tables are stored twice. A read hands out a new handle to the same buffer.
 ///
-/// Important disadvantage is that all data is stored twice. 'Tis the cost of performance.
-pub struct Shard {
+/// This is the LeftRight storage engine, see EngineKind for the others.
+pub struct Shard<R: Reclamation = SharedCounters> {
     id: usize,
-    reader: Reader,
-    writer: Arc<Mutex<Writer>>,
+    reader: Reader<R>,
+    writer: Arc<Mutex<Writer<R>>>,
+}
+
+pub struct Reader<R = SharedCounters> {
+    state: Arc<State>,
+    readers: Arc<R>,
 }
 
-pub struct Reader {
-    data: Arc<AtomicPtr<Map>>,
-    // false: first, true: second
-    mode: Arc<AtomicBool>,
-    first: Arc<AtomicUsize>,
-    second: Arc<AtomicUsize>,
+// The published map with its mode in the lowest bit. Mode false: first, true: second.
+// Shared by all Readers, including the one in the Writer, and the last one frees the map.
+struct State {
+    word: AtomicUsize,
 }
 
-pub struct Writer {
+pub struct Writer<R: Reclamation = SharedCounters> {
     data: Option<Box<Map>>,
-    reader: Reader,
+    reader: Reader<R>,
+    // Bumped by every mutation
+    revision: Revision,
+    expirations: Expirations,
+    // The last write that wasn't replayed onto the stale map yet, see Replay
+    pending: Option<Pending>,
+    replay: Replay,
+    // The writes of the current group. They are published together when the group ends.
+    group: Option<Vec<Entry>>,
+    drain_timeout: Duration,
+    // Number of writes that failed because the readers didn't leave the stale map in time
+    drain_timeouts: u64,
+}
+
+/// When the Writer replays a write onto the stale map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Replay {
+    /// Right before the next write. By then the readers of the stale map are most likely gone, so
+    /// a write returns as soon as it is published. The write stays in a log until then.
+    #[default]
+    Lazy,
+    /// Right after the write is published. The write returns once both maps have it and waits
+    /// for the readers of the stale map to leave it.
+    Eager,
+}
+
+/// The readers of the stale map didn't leave it within the drain timeout. Nothing was written and
+/// the shard is still consistent, the next write will wait for the readers again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrainTimeout {
+    /// Readers that were still holding the map when the writer gave up
+    pub readers: usize,
+    pub waited: Duration,
+}
+
+impl fmt::Display for DrainTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} readers didn't release the stale map in {:?}",
+            self.readers, self.waited
+        )
+    }
+}
+
+impl Error for DrainTimeout {}
+
+// The writes that were published to the readers but not replayed onto the stale map yet
+struct Pending {
+    // The map that was swapped out. Readers might still be using it.
+    data: *mut Map,
+    // The counter of those readers
+    mode: bool,
+    log: Vec<Entry>,
+}
+
+// A single mutation, everything needed to replay it onto the other map
+struct Entry {
+    ops: Vec<Operation>,
+    revision: Revision,
+    now: SystemTime,
+}
+
+// The raw pointer in Pending is owned by the Writer exactly like the Box in Writer::data
+unsafe impl<R: Reclamation> Send for Writer<R> {}
+
+fn pack(data: Box<Map>, mode: bool) -> usize {
+    let data = Box::into_raw(data) as usize;
+    debug_assert_eq!(data & MODE, 0);
+    data | mode as usize
 }
 
-impl Reader {
+fn unpack(state: usize) -> (*mut Map, bool) {
+    ((state & !MODE) as *mut Map, state & MODE == MODE)
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        // Nobody else can get to the map, the Writer is gone together with the last Reader
+        let (data, _) = unpack(self.word.load(Acquire));
+        unsafe { drop(Box::from_raw(data)) };
+    }
+}
+
+impl<R: Reclamation> Reader<R> {
     pub fn new() -> Self {
-        Self {
-            data: Arc::new(AtomicPtr::new(Box::into_raw(Box::new(HashMap::new())))),
-            mode: Arc::new(AtomicBool::new(false)),
-            first: Arc::new(AtomicUsize::new(0)),
-            second: Arc::new(AtomicUsize::new(0)),
-        }
+        Self::with_data(Map::new())
     }
+
     fn with_data(data: Map) -> Self {
+        let state = State {
+            word: AtomicUsize::new(pack(Box::new(data), false)),
+        };
         Self {
-            data: Arc::new(AtomicPtr::new(Box::into_raw(Box::new(data)))),
-            mode: Arc::new(AtomicBool::new(false)),
-            first: Arc::new(AtomicUsize::new(0)),
-            second: Arc::new(AtomicUsize::new(0)),
+            state: Arc::new(state),
+            readers: Arc::new(R::default()),
         }
     }
 
     pub fn get(&self, key: &Key) -> Option<Val> {
-        let result;
+        self.get_record(key).map(|record| record.val)
+    }
 
-        let mode = self.mode.load(Acquire);
-        self.increment_counter(mode);
-        result = self.data().get(key);
-        self.decrement_counter(mode);
+    /// Same as get but also returns the revisions of the value.
+    pub fn get_record(&self, key: &Key) -> Option<Record> {
+        // A point read only looks at the clock if the key has a ttl, so the time of the snapshot
+        // isn't used
+        engine::get_live(self.pin(UNIX_EPOCH).data, key)
+    }
 
-        match result {
-            // TODO: This clone means an extra allocation for every read.
-            Some(r) => Some(r.clone()),
-            None => None,
-        }
+    /// Convenience for users that store UTF-8 strings.
+    /// Invalid UTF-8 sequences in the value are replaced with U+FFFD.
+    pub fn get_str(&self, key: &str) -> Option<String> {
+        let val = self.get(&Key::from(key))?;
+        Some(String::from_utf8_lossy(&val).into_owned())
     }
 
-    #[inline]
-    fn data(&self) -> &Map {
-        // Unwrap should never panic because self.r is always valid
-        unsafe { self.data.load(Acquire).as_ref().unwrap() }
+    /// Returns the time left before the key expires or None if it never expires.
+    /// The outer Option is None if the key is missing.
+    pub fn ttl(&self, key: &Key) -> Option<Option<Duration>> {
+        Some(self.get_record(key)?.ttl())
     }
 
-    fn counter_count(&self, mode: bool) -> usize {
-        if mode {
-            self.second.load(Acquire)
-        } else {
-            self.first.load(Acquire)
-        }
+    /// Returns up to `limit` live records with keys between the bounds in key order.
+    /// All of them are read from the same version of the map, which stays pinned while the page is
+    /// copied, so the next write waits for the scan. See Page for how long a page can get.
+    pub fn scan(&self, start: Bound<&Key>, end: Bound<&Key>, limit: usize) -> Page {
+        self.snapshot().scan(start, end, limit)
     }
 
-    #[inline]
-    fn decrement_counter(&self, mode: bool) {
-        if mode {
-            self.second.fetch_sub(1, AcqRel);
-        } else {
-            self.first.fetch_sub(1, AcqRel);
+    /// Returns up to `limit` live records with keys that start with `prefix` in key order.
+    /// `after` is the last key of the previous page. All records are read from the same version of the map.
+    pub fn scan_prefix(&self, prefix: &Key, after: Option<&Key>, limit: usize) -> Page {
+        self.snapshot().scan_prefix(prefix, after, limit)
+    }
+
+    /// Pins the current version of the map. All reads through the snapshot see the same data.
+    ///
+    /// The writer can't reuse a map while it is pinned, so while the snapshot is alive the writer
+    /// will publish at most one more write and then wait for the snapshot to be dropped.
+    /// Keep snapshots short-lived.
+    pub fn snapshot(&self) -> Snapshot<'_, R> {
+        self.pin(SystemTime::now())
+    }
+
+    fn pin(&self, now: SystemTime) -> Snapshot<'_, R> {
+        // The reader registers in the counter of the map it found and then checks that the map
+        // is still published. The writer publishes and then reads the counter. The two SeqCst
+        // fences make sure at least one of them sees the other:
+        // - the reader sees the new map, leaves the counter and tries again or
+        // - the writer sees the reader in the counter and waits for it.
+        // So a reader never touches a map without the writer waiting for it. A map that was
+        // swapped out and back in again by the time of the check is fine, it is published.
+        let mut state = self.state.word.load(Acquire);
+        loop {
+            let mode = state & MODE == MODE;
+            let token = self.readers.enter(mode);
+            fence(SeqCst);
+
+            let current = self.state.word.load(Acquire);
+            if current == state {
+                return Snapshot {
+                    reader: self,
+                    mode,
+                    token,
+                    // Never null, the map is only freed after the last Reader is gone
+                    data: unsafe { &*unpack(state).0 },
+                    now,
+                };
+            }
+
+            self.readers.exit(mode, token);
+            state = current;
         }
     }
 
+    fn counter_count(&self, mode: bool) -> usize {
+        self.readers.readers(mode)
+    }
+}
+
+/// A pinned version of the shard's map. See Reader::snapshot.
+pub struct Snapshot<'a, R: Reclamation = SharedCounters> {
+    reader: &'a Reader<R>,
+    mode: bool,
+    token: usize,
+    data: &'a Map,
+    now: SystemTime,
+}
+
+impl<'a, R: Reclamation> EngineSnapshot for Snapshot<'a, R> {
     #[inline]
-    fn increment_counter(&self, mode: bool) {
-        if mode {
-            self.second.fetch_add(1, AcqRel);
-        } else {
-            self.first.fetch_add(1, AcqRel);
-        }
+    fn records(&self) -> &dyn Records {
+        self.data
+    }
+
+    fn now(&self) -> SystemTime {
+        self.now
+    }
+}
+
+impl<'a, R: Reclamation> Drop for Snapshot<'a, R> {
+    fn drop(&mut self) {
+        self.reader.readers.exit(self.mode, self.token);
     }
+}
 
-    fn toggle_mode(&self) -> bool {
-        self.mode.fetch_xor(true, AcqRel)
+impl<R: Reclamation> Default for Reader<R> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl Clone for Reader {
+impl<R> Clone for Reader<R> {
     fn clone(&self) -> Self {
         Self {
-            data: self.data.clone(),
-            mode: self.mode.clone(),
-            first: self.first.clone(),
-            second: self.second.clone(),
+            state: self.state.clone(),
+            readers: self.readers.clone(),
         }
     }
 }
 
-impl Writer {
-    pub fn new(reader: Reader) -> Self {
+impl<R: Reclamation> Writer<R> {
+    pub fn new(reader: Reader<R>) -> Self {
         Self {
-            data: Some(Box::new(HashMap::new())),
+            data: Some(Box::new(Map::new())),
             reader,
+            revision: 0,
+            expirations: Expirations::default(),
+            pending: None,
+            replay: Replay::default(),
+            group: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            drain_timeouts: 0,
         }
     }
 
-    fn with_data(reader: Reader, data: Map) -> Self {
+    fn with_data(reader: Reader<R>, data: Map) -> Self {
         Self {
+            revision: engine::last_revision(data.iter()),
+            expirations: Expirations::new(data.iter()),
             data: Some(Box::new(data)),
             reader,
+            pending: None,
+            replay: Replay::default(),
+            group: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            drain_timeouts: 0,
         }
     }
 
-    // TODO: Fix the code duplication with delete
-    pub fn put(&mut self, key: Key, value: Val) -> Option<Val> {
-        let mut data = self.data();
-        data.insert(key.clone(), value.clone());
-
-        self.swap(data);
-
-        // Writer has changed
-        let mut data = self.data();
-        let result = data.insert(key, value);
-        self.data = Some(data);
+    /// Sets how long a write waits for the readers to leave the stale map before it fails.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
 
-        result
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
     }
 
-    // TODO: Fix the code duplication with put
-    pub fn delete(&mut self, key: &Key) -> Option<Val> {
-        let mut data = self.data();
-        data.remove(key);
+    /// The number of writes that have failed with a DrainTimeout.
+    pub fn drain_timeouts(&self) -> u64 {
+        self.drain_timeouts
+    }
 
-        self.swap(data);
+    pub fn set_replay(&mut self, replay: Replay) {
+        self.replay = replay;
+    }
 
-        // Writer has changed
-        let mut data = self.data();
-        let result = data.remove(key);
-        self.data = Some(data);
+    pub fn replay(&self) -> Replay {
+        self.replay
+    }
 
-        result
+    fn publish(&mut self, data: Box<Map>, log: Vec<Entry>) {
+        let (stale, mode) = self.swap(data);
+        self.pending = Some(Pending {
+            data: stale,
+            mode,
+            log,
+        });
+        if self.replay == Replay::Eager {
+            // The write is already published and doesn't fail. On a timeout it stays pending and
+            // the next write tries again.
+            if let Err(err) = self.replay_pending() {
+                log::info!(
+                    "Deferred the replay of a write: {} readers didn't release the stale map in {:?}",
+                    err.readers,
+                    err.waited
+                );
+            }
+        }
     }
 
-    fn swap(&mut self, data: Box<Map>) {
-        // Because Box::into_raw consumes the Box we have to keep the self.data in an Option
-        // so it can be swapped with None and then put back in
-        let new_data = self.reader.data.swap(Box::into_raw(data), Release);
+    /// Replays the pending write before a write that needs the writer's copy of the map, see
+    /// replay_pending. A timeout fails that write.
+    fn sync(&mut self) -> Result<(), DrainTimeout> {
+        self.replay_pending().inspect_err(|err| {
+            self.drain_timeouts += 1;
+            log::warn!(
+                "Write failed: {} readers didn't release the stale map in {:?}",
+                err.readers,
+                err.waited
+            );
+        })
+    }
 
-        let prev_mode = self.reader.toggle_mode();
-        self.wait(prev_mode);
+    /// Replays the pending write onto the stale map. It has to wait for the readers that might
+    /// still be using the map but by the time the next write comes they are most likely done.
+    /// On timeout the pending write is kept so the next call can try again.
+    fn replay_pending(&mut self) -> Result<(), DrainTimeout> {
+        if let Some(mode) = self.pending.as_ref().map(|pending| pending.mode) {
+            self.wait(mode)?;
 
-        unsafe {
-            self.data = Some(Box::from_raw(new_data));
+            let pending = self.pending.take().unwrap();
+            let mut data = unsafe { Box::from_raw(pending.data) };
+            for entry in pending.log {
+                for op in entry.ops {
+                    engine::replay(&mut *data, op, entry.revision, entry.now);
+                }
+            }
+            self.data = Some(data);
         }
+        Ok(())
     }
 
-    // TODO: What if a reader is stuck holding the pointer?
+    /// The writer's copy of the map. Once synced it is identical to the one the readers see.
     #[inline]
-    fn wait(&mut self, prev_mode: bool) {
+    fn synced(&mut self) -> Result<&Map, DrainTimeout> {
+        self.sync()?;
+        Ok(self.data.as_ref().unwrap())
+    }
+
+    /// Publishes the new map and returns the stale one together with the mode of its readers.
+    fn swap(&mut self, data: Box<Map>) -> (*mut Map, bool) {
+        // Because Box::into_raw consumes the Box we have to keep the self.data in an Option
+        // so it can be swapped with None and then put back in by the next sync.
+        // Only the writer changes the state so it can't change between the load and the swap.
+        let word = &self.reader.state.word;
+        let (_, mode) = unpack(word.load(Relaxed));
+        let stale = word.swap(pack(data, !mode), AcqRel);
+        // Pairs with the fence in Reader::snapshot, the counter is read only after this
+        fence(SeqCst);
+
+        unpack(stale)
+    }
+
+    /// Waits for the readers of the stale map to leave it. Gives up after the drain timeout
+    /// because a stuck reader would otherwise block every write to the shard.
+    fn wait(&mut self, prev_mode: bool) -> Result<(), DrainTimeout> {
+        let start = Instant::now();
+        let mut backoff = Backoff::new();
         loop {
-            let stale_pointers = self.reader.counter_count(prev_mode);
-            if stale_pointers > 0 {
-                thread::yield_now();
-            } else {
-                break;
+            let readers = self.reader.counter_count(prev_mode);
+            if readers == 0 {
+                return Ok(());
             }
+
+            let waited = start.elapsed();
+            if waited >= self.drain_timeout {
+                return Err(DrainTimeout { readers, waited });
+            }
+            backoff.snooze();
         }
     }
 
@@ -202,115 +443,831 @@ impl Writer {
     }
 }
 
+impl<R: Reclamation> EngineWriter for Writer<R> {
+    fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        self.revision = self.revision.max(revision);
+    }
+
+    #[inline]
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        Ok(engine::live(self.synced()?, key, now).cloned())
+    }
+
+    /// Applies all operations to the stale map and swaps the pointers once. The operations are
+    /// kept as a log and replayed onto the other map right before the next write, so the readers
+    /// that are still using it have had time to finish. See Replay::Eager for the other way.
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.sync()?;
+        self.revision += 1;
+        let revision = self.revision;
+
+        let mut data = self.data();
+        let mut result = Vec::with_capacity(ops.len());
+        for op in &ops {
+            self.expirations.track(op);
+            let previous = engine::replay(&mut *data, op.clone(), revision, now);
+            result.push(previous.map(|record| record.val));
+        }
+
+        let entry = Entry { ops, revision, now };
+        match &mut self.group {
+            Some(log) => {
+                self.data = Some(data);
+                log.push(entry);
+            }
+            None => self.publish(data, vec![entry]),
+        }
+
+        Ok(result)
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        // Sync before popping anything so a timeout doesn't lose entries from the heap
+        self.sync()?;
+        let data = self.data.as_ref().unwrap();
+        Ok(self
+            .expirations
+            .expired(now, |key| data.get(key)?.expires_at))
+    }
+
+    fn prepare(&mut self) -> Result<(), WriteError> {
+        Ok(self.sync()?)
+    }
+
+    /// Runs `f` and publishes all writes it makes with a single swap. The writes see each other
+    /// but the readers see none of them until `f` returns.
+    ///
+    /// The readers are drained once before `f` runs, so none of the writes in the group can fail
+    /// with a DrainTimeout.
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        if self.group.is_some() {
+            f(self);
+            return Ok(());
+        }
+
+        self.sync()?;
+        self.group = Some(Vec::new());
+        f(self);
+
+        let log = self.group.take().unwrap();
+        if !log.is_empty() {
+            let data = self.data();
+            self.publish(data, log);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Reclamation> Drop for Writer<R> {
+    fn drop(&mut self) {
+        // Free the stale map if the last write was never replayed onto it. If a reader is still
+        // holding it after the timeout the map is leaked, freeing it would pull it out from under
+        // the reader.
+        if let Some(mode) = self.pending.as_ref().map(|pending| pending.mode) {
+            let pending = self.pending.take().unwrap();
+            match self.wait(mode) {
+                Ok(()) => unsafe { drop(Box::from_raw(pending.data)) },
+                Err(_) => log::error!("Leaking the stale map of a dropped writer"),
+            }
+        }
+    }
+}
+
 impl Shard {
     pub fn new(id: usize) -> Self {
+        Self::with_reclamation(id)
+    }
+
+    /// When autoscaling a shard will have to be moved to a different server. This constructor
+    /// will import the existing data in a more performant way.
+    pub fn with_data(id: usize, data: HashMap<Key, Record>) -> Self {
+        Self::import(id, data)
+    }
+}
+
+impl<R: Reclamation> Shard<R> {
+    /// Creates an empty shard that keeps track of its readers with `R`.
+    pub fn with_reclamation(id: usize) -> Self {
         let reader = Reader::new();
         let writer = Writer::new(reader.clone());
 
-        Self {
-            id,
-            writer: Arc::new(Mutex::new(writer)),
-            reader,
-        }
+        Self::start(id, reader, writer)
     }
 
-    /// When autoscaling a shard will have to be moved to a different server. This constructor
-    /// will import the existing data in a more performant way.
-    pub fn with_data(id: usize, data: Map) -> Self {
-        let mut reader_data = HashMap::with_capacity(data.len());
-        data.iter().for_each(|(key, value)| {
-            reader_data.insert(key.clone(), value.clone());
-        });
+    fn import(id: usize, data: HashMap<Key, Record>) -> Self {
+        let data = Map::from(data);
+        let reader_data = data.clone();
 
         let reader = Reader::with_data(reader_data);
         let writer = Writer::with_data(reader.clone(), data);
 
+        Self::start(id, reader, writer)
+    }
+
+    fn start(id: usize, reader: Reader<R>, writer: Writer<R>) -> Self {
         Self {
             id,
-            writer: Arc::new(Mutex::new(writer)),
             reader,
+            writer: Arc::new(Mutex::new(writer)),
         }
     }
 
-    pub fn writer(&self) -> Arc<Mutex<Writer>> {
+    pub fn writer(&self) -> Arc<Mutex<Writer<R>>> {
         self.writer.clone()
     }
 
-    pub fn reader(&self) -> Reader {
+    pub fn reader(&self) -> Reader<R> {
         self.reader.clone()
     }
+}
 
-    pub fn id(&self) -> usize {
+impl<R: Reclamation> StorageEngine for Shard<R> {
+    fn id(&self) -> usize {
         self.id
     }
-}
 
-impl Drop for Shard {
-    fn drop(&mut self) {
-        use std::ptr;
+    fn kind(&self) -> EngineKind {
+        EngineKind::LeftRight
+    }
 
-        // Free the raw pointers in the Reader.
-        // This is safe because by the time we drop the Shard all references to the Reader are already dropped.
-        unsafe { Box::from_raw(self.reader.data.swap(ptr::null_mut(), Release)) };
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        Box::new(self.reader.snapshot())
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        self.reader.get_record(key)
+    }
+
+    fn approximate_bytes(&self) -> usize {
+        // The keys and values are shared by the two maps but each has its own entries. The index
+        // holds another handle to every key.
+        let snapshot = self.reader.snapshot();
+        engine::approximate_bytes(snapshot.records(), 2) + snapshot.len() * mem::size_of::<Key>()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Shard;
+    use super::{Replay, Shard};
+    use crate::storage::engine::{EngineSnapshot, EngineWriter, WriteError};
+    use crate::storage::reclamation::ShardedCounters;
+    use crate::storage::types::{Operation, Precondition, Record};
+    use crate::storage::write_queue::WriteQueue;
+    use bytes::Bytes;
     use std::collections::HashMap;
+    use std::ops::Bound;
     use std::thread;
+    use std::time::{Duration, SystemTime};
+    #[cfg(r_db_loom)]
+    use {
+        super::{Reader, Writer},
+        crate::storage::reclamation::{Reclamation, SharedCounters},
+    };
 
     #[test]
     fn test_with_data() {
         let mut data = HashMap::new();
         for i in 0..10 {
-            data.insert(i.to_string(), i.to_string());
+            let record = Record {
+                val: Bytes::from(i.to_string()),
+                create_revision: i,
+                mod_revision: i,
+                expires_at: None,
+            };
+            data.insert(Bytes::from(i.to_string()), record);
         }
 
-        let mut s = Shard::with_data(42, data);
+        let s = Shard::with_data(42, data);
         let r = s.reader();
 
         for i in 0..10 {
-            assert_eq!(r.get(&i.to_string()), Some(i.to_string()));
+            assert_eq!(
+                r.get(&Bytes::from(i.to_string())),
+                Some(Bytes::from(i.to_string()))
+            );
         }
 
         let w = s.writer();
         let mut w = w.lock().unwrap();
-        w.put("1".to_string(), "2".to_string());
-        assert_eq!(r.get(&"1".to_string()), Some("2".to_string()));
+        w.put(Bytes::from("1"), Bytes::from("2")).unwrap();
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("2")));
         // Check that after the swap all the data is still there
-        assert_eq!(r.get(&"0".to_string()), Some("0".to_string()));
+        assert_eq!(r.get(&Bytes::from("0")), Some(Bytes::from("0")));
     }
 
     #[test]
     fn test_basic() {
-        let mut s = Shard::new(42);
+        let s = Shard::new(42);
         let r = s.reader();
-        assert_eq!(r.get(&"1".to_string()), None);
+        assert_eq!(r.get(&Bytes::from("1")), None);
         let w = s.writer();
         let mut w = w.lock().unwrap();
-        w.put("1".to_string(), "2".to_string());
-        assert_eq!(r.get(&"1".to_string()), Some("2".to_string()));
-        w.put("1".to_string(), "3".to_string());
-        assert_eq!(r.get(&"1".to_string()), Some("3".to_string()));
-        w.delete(&"1".to_string());
-        assert_eq!(r.get(&"1".to_string()), None);
+        w.put(Bytes::from("1"), Bytes::from("2")).unwrap();
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("2")));
+        w.put(Bytes::from("1"), Bytes::from("3")).unwrap();
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("3")));
+        w.delete(&Bytes::from("1")).unwrap();
+        assert_eq!(r.get(&Bytes::from("1")), None);
+    }
+
+    #[test]
+    fn test_previous_value() {
+        let s = Shard::new(42);
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from("1");
+
+        assert_eq!(w.put(key.clone(), Bytes::from("a")).unwrap(), None);
+        assert_eq!(
+            w.put(key.clone(), Bytes::from("b")).unwrap(),
+            Some(Bytes::from("a"))
+        );
+        assert_eq!(w.delete(&key).unwrap(), Some(Bytes::from("b")));
+        assert_eq!(w.delete(&key).unwrap(), None);
+        assert_eq!(w.delete(&Bytes::from("2")).unwrap(), None);
+        assert_eq!(w.put(key, Bytes::from("c")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_binary() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from(vec![0u8, 159, 146, 150]);
+        let val = Bytes::from(vec![255u8, 0, 1]);
+
+        w.put(key.clone(), val.clone()).unwrap();
+        assert_eq!(r.get(&key), Some(val));
+
+        w.put_str("1", "один").unwrap();
+        assert_eq!(r.get_str("1"), Some("один".to_string()));
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("один")));
+        w.put(Bytes::from("2"), Bytes::from(vec![b'a', 255]))
+            .unwrap();
+        assert_eq!(r.get_str("2"), Some("a\u{FFFD}".to_string()));
+    }
+
+    #[test]
+    fn test_batch() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        w.put(Bytes::from("0"), Bytes::from("0")).unwrap();
+
+        let mut batch = w.batch();
+        for i in 1..10 {
+            batch.put(Bytes::from(i.to_string()), Bytes::from(i.to_string()));
+        }
+        batch.put(Bytes::from("1"), Bytes::from("one"));
+        batch.delete(Bytes::from("0"));
+        assert_eq!(r.get(&Bytes::from("1")), None);
+
+        let result = batch.commit().unwrap();
+        assert_eq!(result.len(), 11);
+        assert_eq!(result[9], Some(Bytes::from("1")));
+        assert_eq!(result[10], Some(Bytes::from("0")));
+
+        assert_eq!(r.get(&Bytes::from("0")), None);
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("one")));
+        for i in 2..10 {
+            assert_eq!(
+                r.get(&Bytes::from(i.to_string())),
+                Some(Bytes::from(i.to_string()))
+            );
+        }
+
+        // The other map must have received the whole log as well
+        w.put(Bytes::from("10"), Bytes::from("10")).unwrap();
+        assert_eq!(r.get(&Bytes::from("0")), None);
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("one")));
+        assert_eq!(r.get(&Bytes::from("9")), Some(Bytes::from("9")));
+    }
+
+    #[test]
+    fn test_transaction() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        w.put(Bytes::from("counter"), Bytes::from("1")).unwrap();
+
+        let result = w
+            .transaction(
+                &[
+                    Precondition::Equals(Bytes::from("counter"), Bytes::from("1")),
+                    Precondition::NotExists(Bytes::from("index")),
+                ],
+                vec![
+                    Operation::Put(Bytes::from("counter"), Bytes::from("2")),
+                    Operation::Put(Bytes::from("index"), Bytes::from("2")),
+                ],
+            )
+            .unwrap();
+        assert_eq!(result, Ok(vec![Some(Bytes::from("1")), None]));
+        assert_eq!(r.get(&Bytes::from("counter")), Some(Bytes::from("2")));
+        assert_eq!(r.get(&Bytes::from("index")), Some(Bytes::from("2")));
+
+        let result = w
+            .transaction(
+                &[
+                    Precondition::Exists(Bytes::from("index")),
+                    Precondition::Equals(Bytes::from("counter"), Bytes::from("1")),
+                ],
+                vec![
+                    Operation::Put(Bytes::from("counter"), Bytes::from("3")),
+                    Operation::Delete(Bytes::from("index")),
+                ],
+            )
+            .unwrap();
+        assert_eq!(result, Err(1));
+        assert_eq!(r.get(&Bytes::from("counter")), Some(Bytes::from("2")));
+        assert_eq!(r.get(&Bytes::from("index")), Some(Bytes::from("2")));
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from("lease");
+
+        assert_eq!(
+            w.put_if_absent(key.clone(), Bytes::from("a")).unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            w.put_if_absent(key.clone(), Bytes::from("b")).unwrap(),
+            Err(Bytes::from("a"))
+        );
+        assert_eq!(r.get(&key), Some(Bytes::from("a")));
+
+        assert_eq!(
+            w.compare_and_swap(key.clone(), &Bytes::from("b"), Bytes::from("c"))
+                .unwrap(),
+            Err(Some(Bytes::from("a")))
+        );
+        assert_eq!(
+            w.compare_and_swap(key.clone(), &Bytes::from("a"), Bytes::from("c"))
+                .unwrap(),
+            Ok(Bytes::from("a"))
+        );
+        assert_eq!(r.get(&key), Some(Bytes::from("c")));
+
+        assert_eq!(
+            w.delete_if_equals(&key, &Bytes::from("a")).unwrap(),
+            Err(Some(Bytes::from("c")))
+        );
+        assert_eq!(
+            w.delete_if_equals(&key, &Bytes::from("c")).unwrap(),
+            Ok(Bytes::from("c"))
+        );
+        assert_eq!(r.get(&key), None);
+        assert_eq!(
+            w.compare_and_swap(key.clone(), &Bytes::from("c"), Bytes::from("d"))
+                .unwrap(),
+            Err(None)
+        );
+    }
+
+    #[test]
+    fn test_revisions() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from("1");
+
+        w.put(key.clone(), Bytes::from("a")).unwrap();
+        w.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+        w.put(key.clone(), Bytes::from("c")).unwrap();
+        assert_eq!(w.revision(), 3);
+        assert_eq!(
+            r.get_record(&key),
+            Some(Record {
+                val: Bytes::from("c"),
+                create_revision: 1,
+                mod_revision: 3,
+                expires_at: None,
+            })
+        );
+
+        let stale = vec![Precondition::Revision(key.clone(), 1)];
+        let put = vec![Operation::Put(key.clone(), Bytes::from("d"))];
+        assert_eq!(w.transaction(&stale, put.clone()).unwrap(), Err(0));
+
+        let current = vec![Precondition::Revision(key.clone(), 3)];
+        assert_eq!(
+            w.transaction(&current, put).unwrap(),
+            Ok(vec![Some(Bytes::from("c"))])
+        );
+        assert_eq!(r.get_record(&key).unwrap().mod_revision, 4);
+
+        w.delete(&key).unwrap();
+        w.put(key.clone(), Bytes::from("e")).unwrap();
+        assert_eq!(r.get_record(&key).unwrap().create_revision, 6);
+
+        // Deleting or expiring a missing key is not a mutation
+        let missing = Bytes::from("missing");
+        assert_eq!(w.delete(&missing).unwrap(), None);
+        assert!(!w.expire(&missing, None).unwrap());
+        assert_eq!(
+            w.transaction(&[], vec![Operation::Delete(missing.clone())])
+                .unwrap(),
+            Ok(vec![None])
+        );
+        assert_eq!(w.revision(), 6);
+        assert_eq!(
+            w.apply(vec![Operation::Delete(missing), Operation::Delete(key)])
+                .unwrap(),
+            vec![None, Some(Bytes::from("e"))]
+        );
+        assert_eq!(w.revision(), 7);
+        assert_eq!(
+            w.transaction(&[Precondition::Revision(Bytes::from("3"), 0)], vec![])
+                .unwrap(),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let now = SystemTime::now();
+        let ttl = Duration::from_secs(60);
+        // The readers check expiry against the clock, the writer is handed a time past the ttl
+        let later = now + ttl;
+
+        for key in &["1", "2", "3"] {
+            let key = Bytes::from(*key);
+            let op = Operation::PutWithExpiry(key.clone(), key, now + ttl);
+            w.apply_at(vec![op], now).unwrap();
+        }
+        w.put(Bytes::from("2"), Bytes::from("two")).unwrap();
+        assert!(w
+            .expire(&Bytes::from("3"), Some(Duration::from_secs(3600)))
+            .unwrap());
+        assert!(!w.expire(&Bytes::from("4"), None).unwrap());
+
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("1")));
+        assert!(r.ttl(&Bytes::from("1")).unwrap().unwrap() <= ttl);
+        assert_eq!(r.ttl(&Bytes::from("2")), Some(None));
+        assert_eq!(r.ttl(&Bytes::from("4")), None);
+
+        // "1" has expired by then and is created anew
+        assert_eq!(w.current(&Bytes::from("1"), later).unwrap(), None);
+        let put = Operation::Put(Bytes::from("1"), Bytes::from("one"));
+        assert_eq!(w.apply_at(vec![put], later).unwrap(), vec![None]);
+        assert_eq!(r.get_record(&Bytes::from("1")).unwrap().create_revision, 6);
+
+        let put = Operation::PutWithExpiry(Bytes::from("5"), Bytes::from("5"), now);
+        w.apply_at(vec![put], now).unwrap();
+        assert_eq!(r.get(&Bytes::from("5")), None);
+        assert_eq!(r.ttl(&Bytes::from("5")), None);
+        assert_eq!(w.expired(later).unwrap(), vec![Bytes::from("5")]);
+        assert_eq!(w.expired(later).unwrap(), Vec::<Bytes>::new());
+        assert_eq!(w.remove_expired().unwrap(), 0);
+        assert_eq!(r.get(&Bytes::from("2")), Some(Bytes::from("two")));
+        assert_eq!(r.get(&Bytes::from("3")), Some(Bytes::from("3")));
+    }
+
+    #[test]
+    fn test_scan() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let mut batch = w.batch();
+        for i in 0..20 {
+            batch.put(
+                Bytes::from(format!("a{:02}", i)),
+                Bytes::from(i.to_string()),
+            );
+            batch.put(
+                Bytes::from(format!("b{:02}", i)),
+                Bytes::from(i.to_string()),
+            );
+        }
+        batch.commit().unwrap();
+        w.put_with_ttl(
+            Bytes::from("a05"),
+            Bytes::from("5"),
+            Duration::from_millis(0),
+        )
+        .unwrap();
+        w.delete(&Bytes::from("a06")).unwrap();
+
+        let keys = |entries: Vec<(Bytes, Record)>| -> Vec<Bytes> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+
+        let start = Bytes::from("a03");
+        let end = Bytes::from("a09");
+        let result = r
+            .scan(Bound::Included(&start), Bound::Excluded(&end), 100)
+            .entries;
+        assert_eq!(keys(result), vec!["a03", "a04", "a07", "a08"]);
+
+        let result = r.scan(Bound::Included(&start), Bound::Unbounded, 2).entries;
+        assert_eq!(keys(result), vec!["a03", "a04"]);
+        assert!(r
+            .scan(Bound::Excluded(&end), Bound::Excluded(&start), 10)
+            .entries
+            .is_empty());
+
+        let prefix = Bytes::from("b");
+        let page = r.scan_prefix(&prefix, None, 15).entries;
+        assert_eq!(page.len(), 15);
+        let last = page.last().unwrap().0.clone();
+        let page = r.scan_prefix(&prefix, Some(&last), 15).entries;
+        assert_eq!(keys(page), vec!["b15", "b16", "b17", "b18", "b19"]);
+    }
+
+    #[test]
+    fn test_values_are_shared() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        // Big enough to not be stored inline
+        let val = Bytes::from(vec![7u8; 1024]);
+        w.put(Bytes::from("1"), val.clone()).unwrap();
+
+        let first = r.get(&Bytes::from("1")).unwrap();
+        // Swap the maps so the next read comes from the other one
+        w.put(Bytes::from("2"), Bytes::from("2")).unwrap();
+        let second = r.get(&Bytes::from("1")).unwrap();
+
+        assert_eq!(first.as_ptr(), val.as_ptr());
+        assert_eq!(second.as_ptr(), val.as_ptr());
+    }
+
+    #[test]
+    fn test_write_does_not_wait_for_readers() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from("1");
+
+        let snapshot = r.snapshot();
+        // Only the next write has to wait for the readers of the swapped out map
+        assert_eq!(w.put(key.clone(), Bytes::from("a")).unwrap(), None);
+        assert_eq!(r.get(&key), Some(Bytes::from("a")));
+        assert_eq!(snapshot.get(&key), None);
+        drop(snapshot);
+
+        assert_eq!(
+            w.put(key.clone(), Bytes::from("b")).unwrap(),
+            Some(Bytes::from("a"))
+        );
+        assert_eq!(w.delete(&key).unwrap(), Some(Bytes::from("b")));
+        assert_eq!(r.get(&key), None);
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        w.set_drain_timeout(Duration::from_millis(20));
+        let key = Bytes::from("1");
+
+        let snapshot = r.snapshot();
+        w.put(key.clone(), Bytes::from("a")).unwrap();
+        // The snapshot is still holding the map that the second write has to replay onto
+        let err = match w.put(key.clone(), Bytes::from("b")) {
+            Err(WriteError::DrainTimeout(err)) => err,
+            result => panic!("Expected a DrainTimeout, got {:?}", result),
+        };
+        assert_eq!(err.readers, 1);
+        assert!(err.waited >= Duration::from_millis(20));
+        assert_eq!(w.drain_timeouts(), 1);
+        assert!(w.remove_expired().is_err());
+        assert_eq!(r.get(&key), Some(Bytes::from("a")));
+        drop(snapshot);
+
+        // Nothing was lost, the pending write is replayed by the next one
+        assert_eq!(
+            w.put(key.clone(), Bytes::from("c")).unwrap(),
+            Some(Bytes::from("a"))
+        );
+        assert_eq!(w.delete(&key).unwrap(), Some(Bytes::from("c")));
+        assert_eq!(w.revision(), 3);
+        assert_eq!(r.get(&key), None);
+        assert_eq!(w.drain_timeouts(), 2);
+    }
+
+    #[test]
+    fn test_eager_replay() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        w.set_replay(Replay::Eager);
+        w.set_drain_timeout(Duration::from_millis(10));
+
+        w.put(Bytes::from("1"), Bytes::from("a")).unwrap();
+        assert!(w.pending.is_none());
+
+        // The write is published even if the readers hold the stale map, the next one replays it
+        let snapshot = r.snapshot();
+        w.put(Bytes::from("2"), Bytes::from("b")).unwrap();
+        assert!(w.pending.is_some());
+        assert_eq!(r.get(&Bytes::from("2")), Some(Bytes::from("b")));
+        // The write didn't fail, so it isn't counted
+        assert_eq!(w.drain_timeouts(), 0);
+        drop(snapshot);
+
+        w.put(Bytes::from("3"), Bytes::from("c")).unwrap();
+        assert!(w.pending.is_none());
+        for (key, val) in &[("1", "a"), ("2", "b"), ("3", "c")] {
+            assert_eq!(r.get_str(key), Some(val.to_string()));
+        }
+        assert_eq!(
+            w.current(&Bytes::from("2"), SystemTime::now())
+                .unwrap()
+                .unwrap()
+                .val,
+            Bytes::from("b")
+        );
+    }
+
+    #[test]
+    fn test_group() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        let mut w = w.lock().unwrap();
+        let key = Bytes::from("1");
+
+        let mut revision = 0;
+        w.group(&mut |w| {
+            w.put(key.clone(), Bytes::from("a")).unwrap();
+            // The writes in the group see each other but the readers don't
+            assert_eq!(
+                w.compare_and_swap(key.clone(), &Bytes::from("a"), Bytes::from("b"))
+                    .unwrap(),
+                Ok(Bytes::from("a"))
+            );
+            assert_eq!(r.get(&key), None);
+            w.put(Bytes::from("2"), Bytes::from("c")).unwrap();
+            revision = w.revision();
+        })
+        .unwrap();
+        assert_eq!(revision, 3);
+        assert_eq!(r.get(&key), Some(Bytes::from("b")));
+        assert_eq!(r.get_record(&Bytes::from("2")).unwrap().mod_revision, 3);
+
+        // The whole group is replayed onto the other map
+        w.put(Bytes::from("3"), Bytes::from("d")).unwrap();
+        assert_eq!(r.get(&key), Some(Bytes::from("b")));
+        assert_eq!(r.get(&Bytes::from("2")), Some(Bytes::from("c")));
+        assert_eq!(w.group(&mut |_| ()), Ok(()));
+        assert_eq!(w.revision(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_write_queue() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let (queue, writer_thread) = WriteQueue::start(42, s.writer());
+
+        let writes: Vec<_> = (0..100)
+            .map(|i| {
+                queue.submit(move |w| {
+                    w.put(Bytes::from(i.to_string()), Bytes::from(i.to_string()))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for write in writes {
+            assert_eq!(write.await, Ok(None));
+        }
+        for i in 0..100 {
+            assert_eq!(
+                r.get(&Bytes::from(i.to_string())),
+                Some(Bytes::from(i.to_string()))
+            );
+        }
+        assert_eq!(s.writer().lock().unwrap().revision(), 100);
+
+        s.writer()
+            .lock()
+            .unwrap()
+            .set_drain_timeout(Duration::from_millis(10));
+        let snapshot = r.snapshot();
+        let put = |val: &'static str| {
+            queue.submit(move |w| w.put(Bytes::from("1"), Bytes::from(val)).unwrap())
+        };
+        assert_eq!(put("a").await, Ok(Some(Bytes::from("1"))));
+        assert!(put("b").await.is_err());
+        drop(snapshot);
+        assert_eq!(put("c").await, Ok(Some(Bytes::from("a"))));
+
+        queue.stop();
+        writer_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_sharded_counters() {
+        let s: Shard<ShardedCounters> = Shard::with_reclamation(42);
+        let r = s.reader();
+        let w = s.writer();
+        {
+            let mut w = w.lock().unwrap();
+            w.set_drain_timeout(Duration::from_millis(20));
+            w.put(Bytes::from("1"), Bytes::from("a")).unwrap();
+        }
+
+        // A snapshot taken on one thread and dropped on another must leave its map all the same
+        let snapshot = thread::spawn(move || {
+            let mut w = w.lock().unwrap();
+            let snapshot = r.snapshot();
+            assert_eq!(snapshot.get(&Bytes::from("1")), Some(Bytes::from("a")));
+            w.put(Bytes::from("1"), Bytes::from("b")).unwrap();
+            assert!(w.put(Bytes::from("1"), Bytes::from("c")).is_err());
+            thread::scope(|scope| scope.spawn(move || drop(snapshot)).join().unwrap());
+            w.put(Bytes::from("1"), Bytes::from("c")).unwrap()
+        });
+        assert_eq!(snapshot.join().unwrap(), Some(Bytes::from("b")));
+        assert_eq!(s.reader().get(&Bytes::from("1")), Some(Bytes::from("c")));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let s = Shard::new(42);
+        let r = s.reader();
+        let w = s.writer();
+        w.lock()
+            .unwrap()
+            .put(Bytes::from("1"), Bytes::from("1"))
+            .unwrap();
+
+        let snapshot = r.snapshot();
+        let writer = {
+            let w = w.clone();
+            thread::spawn(move || {
+                let mut w = w.lock().unwrap();
+                w.put(Bytes::from("1"), Bytes::from("2")).unwrap();
+                w.put(Bytes::from("2"), Bytes::from("2")).unwrap();
+            })
+        };
+
+        // The second put can't finish while the snapshot is alive
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(snapshot.get(&Bytes::from("1")), Some(Bytes::from("1")));
+        assert_eq!(snapshot.get(&Bytes::from("2")), None);
+        let all = snapshot
+            .scan(Bound::Unbounded, Bound::Unbounded, 10)
+            .entries;
+        assert_eq!(all.len(), 1);
+        drop(snapshot);
+
+        writer.join().unwrap();
+        assert_eq!(r.get(&Bytes::from("1")), Some(Bytes::from("2")));
+        assert_eq!(r.get(&Bytes::from("2")), Some(Bytes::from("2")));
     }
 
     #[test]
     fn test_very_busy() {
-        let mut s = Shard::new(42);
-        let n = 255 as u8;
+        let s = Shard::new(42);
+        let n: u8 = 255;
         let readers: Vec<_> = (0..6)
             .map(|_| {
                 let r = s.reader();
                 thread::spawn(move || {
                     let mut i = 0;
                     while i < n {
-                        match r.get(&i.to_string()) {
+                        match r.get(&Bytes::from(i.to_string())) {
                             Some(val) => {
-                                assert_eq!(val, i.to_string());
+                                assert_eq!(val, Bytes::from(i.to_string()));
                                 i += 1;
                             }
                             None => thread::yield_now(),
@@ -326,7 +1283,8 @@ mod tests {
                 thread::spawn(move || {
                     for i in 0..n {
                         let mut w = lock.lock().unwrap();
-                        w.put(i.to_string(), i.to_string());
+                        w.put(Bytes::from(i.to_string()), Bytes::from(i.to_string()))
+                            .unwrap();
                     }
                 })
             })
@@ -340,4 +1298,46 @@ mod tests {
             handle.join().unwrap();
         }
     }
+
+    // Loom runs the closure for every possible interleaving of the threads and reports a race if
+    // the writer touches a map while a reader is still using it, see map::Access.
+    // RUSTFLAGS="--cfg r_db_loom" cargo test -p r_db --release loom
+    #[cfg(r_db_loom)]
+    fn loom_model<R: Reclamation>() {
+        loom::model(|| {
+            let reader: Reader<R> = Reader::new();
+            let mut writer = Writer::new(reader.clone());
+            let key = Bytes::from("1");
+            writer.put(key.clone(), Bytes::from("a")).unwrap();
+
+            let thread = {
+                let reader = reader.clone();
+                let key = key.clone();
+                loom::thread::spawn(move || {
+                    let first = reader.get(&key).unwrap();
+                    let second = reader.get(&key).unwrap();
+                    // A later read never sees an older value
+                    assert!(first <= second, "{:?} after {:?}", second, first);
+                })
+            };
+
+            // It takes three swaps to get back to the map the reader started with and change it
+            for val in &["b", "c", "d"] {
+                writer.put(key.clone(), Bytes::from(*val)).unwrap();
+            }
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg(r_db_loom)]
+    fn loom_shared_counters() {
+        loom_model::<SharedCounters>();
+    }
+
+    #[test]
+    #[cfg(r_db_loom)]
+    fn loom_sharded_counters() {
+        loom_model::<ShardedCounters>();
+    }
 }
@@ -1,32 +1,1531 @@
-use super::shard::{Reader, Shard, Writer};
+use super::durable::{Durable, SnapshotInfo};
+use super::engine::{EngineKind, EngineWriter, StorageEngine};
+use super::error::StorageError;
+use super::replicated::Replicated;
+use super::sync;
+use super::types::{Key, Record, Revision};
+use super::wal::{Header, SyncPolicy};
+use super::write_queue::WriteQueue;
+use crate::raft::group::{Install, Persist, RaftEngine, RaftGroup, RaftStatus};
+use crate::raft::node::{RaftNode, State};
+use crate::raft::state::{self, HardState};
+use crate::raft::transport::Transport;
+use crate::raft::{Member, NodeId, RaftConfig};
 use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Locked by the process that uses the data directory
+const LOCK_FILE: &str = ".lock";
 
 /// A HashMap behind a RW lock. The writer lock will be taken very rarely. Only when shards are added or removed.
+///
+/// Every shard gets a writer thread behind a WriteQueue for callers that must not block, like the
+/// async gRPC handlers. The writer of the engine can still be locked directly.
+///
+/// A shard either lives only in memory or is durable and keeps a write-ahead log in the data
+/// directory of the node, in `shard-{id}`. ShardMap::open recovers the durable shards. Only one
+/// process uses a data directory at a time, it holds a lock on its `.lock` file.
+///
+/// A shard can be the primary or a backup of a replication group, see ShardMap::set_role, or a
+/// member of a Raft group, see ShardMap::start_raft. A durable member is read-only after a restart
+/// until it rejoins its group.
 pub struct ShardMap {
-    shards: RwLock<HashMap<usize, Shard>>,
+    shards: RwLock<HashMap<usize, Entry>>,
+    // None if the node only keeps shards in memory
+    dir: Option<PathBuf>,
+    // Released when the file is closed
+    _lock: Option<File>,
+    // Makes the names of the directories of the shards that are being built or dropped unique
+    next_tmp: AtomicUsize,
+    // Tells the Raft groups of a shard apart, so a group that was left can't install a snapshot
+    next_group: AtomicUsize,
+}
+
+struct Entry {
+    // The engine of a primary is wrapped by `replicated`, the one of a member of a Raft group by
+    // a RaftEngine
+    engine: Arc<dyn StorageEngine>,
+    base: Arc<dyn StorageEngine>,
+    role: Role,
+    replicated: Option<Arc<Replicated>>,
+    raft: Option<Arc<RaftGroup>>,
+    // The state of a Raft member that was restarted and hasn't rejoined its group yet
+    recovered: Option<HardState>,
+    queue: WriteQueue,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+/// The part of a shard in its replication group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Standalone,
+    /// Accepts the writes and ships them to its backups. A write is acknowledged once `acks`
+    /// backups have applied it, or fails with NotReplicated after `timeout`.
+    Primary {
+        backups: usize,
+        acks: usize,
+        timeout: Duration,
+    },
+    /// Only applies the writes of its primary and serves reads
+    Backup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShardState {
+    Active,
+    /// A write panicked and might have left part of it in the engine, so the writer thread has
+    /// exited and the shard rejects writes with WriteFailed. The shard can still be read. It can
+    /// be replaced by a new copy, or recovered from its log by restarting the node.
+    Stopped,
+}
+
+/// A point in time description of a shard, see ShardMap::stats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShardStats {
+    pub shard_id: usize,
+    pub engine: EngineKind,
+    pub state: ShardState,
+    /// Including the expired keys that the sweeper hasn't deleted yet
+    pub key_count: usize,
+    pub approximate_bytes: usize,
+    pub revision: Revision,
+    /// None if the shard only lives in memory
+    pub wal: Option<SyncPolicy>,
+    pub role: Role,
+    /// None if the shard isn't in a Raft group
+    pub raft: Option<RaftStatus>,
 }
 
 impl ShardMap {
+    /// A ShardMap without a data directory. Its shards only live in memory.
     pub fn new() -> Self {
         ShardMap {
             shards: RwLock::new(HashMap::new()),
+            dir: None,
+            _lock: None,
+            next_tmp: AtomicUsize::new(0),
+            next_group: AtomicUsize::new(0),
         }
     }
 
-    pub fn insert(&self, shard: Shard) {
-        self.shards.write().unwrap().insert(shard.id(), shard);
+    /// Uses `dir` as the data directory and recovers the durable shards in it by replaying their
+    /// logs. The directory is created if it doesn't exist. Fails if another process uses it.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        let lock = lock_dir(&dir)?;
+
+        let mut shards = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let shard_id = match parse_name(&path) {
+                Some(("shard", shard_id)) => shard_id,
+                None if path.file_name() == Some(LOCK_FILE.as_ref()) => continue,
+                _ => {
+                    log::warn!("Skipping {}", path.display());
+                    continue;
+                }
+            };
+            let engine = Durable::open(&path)?;
+            if engine.id() != shard_id {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} holds shard {}", path.display(), engine.id()),
+                ));
+            }
+            let recovered = state::load(&path)?;
+            match recovered {
+                Some(_) => log::info!(
+                    "Recovered shard {} with {} keys, it is read-only until it rejoins its Raft group",
+                    shard_id,
+                    engine.len()
+                ),
+                None => log::info!("Recovered shard {} with {} keys", shard_id, engine.len()),
+            }
+            let mut entry = Entry::start(Arc::new(engine), Role::Standalone, None);
+            entry.recovered = recovered;
+            shards.insert(shard_id, entry);
+        }
+
+        Ok(ShardMap {
+            shards: RwLock::new(shards),
+            dir: Some(dir),
+            _lock: Some(lock),
+            next_tmp: AtomicUsize::new(0),
+            next_group: AtomicUsize::new(0),
+        })
+    }
+
+    /// Adds the shard, replacing the one with the same id. The shard only lives in memory, see
+    /// install for durable shards.
+    pub fn insert(&self, engine: Box<dyn StorageEngine>) -> Result<(), StorageError> {
+        self.add(engine.into(), None, true, Role::Standalone)
+    }
+
+    /// Builds a shard with the given records and adds it. With a sync policy the shard is durable
+    /// and the records are in its log before it is added. Fails with ShardExists if the id is
+    /// taken, unless `replace` is set.
+    pub fn install(
+        &self,
+        shard_id: usize,
+        kind: EngineKind,
+        wal: Option<SyncPolicy>,
+        data: HashMap<Key, Record>,
+        replace: bool,
+    ) -> Result<(), StorageError> {
+        // Fail early instead of writing the whole log first
+        if !replace && self.contains(&shard_id) {
+            return Err(StorageError::ShardExists(shard_id));
+        }
+
+        let (engine, staged) = self.build(shard_id, kind, wal, data)?;
+        let result = self.add(engine, staged.as_deref(), replace, Role::Standalone);
+        if let (Err(_), Some(staged)) = (&result, &staged) {
+            remove_dir(staged);
+        }
+        result
+    }
+
+    /// Installs a durable shard into the data directory of a node that isn't running, like
+    /// install. Only the directory of the shard is touched, the other shards aren't recovered and
+    /// no writer thread is started. Fails if a node uses the directory.
+    pub fn install_offline(
+        dir: impl Into<PathBuf>,
+        shard_id: usize,
+        kind: EngineKind,
+        sync: SyncPolicy,
+        data: HashMap<Key, Record>,
+        replace: bool,
+    ) -> Result<(), StorageError> {
+        let dir = dir.into();
+        let lock = lock_dir(&dir).map_err(|err| wal_error(shard_id, err))?;
+        let shard_map = ShardMap {
+            shards: RwLock::default(),
+            dir: Some(dir.clone()),
+            _lock: Some(lock),
+            next_tmp: AtomicUsize::new(0),
+            next_group: AtomicUsize::new(0),
+        };
+        if !replace && dir.join(shard_name(shard_id)).exists() {
+            return Err(StorageError::ShardExists(shard_id));
+        }
+
+        let (engine, staged) = shard_map.build(shard_id, kind, Some(sync), data)?;
+        match shard_map.move_in(&engine, staged.as_deref()) {
+            Ok(replaced) => {
+                if let Some(replaced) = replaced {
+                    remove_dir(&replaced);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(staged) = &staged {
+                    remove_dir(staged);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Replaces the records of a backup shard with a copy of its primary at `revision`. The
+    /// shard keeps its engine, its sync policy and its role.
+    pub fn restore_backup(
+        &self,
+        shard_id: usize,
+        revision: Revision,
+        data: HashMap<Key, Record>,
+    ) -> Result<(), StorageError> {
+        let (kind, wal) = {
+            let shards = sync::read(&self.shards);
+            let entry = shards
+                .get(&shard_id)
+                .ok_or(StorageError::ShardNotFound(shard_id))?;
+            if entry.role != Role::Backup {
+                return Err(StorageError::NotBackup(shard_id));
+            }
+            (entry.base.kind(), entry.base.wal())
+        };
+
+        let (engine, staged) = self.build(shard_id, kind, wal, data)?;
+        // The last writes of the primary might have deleted their keys
+        sync::lock(&*engine.writer()).skip_to(revision);
+        let mut result = Ok(());
+        if let Some(durable) = engine.durable() {
+            // The log only holds the records, the snapshot keeps the revision after a restart
+            result = durable
+                .save_snapshot()
+                .map(|_| ())
+                .map_err(|err| wal_error(shard_id, err));
+        }
+        if result.is_ok() {
+            result = self.add(engine, staged.as_deref(), true, Role::Backup);
+        }
+        if let (Err(_), Some(staged)) = (&result, &staged) {
+            remove_dir(staged);
+        }
+        result
+    }
+
+    // Builds the engine of a shard with the given records. The log of a durable shard is staged
+    // next to the shards and moved in once the shard is added.
+    fn build(
+        &self,
+        shard_id: usize,
+        kind: EngineKind,
+        wal: Option<SyncPolicy>,
+        data: HashMap<Key, Record>,
+    ) -> Result<(Arc<dyn StorageEngine>, Option<PathBuf>), StorageError> {
+        let sync = match wal {
+            Some(sync) => sync,
+            None => return Ok((kind.import(shard_id, data).into(), None)),
+        };
+        if self.dir.is_none() {
+            return Err(StorageError::WalDisabled);
+        }
+        let staged = self.tmp_dir("new", shard_id);
+        let header = Header {
+            shard_id,
+            kind,
+            sync,
+        };
+        match Durable::create(&staged, header, data) {
+            Ok(engine) => Ok((Arc::new(engine), Some(staged))),
+            Err(err) => {
+                remove_dir(&staged);
+                Err(wal_error(shard_id, err))
+            }
+        }
+    }
+
+    // Adds the shard and moves the staged log of a durable shard to its place. The directory of
+    // a replaced shard is deleted.
+    fn add(
+        &self,
+        engine: Arc<dyn StorageEngine>,
+        staged: Option<&Path>,
+        replace: bool,
+        role: Role,
+    ) -> Result<(), StorageError> {
+        let shard_id = engine.id();
+        let mut shards = sync::write(&self.shards);
+        if !replace && shards.contains_key(&shard_id) {
+            return Err(StorageError::ShardExists(shard_id));
+        }
+
+        let replaced = self.move_in(&engine, staged)?;
+        let previous = shards.insert(shard_id, Entry::start(engine, role, None));
+        drop(shards);
+
+        // Joins the writer thread of the replaced shard without holding the lock
+        drop(previous);
+        if let Some(replaced) = replaced {
+            remove_dir(&replaced);
+        }
+        Ok(())
+    }
+
+    // Moves the staged log of a durable shard to its place and returns the directory of the shard
+    // it replaces. Both renames are done under the lock of the shards, so the directory always
+    // belongs to the shard in the map. A node that stops in between restores the replaced shard.
+    fn move_in(
+        &self,
+        engine: &Arc<dyn StorageEngine>,
+        staged: Option<&Path>,
+    ) -> Result<Option<PathBuf>, StorageError> {
+        let shard_id = engine.id();
+        let replaced = self
+            .retire("replaced", shard_id)
+            .map_err(|err| wal_error(shard_id, err))?;
+        if let (Some(dir), Some(staged)) = (&self.dir, staged) {
+            let path = dir.join(shard_name(shard_id));
+            if let Err(err) = fs::rename(staged, &path) {
+                if let Some(replaced) = &replaced {
+                    let _ = fs::rename(replaced, &path);
+                }
+                return Err(wal_error(shard_id, err));
+            }
+            if let Some(durable) = engine.durable() {
+                durable.relocate(path);
+            }
+        }
+        Ok(replaced)
+    }
+
+    // Moves the directory of the shard out of the way, if it has one
+    fn retire(&self, kind: &str, shard_id: usize) -> io::Result<Option<PathBuf>> {
+        let path = match &self.dir {
+            Some(dir) => dir.join(shard_name(shard_id)),
+            None => return Ok(None),
+        };
+        if !path.exists() {
+            return Ok(None);
+        }
+        let retired = self.tmp_dir(kind, shard_id);
+        fs::rename(&path, &retired)?;
+        Ok(Some(retired))
+    }
+
+    // The directory of a durable shard, which holds its log and the state of its Raft member
+    fn shard_dir(&self, engine: &dyn StorageEngine) -> Option<PathBuf> {
+        engine.durable()?;
+        Some(self.dir.as_ref()?.join(shard_name(engine.id())))
+    }
+
+    // The dot hides the directory from ShardMap::open
+    fn tmp_dir(&self, kind: &str, shard_id: usize) -> PathBuf {
+        let n = self.next_tmp.fetch_add(1, Ordering::Relaxed);
+        let dir = self.dir.as_ref().expect("The node has no data directory");
+        dir.join(format!(".{}-{}-{}", kind, shard_id, n))
+    }
+
+    /// Creates an empty shard that stores its records in the given engine.
+    /// Returns false without touching the existing shard if the id is taken.
+    pub fn create(&self, shard_id: usize, kind: EngineKind) -> bool {
+        self.try_insert(kind.create(shard_id))
+    }
+
+    /// Adds the shard unless the id is taken, in which case the existing shard is kept and false
+    /// is returned. The check and the insert are atomic. The shard only lives in memory.
+    pub fn try_insert(&self, engine: Box<dyn StorageEngine>) -> bool {
+        let mut shards = sync::write(&self.shards);
+        if shards.contains_key(&engine.id()) {
+            return false;
+        }
+        shards.insert(
+            engine.id(),
+            Entry::start(engine.into(), Role::Standalone, None),
+        );
+        true
+    }
+
+    pub fn contains(&self, shard_id: &usize) -> bool {
+        sync::read(&self.shards).contains_key(shard_id)
+    }
+
+    /// Changes the role of the shard. A new primary replicates the writes after its current
+    /// revision and the returned engine holds the log for its backups. A write that is being
+    /// applied is finished in the old role, the ones still queued for the shard fail with
+    /// WriterStopped. A member of a Raft group leaves it and deletes its state.
+    pub fn set_role(
+        &self,
+        shard_id: usize,
+        role: Role,
+    ) -> Result<Option<Arc<Replicated>>, StorageError> {
+        let (replicated, old) = {
+            let mut shards = sync::write(&self.shards);
+            let old = detach(&mut shards, shard_id)?;
+            if let Some(dir) = self.shard_dir(&*old.base) {
+                // It would be read-only after a restart
+                if let Err(err) = state::remove(&dir) {
+                    log::error!("Can't delete the Raft state of shard {}: {}", shard_id, err);
+                }
+            }
+            let entry = Entry::start(old.base.clone(), role, None);
+            let replicated = entry.replicated.clone();
+            shards.insert(shard_id, entry);
+            (replicated, old)
+        };
+
+        // Joins the old writer thread without holding the lock
+        drop(old);
+        Ok(replicated)
+    }
+
+    /// Makes the shard a member of a Raft group, see RaftGroup. With `members` the shard founds the
+    /// group with them, and they must all have the same records. Without, it waits until the
+    /// leader adds it and replaces its records with a copy of the shard of the leader. A shard
+    /// that is already in a group leaves it. The writes that are queued for the shard are handled
+    /// like by set_role.
+    ///
+    /// A durable shard saves the log and the state of its member in its directory, see state. A
+    /// member that was restarted rejoins its group without `members` and recovers its log, see
+    /// restart_raft. On failure the shard is left without a group.
+    pub fn start_raft(
+        shard_map: &Arc<Self>,
+        shard_id: usize,
+        node_id: NodeId,
+        members: Vec<Member>,
+        transport: Arc<dyn Transport>,
+        config: RaftConfig,
+    ) -> Result<Arc<RaftGroup>, StorageError> {
+        let mut shards = sync::write(&shard_map.shards);
+        let joins = members.is_empty();
+        let old = detach(&mut shards, shard_id)?;
+        let base = old.base.clone();
+        let recovered = old
+            .recovered
+            .filter(|saved| joins && saved.node_id == node_id);
+        let mut node = RaftNode::new(node_id, members, &config);
+        // Saved before the group starts, so the shard rejoins it after a restart from now on
+        if let Err(err) = shard_map.save_member(&*base, &mut node, recovered) {
+            let mut entry = Entry::start(base, Role::Standalone, None);
+            entry.recovered = old.recovered;
+            shards.insert(shard_id, entry);
+            drop(shards);
+            drop(old);
+            return Err(wal_error(shard_id, err));
+        }
+
+        let group_id = shard_map.next_group.fetch_add(1, Ordering::Relaxed);
+        let weak = Arc::downgrade(shard_map);
+        let install: Install = Box::new(move |revision, data| match weak.upgrade() {
+            Some(shard_map) => shard_map.install_raft(shard_id, group_id, revision, data),
+            None => Err(StorageError::ShardNotFound(shard_id)),
+        });
+        let persist = shard_map.shard_dir(&*base).map(|_| {
+            let weak = Arc::downgrade(shard_map);
+            let persist: Persist = Box::new(move |save| match weak.upgrade() {
+                Some(shard_map) => shard_map.in_raft_dir(shard_id, group_id, save),
+                None => Err(StorageError::ShardNotFound(shard_id)),
+            });
+            persist
+        });
+        let group = Arc::new(RaftGroup::start(
+            group_id,
+            node,
+            base.clone(),
+            transport,
+            config,
+            install,
+            persist,
+        ));
+        let entry = Entry::start(base, Role::Standalone, Some(group.clone()));
+        shards.insert(shard_id, entry);
+        drop(shards);
+
+        // Joins the old writer thread without holding the lock, like set_role
+        drop(old);
+        Ok(group)
+    }
+
+    /// Rejoins the Raft groups the durable shards were members of before the node was restarted,
+    /// see start_raft. A shard that can't rejoin stays read-only.
+    pub fn restart_raft(
+        shard_map: &Arc<Self>,
+        node_id: NodeId,
+        transport: Arc<dyn Transport>,
+        config: RaftConfig,
+    ) {
+        let recovered: Vec<_> = sync::read(&shard_map.shards)
+            .iter()
+            .filter_map(|(shard_id, entry)| Some((*shard_id, entry.recovered?)))
+            .collect();
+        for (shard_id, saved) in recovered {
+            if saved.node_id != node_id {
+                log::error!(
+                    "Shard {} was a member of its Raft group as node {}, not {}",
+                    shard_id,
+                    saved.node_id,
+                    node_id
+                );
+                continue;
+            }
+            let transport = transport.clone();
+            let config = config.clone();
+            match Self::start_raft(shard_map, shard_id, node_id, Vec::new(), transport, config) {
+                Ok(_) => log::info!("Shard {} rejoined its Raft group", shard_id),
+                Err(err) => log::error!("Shard {} can't rejoin its Raft group: {}", shard_id, err),
+            }
+        }
+    }
+
+    // Saves the log and the state a member starts with, see start_raft. A restarted member
+    // recovers them first. After the old writer thread, so no write lands after the revision the
+    // log starts at.
+    fn save_member(
+        &self,
+        base: &dyn StorageEngine,
+        node: &mut RaftNode,
+        recovered: Option<HardState>,
+    ) -> io::Result<()> {
+        let dir = match self.shard_dir(base) {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let revision = sync::lock(&*base.writer()).revision();
+        if let Some(saved) = recovered {
+            // Without its log the member gets a copy of the shard of the leader
+            let log = state::load_log(&dir, revision).unwrap_or_else(|err| {
+                log::warn!("Can't recover the Raft log of shard {}: {}", base.id(), err);
+                None
+            });
+            node.recover(&saved, log);
+        }
+        state::save_log(&dir, node.log(), node.log_revision(revision))?;
+        state::save(&dir, &node.hard_state())
+    }
+
+    /// The Raft group of the shard.
+    pub fn raft(&self, shard_id: &usize) -> Result<Arc<RaftGroup>, StorageError> {
+        let shards = sync::read(&self.shards);
+        let entry = shards
+            .get(shard_id)
+            .ok_or(StorageError::ShardNotFound(*shard_id))?;
+        entry.raft.clone().ok_or(StorageError::NotRaft(*shard_id))
+    }
+
+    // Saves the log or the state of a member of a Raft group, see Persist. Fails if the shard has
+    // left the group in the meantime, which deletes them.
+    fn in_raft_dir(
+        &self,
+        shard_id: usize,
+        group_id: usize,
+        save: &mut dyn FnMut(&Path) -> io::Result<()>,
+    ) -> Result<(), StorageError> {
+        let shards = sync::read(&self.shards);
+        let entry = shards
+            .get(&shard_id)
+            .ok_or(StorageError::ShardNotFound(shard_id))?;
+        if entry.raft.as_ref().map(|group| group.id()) != Some(group_id) {
+            return Err(StorageError::NotRaft(shard_id));
+        }
+        // Under the lock, so the directory isn't replaced in the meantime
+        match self.shard_dir(&*entry.base) {
+            Some(dir) => save(&dir).map_err(|err| wal_error(shard_id, err)),
+            None => Ok(()),
+        }
+    }
+
+    // Replaces the records of a member of a Raft group with a snapshot of its leader, see
+    // Install. Fails if the shard has left the group in the meantime.
+    fn install_raft(
+        &self,
+        shard_id: usize,
+        group_id: usize,
+        revision: Revision,
+        data: HashMap<Key, Record>,
+    ) -> Result<Arc<dyn StorageEngine>, StorageError> {
+        let (kind, wal) = {
+            let shards = sync::read(&self.shards);
+            let entry = shards
+                .get(&shard_id)
+                .ok_or(StorageError::ShardNotFound(shard_id))?;
+            if entry.raft.as_ref().map(|group| group.id()) != Some(group_id) {
+                return Err(StorageError::NotRaft(shard_id));
+            }
+            (entry.base.kind(), entry.base.wal())
+        };
+
+        let (engine, staged) = self.build(shard_id, kind, wal, data)?;
+        sync::lock(&*engine.writer()).skip_to(revision);
+        let mut result = Ok(());
+        if let Some(durable) = engine.durable() {
+            result = durable
+                .save_snapshot()
+                .map(|_| ())
+                .map_err(|err| wal_error(shard_id, err));
+        }
+        if let (Ok(()), Some(staged), Some(dir)) = (&result, &staged, self.shard_dir(&*engine)) {
+            // The new directory replaces the one that holds the state of the member. Only this
+            // thread saves it, so it doesn't change in the meantime. The member saves its log
+            // again once it has restored the snapshot.
+            result = state::load(&dir)
+                .and_then(|saved| match saved {
+                    Some(saved) => state::save(staged, &saved),
+                    None => Ok(()),
+                })
+                .map_err(|err| wal_error(shard_id, err));
+        }
+        if result.is_ok() {
+            result = self.replace_base(group_id, &engine, staged.as_deref());
+        }
+        if let (Err(_), Some(staged)) = (&result, &staged) {
+            remove_dir(staged);
+        }
+        result.map(|_| engine)
+    }
+
+    // Swaps the engine of a member of a Raft group. The writer thread stays, its writer is the one
+    // of the group.
+    fn replace_base(
+        &self,
+        group_id: usize,
+        engine: &Arc<dyn StorageEngine>,
+        staged: Option<&Path>,
+    ) -> Result<(), StorageError> {
+        let shard_id = engine.id();
+        let replaced = {
+            let mut shards = sync::write(&self.shards);
+            let entry = shards
+                .get_mut(&shard_id)
+                .ok_or(StorageError::ShardNotFound(shard_id))?;
+            let group = match &entry.raft {
+                Some(group) if group.id() == group_id => group.clone(),
+                _ => return Err(StorageError::NotRaft(shard_id)),
+            };
+            let replaced = self.move_in(engine, staged)?;
+            entry.base = engine.clone();
+            entry.engine = Arc::new(RaftEngine::new(engine.clone(), group.writer()));
+            replaced
+        };
+
+        if let Some(replaced) = replaced {
+            remove_dir(&replaced);
+        }
+        Ok(())
+    }
+
+    pub fn role(&self, shard_id: &usize) -> Option<Role> {
+        Some(sync::read(&self.shards).get(shard_id)?.role)
+    }
+
+    /// Returns false if there is no such shard. The writes that are already queued for the shard
+    /// are applied before it is dropped. The log of a durable shard is deleted.
+    pub fn remove(&self, shard_id: &usize) -> bool {
+        let (removed, dropped) = {
+            let mut shards = sync::write(&self.shards);
+            let removed = shards.remove(shard_id);
+            let dropped = match removed {
+                Some(_) => self.retire("dropped", *shard_id),
+                None => Ok(None),
+            };
+            (removed, dropped)
+        };
+
+        let removed = removed.is_some();
+        match dropped {
+            Ok(Some(dropped)) => remove_dir(&dropped),
+            Ok(None) => {}
+            // It would come back after a restart
+            Err(err) => log::error!("Can't delete the log of shard {}: {}", shard_id, err),
+        }
+        removed
+    }
+
+    /// The ids of all shards in ascending order.
+    pub fn ids(&self) -> Vec<usize> {
+        let mut ids: Vec<_> = sync::read(&self.shards).keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Describes the shard. Walks all of its records, so it is as slow as a full scan.
+    pub fn stats(&self, shard_id: &usize) -> Option<ShardStats> {
+        let (engine, state, role, raft) = {
+            let shards = sync::read(&self.shards);
+            let entry = shards.get(shard_id)?;
+            let raft = match (&entry.raft, &entry.recovered) {
+                (Some(group), _) => Some(group.status()),
+                // A follower that hasn't heard from its group since the restart
+                (None, Some(state)) => Some(RaftStatus {
+                    node_id: state.node_id,
+                    term: state.term,
+                    state: State::Follower,
+                    leader: None,
+                    commit: 0,
+                    applied: 0,
+                    members: Vec::new(),
+                }),
+                (None, None) => None,
+            };
+            (entry.engine.clone(), entry.state(), entry.role, raft)
+        };
+
+        let revision = sync::lock(&*engine.writer()).revision();
+        Some(ShardStats {
+            shard_id: *shard_id,
+            engine: engine.kind(),
+            state,
+            key_count: engine.len(),
+            approximate_bytes: engine.approximate_bytes(),
+            revision,
+            wal: engine.wal(),
+            role,
+            raft,
+        })
+    }
+
+    /// The stats of all shards in ascending order of their ids.
+    pub fn all_stats(&self) -> Vec<ShardStats> {
+        // A shard removed in the meantime is skipped
+        self.ids()
+            .iter()
+            .filter_map(|shard_id| self.stats(shard_id))
+            .collect()
+    }
+
+    /// Copies the live records of the shard in the order of their keys, like Replicated::copy.
+    /// The writes wait while the records are copied, keys and values are shared.
+    pub fn copy(&self, shard_id: &usize) -> Option<Vec<(Key, Record)>> {
+        let engine = self.engine(shard_id)?;
+        let mut records: Vec<_> = {
+            let writer = engine.writer();
+            let _writer = sync::lock(&*writer);
+            // No write is published while the records are copied, so the readers of a left-right
+            // shard never wait for the snapshot and the copy matches a single revision
+            let snapshot = engine.snapshot();
+            let now = snapshot.now();
+            snapshot
+                .records()
+                .iter()
+                .filter(|(_, record)| !record.is_expired(now))
+                .map(|(key, record)| (key.clone(), record.clone()))
+                .collect()
+        };
+        records.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Some(records)
+    }
+
+    pub fn engine(&self, shard_id: &usize) -> Option<Arc<dyn StorageEngine>> {
+        Some(sync::read(&self.shards).get(shard_id)?.engine.clone())
     }
 
-    pub fn remove(&self, shard_id: &usize) {
-        self.shards.write().unwrap().remove(shard_id);
+    pub fn writer(&self, shard_id: &usize) -> Option<Arc<Mutex<dyn EngineWriter>>> {
+        Some(sync::read(&self.shards).get(shard_id)?.engine.writer())
     }
 
-    pub fn reader(&self, shard_id: &usize) -> Option<Reader> {
-        Some(self.shards.read().unwrap().get(shard_id)?.reader())
+    pub fn write_queue(&self, shard_id: &usize) -> Option<WriteQueue> {
+        Some(sync::read(&self.shards).get(shard_id)?.queue.clone())
     }
 
-    pub fn writer(&self, shard_id: &usize) -> Option<Arc<Mutex<Writer>>> {
-        Some(self.shards.read().unwrap().get(shard_id)?.writer())
+    /// The write queue for the writes of clients, together with the engine of a primary that
+    /// they have to be replicated by. A backup only takes the writes of its primary.
+    pub fn client_queue(
+        &self,
+        shard_id: &usize,
+    ) -> Result<(WriteQueue, Option<Arc<Replicated>>), StorageError> {
+        let shards = sync::read(&self.shards);
+        let entry = shards
+            .get(shard_id)
+            .ok_or(StorageError::ShardNotFound(*shard_id))?;
+        if entry.role == Role::Backup {
+            return Err(StorageError::NotPrimary(*shard_id));
+        }
+        // A restarted member of a Raft group can't tell whether it is still the leader
+        if entry.recovered.is_some() {
+            return Err(StorageError::NotLeader {
+                shard_id: *shard_id,
+                leader: None,
+            });
+        }
+        Ok((entry.queue.clone(), entry.replicated.clone()))
+    }
+
+    /// The write queue of a backup shard, for the writes of its primary.
+    pub fn backup_queue(&self, shard_id: &usize) -> Result<WriteQueue, StorageError> {
+        let shards = sync::read(&self.shards);
+        let entry = shards
+            .get(shard_id)
+            .ok_or(StorageError::ShardNotFound(*shard_id))?;
+        if entry.role != Role::Backup {
+            return Err(StorageError::NotBackup(*shard_id));
+        }
+        Ok(entry.queue.clone())
+    }
+
+    /// Deletes the expired keys from all shards. Returns the number of deleted keys.
+    /// A shard whose readers don't release the stale map in time is skipped until the next call.
+    /// The backups get the deletes from their primary, a stopped shard and a restarted Raft member
+    /// that hasn't rejoined its group are skipped.
+    pub fn remove_expired(&self) -> usize {
+        // Don't hold the shards lock while waiting for the writers
+        let writers: Vec<_> = sync::read(&self.shards)
+            .values()
+            .filter(|entry| {
+                entry.role != Role::Backup
+                    && entry.recovered.is_none()
+                    && entry.state() == ShardState::Active
+            })
+            .map(|entry| entry.engine.writer())
+            .collect();
+
+        writers
+            .iter()
+            .filter_map(|writer| sync::lock(writer).remove_expired().ok())
+            .sum()
+    }
+
+    /// Writes a snapshot of a durable shard and truncates its log, see Durable::save_snapshot.
+    /// A stopped shard isn't saved, its log still holds what was acknowledged.
+    pub fn snapshot(&self, shard_id: &usize) -> Result<SnapshotInfo, StorageError> {
+        let engine = sync::read(&self.shards)
+            .get(shard_id)
+            .ok_or(StorageError::ShardNotFound(*shard_id))?
+            .active()?
+            .engine
+            .clone();
+        let durable = engine
+            .durable()
+            .ok_or(StorageError::NotDurable(*shard_id))?;
+
+        durable
+            .save_snapshot()
+            .map_err(|err| StorageError::SnapshotFailed {
+                shard_id: *shard_id,
+                message: err.to_string(),
+            })
+    }
+
+    /// Writes a snapshot of every durable shard whose log has grown by at least `min_log_bytes`
+    /// since its last snapshot. Returns the number of snapshots. Stopped shards are skipped.
+    pub fn save_snapshots(&self, min_log_bytes: u64) -> usize {
+        // Don't hold the shards lock while the snapshots are written
+        let engines: Vec<_> = sync::read(&self.shards)
+            .values()
+            .filter(|entry| entry.state() == ShardState::Active)
+            .map(|entry| entry.engine.clone())
+            .collect();
+
+        let mut count = 0;
+        for engine in engines {
+            let durable = match engine.durable() {
+                Some(durable) if durable.since_snapshot() >= min_log_bytes => durable,
+                _ => continue,
+            };
+            match durable.save_snapshot() {
+                Ok(info) => {
+                    log::info!(
+                        "Saved a snapshot of shard {} at revision {}",
+                        engine.id(),
+                        info.revision
+                    );
+                    count += 1;
+                }
+                Err(err) => log::error!("Can't save a snapshot of shard {}: {}", engine.id(), err),
+            }
+        }
+        count
+    }
+
+    /// Starts a background thread that periodically saves snapshots, see save_snapshots, so the
+    /// time it takes to recover a shard stays bounded. The thread exits once the ShardMap is
+    /// dropped.
+    pub fn start_snapshots(
+        shard_map: &Arc<Self>,
+        interval: Duration,
+        min_log_bytes: u64,
+    ) -> JoinHandle<()> {
+        let shard_map = Arc::downgrade(shard_map);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match shard_map.upgrade() {
+                Some(shard_map) => {
+                    shard_map.save_snapshots(min_log_bytes);
+                }
+                None => break,
+            }
+        })
+    }
+
+    /// Starts a background thread that periodically deletes the expired keys from all shards.
+    /// The thread exits once the ShardMap is dropped.
+    pub fn start_sweeper(shard_map: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let shard_map = Arc::downgrade(shard_map);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match shard_map.upgrade() {
+                Some(shard_map) => {
+                    shard_map.remove_expired();
+                }
+                None => break,
+            }
+        })
+    }
+}
+
+impl Default for ShardMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Locks the data directory for this process and cleans up after a node that stopped in the middle
+// of ShardMap::install or remove. The directory is created if it doesn't exist.
+fn lock_dir(dir: &Path) -> io::Result<File> {
+    fs::create_dir_all(dir)?;
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    if let Err(err) = lock.try_lock() {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is used by another process: {}", dir.display(), err),
+        ));
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match parse_name(&path) {
+            Some((".replaced", shard_id)) if !dir.join(shard_name(shard_id)).exists() => {
+                // The new shard wasn't moved in yet, so the replace never happened
+                log::warn!("Restoring shard {} from {}", shard_id, path.display());
+                fs::rename(&path, dir.join(shard_name(shard_id)))?;
+            }
+            Some((".new", _)) | Some((".replaced", _)) | Some((".dropped", _)) => {
+                fs::remove_dir_all(&path)?
+            }
+            _ => {}
+        }
+    }
+    Ok(lock)
+}
+
+// Takes the shard out of the map to start it in a new role. The old writer thread drops the
+// writes that are still queued and the one it is applying is finished before this returns, so
+// none of its writes lands after the new role has started. The caller joins the thread once it
+// has released the lock of the shards.
+fn detach(shards: &mut HashMap<usize, Entry>, shard_id: usize) -> Result<Entry, StorageError> {
+    shards
+        .get(&shard_id)
+        .ok_or(StorageError::ShardNotFound(shard_id))?
+        .active()?;
+    let old = shards.remove(&shard_id).unwrap();
+    old.queue.abort();
+    // The thread holds the writer of the shard while it applies a group
+    drop(sync::lock(&*old.engine.writer()));
+    Ok(old)
+}
+
+fn shard_name(shard_id: usize) -> String {
+    format!("shard-{}", shard_id)
+}
+
+// Splits "shard-7" and ".replaced-7-0" into the kind of the directory and the shard id
+fn parse_name(path: &Path) -> Option<(&str, usize)> {
+    let (kind, rest) = path.file_name()?.to_str()?.split_once('-')?;
+    // The temporary directories end with a counter
+    let id = match kind.starts_with('.') {
+        true => rest.split('-').next()?,
+        false => rest,
+    };
+    let shard_id = id.parse().ok()?;
+    Some((kind, shard_id))
+}
+
+fn wal_error(shard_id: usize, err: io::Error) -> StorageError {
+    StorageError::Wal {
+        shard_id,
+        message: err.to_string(),
+    }
+}
+
+fn remove_dir(path: &Path) {
+    if let Err(err) = fs::remove_dir_all(path) {
+        log::warn!("Can't delete {}: {}", path.display(), err);
+    }
+}
+
+impl Entry {
+    fn start(base: Arc<dyn StorageEngine>, role: Role, raft: Option<Arc<RaftGroup>>) -> Self {
+        let replicated = match role {
+            Role::Primary {
+                backups,
+                acks,
+                timeout,
+            } => Some(Arc::new(Replicated::new(
+                base.clone(),
+                backups,
+                acks,
+                timeout,
+            ))),
+            Role::Standalone | Role::Backup => None,
+        };
+        let engine = match (&replicated, &raft) {
+            (Some(replicated), _) => replicated.clone() as Arc<dyn StorageEngine>,
+            (None, Some(group)) => Arc::new(RaftEngine::new(base.clone(), group.writer())),
+            (None, None) => base.clone(),
+        };
+
+        let (queue, writer_thread) = WriteQueue::start(engine.id(), engine.writer());
+        Self {
+            engine,
+            base,
+            role,
+            replicated,
+            raft,
+            recovered: None,
+            queue,
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    fn state(&self) -> ShardState {
+        match &self.writer_thread {
+            _ if self.queue.failed() || self.engine.writer().is_poisoned() => ShardState::Stopped,
+            Some(writer_thread) if !writer_thread.is_finished() => ShardState::Active,
+            _ => ShardState::Stopped,
+        }
+    }
+
+    // A stopped shard mustn't get a new writer thread, it would go on from a partial write
+    fn active(&self) -> Result<&Self, StorageError> {
+        match self.state() {
+            ShardState::Active => Ok(self),
+            ShardState::Stopped => Err(StorageError::WriteFailed(self.engine.id())),
+        }
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // The writes that are already queued are still applied
+        self.queue.stop();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, ShardMap, ShardState};
+    use crate::storage::engine::EngineKind;
+    use crate::storage::error::StorageError;
+    use crate::storage::types::{Operation, Record};
+    use crate::storage::wal::SyncPolicy;
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_lifecycle() {
+        let shard_map = ShardMap::new();
+        assert!(shard_map.create(2, EngineKind::Ordered));
+        assert!(shard_map.create(1, EngineKind::LeftRight));
+        assert!(!shard_map.create(1, EngineKind::Striped));
+        assert!(!shard_map.try_insert(EngineKind::Striped.create(2)));
+        assert!(shard_map.contains(&2));
+        assert_eq!(shard_map.ids(), vec![1, 2]);
+
+        let writer = shard_map.writer(&1).unwrap();
+        writer
+            .lock()
+            .unwrap()
+            .put(Bytes::from("1"), Bytes::from("a"))
+            .unwrap();
+        let stats = shard_map.stats(&1).unwrap();
+        assert_eq!(stats.engine, EngineKind::LeftRight);
+        assert_eq!(stats.state, ShardState::Active);
+        assert_eq!(stats.key_count, 1);
+        assert_eq!(stats.revision, 1);
+        assert!(stats.approximate_bytes > 0);
+
+        let all = shard_map.all_stats();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1].engine, EngineKind::Ordered);
+        assert_eq!(all[1].key_count, 0);
+
+        assert!(shard_map.remove(&1));
+        assert!(!shard_map.remove(&1));
+        assert!(shard_map.stats(&1).is_none());
+        assert_eq!(shard_map.ids(), vec![2]);
+    }
+
+    #[test]
+    fn test_copy() {
+        let shard_map = ShardMap::new();
+        shard_map.create(1, EngineKind::LeftRight);
+        let expires_at = UNIX_EPOCH + Duration::from_secs(1);
+        let ops = vec![
+            Operation::Put(Bytes::from("b"), Bytes::from("2")),
+            Operation::PutWithExpiry(Bytes::from("c"), Bytes::from("3"), expires_at),
+            Operation::Put(Bytes::from("a"), Bytes::from("1")),
+        ];
+        let writer = shard_map.writer(&1).unwrap();
+        writer.lock().unwrap().apply_at(ops, UNIX_EPOCH).unwrap();
+
+        // Sorted by key, without the expired record
+        let records = shard_map.copy(&1).unwrap();
+        let keys: Vec<_> = records.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, vec![Bytes::from("a"), Bytes::from("b")]);
+        assert_eq!(records[1].1.val, Bytes::from("2"));
+        assert!(shard_map.copy(&2).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_write() {
+        let shard_map = ShardMap::new();
+        shard_map.create(1, EngineKind::Locked);
+        let queue = shard_map.write_queue(&1).unwrap();
+        queue
+            .submit(|w| w.put_str("1", "a"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let result = queue
+            .submit(|w| {
+                w.put_str("2", "b").unwrap();
+                panic!("Write failed")
+            })
+            .await;
+        assert_eq!(result, Err(StorageError::WriteFailed(1)));
+
+        // The shard might hold part of the write, so it stops taking writes
+        let result = queue.submit(|w| w.put_str("3", "c")).await;
+        assert_eq!(result, Err(StorageError::WriteFailed(1)));
+        let stats = shard_map.stats(&1).unwrap();
+        assert_eq!(stats.state, ShardState::Stopped);
+        assert_eq!(stats.key_count, 2);
+        assert_eq!(shard_map.remove_expired(), 0);
+        assert_eq!(
+            shard_map.set_role(1, Role::Backup).err(),
+            Some(StorageError::WriteFailed(1))
+        );
+
+        // A new copy of the shard takes writes again
+        shard_map
+            .install(1, EngineKind::Locked, None, HashMap::new(), true)
+            .unwrap();
+        let queue = shard_map.write_queue(&1).unwrap();
+        queue
+            .submit(|w| w.put_str("3", "c"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(shard_map.stats(&1).unwrap().state, ShardState::Active);
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_writer() {
+        let shard_map = ShardMap::new();
+        shard_map.create(1, EngineKind::Striped);
+        let writer = shard_map.writer(&1).unwrap();
+        thread::spawn(move || {
+            let _writer = writer.lock().unwrap();
+            panic!("Poison the writer");
+        })
+        .join()
+        .unwrap_err();
+        assert!(shard_map.writer(&1).unwrap().is_poisoned());
+
+        // Stops like after a panicking write
+        assert_eq!(shard_map.stats(&1).unwrap().state, ShardState::Stopped);
+        let queue = shard_map.write_queue(&1).unwrap();
+        let result = queue.submit(|w| w.put_str("1", "a")).await;
+        assert_eq!(result, Err(StorageError::WriteFailed(1)));
+        assert_eq!(shard_map.remove_expired(), 0);
+        assert_eq!(shard_map.stats(&1).unwrap().revision, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_shard() {
+        let shard_map = ShardMap::new();
+        shard_map.create(1, EngineKind::Striped);
+        let queue = shard_map.write_queue(&1).unwrap();
+        queue
+            .submit(|w| w.put_str("1", "a"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The queued writes are applied before the shard is dropped, the later ones are rejected
+        assert!(shard_map.remove(&1));
+        let result = queue.submit(|w| w.put_str("2", "b")).await;
+        assert_eq!(result, Err(StorageError::WriterStopped(1)));
+    }
+
+    #[tokio::test]
+    async fn test_set_role_drops_queued_writes() {
+        let shard_map = ShardMap::new();
+        shard_map.create(1, EngineKind::LeftRight);
+        let queue = shard_map.write_queue(&1).unwrap();
+
+        // The write waits for the writer, which is taken once the queue is aborted
+        let writer = shard_map.writer(&1).unwrap();
+        let locked = writer.lock().unwrap();
+        let write = queue.submit(|w| w.put_str("1", "a"));
+        queue.abort();
+        drop(locked);
+        assert_eq!(write.await, Err(StorageError::WriterStopped(1)));
+        assert_eq!(shard_map.stats(&1).unwrap().revision, 0);
+
+        // The old queue of a shard in a new role doesn't take writes anymore
+        shard_map.create(2, EngineKind::LeftRight);
+        let queue = shard_map.write_queue(&2).unwrap();
+        shard_map.set_role(2, Role::Backup).unwrap();
+        let result = queue.submit(|w| w.put_str("2", "b")).await;
+        assert_eq!(result, Err(StorageError::WriterStopped(2)));
+        shard_map
+            .backup_queue(&2)
+            .unwrap()
+            .submit(|w| w.put_str("3", "c"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(shard_map.stats(&2).unwrap().revision, 1);
+    }
+
+    #[test]
+    fn test_durable_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let shard_map = ShardMap::open(dir.path()).unwrap();
+            shard_map
+                .install(
+                    1,
+                    EngineKind::Ordered,
+                    Some(SyncPolicy::Always),
+                    HashMap::new(),
+                    false,
+                )
+                .unwrap();
+            shard_map
+                .install(
+                    2,
+                    EngineKind::Locked,
+                    Some(SyncPolicy::Never),
+                    HashMap::new(),
+                    false,
+                )
+                .unwrap();
+            shard_map
+                .install(3, EngineKind::Striped, None, HashMap::new(), false)
+                .unwrap();
+            assert_eq!(
+                shard_map.install(1, EngineKind::Locked, None, HashMap::new(), false),
+                Err(StorageError::ShardExists(1))
+            );
+            shard_map
+                .writer(&1)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .put_str("1", "a")
+                .unwrap();
+            shard_map
+                .writer(&2)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .put_str("2", "b")
+                .unwrap();
+            assert_eq!(shard_map.stats(&1).unwrap().wal, Some(SyncPolicy::Always));
+            assert_eq!(shard_map.stats(&3).unwrap().wal, None);
+
+            // The replacing shard starts a new log
+            let mut data = HashMap::new();
+            data.insert(
+                Bytes::from("3"),
+                Record {
+                    val: Bytes::from("c"),
+                    create_revision: 1,
+                    mod_revision: 1,
+                    expires_at: None,
+                },
+            );
+            shard_map
+                .install(
+                    2,
+                    EngineKind::LeftRight,
+                    Some(SyncPolicy::Always),
+                    data,
+                    true,
+                )
+                .unwrap();
+            assert!(shard_map.create(4, EngineKind::Locked));
+            assert!(shard_map.remove(&1));
+        }
+
+        // Leftovers of a node that stopped while it installed a shard
+        fs::create_dir(dir.path().join(".new-5-0")).unwrap();
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        assert_eq!(shard_map.ids(), vec![2]);
+        let engine = shard_map.engine(&2).unwrap();
+        assert_eq!(engine.kind(), EngineKind::LeftRight);
+        assert_eq!(engine.get(&Bytes::from("2")), None);
+        assert_eq!(engine.get(&Bytes::from("3")), Some(Bytes::from("c")));
+        // The shard and the lock
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_interrupted_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let shard_map = ShardMap::open(dir.path()).unwrap();
+            shard_map
+                .install(
+                    1,
+                    EngineKind::Locked,
+                    Some(SyncPolicy::Always),
+                    HashMap::new(),
+                    false,
+                )
+                .unwrap();
+            shard_map
+                .writer(&1)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .put_str("1", "a")
+                .unwrap();
+        }
+
+        // The node stopped after it moved the old shard away and before it moved the new one in
+        fs::rename(dir.path().join("shard-1"), dir.path().join(".replaced-1-0")).unwrap();
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        let engine = shard_map.engine(&1).unwrap();
+        assert_eq!(engine.get(&Bytes::from("1")), Some(Bytes::from("a")));
+    }
+
+    #[test]
+    fn test_install_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = Record {
+            val: Bytes::from("a"),
+            create_revision: 1,
+            mod_revision: 1,
+            expires_at: None,
+        };
+        let data: HashMap<_, _> = vec![(Bytes::from("1"), record)].into_iter().collect();
+        let install = |replace| {
+            ShardMap::install_offline(
+                dir.path(),
+                1,
+                EngineKind::Ordered,
+                SyncPolicy::Never,
+                data.clone(),
+                replace,
+            )
+        };
+
+        // Not while a node uses the directory
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        assert!(install(false).is_err());
+        assert!(ShardMap::open(dir.path()).is_err());
+        drop(shard_map);
+
+        install(false).unwrap();
+        assert_eq!(install(false), Err(StorageError::ShardExists(1)));
+        install(true).unwrap();
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        assert_eq!(shard_map.ids(), vec![1]);
+        assert_eq!(shard_map.stats(&1).unwrap().key_count, 1);
+    }
+
+    #[test]
+    fn test_wal_disabled() {
+        let shard_map = ShardMap::new();
+        assert_eq!(
+            shard_map.install(
+                1,
+                EngineKind::Locked,
+                Some(SyncPolicy::Always),
+                HashMap::new(),
+                false
+            ),
+            Err(StorageError::WalDisabled)
+        );
+        assert!(!shard_map.contains(&1));
+        shard_map
+            .install(1, EngineKind::Locked, None, HashMap::new(), false)
+            .unwrap();
+        assert!(shard_map.contains(&1));
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        shard_map
+            .install(
+                1,
+                EngineKind::Striped,
+                Some(SyncPolicy::Never),
+                HashMap::new(),
+                false,
+            )
+            .unwrap();
+        shard_map
+            .install(
+                2,
+                EngineKind::Locked,
+                Some(SyncPolicy::Never),
+                HashMap::new(),
+                false,
+            )
+            .unwrap();
+        assert!(shard_map.create(3, EngineKind::Locked));
+        assert_eq!(shard_map.snapshot(&3), Err(StorageError::NotDurable(3)));
+        assert_eq!(shard_map.snapshot(&4), Err(StorageError::ShardNotFound(4)));
+
+        shard_map
+            .writer(&1)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put_str("1", "a")
+            .unwrap();
+        assert_eq!(shard_map.snapshot(&1).unwrap().key_count, 1);
+        shard_map
+            .writer(&2)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .put_str("2", "b")
+            .unwrap();
+        // Only the log of shard 2 has grown since its last snapshot
+        let engine = shard_map.engine(&1).unwrap();
+        let snapshotted = engine.durable().unwrap().since_snapshot();
+        assert_eq!(shard_map.save_snapshots(1 << 10), 0);
+        assert_eq!(shard_map.save_snapshots(snapshotted + 1), 1);
+        drop(shard_map);
+
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        assert_eq!(shard_map.ids(), vec![1, 2]);
+        assert_eq!(shard_map.stats(&1).unwrap().revision, 1);
+        assert_eq!(shard_map.stats(&2).unwrap().key_count, 1);
+    }
+
+    #[test]
+    fn test_roles() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let shard_map = ShardMap::open(dir.path()).unwrap();
+            shard_map
+                .install(
+                    1,
+                    EngineKind::LeftRight,
+                    Some(SyncPolicy::Always),
+                    HashMap::new(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(
+                shard_map.set_role(2, Role::Backup).err(),
+                Some(StorageError::ShardNotFound(2))
+            );
+            assert_eq!(
+                shard_map.restore_backup(1, 5, HashMap::new()),
+                Err(StorageError::NotBackup(1))
+            );
+            assert!(shard_map.set_role(1, Role::Backup).unwrap().is_none());
+            assert_eq!(shard_map.role(&1), Some(Role::Backup));
+
+            // The last writes of the primary deleted their keys, the revision is kept anyway
+            let mut data = HashMap::new();
+            data.insert(
+                Bytes::from("1"),
+                Record {
+                    val: Bytes::from("a"),
+                    create_revision: 2,
+                    mod_revision: 3,
+                    expires_at: None,
+                },
+            );
+            shard_map.restore_backup(1, 5, data).unwrap();
+            let stats = shard_map.stats(&1).unwrap();
+            assert_eq!(stats.role, Role::Backup);
+            assert_eq!(stats.engine, EngineKind::LeftRight);
+            assert_eq!(stats.wal, Some(SyncPolicy::Always));
+            assert_eq!(stats.revision, 5);
+        }
+
+        // The roles aren't persisted, the records and the revision are
+        let shard_map = ShardMap::open(dir.path()).unwrap();
+        let stats = shard_map.stats(&1).unwrap();
+        assert_eq!(stats.role, Role::Standalone);
+        assert_eq!(stats.key_count, 1);
+        assert_eq!(stats.revision, 5);
     }
 }
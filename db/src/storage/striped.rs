@@ -0,0 +1,284 @@
+use super::engine::{
+    self, Batch, EngineKind, EngineSnapshot, EngineWriter, Expirations, Records, StorageEngine,
+    WriteError,
+};
+use super::sync;
+use super::types::{Key, Operation, Record, Revision, Val};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::SystemTime;
+
+type Stripe = HashMap<Key, Record>;
+
+/// Spreads the records over a number of HashMaps with a RwLock each, the same way DashMap does.
+/// A read or a write only locks the stripe of its key, so the readers of one stripe are not
+/// blocked by a write to another one and don't all fight over the same lock.
+///
+/// A write that touches more than one stripe locks all of them before it changes anything, so it
+/// is still visible all at once. Snapshots and scans lock every stripe and scans have to sort the
+/// keys of the whole shard, which makes this a poor fit for scan heavy shards.
+pub struct Striped {
+    id: usize,
+    stripes: Arc<Stripes>,
+    writer: Arc<Mutex<StripedWriter>>,
+}
+
+struct Stripes {
+    hasher: RandomState,
+    // The number of stripes is a power of two
+    maps: Box<[RwLock<Stripe>]>,
+}
+
+pub struct StripedWriter {
+    stripes: Arc<Stripes>,
+    // Bumped by every mutation
+    revision: Revision,
+    expirations: Expirations,
+}
+
+// Holds the read lock of every stripe
+struct StripedSnapshot<'a> {
+    stripes: &'a Stripes,
+    maps: Vec<RwLockReadGuard<'a, Stripe>>,
+    now: SystemTime,
+}
+
+impl Striped {
+    pub fn new(id: usize) -> Self {
+        Self::with_stripes(id, Self::default_stripes())
+    }
+
+    pub fn with_data(id: usize, data: HashMap<Key, Record>) -> Self {
+        let striped = Self::new(id);
+        {
+            let mut writer = sync::lock(&striped.writer);
+            writer.revision = engine::last_revision(data.iter());
+            writer.expirations = Expirations::new(data.iter());
+        }
+        for (key, record) in data {
+            sync::write(striped.stripes.stripe(&key)).insert(key, record);
+        }
+        striped
+    }
+
+    /// Rounded up to a power of two.
+    pub fn with_stripes(id: usize, stripes: usize) -> Self {
+        let stripes = Arc::new(Stripes {
+            hasher: RandomState::new(),
+            maps: (0..stripes.max(1).next_power_of_two())
+                .map(|_| RwLock::default())
+                .collect(),
+        });
+        let writer = StripedWriter {
+            stripes: stripes.clone(),
+            revision: 0,
+            expirations: Expirations::default(),
+        };
+
+        Self {
+            id,
+            stripes,
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    /// Four stripes per core, same as DashMap.
+    fn default_stripes() -> usize {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        cores * 4
+    }
+}
+
+impl Stripes {
+    #[inline]
+    fn index(&self, key: &Key) -> usize {
+        self.hasher.hash_one(key) as usize & (self.maps.len() - 1)
+    }
+
+    #[inline]
+    fn stripe(&self, key: &Key) -> &RwLock<Stripe> {
+        &self.maps[self.index(key)]
+    }
+}
+
+impl StorageEngine for Striped {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn kind(&self) -> EngineKind {
+        EngineKind::Striped
+    }
+
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        // Always in the same order as the writer, so the two can't deadlock
+        let maps = self.stripes.maps.iter().map(sync::read).collect();
+        Box::new(StripedSnapshot {
+            stripes: &self.stripes,
+            maps,
+            now: SystemTime::now(),
+        })
+    }
+
+    fn writer(&self) -> Arc<Mutex<dyn EngineWriter>> {
+        self.writer.clone()
+    }
+
+    fn get_record(&self, key: &Key) -> Option<Record> {
+        engine::get_live(&*sync::read(self.stripes.stripe(key)), key)
+    }
+
+    fn len(&self) -> usize {
+        // The count of each stripe is read at a different time, but a len is stale anyway
+        self.stripes
+            .maps
+            .iter()
+            .map(|map| sync::read(map).len())
+            .sum()
+    }
+}
+
+impl<'a> Records for StripedSnapshot<'a> {
+    #[inline]
+    fn get(&self, key: &Key) -> Option<&Record> {
+        HashMap::get(&self.maps[self.stripes.index(key)], key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Record)> + '_> {
+        Box::new(self.maps.iter().flat_map(|map| map.iter()))
+    }
+
+    fn range<'b>(
+        &'b self,
+        start: Bound<&'b Key>,
+        end: Bound<&'b Key>,
+    ) -> Box<dyn Iterator<Item = (&'b Key, &'b Record)> + 'b> {
+        engine::sorted(self.maps.iter().flat_map(|map| map.iter()), start, end)
+    }
+
+    fn len(&self) -> usize {
+        self.maps.iter().map(|map| map.len()).sum()
+    }
+}
+
+impl<'a> EngineSnapshot for StripedSnapshot<'a> {
+    fn records(&self) -> &dyn Records {
+        self
+    }
+
+    fn now(&self) -> SystemTime {
+        self.now
+    }
+}
+
+impl EngineWriter for StripedWriter {
+    fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    fn skip_to(&mut self, revision: Revision) {
+        self.revision = self.revision.max(revision);
+    }
+
+    fn current(&mut self, key: &Key, now: SystemTime) -> Result<Option<Record>, WriteError> {
+        let map = sync::read(self.stripes.stripe(key));
+        Ok(engine::live(&*map, key, now).cloned())
+    }
+
+    fn apply_at(
+        &mut self,
+        ops: Vec<Operation>,
+        now: SystemTime,
+    ) -> Result<Vec<Option<Val>>, WriteError> {
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.revision += 1;
+        let indices: Vec<_> = ops.iter().map(|op| self.stripes.index(op.key())).collect();
+        // Every stripe the write touches is locked before anything is changed, so the readers see
+        // all of it or none. The locks are taken in the same order as by the snapshots.
+        let mut locked = indices.clone();
+        locked.sort_unstable();
+        locked.dedup();
+        let mut maps: Vec<_> = self.stripes.maps.iter().map(|_| None).collect();
+        for index in locked {
+            maps[index] = Some(sync::write(&self.stripes.maps[index]));
+        }
+
+        let mut result = Vec::with_capacity(ops.len());
+        for (op, index) in ops.into_iter().zip(indices) {
+            self.expirations.track(&op);
+            let map = maps[index].as_mut().unwrap();
+            let previous = engine::replay(&mut **map, op, self.revision, now);
+            result.push(previous.map(|record| record.val));
+        }
+        Ok(result)
+    }
+
+    fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    fn expired(&mut self, now: SystemTime) -> Result<Vec<Key>, WriteError> {
+        let stripes = &self.stripes;
+        Ok(self.expirations.expired(now, |key| {
+            HashMap::get(&sync::read(stripes.stripe(key)), key)?.expires_at
+        }))
+    }
+
+    // Every write is visible as soon as it is applied
+    fn group(&mut self, f: &mut dyn FnMut(&mut dyn EngineWriter)) -> Result<(), WriteError> {
+        f(self);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Striped;
+    use crate::storage::engine::StorageEngine;
+    use bytes::Bytes;
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_batches_are_atomic() {
+        let engine = Arc::new(Striped::with_stripes(42, 16));
+        let keys: Vec<_> = (0..64).map(|i| Bytes::from(format!("{:02}", i))).collect();
+
+        // Every batch writes the same value to all keys, which are spread over all stripes
+        let writer = {
+            let engine = engine.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                let writer = engine.writer();
+                for i in 0..100 {
+                    let mut writer = writer.lock().unwrap();
+                    let mut batch = writer.batch();
+                    for key in &keys {
+                        batch.put(key.clone(), Bytes::from(i.to_string()));
+                    }
+                    batch.commit().unwrap();
+                }
+            })
+        };
+
+        loop {
+            let entries = engine.scan(Bound::Unbounded, Bound::Unbounded, 100).entries;
+            if let Some((_, first)) = entries.first() {
+                assert_eq!(entries.len(), keys.len());
+                assert!(entries.iter().all(|(_, record)| record.val == first.val));
+                if first.val == "99" {
+                    break;
+                }
+            }
+        }
+        writer.join().unwrap();
+    }
+}
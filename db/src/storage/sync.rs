@@ -0,0 +1,31 @@
+//! The atomics shared by the readers and the writer of a Shard. With `--cfg r_db_loom` they are
+//! replaced by loom's so the model checker can explore how the two interleave.
+//!
+//! Also the helpers that take the std locks of the storage. They recover from poisoning, which is
+//! only meant for the read-side and bookkeeping locks, like the map of shards or the status of a
+//! Raft member, whose data a panic doesn't leave half changed. A write that panics can leave part
+//! of it in the engine, so the writer path doesn't go on after it: the WriteQueue stops the shard
+//! once its writer is poisoned and every later write fails, see WriteQueue.
+
+#[cfg(r_db_loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicUsize};
+
+#[cfg(not(r_db_loom))]
+pub(crate) use std::sync::atomic::{fence, AtomicUsize};
+
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[inline]
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[inline]
+pub(crate) fn read<T: ?Sized>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[inline]
+pub(crate) fn write<T: ?Sized>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
@@ -1,4 +1,83 @@
-// TODO: Rust strings don't have SSO.
-//  Depending on the average string length SSO might perform better due to less heap allocations.
-pub type Key = String;
-pub type Val = String;
+use bytes::Bytes;
+use std::time::{Duration, SystemTime};
+
+// Keys and values are arbitrary bytes. Bytes stores short buffers inline so small keys
+// don't need a heap allocation.
+pub type Key = Bytes;
+pub type Val = Bytes;
+pub type Revision = u64;
+
+/// A value together with its revision metadata. Every mutation of a shard bumps the shard's
+/// revision counter and the records it touches are stamped with the new revision.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub val: Val,
+    /// The revision at which the key was created
+    pub create_revision: Revision,
+    /// The revision of the last write to the key
+    pub mod_revision: Revision,
+    /// Expired records are invisible to the readers and are eventually deleted by the sweeper
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Record {
+    #[inline]
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+
+    /// The time left before the record expires or None if it never expires.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// A single mutation of a shard. Writes are applied to the stale map, the pointers are swapped
+/// and then the same operations are replayed to the other map.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Put(Key, Val),
+    PutWithExpiry(Key, Val, SystemTime),
+    /// Changes the expiration of an existing key. None makes the key persistent.
+    Expire(Key, Option<SystemTime>),
+    Delete(Key),
+}
+
+impl Operation {
+    pub fn key(&self) -> &Key {
+        match self {
+            Operation::Put(key, _)
+            | Operation::PutWithExpiry(key, _, _)
+            | Operation::Expire(key, _)
+            | Operation::Delete(key) => key,
+        }
+    }
+}
+
+/// A condition that has to hold for a transaction to be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Precondition {
+    Exists(Key),
+    NotExists(Key),
+    Equals(Key, Val),
+    /// The mod revision of the key must match. 0 means that the key must not exist.
+    Revision(Key, Revision),
+}
+
+impl Precondition {
+    pub fn key(&self) -> &Key {
+        match self {
+            Precondition::Exists(key)
+            | Precondition::NotExists(key)
+            | Precondition::Equals(key, _)
+            | Precondition::Revision(key, _) => key,
+        }
+    }
+}
@@ -0,0 +1,1008 @@
+use super::engine::EngineKind;
+use super::types::{Key, Operation, Record, Revision, Val};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"r_db wal";
+const VERSION: u8 = 1;
+// A longer record is a torn or corrupted length, so none is written
+const MAX_RECORD_LEN: usize = 1 << 30;
+// A LOAD record is cut once it is this long, so a large shard or snapshot can't hit MAX_RECORD_LEN
+const LOAD_CHUNK_LEN: usize = 1 << 20;
+// The length and the checksum in front of every record
+const RECORD_HEADER_LEN: usize = 8;
+
+/// When the log is flushed to the disk with fsync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Before every write is applied. An acknowledged write survives a crash of the machine.
+    Always,
+    /// From a background thread. A crash of the machine loses up to the last interval of writes.
+    Interval(Duration),
+    /// Left to the OS. Survives a crash of the process but not a crash of the machine.
+    Never,
+}
+
+/// Written at the start of every segment, so a node can rebuild the shard from its log alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub shard_id: usize,
+    pub kind: EngineKind,
+    pub sync: SyncPolicy,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    /// The records the shard was created with. Only at the start of the log, the LOAD records
+    /// they are split into are read back as a single entry.
+    Load(HashMap<Key, Record>),
+    /// The records of the shard at `revision`, from the snapshot the log is replayed after
+    Snapshot {
+        revision: Revision,
+        data: HashMap<Key, Record>,
+    },
+    /// A single mutation, see EngineWriter::apply_at
+    Write {
+        revision: Revision,
+        now: SystemTime,
+        ops: Vec<Operation>,
+    },
+}
+
+/// The write-ahead log of a durable shard, a directory of segment files and snapshots. Every
+/// record is framed with its length and a CRC32 of its contents.
+///
+/// The snapshot `{index}.snap` holds the records of the shard at the end of the segments before
+/// `index`, so those segments are deleted once it is written. Recovery loads the latest complete
+/// snapshot and replays only the segments after it.
+///
+/// A crash can leave a partly written record at the end of the log. Recovery drops everything from
+/// the first record that is incomplete or doesn't match its checksum.
+pub struct Wal {
+    dir: PathBuf,
+    header: Header,
+    // The segment that is appended to
+    index: u64,
+    file: File,
+    // The end of the last complete record
+    len: u64,
+    // The size of the segments that are replayed after the latest snapshot
+    since_snapshot: u64,
+    // Set when a failed append couldn't be rolled back. Nothing can be appended after it.
+    broken: bool,
+    syncer: Option<Syncer>,
+}
+
+// Syncs the log of a shard with SyncPolicy::Interval
+struct Syncer {
+    dirty: Arc<AtomicBool>,
+    // Dropped to stop the thread
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Wal {
+    /// Starts a new log in an empty directory, with the records the shard is created with.
+    /// Everything is synced before it returns.
+    pub fn create(dir: &Path, header: Header, data: &HashMap<Key, Record>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Self::start_segment(dir.to_path_buf(), header, 1, data)
+    }
+
+    /// Reads the log from the latest snapshot on and opens it for appending. The first entry is
+    /// the snapshot, if there is one. A torn record at the end is truncated.
+    ///
+    /// Fails if the latest snapshot is damaged. It is only renamed into place once it is complete,
+    /// and the segments it replaces might be gone, so it is left for an operator to look at.
+    pub fn open(dir: &Path) -> io::Result<(Header, Vec<Entry>, Self)> {
+        remove_tmp(dir)?;
+
+        let mut header = None;
+        let mut entries = Vec::new();
+        let mut first = 1;
+        if let Some((index, path)) = files(dir, "snap")?.pop() {
+            let (snapshot_header, revision, data) = read_snapshot(&path)
+                .map_err(|err| invalid(format!("Damaged snapshot {}: {}", path.display(), err)))?;
+            header = Some(snapshot_header);
+            entries.push(Entry::Snapshot { revision, data });
+            first = index;
+        }
+
+        let mut segments = files(dir, "wal")?;
+        segments.retain(|(index, _)| *index >= first);
+        let mut last = None;
+        let mut since_snapshot = 0;
+        for (i, (index, path)) in segments.iter().enumerate() {
+            if *index != first + i as u64 {
+                return Err(invalid(format!(
+                    "Missing segment before {}",
+                    path.display()
+                )));
+            }
+            let is_last = i + 1 == segments.len();
+            let data = fs::read(path)?;
+            let (records, end) = records(&data);
+            if end < data.len() {
+                if !is_last {
+                    return Err(invalid(format!("Corrupted record in {}", path.display())));
+                }
+                log::warn!(
+                    "Truncating {} torn bytes at the end of {}",
+                    data.len() - end,
+                    path.display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(end as u64)?;
+            }
+
+            let mut records = records.into_iter();
+            let segment_header = match records.next() {
+                Some(record) => decode_header(record)?,
+                // The node stopped before the new segment was started, see Wal::roll
+                None if is_last && i > 0 => {
+                    log::warn!("Removing the empty segment {}", path.display());
+                    fs::remove_file(path)?;
+                    break;
+                }
+                None => return Err(invalid(format!("No header in {}", path.display()))),
+            };
+            if *header.get_or_insert(segment_header) != segment_header {
+                return Err(invalid(format!("Wrong header in {}", path.display())));
+            }
+            for record in records {
+                match (decode_entry(record)?, entries.last_mut()) {
+                    (Entry::Load(chunk), Some(Entry::Load(data))) => data.extend(chunk),
+                    (entry, _) => entries.push(entry),
+                }
+            }
+            since_snapshot += end as u64;
+            last = Some((*index, path));
+        }
+
+        let (index, last) = match last {
+            Some(last) => last,
+            None => return Err(invalid(format!("No log in {}", dir.display()))),
+        };
+        let header = header.unwrap();
+        let file = OpenOptions::new().append(true).open(last)?;
+        let len = file.metadata()?.len();
+        let mut wal = Self::start(dir.to_path_buf(), header, index, file, len)?;
+        wal.since_snapshot = since_snapshot;
+        // The node might have stopped right after the snapshot was written
+        truncate(dir, first);
+        Ok((header, entries, wal))
+    }
+
+    // Starts the segment `index` with the header and the records, if any, and syncs it
+    fn start_segment(
+        dir: PathBuf,
+        header: Header,
+        index: u64,
+        data: &HashMap<Key, Record>,
+    ) -> io::Result<Self> {
+        let path = segment(&dir, index);
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut wal = Self::start(dir, header, index, file, 0)?;
+
+        let result = (|| {
+            wal.write(&encode_header(&header))?;
+            for payload in encode_load(data.iter()) {
+                wal.write(&payload)?;
+            }
+            wal.file.sync_all()?;
+            File::open(&wal.dir)?.sync_all()
+        })();
+        if let Err(err) = result {
+            // Or the index would be taken for good
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+        Ok(wal)
+    }
+
+    fn start(dir: PathBuf, header: Header, index: u64, file: File, len: u64) -> io::Result<Self> {
+        let syncer = match header.sync {
+            SyncPolicy::Interval(interval) => Some(Syncer::start(file.try_clone()?, interval)),
+            _ => None,
+        };
+
+        Ok(Self {
+            dir,
+            header,
+            index,
+            file,
+            len,
+            since_snapshot: len,
+            broken: false,
+            syncer,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Points the log to its directory after the directory was renamed. The open segment is
+    /// still appended to.
+    pub fn set_dir(&mut self, dir: PathBuf) {
+        self.dir = dir;
+    }
+
+    /// The size of the log that would be replayed after a restart, not counting the snapshot.
+    pub fn since_snapshot(&self) -> u64 {
+        self.since_snapshot
+    }
+
+    /// Continues the log in a new segment and returns its index. A snapshot of the records as
+    /// they are now replaces all segments before it, see write_snapshot.
+    pub fn roll(&mut self) -> io::Result<u64> {
+        let index = self.index + 1;
+        let next = Self::start_segment(self.dir.clone(), self.header, index, &HashMap::new())?;
+        // Drops the syncer of the old segment, which syncs its last writes
+        *self = next;
+        Ok(index)
+    }
+
+    /// Appends a mutation and syncs it if the policy says so. Nothing is appended on failure.
+    pub fn append(
+        &mut self,
+        revision: Revision,
+        now: SystemTime,
+        ops: &[Operation],
+    ) -> io::Result<()> {
+        self.write(&encode_write(revision, now, ops))?;
+        match &self.syncer {
+            Some(syncer) => syncer.dirty.store(true, Ordering::Release),
+            None if self.header.sync == SyncPolicy::Always => {
+                if let Err(err) = self.file.sync_data() {
+                    // The kernel might have dropped the pages, so what's on the disk is unknown
+                    self.broken = true;
+                    return Err(err);
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    // Appends a framed record
+    fn write(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other(
+                "The log is broken by an earlier failed write",
+            ));
+        }
+
+        let record = frame(payload)?;
+        if let Err(err) = self.file.write_all(&record) {
+            // Roll back a partial write so the next record doesn't follow a torn one
+            if self.file.set_len(self.len).is_err() {
+                self.broken = true;
+            }
+            return Err(err);
+        }
+        self.len += record.len() as u64;
+        self.since_snapshot += record.len() as u64;
+        Ok(())
+    }
+}
+
+impl Syncer {
+    fn start(file: File, interval: Duration) -> Self {
+        let dirty = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let dirty = dirty.clone();
+            thread::spawn(move || loop {
+                // Stopped or the sender was dropped
+                let stop = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                if dirty.swap(false, Ordering::AcqRel) {
+                    if let Err(err) = file.sync_data() {
+                        log::error!("Can't sync the log: {}", err);
+                    }
+                }
+                if stop {
+                    break;
+                }
+            })
+        };
+
+        Self {
+            dirty,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // The thread syncs the last writes before it exits
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn segment(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.wal", index))
+}
+
+fn snapshot(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.snap", index))
+}
+
+// The segments or the snapshots in the directory with their indexes, oldest first
+fn files(dir: &Path, extension: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|found| found != extension) {
+            continue;
+        }
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok());
+        if let Some(index) = index {
+            files.push((index, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Snapshots that were being written when the node stopped
+fn remove_tmp(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a snapshot of the shard at `revision` that replaces the segments of the log before
+/// `index`. It is only read once it is complete and synced. Returns its size in bytes.
+pub fn write_snapshot(
+    dir: &Path,
+    header: Header,
+    index: u64,
+    revision: Revision,
+    data: &HashMap<Key, Record>,
+) -> io::Result<u64> {
+    let tmp = dir.join(format!("{:020}.snap.tmp", index));
+    let result = (|| {
+        let mut out = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(&tmp)?);
+        let payloads = iter::once(encode_header(&header))
+            .chain(iter::once(encode_snapshot(revision, data.len())))
+            .chain(encode_load(data.iter()));
+        let mut size = 0;
+        for payload in payloads {
+            let record = frame(&payload)?;
+            out.write_all(&record)?;
+            size += record.len() as u64;
+        }
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        Ok(size)
+    })();
+
+    let size = match result {
+        Ok(size) => size,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+    };
+    fs::rename(&tmp, snapshot(dir, index))?;
+    File::open(dir)?.sync_all()?;
+    Ok(size)
+}
+
+/// Reads a snapshot and checks that it is complete. Only reads the file, so it is safe to call on
+/// the data directory of a running node.
+pub fn read_snapshot(path: &Path) -> io::Result<(Header, Revision, HashMap<Key, Record>)> {
+    let data = fs::read(path)?;
+    let (records, end) = records(&data);
+    if end < data.len() {
+        return Err(invalid("Corrupted record".to_string()));
+    }
+
+    let mut records = records.into_iter();
+    let mut next = || {
+        records
+            .next()
+            .ok_or_else(|| invalid("Incomplete".to_string()))
+    };
+    let header = decode_header(next()?)?;
+    let (revision, count) = decode_snapshot(next()?)?;
+    let mut data = HashMap::with_capacity(count);
+    for record in records {
+        match decode_entry(record)? {
+            Entry::Load(chunk) => data.extend(chunk),
+            _ => return Err(invalid("Unexpected entry".to_string())),
+        }
+    }
+    if data.len() != count {
+        return Err(invalid("Incomplete".to_string()));
+    }
+    Ok((header, revision, data))
+}
+
+/// Deletes the segments and the snapshots before `index`, once the snapshot `index` replaces
+/// them. A file that can't be deleted is deleted by the next snapshot.
+pub fn truncate(dir: &Path, index: u64) {
+    let files = files(dir, "wal").and_then(|segments| {
+        let mut snapshots = files(dir, "snap")?;
+        snapshots.extend(segments);
+        Ok(snapshots)
+    });
+    let files = match files {
+        Ok(files) => files,
+        Err(err) => {
+            log::warn!("Can't truncate the log in {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    for (_, path) in files.iter().filter(|(found, _)| *found < index) {
+        if let Err(err) = fs::remove_file(path) {
+            log::warn!("Can't delete {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Prepends the length and the checksum of the payload. Fails if the payload is too long to be
+// read back.
+pub(crate) fn frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "A record of {} bytes is longer than {} bytes",
+                payload.len(),
+                MAX_RECORD_LEN
+            ),
+        ));
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+// Splits the log into the payloads of its records. Returns the end of the last complete record.
+pub(crate) fn records(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while data.len() - pos >= RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+        let start = pos + RECORD_HEADER_LEN;
+        if len > MAX_RECORD_LEN || len > data.len() - start {
+            break;
+        }
+        let payload = &data[start..start + len];
+        if crc32(payload) != crc {
+            break;
+        }
+        records.push(payload);
+        pos = start + len;
+    }
+    (records, pos)
+}
+
+const HEADER: u8 = 0;
+const LOAD: u8 = 1;
+const WRITE: u8 = 2;
+const SNAPSHOT: u8 = 3;
+
+const PUT: u8 = 0;
+const PUT_WITH_EXPIRY: u8 = 1;
+const EXPIRE: u8 = 2;
+const DELETE: u8 = 3;
+
+fn encode_header(header: &Header) -> Vec<u8> {
+    let mut out = Encoder(Vec::new());
+    out.u8(HEADER);
+    out.0.extend_from_slice(MAGIC);
+    out.u8(VERSION);
+    out.u64(header.shard_id as u64);
+    out.u8(match header.kind {
+        EngineKind::LeftRight => 0,
+        EngineKind::Locked => 1,
+        EngineKind::Ordered => 2,
+        EngineKind::Striped => 3,
+    });
+    match header.sync {
+        SyncPolicy::Always => out.u8(0),
+        SyncPolicy::Interval(interval) => {
+            out.u8(1);
+            out.u64(interval.as_millis() as u64);
+        }
+        SyncPolicy::Never => out.u8(2),
+    }
+    out.0
+}
+
+fn decode_header(payload: &[u8]) -> io::Result<Header> {
+    let mut input = Decoder(payload);
+    if input.u8()? != HEADER || input.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("Not a log of r_db".to_string()));
+    }
+    let version = input.u8()?;
+    if version != VERSION {
+        return Err(invalid(format!("Unknown log version: {}", version)));
+    }
+
+    let shard_id = input.u64()? as usize;
+    let kind = match input.u8()? {
+        0 => EngineKind::LeftRight,
+        1 => EngineKind::Locked,
+        2 => EngineKind::Ordered,
+        3 => EngineKind::Striped,
+        kind => return Err(invalid(format!("Unknown engine: {}", kind))),
+    };
+    let sync = match input.u8()? {
+        0 => SyncPolicy::Always,
+        1 => SyncPolicy::Interval(Duration::from_millis(input.u64()?)),
+        2 => SyncPolicy::Never,
+        sync => return Err(invalid(format!("Unknown sync policy: {}", sync))),
+    };
+    Ok(Header {
+        shard_id,
+        kind,
+        sync,
+    })
+}
+
+fn encode_snapshot(revision: Revision, count: usize) -> Vec<u8> {
+    let mut out = Encoder(Vec::new());
+    out.u8(SNAPSHOT);
+    out.u64(revision);
+    out.u64(count as u64);
+    out.0
+}
+
+fn decode_snapshot(payload: &[u8]) -> io::Result<(Revision, usize)> {
+    let mut input = Decoder(payload);
+    if input.u8()? != SNAPSHOT {
+        return Err(invalid("Not a snapshot".to_string()));
+    }
+    Ok((input.u64()?, input.u64()? as usize))
+}
+
+// Splits the records into LOAD payloads of about LOAD_CHUNK_LEN bytes. A payload only grows
+// past it by its last record.
+fn encode_load<'a>(data: impl Iterator<Item = (&'a Key, &'a Record)>) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    let mut out = Encoder(Vec::new());
+    let mut count = 0u64;
+    for (key, record) in data {
+        if count == 0 {
+            out.u8(LOAD);
+            // Filled in once the payload is complete
+            out.u64(0);
+        }
+        out.bytes(key);
+        out.bytes(&record.val);
+        out.u64(record.create_revision);
+        out.u64(record.mod_revision);
+        out.expiry(record.expires_at);
+        count += 1;
+        if out.0.len() >= LOAD_CHUNK_LEN {
+            payloads.push(out.load(count));
+            out = Encoder(Vec::new());
+            count = 0;
+        }
+    }
+    if count > 0 {
+        payloads.push(out.load(count));
+    }
+    payloads
+}
+
+fn encode_write(revision: Revision, now: SystemTime, ops: &[Operation]) -> Vec<u8> {
+    let mut out = Encoder(Vec::new());
+    out.u8(WRITE);
+    out.u64(revision);
+    out.time(now);
+    out.ops(ops);
+    out.0
+}
+
+// The checksum of the record matched, so a payload that can't be decoded was written that way
+fn decode_entry(payload: &[u8]) -> io::Result<Entry> {
+    let mut input = Decoder(payload);
+    match input.u8()? {
+        LOAD => {
+            let count = input.u64()?;
+            let mut data = HashMap::new();
+            for _ in 0..count {
+                let key = input.bytes()?;
+                let record = Record {
+                    val: input.bytes()?,
+                    create_revision: input.u64()?,
+                    mod_revision: input.u64()?,
+                    expires_at: input.expiry()?,
+                };
+                data.insert(key, record);
+            }
+            Ok(Entry::Load(data))
+        }
+        WRITE => {
+            let revision = input.u64()?;
+            let now = input.time()?;
+            let ops = input.ops()?;
+            Ok(Entry::Write { revision, now, ops })
+        }
+        entry => Err(invalid(format!("Unknown log entry: {}", entry))),
+    }
+}
+
+/// Encodes the payload of a record, also used for the log of a Raft member, see raft::state.
+pub(crate) struct Encoder(pub(crate) Vec<u8>);
+
+impl Encoder {
+    // Completes a LOAD payload with the number of its records
+    fn load(mut self, count: u64) -> Vec<u8> {
+        self.0[1..9].copy_from_slice(&count.to_le_bytes());
+        self.0
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    pub(crate) fn time(&mut self, time: SystemTime) {
+        // Nothing is written before 1970
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u64(since_epoch.as_secs());
+        self.u32(since_epoch.subsec_nanos());
+    }
+
+    fn expiry(&mut self, expires_at: Option<SystemTime>) {
+        match expires_at {
+            Some(expires_at) => {
+                self.u8(1);
+                self.time(expires_at);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub(crate) fn ops(&mut self, ops: &[Operation]) {
+        self.u64(ops.len() as u64);
+        for op in ops {
+            match op {
+                Operation::Put(key, val) => {
+                    self.u8(PUT);
+                    self.bytes(key);
+                    self.bytes(val);
+                }
+                Operation::PutWithExpiry(key, val, expires_at) => {
+                    self.u8(PUT_WITH_EXPIRY);
+                    self.bytes(key);
+                    self.bytes(val);
+                    self.time(*expires_at);
+                }
+                Operation::Expire(key, expires_at) => {
+                    self.u8(EXPIRE);
+                    self.bytes(key);
+                    self.expiry(*expires_at);
+                }
+                Operation::Delete(key) => {
+                    self.u8(DELETE);
+                    self.bytes(key);
+                }
+            }
+        }
+    }
+}
+
+/// Decodes the payload of a record, see Encoder.
+pub(crate) struct Decoder<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("Truncated log entry".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<Val> {
+        let len = self.u32()? as usize;
+        Ok(Val::from(self.take(len)?))
+    }
+
+    pub(crate) fn time(&mut self) -> io::Result<SystemTime> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+    }
+
+    fn expiry(&mut self) -> io::Result<Option<SystemTime>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.time()?)),
+        }
+    }
+
+    pub(crate) fn ops(&mut self) -> io::Result<Vec<Operation>> {
+        let count = self.u64()?;
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let op = match self.u8()? {
+                PUT => Operation::Put(self.bytes()?, self.bytes()?),
+                PUT_WITH_EXPIRY => {
+                    Operation::PutWithExpiry(self.bytes()?, self.bytes()?, self.time()?)
+                }
+                EXPIRE => Operation::Expire(self.bytes()?, self.expiry()?),
+                DELETE => Operation::Delete(self.bytes()?),
+                op => return Err(invalid(format!("Unknown operation: {}", op))),
+            };
+            ops.push(op);
+        }
+        Ok(ops)
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+// CRC-32 (IEEE), the one used by zlib and Ethernet
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        crc32, files, frame, records, truncate, write_snapshot, Entry, Header, SyncPolicy, Wal,
+        LOAD_CHUNK_LEN, MAX_RECORD_LEN,
+    };
+    use crate::storage::engine::EngineKind;
+    use crate::storage::types::{Operation, Record};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = Header {
+            shard_id: 7,
+            kind: EngineKind::Striped,
+            sync: SyncPolicy::Interval(Duration::from_millis(20)),
+        };
+        let mut data = HashMap::new();
+        data.insert(
+            Bytes::from("a"),
+            Record {
+                val: Bytes::from("1"),
+                create_revision: 1,
+                mod_revision: 2,
+                expires_at: Some(UNIX_EPOCH + Duration::new(10, 5)),
+            },
+        );
+        let now = UNIX_EPOCH + Duration::new(100, 42);
+        let ops = vec![
+            Operation::Put(Bytes::from("b"), Bytes::from("2")),
+            Operation::PutWithExpiry(Bytes::from("c"), Bytes::from(""), now),
+            Operation::Expire(Bytes::from("a"), None),
+            Operation::Delete(Bytes::from("b")),
+        ];
+
+        let mut wal = Wal::create(dir.path(), header, &data).unwrap();
+        wal.append(3, now, &ops).unwrap();
+        drop(wal);
+
+        let (read, entries, _) = Wal::open(dir.path()).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Load(data),
+                Entry::Write {
+                    revision: 3,
+                    now,
+                    ops
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_large_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = Header {
+            shard_id: 1,
+            kind: EngineKind::LeftRight,
+            sync: SyncPolicy::Never,
+        };
+        // Longer than a chunk, so it takes more than one LOAD record
+        let data: HashMap<_, _> = (0..40)
+            .map(|i| {
+                let record = Record {
+                    val: Bytes::from(vec![i as u8; 64 * 1024]),
+                    create_revision: 1,
+                    mod_revision: 1,
+                    expires_at: None,
+                };
+                (Bytes::from(i.to_string()), record)
+            })
+            .collect();
+        let mut wal = Wal::create(dir.path(), header, &data).unwrap();
+        let now = SystemTime::now();
+        let ops = vec![Operation::Delete(Bytes::from("0"))];
+        wal.append(2, now, &ops).unwrap();
+        drop(wal);
+
+        let (_, segment) = files(dir.path(), "wal").unwrap().remove(0);
+        let segment = fs::read(segment).unwrap();
+        let (records, _) = records(&segment);
+        assert!(records.len() > 3);
+        assert!(records
+            .iter()
+            .all(|record| record.len() < 2 * LOAD_CHUNK_LEN));
+
+        let (_, entries, _) = Wal::open(dir.path()).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Load(data),
+                Entry::Write {
+                    revision: 2,
+                    now,
+                    ops
+                }
+            ]
+        );
+
+        // Wouldn't be read back
+        assert!(frame(&vec![0; MAX_RECORD_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = Header {
+            shard_id: 1,
+            kind: EngineKind::Ordered,
+            sync: SyncPolicy::Always,
+        };
+        let put = |key: &str| vec![Operation::Put(Bytes::from(key), Bytes::from("v"))];
+        let now = SystemTime::now();
+        let mut wal = Wal::create(dir.path(), header, &HashMap::new()).unwrap();
+        wal.append(1, now, &put("a")).unwrap();
+        assert_eq!(wal.roll().unwrap(), 2);
+        wal.append(2, now, &put("b")).unwrap();
+        drop(wal);
+
+        // Longer than a chunk, so the snapshot is split into LOAD records
+        let data: HashMap<_, _> = (0..25_000)
+            .map(|i| {
+                let record = Record {
+                    val: Bytes::from(vec![b'v'; 100]),
+                    create_revision: 1,
+                    mod_revision: 1,
+                    expires_at: None,
+                };
+                (Bytes::from(i.to_string()), record)
+            })
+            .collect();
+        write_snapshot(dir.path(), header, 2, 1, &data).unwrap();
+
+        // A damaged snapshot fails the recovery and is kept
+        let path = dir.path().join(format!("{:020}.snap", 2));
+        let len = path.metadata().unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+        assert!(Wal::open(dir.path()).is_err());
+        assert_eq!(files(dir.path(), "snap").unwrap().len(), 1);
+
+        write_snapshot(dir.path(), header, 2, 1, &data).unwrap();
+        truncate(dir.path(), 2);
+        assert_eq!(files(dir.path(), "wal").unwrap().len(), 1);
+        let (read, entries, mut wal) = Wal::open(dir.path()).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Snapshot { revision: 1, data },
+                Entry::Write {
+                    revision: 2,
+                    now,
+                    ops: put("b")
+                }
+            ]
+        );
+
+        // The next segment continues after the last one
+        assert_eq!(wal.roll().unwrap(), 3);
+    }
+}
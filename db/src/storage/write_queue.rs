@@ -0,0 +1,189 @@
+use super::engine::{EngineWriter, WriteError};
+use super::error::StorageError;
+use super::sync;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::sync::oneshot;
+
+// Upper bound for the number of writes published with a single swap
+const MAX_GROUP_SIZE: usize = 256;
+
+// Applies the write and returns its reply, which is sent once the group is published
+type Job = Box<dyn FnOnce(Result<&mut dyn EngineWriter, &WriteError>) -> Reply + Send>;
+type Reply = Box<dyn FnOnce() + Send>;
+
+enum Message {
+    Write(Job),
+    Stop,
+}
+
+/// Hands the writes for a shard to a dedicated thread, so an async handler never blocks a
+/// runtime thread on the writer lock or while the writer drains the readers.
+///
+/// The thread commits all writes that queued up while it was busy as a single group. Under load
+/// many writes share one swap and one drain instead of paying for them one by one.
+///
+/// A write that panics might leave the engine with part of it, so the queue fails for good: the
+/// thread exits and every later write fails with WriteFailed. The same goes for a writer that
+/// was poisoned by somebody else.
+#[derive(Clone)]
+pub struct WriteQueue {
+    shard_id: usize,
+    sender: Sender<Message>,
+    failed: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl WriteQueue {
+    /// Starts the writer thread. It runs until `stop` is called.
+    pub fn start(shard_id: usize, writer: Arc<Mutex<dyn EngineWriter>>) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let aborted = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let failed = failed.clone();
+            let aborted = aborted.clone();
+            thread::Builder::new()
+                .name(format!("shard-{}-writer", shard_id))
+                .spawn(move || Self::run(shard_id, writer, receiver, &failed, &aborted))
+                .unwrap_or_else(|_| panic!("Can't start writer thread for shard: {}", shard_id))
+        };
+
+        let queue = Self {
+            shard_id,
+            sender,
+            failed,
+            aborted,
+        };
+        (queue, thread)
+    }
+
+    /// True once a write has panicked, see WriteQueue. The shard doesn't accept writes anymore.
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Queues `f` to be executed on the writer thread. The write is sent right away, the returned
+    /// future only waits for it to be published to the readers.
+    ///
+    /// If `f` panics the write fails with WriteFailed, together with the rest of its group and all
+    /// later writes.
+    pub fn submit<T, F>(&self, f: F) -> impl Future<Output = Result<T, StorageError>>
+    where
+        F: FnOnce(&mut dyn EngineWriter) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let shard_id = self.shard_id;
+        let (sender, receiver) = oneshot::channel();
+        let failed = self.failed.clone();
+        let job: Job = Box::new(move |writer| {
+            let result = match writer {
+                Ok(writer) => match panic::catch_unwind(AssertUnwindSafe(|| f(writer))) {
+                    Ok(result) => Ok(result),
+                    Err(panic) => {
+                        // Set before any reply of the group is dropped, so they all fail with
+                        // WriteFailed. The panic goes on out of the group, which isn't published.
+                        failed.store(true, Ordering::SeqCst);
+                        panic::resume_unwind(panic)
+                    }
+                },
+                Err(err) => Err(StorageError::write(shard_id)(err.clone())),
+            };
+            let reply: Reply = Box::new(move || {
+                // The handler might have been cancelled, nothing to do then
+                let _ = sender.send(result);
+            });
+            reply
+        });
+        // If the thread has stopped the job is dropped together with its sender
+        if !self.failed() {
+            let _ = self.sender.send(Message::Write(job));
+        }
+
+        let failed = self.failed.clone();
+        async move {
+            receiver
+                .await
+                .unwrap_or_else(|_| match failed.load(Ordering::SeqCst) {
+                    true => Err(StorageError::WriteFailed(shard_id)),
+                    false => Err(StorageError::WriterStopped(shard_id)),
+                })
+        }
+    }
+
+    /// Asks the writer thread to exit after the writes that are already queued.
+    pub fn stop(&self) {
+        let _ = self.sender.send(Message::Stop);
+    }
+
+    /// Asks the writer thread to exit without applying the writes that are still queued, they
+    /// fail with WriterStopped. The thread checks it with the writer locked, so once the writer is
+    /// locked after this call the thread doesn't apply anything anymore.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.stop();
+    }
+
+    fn run(
+        shard_id: usize,
+        writer: Arc<Mutex<dyn EngineWriter>>,
+        receiver: Receiver<Message>,
+        failed: &AtomicBool,
+        aborted: &AtomicBool,
+    ) {
+        let mut stopped = false;
+        while !stopped {
+            let mut jobs = match receiver.recv() {
+                Ok(Message::Write(job)) => vec![job],
+                Ok(Message::Stop) | Err(_) => break,
+            };
+            // Everything that was queued while the last group was committed goes into this one
+            while jobs.len() < MAX_GROUP_SIZE {
+                match receiver.try_recv() {
+                    Ok(Message::Write(job)) => jobs.push(job),
+                    Ok(Message::Stop) => {
+                        stopped = true;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            if writer.is_poisoned() {
+                log::error!("The writer of shard {} is poisoned, it stops", shard_id);
+                failed.store(true, Ordering::SeqCst);
+                return;
+            }
+
+            let mut replies = Vec::with_capacity(jobs.len());
+            // A panic unwinds out of the group with the lock held, which poisons the writer
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                sync::lock(&*writer).group(&mut |writer| {
+                    for job in jobs.drain(..) {
+                        // A dropped job fails with WriterStopped
+                        if !aborted.load(Ordering::SeqCst) {
+                            replies.push(job(Ok(writer)));
+                        }
+                    }
+                })
+            }));
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => replies.extend(jobs.drain(..).map(|job| job(Err(&err)))),
+                Err(_) => {
+                    // None of the group is acknowledged, the dropped replies fail with WriteFailed
+                    log::error!("A write to shard {} panicked, it stops", shard_id);
+                    failed.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+            for reply in replies {
+                reply();
+            }
+        }
+    }
+}
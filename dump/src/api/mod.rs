@@ -0,0 +1,2 @@
+/// Auto-generated gRPC clients
+pub mod admin_api;
@@ -0,0 +1,262 @@
+//! The formats of a dump. Both hold the records of a single shard, one after the other.
+
+use crate::api::admin_api::ShardRecord;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+const MAGIC: &[u8; 9] = b"r_db dump";
+const VERSION: u8 = 1;
+// Takes the place of the length of a key after the last record
+const END: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A JSON object per line with the key and the value in base64, easy to inspect and to edit
+    Jsonl,
+    /// Length prefixed records behind a header. About half the size of JSONL.
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "jsonl" => Ok(Format::Jsonl),
+            "binary" => Ok(Format::Binary),
+            _ => Err(format!("Unknown format: {}", format)),
+        }
+    }
+}
+
+// A line of a JSONL dump
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    val: String,
+    create_revision: u64,
+    mod_revision: u64,
+    // Milliseconds since the Unix epoch, 0 if the record doesn't expire
+    #[serde(default, skip_serializing_if = "is_zero")]
+    expires_at_ms: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Writes the records of a shard in the given format.
+pub struct DumpWriter<W: Write> {
+    out: W,
+    format: Format,
+    count: usize,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub fn new(mut out: W, format: Format) -> io::Result<Self> {
+        if format == Format::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&[VERSION])?;
+        }
+        Ok(Self {
+            out,
+            format,
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, record: &ShardRecord) -> io::Result<()> {
+        match self.format {
+            Format::Jsonl => {
+                let line = JsonRecord {
+                    key: base64::encode(&record.key),
+                    val: base64::encode(&record.val),
+                    create_revision: record.create_revision,
+                    mod_revision: record.mod_revision,
+                    expires_at_ms: record.expires_at_ms,
+                };
+                serde_json::to_writer(&mut self.out, &line)?;
+                self.out.write_all(b"\n")?;
+            }
+            Format::Binary => {
+                write_bytes(&mut self.out, &record.key)?;
+                write_bytes(&mut self.out, &record.val)?;
+                self.out.write_all(&record.create_revision.to_le_bytes())?;
+                self.out.write_all(&record.mod_revision.to_le_bytes())?;
+                self.out.write_all(&record.expires_at_ms.to_le_bytes())?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Ends the dump and returns the number of records in it.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if self.format == Format::Binary {
+            self.out.write_all(&END.to_le_bytes())?;
+        }
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    let len: u32 = bytes
+        .len()
+        .try_into()
+        .ok()
+        .filter(|len| *len != END)
+        .ok_or_else(|| invalid("A key or a value is too long for the binary format"))?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+/// Reads a whole dump. The format is recognized by the header of the binary format.
+pub fn read(mut input: impl BufRead) -> io::Result<Vec<ShardRecord>> {
+    if input.fill_buf()?.starts_with(MAGIC) {
+        read_binary(input)
+    } else {
+        read_jsonl(input)
+    }
+}
+
+fn read_jsonl(input: impl BufRead) -> io::Result<Vec<ShardRecord>> {
+    let mut records = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |err: &dyn ToString| invalid(&format!("Line {}: {}", i + 1, err.to_string()));
+        let record: JsonRecord = serde_json::from_str(&line).map_err(|err| error(&err))?;
+        records.push(ShardRecord {
+            key: base64::decode(&record.key).map_err(|err| error(&err))?,
+            val: base64::decode(&record.val).map_err(|err| error(&err))?,
+            create_revision: record.create_revision,
+            mod_revision: record.mod_revision,
+            expires_at_ms: record.expires_at_ms,
+        });
+    }
+    Ok(records)
+}
+
+fn read_binary(mut input: impl Read) -> io::Result<Vec<ShardRecord>> {
+    let mut header = [0; MAGIC.len() + 1];
+    input.read_exact(&mut header)?;
+    if header[MAGIC.len()] != VERSION {
+        return Err(invalid(&format!(
+            "Unknown dump version: {}",
+            header[MAGIC.len()]
+        )));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let len = read_u32(&mut input)?;
+        if len == END {
+            return Ok(records);
+        }
+        let key = read_exact(&mut input, len)?;
+        let len = read_u32(&mut input)?;
+        records.push(ShardRecord {
+            key,
+            val: read_exact(&mut input, len)?,
+            create_revision: read_u64(&mut input)?,
+            mod_revision: read_u64(&mut input)?,
+            expires_at_ms: read_u64(&mut input)?,
+        });
+    }
+}
+
+// A dump that ends early is truncated, not empty
+fn truncated(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("The dump is truncated"),
+        _ => err,
+    }
+}
+
+fn read_exact(input: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    input.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(invalid("The dump is truncated"));
+    }
+    Ok(buf)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf).map_err(truncated)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf).map_err(truncated)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, DumpWriter, Format};
+    use crate::api::admin_api::ShardRecord;
+
+    fn records() -> Vec<ShardRecord> {
+        vec![
+            ShardRecord {
+                key: b"a".to_vec(),
+                val: vec![0, 255, b'\n'],
+                create_revision: 1,
+                mod_revision: 4,
+                expires_at_ms: 0,
+            },
+            ShardRecord {
+                key: "κλειδί".as_bytes().to_vec(),
+                val: Vec::new(),
+                create_revision: 2,
+                mod_revision: 2,
+                expires_at_ms: 1_600_000_000_000,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in [Format::Jsonl, Format::Binary].iter().copied() {
+            let mut out = Vec::new();
+            let mut writer = DumpWriter::new(&mut out, format).unwrap();
+            for record in records() {
+                writer.write(&record).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), 2);
+            assert_eq!(read(&out[..]).unwrap(), records());
+
+            // Without the end of the binary format or in the middle of a line
+            out.truncate(out.len() - 3);
+            assert!(read(&out[..]).is_err());
+        }
+
+        let empty = DumpWriter::new(Vec::new(), Format::Binary).unwrap();
+        assert_eq!(empty.count, 0);
+        assert_eq!(read(&b""[..]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let line = r#"{"key":"YQ==","val":"Yg==","create_revision":1,"mod_revision":1}"#;
+        let read = read(line.as_bytes()).unwrap();
+        assert_eq!(read[0].key, b"a");
+        assert_eq!(read[0].val, b"b");
+        assert_eq!(read[0].expires_at_ms, 0);
+
+        let err = super::read(&b"{}\n"[..]).unwrap_err();
+        assert!(err.to_string().starts_with("Line 1: "));
+    }
+}
@@ -0,0 +1,316 @@
+#![warn(clippy::all)]
+
+mod api;
+mod format;
+
+use api::admin_api::admin_client::AdminClient;
+use api::admin_api::{self as admin, ExportShardRequest, ImportShardRequest};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use format::{DumpWriter, Format};
+use r_db::admin::{export_record, import_record};
+use r_db::storage::engine::EngineKind;
+use r_db::storage::shard_map::ShardMap;
+use r_db::storage::wal::{self, SyncPolicy};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use tonic::transport::Channel;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+// Number of records in a single message of an import, the same as the chunks of an export
+const IMPORT_CHUNK_SIZE: usize = 1000;
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Copies the records of shards out of a node into files and back.
+async fn run() -> Result<()> {
+    let addr = Arg::with_name("addr")
+        .long("addr")
+        .takes_value(true)
+        .help("The node to talk to, e.g. http://127.0.0.1:10000");
+    let shard = Arg::with_name("shard")
+        .long("shard")
+        .takes_value(true)
+        .help("The id of the shard");
+    let matches = App::new("r_db-dump")
+        .about("Dumps the records of shards to files and restores them")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Writes the records of a shard of a running node or of a snapshot file")
+                .arg(addr.clone().required_unless_one(&["snapshot"]))
+                .arg(shard.clone().conflicts_with("all"))
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .requires_all(&["addr", "output"])
+                        .help("Dumps every shard of the node into shard-{id}.{format} in --output"),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .conflicts_with("addr")
+                        .help("A {segment}.snap file from the data directory of a node"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["jsonl", "binary"])
+                        .default_value("jsonl"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("The file, or the directory with --all. Stdout by default."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Loads a dump into a shard of a running node or of a stopped node's data")
+                .arg(addr.required_unless_one(&["data-dir"]))
+                .arg(shard.required(true))
+                .arg(
+                    Arg::with_name("data-dir")
+                        .long("data-dir")
+                        .takes_value(true)
+                        .conflicts_with("addr")
+                        .help("The data directory of a node that isn't running"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .takes_value(true)
+                        .help("A dump in either format. Stdin by default."),
+                )
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .takes_value(true)
+                        .possible_values(&["left-right", "locked", "ordered", "striped"])
+                        .default_value("left-right"),
+                )
+                .arg(
+                    Arg::with_name("wal")
+                        .long("wal")
+                        .takes_value(true)
+                        .possible_values(&["none", "always", "interval", "never"])
+                        .help("How the log of the shard is synced. Always with --data-dir."),
+                )
+                .arg(
+                    Arg::with_name("sync-interval-ms")
+                        .long("sync-interval-ms")
+                        .takes_value(true)
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("Replaces the shard if it exists"),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("dump", Some(matches)) => dump(matches).await,
+        ("restore", Some(matches)) => restore(matches).await,
+        _ => unreachable!(),
+    }
+}
+
+async fn dump(matches: &ArgMatches<'_>) -> Result<()> {
+    let format: Format = matches.value_of("format").unwrap().parse()?;
+    let output = matches.value_of("output");
+
+    if let Some(snapshot) = matches.value_of("snapshot") {
+        // Reading the file doesn't change it, so the node can keep running
+        let (header, revision, data) = wal::read_snapshot(Path::new(snapshot))?;
+        let mut records: Vec<_> = data.into_iter().collect();
+        records.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut out = DumpWriter::new(open_output(output)?, format)?;
+        for record in records {
+            out.write(&export_record(record))?;
+        }
+        let count = out.finish()?;
+        eprintln!(
+            "Dumped {} records of shard {} at revision {}",
+            count, header.shard_id, revision
+        );
+        return Ok(());
+    }
+
+    let mut client = AdminClient::connect(matches.value_of("addr").unwrap().to_string()).await?;
+    if !matches.is_present("all") {
+        let shard_id = parse_shard(matches)?;
+        let count = export(&mut client, shard_id as i64, open_output(output)?, format).await?;
+        eprintln!("Dumped {} records of shard {}", count, shard_id);
+        return Ok(());
+    }
+
+    let dir = Path::new(output.unwrap());
+    fs::create_dir_all(dir)?;
+    let shards = client
+        .list_shards(admin::ListShardsRequest {})
+        .await?
+        .into_inner()
+        .shards;
+    let extension = match format {
+        Format::Jsonl => "jsonl",
+        Format::Binary => "bin",
+    };
+    for shard in shards {
+        let path = dir.join(format!("shard-{}.{}", shard.shard_id, extension));
+        let out = BufWriter::new(File::create(&path)?);
+        let count = export(&mut client, shard.shard_id, out, format).await?;
+        eprintln!(
+            "Dumped {} records of shard {} into {}",
+            count,
+            shard.shard_id,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+// Streams the records of the shard into the dump. They all come from a single snapshot of it.
+async fn export(
+    client: &mut AdminClient<Channel>,
+    shard_id: i64,
+    out: impl Write,
+    format: Format,
+) -> Result<usize> {
+    let mut stream = client
+        .export_shard(ExportShardRequest { shard_id })
+        .await?
+        .into_inner();
+    let mut out = DumpWriter::new(out, format)?;
+    while let Some(message) = stream.message().await? {
+        for record in &message.records {
+            out.write(record)?;
+        }
+    }
+    Ok(out.finish()?)
+}
+
+async fn restore(matches: &ArgMatches<'_>) -> Result<()> {
+    let shard_id = parse_shard(matches)?;
+    let replace = matches.is_present("replace");
+    let engine = matches.value_of("engine").unwrap();
+    let sync_interval_ms: u64 = matches.value_of("sync-interval-ms").unwrap().parse()?;
+    let records = match matches.value_of("input") {
+        Some(input) => format::read(BufReader::new(File::open(input)?))?,
+        None => format::read(io::stdin().lock())?,
+    };
+    let count = records.len();
+
+    if let Some(data_dir) = matches.value_of("data-dir") {
+        // The shard would be gone once this process exits without a log
+        let sync = match matches.value_of("wal").unwrap_or("always") {
+            "always" => SyncPolicy::Always,
+            "interval" => SyncPolicy::Interval(Duration::from_millis(sync_interval_ms)),
+            "never" => SyncPolicy::Never,
+            _ => return Err("A restore into a data directory needs a --wal".into()),
+        };
+        let kind = match engine {
+            "locked" => EngineKind::Locked,
+            "ordered" => EngineKind::Ordered,
+            "striped" => EngineKind::Striped,
+            _ => EngineKind::LeftRight,
+        };
+        let mut data = HashMap::with_capacity(count);
+        for record in records {
+            let (key, record) = import_record(record)?;
+            if data.insert(key, record).is_some() {
+                return Err("Duplicate key in the dump".into());
+            }
+        }
+
+        // Builds the shard with with_data and writes its log next to the other shards, which are
+        // left alone. Fails if a node is running on the directory.
+        ShardMap::install_offline(data_dir, shard_id, kind, sync, data, replace)?;
+        eprintln!(
+            "Restored {} records into shard {} in {}",
+            count, shard_id, data_dir
+        );
+        return Ok(());
+    }
+
+    let engine = match engine {
+        "locked" => admin::Engine::Locked,
+        "ordered" => admin::Engine::Ordered,
+        "striped" => admin::Engine::Striped,
+        _ => admin::Engine::LeftRight,
+    };
+    let wal = match matches.value_of("wal").unwrap_or("none") {
+        "always" => admin::Wal::SyncAlways,
+        "interval" => admin::Wal::SyncInterval,
+        "never" => admin::Wal::SyncNever,
+        _ => admin::Wal::None,
+    };
+    // The first message names the shard, even if there are no records
+    let mut chunks: Vec<_> = records
+        .chunks(IMPORT_CHUNK_SIZE)
+        .map(<[_]>::to_vec)
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+    let messages: Vec<_> = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, records)| match i {
+            0 => ImportShardRequest {
+                shard_id: shard_id as i64,
+                engine: engine as i32,
+                replace,
+                records,
+                wal: wal as i32,
+                sync_interval_ms,
+            },
+            _ => ImportShardRequest {
+                records,
+                ..Default::default()
+            },
+        })
+        .collect();
+
+    let mut client = AdminClient::connect(matches.value_of("addr").unwrap().to_string()).await?;
+    let response = client
+        .import_shard(tokio::stream::iter(messages))
+        .await?
+        .into_inner();
+    eprintln!(
+        "Restored {} records into shard {}",
+        response.key_count, shard_id
+    );
+    Ok(())
+}
+
+fn parse_shard(matches: &ArgMatches<'_>) -> Result<usize> {
+    let shard = matches
+        .value_of("shard")
+        .ok_or("Missing --shard or --all")?;
+    Ok(shard
+        .parse()
+        .map_err(|_| format!("Invalid shard id: {}", shard))?)
+}
+
+fn open_output(output: Option<&str>) -> Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
@@ -1,2 +1,3 @@
 /// Auto-generated gRPC services
+pub mod admin_api;
 pub mod storage_api;
@@ -2,33 +2,384 @@
 pub struct PutRequest {
     #[prost(int64, tag = "1")]
     pub shard_id: i64,
-    #[prost(string, tag = "2")]
-    pub key: std::string::String,
-    #[prost(string, tag = "3")]
-    pub val: std::string::String,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(bool, tag = "4")]
+    pub return_previous: bool,
+    /// The put is applied only if the key's mod revision matches. 0 disables the check.
+    #[prost(uint64, tag = "5")]
+    pub expected_revision: u64,
+    /// The key expires after this many milliseconds. 0 means that it never expires.
+    #[prost(uint64, tag = "6")]
+    pub ttl_ms: u64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct PutResponse {}
+pub struct PutResponse {
+    /// The replaced value. Only set when return_previous is true and the key existed.
+    #[prost(message, optional, tag = "1")]
+    pub previous: ::std::option::Option<Value>,
+    /// The revision of the shard after the put
+    #[prost(uint64, tag = "2")]
+    pub revision: u64,
+}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct DeleteRequest {
     #[prost(int64, tag = "1")]
     pub shard_id: i64,
-    #[prost(string, tag = "2")]
-    pub key: std::string::String,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bool, tag = "3")]
+    pub return_previous: bool,
+    /// The delete is applied only if the key's mod revision matches. 0 disables the check.
+    #[prost(uint64, tag = "4")]
+    pub expected_revision: u64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct DeleteResponse {}
+pub struct DeleteResponse {
+    /// The deleted value. Only set when return_previous is true and the key existed.
+    #[prost(message, optional, tag = "1")]
+    pub previous: ::std::option::Option<Value>,
+    /// The revision of the shard after the delete
+    #[prost(uint64, tag = "2")]
+    pub revision: u64,
+}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetRequest {
     #[prost(int64, tag = "1")]
     pub shard_id: i64,
-    #[prost(string, tag = "2")]
-    pub key: std::string::String,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetResponse {
-    #[prost(string, tag = "1")]
-    pub val: std::string::String,
+    #[prost(bytes, tag = "1")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    pub create_revision: u64,
+    #[prost(uint64, tag = "3")]
+    pub mod_revision: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PutOperation {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+    /// The key expires after this many milliseconds. 0 means that it never expires.
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteOperation {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Operation {
+    #[prost(oneof = "operation::Operation", tags = "1, 2")]
+    pub operation: ::std::option::Option<operation::Operation>,
+}
+pub mod operation {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Operation {
+        #[prost(message, tag = "1")]
+        Put(super::PutOperation),
+        #[prost(message, tag = "2")]
+        Delete(super::DeleteOperation),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WriteBatchRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(message, repeated, tag = "2")]
+    pub ops: ::std::vec::Vec<Operation>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WriteBatchResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExistsCondition {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NotExistsCondition {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EqualsCondition {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+}
+/// The key's mod revision must match. 0 means that the key must not exist.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RevisionCondition {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    pub revision: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Precondition {
+    #[prost(oneof = "precondition::Condition", tags = "1, 2, 3, 4")]
+    pub condition: ::std::option::Option<precondition::Condition>,
+}
+pub mod precondition {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Condition {
+        #[prost(message, tag = "1")]
+        Exists(super::ExistsCondition),
+        #[prost(message, tag = "2")]
+        NotExists(super::NotExistsCondition),
+        #[prost(message, tag = "3")]
+        Equals(super::EqualsCondition),
+        #[prost(message, tag = "4")]
+        Revision(super::RevisionCondition),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransactionRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(message, repeated, tag = "2")]
+    pub preconditions: ::std::vec::Vec<Precondition>,
+    #[prost(message, repeated, tag = "3")]
+    pub ops: ::std::vec::Vec<Operation>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransactionResponse {
+    #[prost(bool, tag = "1")]
+    pub succeeded: bool,
+    /// Index of the first precondition that did not hold. Only set when succeeded is false.
+    #[prost(uint32, tag = "2")]
+    pub failed_precondition: u32,
+}
+/// Wraps a value so that a missing key can be told apart from an empty value
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Value {
+    #[prost(bytes, tag = "1")]
+    pub val: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CompareAndSwapRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub expected: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "4")]
+    pub new: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CompareAndSwapResponse {
+    #[prost(bool, tag = "1")]
+    pub applied: bool,
+    /// The value stored after the call. Missing if the key does not exist.
+    #[prost(message, optional, tag = "2")]
+    pub current: ::std::option::Option<Value>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PutIfAbsentRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub val: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PutIfAbsentResponse {
+    #[prost(bool, tag = "1")]
+    pub applied: bool,
+    /// The value stored after the call
+    #[prost(message, optional, tag = "2")]
+    pub current: ::std::option::Option<Value>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteIfEqualsRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub expected: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteIfEqualsResponse {
+    #[prost(bool, tag = "1")]
+    pub applied: bool,
+    /// The value stored after the call. Missing if the key does not exist.
+    #[prost(message, optional, tag = "2")]
+    pub current: ::std::option::Option<Value>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExpireRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    /// The key expires after this many milliseconds. 0 makes the key persistent.
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExpireResponse {
+    /// False if the key does not exist
+    #[prost(bool, tag = "1")]
+    pub applied: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TtlRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TtlResponse {
+    /// Milliseconds left before the key expires. -1 if the key never expires.
+    #[prost(int64, tag = "1")]
+    pub ttl_ms: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyValue {
+    #[prost(bytes, tag = "1")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub create_revision: u64,
+    #[prost(uint64, tag = "4")]
+    pub mod_revision: u64,
+}
+/// Returns the keys in [start, end) in key order. An empty end means no upper bound.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub start: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub end: std::vec::Vec<u8>,
+    /// Maximum number of keys in the page. 0 means the default page size.
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+    /// The next_page_token of the previous page
+    #[prost(bytes, tag = "5")]
+    pub page_token: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PrefixRequest {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub prefix: std::vec::Vec<u8>,
+    /// Maximum number of keys in the page. 0 means the default page size.
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+    /// The next_page_token of the previous page
+    #[prost(bytes, tag = "4")]
+    pub page_token: std::vec::Vec<u8>,
+}
+/// A page is streamed in chunks. All chunks of a page are read from the same snapshot.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::std::vec::Vec<KeyValue>,
+    /// Only set on the last chunk and only if there might be more keys. A page can be shorter than
+    /// the limit, even empty, and still have a next page.
+    #[prost(bytes, tag = "2")]
+    pub next_page_token: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShardKey {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShardKeyValue {
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, tag = "2")]
+    pub key: std::vec::Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub val: std::vec::Vec<u8>,
+}
+/// The keys can belong to different shards. All keys of a shard are read from a single snapshot of it.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiGetRequest {
+    /// The keys of a single shard, the original form of the request. Only one of the two forms can
+    /// be used in a request.
+    #[prost(int64, tag = "1")]
+    pub shard_id: i64,
+    #[prost(bytes, repeated, tag = "2")]
+    pub keys: ::std::vec::Vec<std::vec::Vec<u8>>,
+    #[prost(message, repeated, tag = "3")]
+    pub shard_keys: ::std::vec::Vec<ShardKey>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetResult {
+    #[prost(bool, tag = "1")]
+    pub found: bool,
+    #[prost(bytes, tag = "2")]
+    pub val: std::vec::Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub create_revision: u64,
+    #[prost(uint64, tag = "4")]
+    pub mod_revision: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiGetResponse {
+    /// One result for every requested key in the same order
+    #[prost(message, repeated, tag = "1")]
+    pub results: ::std::vec::Vec<GetResult>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WriteResult {
+    /// Whether the key existed before the write
+    #[prost(bool, tag = "1")]
+    pub existed: bool,
+    /// The replaced or deleted value. Only set when return_previous is true and the key existed.
+    #[prost(message, optional, tag = "2")]
+    pub previous: ::std::option::Option<Value>,
+}
+/// The entries can belong to different shards. The entries of a shard are applied with a single swap
+/// so they become visible together, but there is no atomicity across shards.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiPutRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::std::vec::Vec<ShardKeyValue>,
+    #[prost(bool, tag = "2")]
+    pub return_previous: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiPutResponse {
+    /// One result for every entry in the same order
+    #[prost(message, repeated, tag = "1")]
+    pub results: ::std::vec::Vec<WriteResult>,
+}
+/// The keys can belong to different shards. The keys of a shard are deleted with a single swap
+/// so they disappear together, but there is no atomicity across shards.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiDeleteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: ::std::vec::Vec<ShardKey>,
+    #[prost(bool, tag = "2")]
+    pub return_previous: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiDeleteResponse {
+    /// One result for every key in the same order
+    #[prost(message, repeated, tag = "1")]
+    pub results: ::std::vec::Vec<WriteResult>,
 }
 #[doc = r" Generated server implementations."]
 pub mod storage_client {
@@ -101,6 +452,180 @@ pub mod storage_client {
             let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Delete");
             self.inner.unary(request.into_request(), path, codec).await
         }
+        pub async fn write_batch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::WriteBatchRequest>,
+        ) -> Result<tonic::Response<super::WriteBatchResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/WriteBatch");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn transaction(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TransactionRequest>,
+        ) -> Result<tonic::Response<super::TransactionResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Transaction");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn compare_and_swap(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CompareAndSwapRequest>,
+        ) -> Result<tonic::Response<super::CompareAndSwapResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/CompareAndSwap");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn put_if_absent(
+            &mut self,
+            request: impl tonic::IntoRequest<super::PutIfAbsentRequest>,
+        ) -> Result<tonic::Response<super::PutIfAbsentResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/PutIfAbsent");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn delete_if_equals(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DeleteIfEqualsRequest>,
+        ) -> Result<tonic::Response<super::DeleteIfEqualsResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/DeleteIfEquals");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn expire(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ExpireRequest>,
+        ) -> Result<tonic::Response<super::ExpireResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Expire");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn ttl(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TtlRequest>,
+        ) -> Result<tonic::Response<super::TtlResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Ttl");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn scan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ScanRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::ScanResponse>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Scan");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        pub async fn prefix(
+            &mut self,
+            request: impl tonic::IntoRequest<super::PrefixRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::ScanResponse>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/Prefix");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        pub async fn multi_get(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MultiGetRequest>,
+        ) -> Result<tonic::Response<super::MultiGetResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/MultiGet");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn multi_put(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MultiPutRequest>,
+        ) -> Result<tonic::Response<super::MultiPutResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/MultiPut");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn multi_delete(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MultiDeleteRequest>,
+        ) -> Result<tonic::Response<super::MultiDeleteResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/storage_api.Storage/MultiDelete");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
     }
     impl<T: Clone> Clone for StorageClient<T> {
         fn clone(&self) -> Self {
@@ -1,13 +1,50 @@
+use std::fs;
+
 fn main() {
     build_clients();
     build_servers();
+    build_replication();
+    build_dump();
 }
 
 fn build_servers() {
     tonic_build::configure()
         .build_client(false)
         .out_dir("db/src/api")
-        .compile(&["proto/storage-api.proto"], &["proto"])
+        .compile(
+            &["proto/storage-api.proto", "proto/admin-api.proto"],
+            &["proto"],
+        )
+        .expect("Failed to compile protos");
+    // The server encodes the value of a get from the buffer of the shard
+    replace_message(
+        "db/src/api/storage_api.rs",
+        "GetResponse",
+        "super::raw::GetResponse",
+    );
+}
+
+// Replaces a generated message with a hand-written one, see db/src/api/raw.rs
+fn replace_message(path: &str, name: &str, with: &str) {
+    let code = fs::read_to_string(path).expect("Failed to read the generated code");
+    let header = format!(
+        "#[derive(Clone, PartialEq, ::prost::Message)]\npub struct {} {{\n",
+        name
+    );
+    let start = code.find(&header).expect("Message not found");
+    let end = start + code[start..].find("\n}\n").expect("Message not closed") + 3;
+    let code = format!("{}pub use {};\n{}", &code[..start], with, &code[end..]);
+    fs::write(path, code).expect("Failed to write the generated code");
+}
+
+// The nodes are both the servers and the clients of the replication and of the Raft groups
+fn build_replication() {
+    tonic_build::configure()
+        .out_dir("db/src/api")
+        .compile(
+            &["proto/replication-api.proto", "proto/raft-api.proto"],
+            &["proto"],
+        )
         .expect("Failed to compile protos");
 }
 
@@ -15,6 +52,23 @@ fn build_clients() {
     tonic_build::configure()
         .build_server(false)
         .out_dir("front-end/src/api")
-        .compile(&["proto/storage-api.proto"], &["proto"])
+        .compile(
+            &["proto/storage-api.proto", "proto/admin-api.proto"],
+            &["proto"],
+        )
+        .expect("Failed to compile protos");
+}
+
+fn build_dump() {
+    tonic_build::configure()
+        .build_server(false)
+        .out_dir("dump/src/api")
+        .compile(&["proto/admin-api.proto"], &["proto"])
         .expect("Failed to compile protos");
+    // The records are converted by r_db::admin, the same as on the node
+    replace_message(
+        "dump/src/api/admin_api.rs",
+        "ShardRecord",
+        "r_db::api::admin_api::ShardRecord",
+    );
 }